@@ -1,59 +1,856 @@
-use std::{cmp::Ordering, collections::BTreeMap};
+use std::{
+    any::Any,
+    borrow::Cow,
+    cell::{RefCell, RefMut},
+    cmp::Ordering,
+    collections::{BTreeMap, HashSet},
+    fmt,
+    ops::{Bound, Deref},
+    rc::Rc,
+};
 
-use crate::vm::VirtualMachine;
+use crate::{
+    coroutine::{Coroutine, ThreadRef},
+    error::LuaError,
+    lex::Span,
+    vm::{VariableName, VirtualMachine},
+};
 
-#[derive(Debug, Clone, PartialEq, PartialOrd)]
+pub type TableRef = Rc<RefCell<Table>>;
+pub type UserDataRef = Rc<UserData>;
+pub type Variable = Rc<RefCell<EvalValue>>;
+pub type NativeFunction =
+    Rc<dyn Fn(&mut VirtualMachine, Vec<EvalValue>) -> Result<Vec<EvalValue>, LuaError>>;
+
+/// A Lua string: an immutable sequence of bytes, usually but not necessarily UTF-8.
+/// Strings built in a `Vec` become Lua strings without being copied.
+#[derive(Clone, Default, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub struct LuaString(Rc<Vec<u8>>);
+
+impl LuaString {
+    /// The string as text, with invalid UTF-8 sequences replaced.
+    pub fn to_str_lossy(&self) -> Cow<'_, str> {
+        String::from_utf8_lossy(&self.0)
+    }
+
+    /// Makes room for `additional` more bytes in `buffer`, a string being built. Lua
+    /// strings can be as large as memory allows, so running out of memory for one is a
+    /// Lua error instead of aborting the process.
+    pub fn reserve(buffer: &mut Vec<u8>, additional: usize) -> Result<(), LuaError> {
+        buffer
+            .try_reserve(additional)
+            .map_err(|_| LuaError::memory())
+    }
+
+    /// The string made of `parts` one after another.
+    pub fn concat(parts: &[&[u8]]) -> Result<LuaString, LuaError> {
+        let mut bytes = Vec::new();
+        LuaString::reserve(&mut bytes, parts.iter().map(|part| part.len()).sum())?;
+        for part in parts {
+            bytes.extend_from_slice(part);
+        }
+
+        Ok(bytes.into())
+    }
+}
+
+impl Deref for LuaString {
+    type Target = [u8];
+
+    fn deref(&self) -> &[u8] {
+        &self.0
+    }
+}
+
+impl From<&[u8]> for LuaString {
+    fn from(bytes: &[u8]) -> Self {
+        bytes.to_vec().into()
+    }
+}
+
+impl From<Vec<u8>> for LuaString {
+    fn from(bytes: Vec<u8>) -> Self {
+        LuaString(Rc::new(bytes))
+    }
+}
+
+impl From<&str> for LuaString {
+    fn from(string: &str) -> Self {
+        string.as_bytes().into()
+    }
+}
+
+impl From<String> for LuaString {
+    fn from(string: String) -> Self {
+        string.into_bytes().into()
+    }
+}
+
+impl fmt::Display for LuaString {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(&self.to_str_lossy())
+    }
+}
+
+impl fmt::Debug for LuaString {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{:?}", self.to_str_lossy())
+    }
+}
+
+#[derive(Clone)]
 pub enum EvalValue {
+    /// Numbers are floats or integers; both have the Lua type `number`.
     Number(f64),
+    Integer(i64),
 
     Boolean(bool),
-    String(String),
+    String(LuaString),
     Nil,
 
-    NativeFunction(fn(Vec<EvalValue>) -> Result<EvalValue, String>),
-    DeclaredFunction {
-        arguments: Vec<String>,
-        body: Vec<Statement>,
-    },
-    Table(BTreeMap<EvalValue, EvalValue>),
-
-    Void, // For internal use, the return value of a statement that doesn't return anything
+    NativeFunction(NativeFunction),
+    DeclaredFunction(Rc<LuaFunction>),
+    Table(TableRef),
+    Thread(ThreadRef),
+    UserData(UserDataRef),
 }
+
 impl EvalValue {
-    fn is_true(&self) -> bool {
+    pub fn native_function(
+        function: impl Fn(&mut VirtualMachine, Vec<EvalValue>) -> Result<Vec<EvalValue>, LuaError>
+            + 'static,
+    ) -> Self {
+        EvalValue::NativeFunction(Rc::new(function))
+    }
+
+    pub fn string(string: impl Into<LuaString>) -> Self {
+        EvalValue::String(string.into())
+    }
+
+    pub fn is_true(&self) -> bool {
         !matches!(self, EvalValue::Nil | EvalValue::Boolean(false))
     }
+
+    /// Converts numbers and numeric strings to a number value, keeping integers and
+    /// floats apart, as arithmetic does.
+    pub fn to_numeric(&self) -> Option<EvalValue> {
+        match self {
+            EvalValue::Number(_) | EvalValue::Integer(_) => Some(self.clone()),
+            EvalValue::String(s) => std::str::from_utf8(s).ok().and_then(string_to_number),
+            _ => None,
+        }
+    }
+
+    /// Converts numbers and numeric strings to a float.
+    pub fn to_number(&self) -> Option<f64> {
+        match self.to_numeric()? {
+            EvalValue::Integer(i) => Some(i as f64),
+            EvalValue::Number(n) => Some(n),
+            _ => None,
+        }
+    }
+
+    /// Converts numbers and numeric strings with an exact integer value to an integer.
+    pub fn to_integer(&self) -> Option<i64> {
+        match self.to_numeric()? {
+            EvalValue::Integer(i) => Some(i),
+            EvalValue::Number(n) => float_to_integer(n),
+            _ => None,
+        }
+    }
+
+    /// Converts strings and numbers to a string, as concatenation does.
+    pub fn to_lua_string(&self) -> Option<LuaString> {
+        match self {
+            EvalValue::String(s) => Some(s.clone()),
+            EvalValue::Number(n) => Some(number_to_string(*n).into()),
+            EvalValue::Integer(i) => Some(i.to_string().into()),
+            _ => None,
+        }
+    }
+
+    pub fn type_name(&self) -> &'static str {
+        match self {
+            EvalValue::Number(_) | EvalValue::Integer(_) => "number",
+            EvalValue::Boolean(_) => "boolean",
+            EvalValue::String(_) => "string",
+            EvalValue::Nil => "nil",
+            EvalValue::NativeFunction(_) | EvalValue::DeclaredFunction(_) => "function",
+            EvalValue::Table(_) => "table",
+            EvalValue::Thread(_) => "thread",
+            EvalValue::UserData(_) => "userdata",
+        }
+    }
+
+    fn type_rank(&self) -> u8 {
+        match self {
+            EvalValue::Nil => 0,
+            EvalValue::Boolean(_) => 1,
+            EvalValue::Number(_) | EvalValue::Integer(_) => 2,
+            EvalValue::String(_) => 3,
+            EvalValue::Table(_) => 4,
+            EvalValue::DeclaredFunction(_) => 5,
+            EvalValue::NativeFunction(_) => 6,
+            EvalValue::Thread(_) => 7,
+            EvalValue::UserData(_) => 8,
+        }
+    }
+}
+
+/// Formats a float the way `tostring` does: `%.14g`, with `.0` appended when the
+/// result would otherwise look like an integer.
+pub fn number_to_string(n: f64) -> String {
+    let mut text = format_general(n);
+    if text.bytes().all(|c| c.is_ascii_digit() || c == b'-') {
+        text.push_str(".0");
+    }
+    text
+}
+
+/// Formats a number the way Lua's `%.14g` does.
+pub fn format_general(n: f64) -> String {
+    if n.is_nan() {
+        return if n.is_sign_negative() { "-nan" } else { "nan" }.to_string();
+    }
+    if n.is_infinite() {
+        return if n > 0.0 { "inf" } else { "-inf" }.to_string();
+    }
+    if n == 0.0 {
+        return if n.is_sign_negative() { "-0" } else { "0" }.to_string();
+    }
+
+    let scientific = format!("{:.13e}", n);
+    let (_, exponent) = scientific
+        .split_once('e')
+        .expect("Exponent is always present");
+    let exponent: i32 = exponent.parse().expect("Exponent is a valid integer");
+
+    fn trim_fraction(digits: &str) -> &str {
+        if digits.contains('.') {
+            digits.trim_end_matches('0').trim_end_matches('.')
+        } else {
+            digits
+        }
+    }
+
+    if (-4..14).contains(&exponent) {
+        let fixed = format!("{:.*}", (13 - exponent) as usize, n);
+        trim_fraction(&fixed).to_string()
+    } else {
+        let (mantissa, _) = scientific
+            .split_once('e')
+            .expect("Exponent is always present");
+        let sign = if exponent < 0 { '-' } else { '+' };
+        format!("{}e{}{:02}", trim_fraction(mantissa), sign, exponent.abs())
+    }
+}
+
+/// Converts a float with an exact integer value to an integer.
+pub fn float_to_integer(n: f64) -> Option<i64> {
+    // Casting saturates, so the bounds are checked against the float range
+    if n.fract() == 0.0 && n >= -(2f64.powi(63)) && n < 2f64.powi(63) {
+        Some(n as i64)
+    } else {
+        None
+    }
+}
+
+/// Compares an integer with a float exactly, without rounding either of them.
+fn compare_integer_float(i: i64, f: f64) -> Option<Ordering> {
+    if f.is_nan() {
+        None
+    } else if f >= 2f64.powi(63) {
+        Some(Ordering::Less)
+    } else if f < -(2f64.powi(63)) {
+        Some(Ordering::Greater)
+    } else {
+        let floor = f.floor();
+        match i.cmp(&(floor as i64)) {
+            Ordering::Equal if f > floor => Some(Ordering::Less),
+            ordering => Some(ordering),
+        }
+    }
+}
+
+/// Compares two numbers, returning `None` if either of them is NaN or is not a number.
+pub fn compare_numbers(left: &EvalValue, right: &EvalValue) -> Option<Ordering> {
+    match (left, right) {
+        (EvalValue::Integer(l), EvalValue::Integer(r)) => Some(l.cmp(r)),
+        (EvalValue::Number(l), EvalValue::Number(r)) => l.partial_cmp(r),
+        (EvalValue::Integer(l), EvalValue::Number(r)) => compare_integer_float(*l, *r),
+        (EvalValue::Number(l), EvalValue::Integer(r)) => {
+            compare_integer_float(*r, *l).map(Ordering::reverse)
+        }
+        _ => None,
+    }
+}
+
+/// Orders two values for the comparison operators: numbers by value and strings byte by
+/// byte. Comparisons involving NaN have no ordering, other values cannot be compared.
+pub fn compare_values(left: &EvalValue, right: &EvalValue) -> Result<Option<Ordering>, LuaError> {
+    match (left, right) {
+        (
+            EvalValue::Number(_) | EvalValue::Integer(_),
+            EvalValue::Number(_) | EvalValue::Integer(_),
+        ) => Ok(compare_numbers(left, right)),
+        (EvalValue::String(l), EvalValue::String(r)) => Ok(Some(l.cmp(r))),
+        _ if left.type_name() == right.type_name() => Err(LuaError::type_mismatch(format!(
+            "attempt to compare two {} values",
+            left.type_name()
+        ))),
+        _ => Err(LuaError::type_mismatch(format!(
+            "attempt to compare {} with {}",
+            left.type_name(),
+            right.type_name()
+        ))),
+    }
+}
+
+/// Applies an arithmetic operator to two numbers. Operations on two integers produce
+/// an integer and wrap around on overflow, except for `/` and `^`, which always produce
+/// a float. Integer `//` and `%` round towards minus infinity.
+fn arithmetic(operator: &str, left: &EvalValue, right: &EvalValue) -> Result<EvalValue, LuaError> {
+    match (operator, left, right) {
+        ("/" | "^", ..) => {}
+        ("//" | "%", EvalValue::Integer(_), EvalValue::Integer(0)) => {
+            return Err(LuaError::runtime(format!(
+                "attempt to perform 'n{}0'",
+                operator
+            )))
+        }
+        (_, EvalValue::Integer(l), EvalValue::Integer(r)) => {
+            return Ok(EvalValue::Integer(match operator {
+                "+" => l.wrapping_add(*r),
+                "-" => l.wrapping_sub(*r),
+                "*" => l.wrapping_mul(*r),
+                "//" => {
+                    let quotient = l.wrapping_div(*r);
+                    if l.wrapping_rem(*r) != 0 && (l ^ r) < 0 {
+                        quotient - 1
+                    } else {
+                        quotient
+                    }
+                }
+                _ => {
+                    let remainder = l.wrapping_rem(*r);
+                    if remainder != 0 && (remainder ^ r) < 0 {
+                        remainder + r
+                    } else {
+                        remainder
+                    }
+                }
+            }))
+        }
+        _ => {}
+    }
+
+    let l = left.to_number().expect("Operand is a number");
+    let r = right.to_number().expect("Operand is a number");
+    Ok(EvalValue::Number(match operator {
+        "+" => l + r,
+        "-" => l - r,
+        "*" => l * r,
+        "/" => l / r,
+        "//" => (l / r).floor(),
+        "^" => l.powf(r),
+        _ => float_modulo(l, r),
+    }))
+}
+
+/// The float remainder of `l / r`, with the sign of `r`.
+pub fn float_modulo(l: f64, r: f64) -> f64 {
+    let remainder = l % r;
+    if (remainder > 0.0 && r < 0.0) || (remainder < 0.0 && r > 0.0) {
+        remainder + r
+    } else {
+        remainder
+    }
+}
+
+/// Parses a numeral the way Lua converts strings in arithmetic, allowing surrounding
+/// whitespace. Decimal integers that do not fit in an integer are read as floats, while
+/// hexadecimal integers wrap around.
+pub fn string_to_number(s: &str) -> Option<EvalValue> {
+    let s = s.trim_matches(|c: char| c.is_ascii_whitespace() || c == '\x0b');
+    let (negative, unsigned) = match s.strip_prefix('-') {
+        Some(rest) => (true, rest),
+        None => (false, s.strip_prefix('+').unwrap_or(s)),
+    };
+
+    if let Some(hex) = unsigned
+        .strip_prefix("0x")
+        .or_else(|| unsigned.strip_prefix("0X"))
+    {
+        return parse_hexadecimal(hex, negative);
+    }
+
+    if !unsigned.is_empty() && unsigned.bytes().all(|c| c.is_ascii_digit()) {
+        if let Ok(value) = s.parse::<i64>() {
+            return Some(EvalValue::Integer(value));
+        }
+    }
+
+    let is_numeric = unsigned
+        .chars()
+        .all(|c| c.is_ascii_digit() || matches!(c, '.' | 'e' | 'E' | '+' | '-'));
+    if !is_numeric || !unsigned.starts_with(|c: char| c.is_ascii_digit() || c == '.') {
+        return None;
+    }
+    let value: f64 = unsigned.parse().ok()?;
+
+    Some(EvalValue::Number(if negative { -value } else { value }))
+}
+
+/// Parses the part of a hexadecimal numeral after `0x`: an integer, or a float if it has
+/// a fraction or a binary exponent.
+fn parse_hexadecimal(hex: &str, negative: bool) -> Option<EvalValue> {
+    let (digits, exponent) = match hex.find(['p', 'P']) {
+        Some(position) => (&hex[..position], Some(&hex[position + 1..])),
+        None => (hex, None),
+    };
+    let (integer_part, fraction) = match digits.split_once('.') {
+        Some((integer_part, fraction)) => (integer_part, Some(fraction)),
+        None => (digits, None),
+    };
+
+    let all_hex = |digits: &str| digits.chars().all(|c| c.is_ascii_hexdigit());
+    if integer_part.len() + fraction.map_or(0, str::len) == 0
+        || !all_hex(integer_part)
+        || !fraction.is_none_or(all_hex)
+    {
+        return None;
+    }
+
+    if fraction.is_none() && exponent.is_none() {
+        let value = integer_part.chars().fold(0i64, |value, digit| {
+            value
+                .wrapping_mul(16)
+                .wrapping_add(digit.to_digit(16).expect("Digit is hexadecimal") as i64)
+        });
+        return Some(EvalValue::Integer(if negative {
+            value.wrapping_neg()
+        } else {
+            value
+        }));
+    }
+
+    let exponent: i32 = match exponent {
+        Some(exponent) => {
+            let unsigned = exponent.strip_prefix(['+', '-']).unwrap_or(exponent);
+            if unsigned.is_empty() || !unsigned.bytes().all(|c| c.is_ascii_digit()) {
+                return None;
+            }
+            exponent.parse().unwrap_or(if exponent.starts_with('-') {
+                i32::MIN
+            } else {
+                i32::MAX
+            })
+        }
+        None => 0,
+    };
+
+    let mut mantissa = 0.0;
+    let mut scale = exponent as f64;
+    for digit in integer_part.chars() {
+        mantissa = mantissa * 16.0 + digit.to_digit(16).expect("Digit is hexadecimal") as f64;
+    }
+    for digit in fraction.unwrap_or_default().chars() {
+        mantissa = mantissa * 16.0 + digit.to_digit(16).expect("Digit is hexadecimal") as f64;
+        scale -= 4.0;
+    }
+    let value = mantissa * 2f64.powf(scale);
+
+    Some(EvalValue::Number(if negative { -value } else { value }))
+}
+
+impl fmt::Debug for EvalValue {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            EvalValue::Number(n) => f.debug_tuple("Number").field(n).finish(),
+            EvalValue::Integer(i) => f.debug_tuple("Integer").field(i).finish(),
+            EvalValue::Boolean(b) => f.debug_tuple("Boolean").field(b).finish(),
+            EvalValue::String(s) => f.debug_tuple("String").field(s).finish(),
+            EvalValue::Nil => write!(f, "Nil"),
+            EvalValue::NativeFunction(function) => {
+                write!(f, "NativeFunction({:p})", Rc::as_ptr(function) as *const ())
+            }
+            EvalValue::DeclaredFunction(function) => {
+                write!(f, "DeclaredFunction({:p})", Rc::as_ptr(function))
+            }
+            EvalValue::Table(table) => write!(f, "Table({:p})", Rc::as_ptr(table)),
+            EvalValue::Thread(thread) => write!(f, "Thread({:p})", Rc::as_ptr(thread)),
+            EvalValue::UserData(data) => write!(f, "UserData({:p})", Rc::as_ptr(data)),
+        }
+    }
+}
+
+// Raw (metamethod-free) equality and ordering, used for table keys. Integers and floats
+// with the same value are equal, and tables and functions are compared by identity.
+impl PartialEq for EvalValue {
+    fn eq(&self, other: &Self) -> bool {
+        match (self, other) {
+            (
+                EvalValue::Number(_) | EvalValue::Integer(_),
+                EvalValue::Number(_) | EvalValue::Integer(_),
+            ) => compare_numbers(self, other) == Some(Ordering::Equal),
+            _ => self.cmp(other) == Ordering::Equal,
+        }
+    }
+}
+
+impl Eq for EvalValue {}
+
+impl PartialOrd for EvalValue {
+    fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
+        Some(self.cmp(other))
+    }
 }
 
 impl Ord for EvalValue {
     fn cmp(&self, other: &Self) -> Ordering {
-        self.partial_cmp(other).unwrap_or(Ordering::Equal)
+        match (self, other) {
+            (
+                EvalValue::Number(_) | EvalValue::Integer(_),
+                EvalValue::Number(_) | EvalValue::Integer(_),
+            ) => compare_numbers(self, other).unwrap_or(Ordering::Equal),
+            (EvalValue::Boolean(l), EvalValue::Boolean(r)) => l.cmp(r),
+            (EvalValue::String(l), EvalValue::String(r)) => l.cmp(r),
+            (EvalValue::Table(l), EvalValue::Table(r)) => Rc::as_ptr(l).cmp(&Rc::as_ptr(r)),
+            (EvalValue::DeclaredFunction(l), EvalValue::DeclaredFunction(r)) => {
+                Rc::as_ptr(l).cmp(&Rc::as_ptr(r))
+            }
+            (EvalValue::NativeFunction(l), EvalValue::NativeFunction(r)) => {
+                (Rc::as_ptr(l) as *const ()).cmp(&(Rc::as_ptr(r) as *const ()))
+            }
+            (EvalValue::Thread(l), EvalValue::Thread(r)) => Rc::as_ptr(l).cmp(&Rc::as_ptr(r)),
+            (EvalValue::UserData(l), EvalValue::UserData(r)) => Rc::as_ptr(l).cmp(&Rc::as_ptr(r)),
+            _ => self.type_rank().cmp(&other.type_rank()),
+        }
     }
 }
 
-impl Eq for EvalValue {}
+#[derive(Default)]
+pub struct Table {
+    entries: BTreeMap<EvalValue, EvalValue>,
+    metatable: Option<TableRef>,
+}
+
+impl Table {
+    pub fn get(&self, key: &EvalValue) -> EvalValue {
+        self.entries
+            .get(&normalize_key(key.clone()))
+            .cloned()
+            .unwrap_or(EvalValue::Nil)
+    }
+
+    pub fn set(&mut self, key: EvalValue, value: EvalValue) -> Result<(), String> {
+        let key = match normalize_key(key) {
+            EvalValue::Nil => return Err("index is nil".to_string()),
+            EvalValue::Number(n) if n.is_nan() => return Err("index is NaN".to_string()),
+            key => key,
+        };
+
+        if value == EvalValue::Nil {
+            self.entries.remove(&key);
+        } else {
+            self.entries.insert(key, value);
+        }
+        Ok(())
+    }
+
+    /// A border of the table: an index `n` where `t[n]` is not nil (unless `n` is zero)
+    /// and `t[n + 1]` is nil.
+    pub fn border(&self) -> usize {
+        self.entries
+            .range(EvalValue::Integer(1)..=EvalValue::Integer(i64::MAX))
+            .rev()
+            .find_map(|(key, _)| match key {
+                EvalValue::Integer(i) => Some(*i as usize),
+                _ => None,
+            })
+            .unwrap_or(0)
+    }
+
+    /// The entry that follows `key` when traversing the table, or the first entry if
+    /// `key` is nil. Keys do not have to be present, so entries can be removed while
+    /// the table is traversed.
+    pub fn next(&self, key: &EvalValue) -> Option<(EvalValue, EvalValue)> {
+        let mut entries = match key {
+            EvalValue::Nil => self.entries.range::<EvalValue, _>(..),
+            key => self.entries.range((
+                Bound::Excluded(normalize_key(key.clone())),
+                Bound::Unbounded,
+            )),
+        };
+        entries
+            .next()
+            .map(|(key, value)| (key.clone(), value.clone()))
+    }
+
+    pub fn metatable(&self) -> Option<TableRef> {
+        self.metatable.clone()
+    }
+
+    pub fn set_metatable(&mut self, metatable: Option<TableRef>) {
+        self.metatable = metatable;
+    }
+}
+
+/// A value owned by native code, such as an open file. Like tables, each userdata has
+/// its own metatable.
+pub struct UserData {
+    data: RefCell<Box<dyn Any>>,
+    metatable: RefCell<Option<TableRef>>,
+}
+
+impl UserData {
+    pub fn new(data: impl Any, metatable: Option<TableRef>) -> UserDataRef {
+        Rc::new(UserData {
+            data: RefCell::new(Box::new(data)),
+            metatable: RefCell::new(metatable),
+        })
+    }
+
+    /// The data, if it is a `T`.
+    pub fn borrow_mut<T: Any>(&self) -> Option<RefMut<'_, T>> {
+        RefMut::filter_map(self.data.borrow_mut(), |data| data.downcast_mut::<T>()).ok()
+    }
+
+    pub fn is<T: Any>(&self) -> bool {
+        self.data.borrow().is::<T>()
+    }
+
+    pub fn metatable(&self) -> Option<TableRef> {
+        self.metatable.borrow().clone()
+    }
+
+    pub fn set_metatable(&self, metatable: Option<TableRef>) {
+        *self.metatable.borrow_mut() = metatable;
+    }
+}
+
+impl Table {
+    /// Removes the entries and the metatable of the table.
+    fn take_contents(&mut self) -> Vec<EvalValue> {
+        let metatable = self.metatable.take().map(EvalValue::Table);
+
+        std::mem::take(&mut self.entries)
+            .into_iter()
+            .flat_map(|(key, value)| [key, value])
+            .chain(metatable)
+            .collect()
+    }
+}
+
+impl Drop for Table {
+    fn drop(&mut self) {
+        release(self.take_contents());
+    }
+}
+
+/// Floats with an integer value are stored as integer keys, so `t[1]` and `t[1.0]` are
+/// the same entry.
+fn normalize_key(key: EvalValue) -> EvalValue {
+    match key {
+        EvalValue::Number(n) => float_to_integer(n).map_or(key, EvalValue::Integer),
+        key => key,
+    }
+}
+
+#[derive(Debug)]
+pub struct FunctionDefinition {
+    /// Name of the chunk the function was defined in, as shown in error messages.
+    pub chunk_name: Rc<str>,
+    /// Where the chunk came from, as `load` names chunks: `@file.lua` for a file,
+    /// `=name` for other named sources, or the source code itself.
+    pub source: Rc<str>,
+    /// Line the function starts on, or 0 for the main function of a chunk.
+    pub line_defined: usize,
+    /// Line the function ends on, or 0 for the main function of a chunk.
+    pub last_line_defined: usize,
+    pub arguments: Vec<String>,
+    pub is_variadic: bool,
+    /// Variables of enclosing functions the function uses, in the order they are first
+    /// used. Global variables are used through `_ENV`.
+    pub upvalues: Vec<String>,
+    pub body: Vec<Statement>,
+}
 
+/// A function value together with the variables of enclosing functions it uses, its
+/// upvalues.
+///
+/// Values are reference counted and there is no cycle collector yet, so a cycle of
+/// references, like a recursive local function capturing itself or a table that
+/// contains itself, is never freed.
+pub struct LuaFunction {
+    pub definition: Rc<FunctionDefinition>,
+    pub captured_variables: Vec<(String, Variable)>,
+}
+
+impl LuaFunction {
+    /// The variable the function refers to by the name of its upvalue `name`.
+    pub fn upvalue(&self, name: &str) -> Option<Variable> {
+        self.captured_variables
+            .iter()
+            .find(|(n, _)| n == name)
+            .map(|(_, variable)| variable.clone())
+    }
+
+    /// Removes the upvalues of the function, returning the values of those no other
+    /// function or scope shares.
+    fn take_captured_values(&mut self) -> Vec<EvalValue> {
+        std::mem::take(&mut self.captured_variables)
+            .into_iter()
+            .filter_map(|(_, variable)| Rc::try_unwrap(variable).ok())
+            .map(RefCell::into_inner)
+            .collect()
+    }
+}
+
+impl Drop for LuaFunction {
+    fn drop(&mut self) {
+        release(self.take_captured_values());
+    }
+}
+
+/// Drops `values` together with the tables and functions only they refer to, one at a
+/// time, so dropping a long chain like a linked list does not recurse once per link.
+fn release(mut values: Vec<EvalValue>) {
+    while let Some(value) = values.pop() {
+        match value {
+            EvalValue::Table(table) => {
+                if let Ok(table) = Rc::try_unwrap(table) {
+                    values.extend(table.into_inner().take_contents());
+                }
+            }
+            EvalValue::DeclaredFunction(function) => {
+                if let Ok(mut function) = Rc::try_unwrap(function) {
+                    values.extend(function.take_captured_values());
+                }
+            }
+            _ => {}
+        }
+    }
+}
+
+/// Estimates the memory in bytes taken by `roots` and the values reachable from them,
+/// counting every string, table, function and userdata once. It leaves out the code of
+/// functions, the state native functions capture and the values suspended coroutines
+/// hold, which cannot be reached from here.
+pub fn memory_estimate(roots: impl IntoIterator<Item = EvalValue>) -> usize {
+    let mut visited: HashSet<*const ()> = HashSet::new();
+    let mut pending: Vec<EvalValue> = roots.into_iter().collect();
+    let mut total = 0;
+
+    while let Some(value) = pending.pop() {
+        let address = match &value {
+            EvalValue::String(string) => Rc::as_ptr(&string.0) as *const (),
+            EvalValue::Table(table) => Rc::as_ptr(table) as *const (),
+            EvalValue::DeclaredFunction(function) => Rc::as_ptr(function) as *const (),
+            EvalValue::NativeFunction(function) => Rc::as_ptr(function) as *const (),
+            EvalValue::Thread(thread) => Rc::as_ptr(thread) as *const (),
+            EvalValue::UserData(userdata) => Rc::as_ptr(userdata) as *const (),
+            _ => continue,
+        };
+        if !visited.insert(address) {
+            continue;
+        }
+
+        total += match value {
+            EvalValue::String(string) => std::mem::size_of::<Vec<u8>>() + string.0.capacity(),
+            EvalValue::Table(table) => {
+                let table = table.borrow();
+                pending.extend(
+                    table
+                        .entries
+                        .iter()
+                        .flat_map(|(key, value)| [key.clone(), value.clone()]),
+                );
+                pending.extend(table.metatable.clone().map(EvalValue::Table));
+                std::mem::size_of::<Table>()
+                    + table.entries.len() * 2 * std::mem::size_of::<EvalValue>()
+            }
+            EvalValue::DeclaredFunction(function) => {
+                pending.extend(
+                    function
+                        .captured_variables
+                        .iter()
+                        .map(|(_, variable)| variable.borrow().clone()),
+                );
+                std::mem::size_of::<LuaFunction>()
+                    + function.captured_variables.len()
+                        * std::mem::size_of::<(String, RefCell<EvalValue>)>()
+            }
+            EvalValue::UserData(userdata) => {
+                pending.extend(userdata.metatable().map(EvalValue::Table));
+                std::mem::size_of::<UserData>()
+            }
+            EvalValue::Thread(_) => std::mem::size_of::<Coroutine>(),
+            _ => std::mem::size_of::<NativeFunction>(),
+        };
+    }
+
+    total
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum VariableAttribute {
+    Regular,
+    Const,
+    Close,
+}
+
+/// How a block of statements finished executing.
+#[derive(Debug)]
+pub enum ControlFlow {
+    Normal,
+    Break,
+    Return(Vec<EvalValue>),
+}
+
+/// An expression of the syntax tree. The last field of every variant is the part of
+/// the source code the expression was parsed from.
 #[derive(Debug, Clone)]
+#[allow(clippy::enum_variant_names)]
 pub enum Expression {
-    NumberLiteral(f64),
-    BooleanLiteral(bool),
-    StringLiteral(String),
-    TableLiteral(BTreeMap<Expression, Expression>),
-    NilLiteral,
-    IdentifierExpression(String),
-    BinaryExpression(Box<Expression>, String, Box<Expression>),
-    FunctionCall(String, Vec<Expression>),
-    IndexOperator(Box<Expression>, Box<Expression>),
-}
-
-#[derive(Debug, Clone, PartialEq, PartialOrd)]
+    NumberLiteral(f64, Span),
+    IntegerLiteral(i64, Span),
+    BooleanLiteral(bool, Span),
+    StringLiteral(LuaString, Span),
+    TableLiteral(Vec<(Option<Expression>, Expression)>, Span),
+    NilLiteral(Span),
+    FunctionLiteral(Rc<FunctionDefinition>, Span),
+    VarargExpression(Span),
+    ParenthesizedExpression(Box<Expression>, Span),
+    IdentifierExpression(String, Span),
+    UnaryExpression(String, Box<Expression>, Span),
+    BinaryExpression(Box<Expression>, String, Box<Expression>, Span),
+    FunctionCall(Box<Expression>, Vec<Expression>, Span),
+    /// `object:method(arguments)`, which passes the object as the first argument.
+    MethodCall(Box<Expression>, String, Vec<Expression>, Span),
+    IndexOperator(Box<Expression>, Box<Expression>, Span),
+}
+
+/// A statement of the syntax tree. Every variant records the part of the source code
+/// the statement was parsed from.
+#[derive(Debug, Clone)]
+#[allow(clippy::enum_variant_names)]
 pub enum Statement {
-    LocalVariableDeclaration(String, Box<Expression>),
-    AssigmentStatement(String, Box<Expression>),
+    LocalVariableDeclaration {
+        variables: Vec<(String, VariableAttribute)>,
+        values: Vec<Expression>,
+        span: Span,
+    },
+    AssigmentStatement {
+        targets: Vec<Expression>,
+        values: Vec<Expression>,
+        span: Span,
+    },
 
     WhileLoop {
         loop_condition: Box<Expression>,
         code_block: Vec<Statement>,
+        span: Span,
     },
     ForLoop {
         iterator_identifier: String,
@@ -61,241 +858,445 @@ pub enum Statement {
         ending_value: Box<Expression>,
         step_value: Box<Expression>,
         code_block: Vec<Statement>,
+        span: Span,
+    },
+    GenericForLoop {
+        iterator_identifiers: Vec<String>,
+        iterator_values: Vec<Expression>,
+        code_block: Vec<Statement>,
+        span: Span,
     },
     RepeatUntilLoop {
         code_block: Vec<Statement>,
         loop_condition: Box<Expression>,
+        span: Span,
     },
     IfStatement {
         basic_condition: Box<Expression>,
         code_block: Vec<Statement>,
         elseif_statements: Vec<(Box<Expression>, Vec<Statement>)>,
         else_block: Option<Vec<Statement>>,
+        span: Span,
     },
-    ExpressionStatement(Box<Expression>),
+    DoBlock(Vec<Statement>, Span),
+    ExpressionStatement(Box<Expression>, Span),
     FunctionDeclaration {
+        /// The variable or field the function is stored in, e.g. `a.b.c` for
+        /// `function a.b.c()` and `a.m` for `function a:m()`.
+        target: Box<Expression>,
+        function: Rc<FunctionDefinition>,
+        span: Span,
+    },
+    LocalFunctionDeclaration {
         function_name: String,
-        function_arguments: Vec<String>,
-        function_body: Vec<Statement>,
+        function: Rc<FunctionDefinition>,
+        span: Span,
     },
-    ReturnStatement(Box<Expression>),
+    ReturnStatement(Vec<Expression>, Span),
+    BreakStatement(Span),
 }
 
 impl Expression {
-    fn execute(&self, _g: &mut VirtualMachine) -> Result<EvalValue, String> {
-        match &self {
-            Expression::NumberLiteral(number) => Ok(EvalValue::Number(*number)),
-            Expression::BooleanLiteral(boolean_value) => Ok(EvalValue::Boolean(*boolean_value)),
-            Expression::StringLiteral(string_value) => Ok(EvalValue::String(string_value.clone())),
-            Expression::NilLiteral => Ok(EvalValue::Nil),
-            Expression::IdentifierExpression(ident) => {
-                Ok(_g.lookup_variable(ident).unwrap_or(EvalValue::Nil))
-            }
-            Expression::BinaryExpression(lhs, operator, rhs) => {
-                let lhs = lhs.execute(_g)?;
-                let rhs = rhs.execute(_g)?;
-
-                match (lhs.clone(), rhs.clone()) {
-                    (EvalValue::Number(left), EvalValue::Number(right)) => {
-                        match operator.as_str() {
-                            "+" => Ok(EvalValue::Number(left + right)),
-                            "-" => Ok(EvalValue::Number(left - right)),
-                            "*" => Ok(EvalValue::Number(left * right)),
-                            "/" => Ok(EvalValue::Number(left / right)),
-
-                            "<" => Ok(EvalValue::Boolean(left < right)),
-                            ">" => Ok(EvalValue::Boolean(left > right)),
+    pub fn span(&self) -> Span {
+        match self {
+            Expression::NumberLiteral(_, span)
+            | Expression::IntegerLiteral(_, span)
+            | Expression::BooleanLiteral(_, span)
+            | Expression::StringLiteral(_, span)
+            | Expression::TableLiteral(_, span)
+            | Expression::NilLiteral(span)
+            | Expression::FunctionLiteral(_, span)
+            | Expression::VarargExpression(span)
+            | Expression::ParenthesizedExpression(_, span)
+            | Expression::IdentifierExpression(_, span)
+            | Expression::UnaryExpression(_, _, span)
+            | Expression::BinaryExpression(_, _, _, span)
+            | Expression::FunctionCall(_, _, span)
+            | Expression::MethodCall(_, _, _, span)
+            | Expression::IndexOperator(_, _, span) => *span,
+        }
+    }
 
-                            "<=" => Ok(EvalValue::Boolean(left <= right)),
-                            ">=" => Ok(EvalValue::Boolean(left >= right)),
+    fn execute(&self, _g: &mut VirtualMachine) -> Result<EvalValue, LuaError> {
+        // Operands are evaluated recursively, so deeply nested expressions can exhaust
+        // the native stack without calling any function
+        if _g.is_stack_exhausted() {
+            return Err(LuaError::stack_overflow());
+        }
+        _g.count_hook()?;
 
-                            "==" => Ok(EvalValue::Boolean(left == right)),
-                            "~=" => Ok(EvalValue::Boolean(left != right)),
+        match &self {
+            Expression::NumberLiteral(number, _) => Ok(EvalValue::Number(*number)),
+            Expression::IntegerLiteral(number, _) => Ok(EvalValue::Integer(*number)),
+            Expression::BooleanLiteral(boolean_value, _) => Ok(EvalValue::Boolean(*boolean_value)),
+            Expression::StringLiteral(string_value, _) => {
+                Ok(EvalValue::String(string_value.clone()))
+            }
+            Expression::NilLiteral(_) => Ok(EvalValue::Nil),
+            Expression::FunctionLiteral(definition, _) => Ok(_g.create_closure(definition.clone())),
+            Expression::FunctionCall(..)
+            | Expression::MethodCall(..)
+            | Expression::VarargExpression(_) => Ok(self
+                .execute_multiple(_g)?
+                .into_iter()
+                .next()
+                .unwrap_or(EvalValue::Nil)),
+            Expression::ParenthesizedExpression(expression, _) => expression.execute(_g),
+            Expression::IdentifierExpression(ident, _) => _g.read_variable(ident),
+            Expression::UnaryExpression(operator, operand, span) => {
+                let value = operand.execute(_g)?;
+                _g.set_position(span.start);
 
-                            _ => Err(format!("Unknown operator for numbers: '{}'", operator)),
-                        }
-                    }
-                    (EvalValue::String(left), EvalValue::String(right)) => {
-                        match operator.as_str() {
-                            ".." => Ok(EvalValue::String(left.clone() + &right.clone())),
-                            "==" => Ok(EvalValue::Boolean(left == right)),
-                            "~=" => Ok(EvalValue::Boolean(left != right)),
-                            _ => Err(format!("Invalid operator for strings: '{}'", operator)),
-                        }
-                    }
-                    (EvalValue::Boolean(left), EvalValue::Boolean(right)) => {
-                        match operator.as_str() {
-                            "==" => Ok(EvalValue::Boolean(left == right)),
-                            "~=" => Ok(EvalValue::Boolean(left != right)),
-                            _ => Err(format!("Invalid operator for booleans: '{}'", operator)),
-                        }
+                match (operator.as_str(), &value) {
+                    ("not", _) => Ok(EvalValue::Boolean(!value.is_true())),
+                    ("-", _) => match value.to_numeric() {
+                        Some(EvalValue::Integer(i)) => Ok(EvalValue::Integer(i.wrapping_neg())),
+                        Some(EvalValue::Number(n)) => Ok(EvalValue::Number(-n)),
+                        _ => Err(operand.operand_error("perform arithmetic on", &value, _g)),
+                    },
+                    ("#", EvalValue::String(_) | EvalValue::Table(_)) => _g.length(&value),
+                    ("#", _) if _g.get_metamethod(&value, "__len") != EvalValue::Nil => {
+                        _g.length(&value)
                     }
-                    _ => Err(format!(
-                        "Invalid expression {:?} {} {:?}",
-                        lhs, operator, rhs
-                    )),
+                    ("#", _) => Err(operand.operand_error("get length of", &value, _g)),
+                    _ => Err(LuaError::runtime(format!(
+                        "Unknown unary operator: '{}'",
+                        operator
+                    ))),
                 }
             }
-            Expression::FunctionCall(function_name, function_arguments) => {
-                let mut args: Vec<EvalValue> = Vec::new();
-                for arg in function_arguments {
-                    args.push(arg.execute(_g)?);
+            Expression::BinaryExpression(lhs, operator, rhs, span) => {
+                let left = lhs.execute(_g)?;
+
+                // `and` and `or` only evaluate their right operand if it decides the result
+                match operator.as_str() {
+                    "and" if !left.is_true() => return Ok(left),
+                    "or" if left.is_true() => return Ok(left),
+                    "and" | "or" => return rhs.execute(_g),
+                    _ => {}
                 }
-                match _g.lookup_variable(function_name) {
-                    Some(EvalValue::NativeFunction(f)) => f(args),
-                    Some(EvalValue::DeclaredFunction { arguments, body }) => {
-                        _g.enter_scope();
-
-                        if arguments.len() != args.len() {
-                            return Err(format!(
-                                "Expected {} arguments, got {}",
-                                arguments.len(),
-                                args.len()
-                            ));
-                        }
 
-                        for (arg_name, arg_value) in arguments.iter().zip(args) {
-                            _g.declare_variable(arg_name.clone(), arg_value);
-                        }
-                        for statement in body {
-                            let return_value = statement.execute(_g)?;
+                let right = rhs.execute(_g)?;
+                _g.set_position(span.start);
 
-                            if return_value != EvalValue::Void {
-                                _g.exit_scope();
-                                return Ok(return_value);
+                match operator.as_str() {
+                    "+" | "-" | "*" | "/" | "//" | "%" | "^" => {
+                        match (left.to_numeric(), right.to_numeric()) {
+                            (Some(l), Some(r)) => arithmetic(operator, &l, &r),
+                            (None, _) => Err(lhs.operand_error("perform arithmetic on", &left, _g)),
+                            (_, None) => {
+                                Err(rhs.operand_error("perform arithmetic on", &right, _g))
                             }
                         }
-                        _g.exit_scope();
-                        Ok(EvalValue::Nil)
                     }
-                    _ => Err(format!("Function '{}' not found", function_name)),
+                    ".." => match (left.to_lua_string(), right.to_lua_string()) {
+                        (Some(l), Some(r)) => Ok(EvalValue::String(LuaString::concat(&[&l, &r])?)),
+                        (None, _) => Err(lhs.operand_error("concatenate", &left, _g)),
+                        (_, None) => Err(rhs.operand_error("concatenate", &right, _g)),
+                    },
+                    "==" => Ok(EvalValue::Boolean(left == right)),
+                    "~=" => Ok(EvalValue::Boolean(left != right)),
+                    "<" | ">" | "<=" | ">=" => {
+                        let ordering = compare_values(&left, &right)?;
+
+                        // Comparisons involving NaN are always false
+                        Ok(EvalValue::Boolean(ordering.is_some_and(|ordering| {
+                            match operator.as_str() {
+                                "<" => ordering.is_lt(),
+                                ">" => ordering.is_gt(),
+                                "<=" => ordering.is_le(),
+                                _ => ordering.is_ge(),
+                            }
+                        })))
+                    }
+                    _ => Err(LuaError::runtime(format!(
+                        "Unknown binary operator: '{}'",
+                        operator
+                    ))),
                 }
             }
-            Expression::TableLiteral(btree_map) => {
-                let mut table = BTreeMap::new();
-                for (key, value) in btree_map {
-                    table.insert(key.execute(_g)?, value.execute(_g)?);
+            Expression::TableLiteral(fields, _) => {
+                let mut table = Table::default();
+                let mut in_table_index = 1;
+                for (field_index, (key, value)) in fields.iter().enumerate() {
+                    let key = match key {
+                        Some(key) => key.execute(_g)?,
+                        // A call or `...` in the last positional field adds all of its values
+                        None if field_index == fields.len() - 1 => {
+                            for value in value.execute_multiple(_g)? {
+                                table.set(EvalValue::Integer(in_table_index), value)?;
+                                in_table_index += 1;
+                            }
+                            continue;
+                        }
+                        None => {
+                            in_table_index += 1;
+                            EvalValue::Integer(in_table_index - 1)
+                        }
+                    };
+                    table.set(key, value.execute(_g)?)?;
                 }
-                Ok(EvalValue::Table(table))
+                Ok(EvalValue::Table(Rc::new(RefCell::new(table))))
             }
-            Expression::IndexOperator(table, index) => {
+            Expression::IndexOperator(table, index, span) => {
                 let table_value = table.execute(_g)?;
                 let index_value = index.execute(_g)?;
-                
-                match table_value {
-                    EvalValue::Table(table) => {
-                        Ok(table.get(&index_value).cloned().unwrap_or(EvalValue::Nil))
-                    }
-                    EvalValue::Nil => Ok(EvalValue::Nil),
-                    _ => Err("Cannot index non-table value".to_string())
-                }
+                _g.set_position(span.start);
+
+                table.index(table_value, index_value, _g)
             }
         }
     }
-}
 
-impl PartialEq for Expression {
-    fn eq(&self, other: &Self) -> bool {
-        match (self, other) {
-            // Compare NumberLiteral
-            (Expression::NumberLiteral(l), Expression::NumberLiteral(r)) => l == r,
-            // Compare BooleanLiteral
-            (Expression::BooleanLiteral(l), Expression::BooleanLiteral(r)) => l == r,
-            // Compare StringLiteral
-            (Expression::StringLiteral(l), Expression::StringLiteral(r)) => l == r,
-            // Compare NilLiteral
-            (Expression::NilLiteral, Expression::NilLiteral) => true,
-            // Compare IdentifierExpression
-            (Expression::IdentifierExpression(l), Expression::IdentifierExpression(r)) => l == r,
-            // Compare TableLiteral
-            (Expression::TableLiteral(l), Expression::TableLiteral(r)) => {
-                if l.len() != r.len() {
-                    return false;
+    /// Evaluates an expression that can produce several values, i.e. a function call or `...`.
+    /// Any other expression produces exactly one value.
+    fn execute_multiple(&self, _g: &mut VirtualMachine) -> Result<Vec<EvalValue>, LuaError> {
+        match self {
+            Expression::FunctionCall(function, function_arguments, span) => {
+                let function_value = function.execute(_g)?;
+                let args = execute_expression_list(function_arguments, _g)?;
+                _g.set_position(span.start);
+
+                match function_value {
+                    EvalValue::NativeFunction(_) | EvalValue::DeclaredFunction(_) => {
+                        let name = function.variable_name(_g);
+                        _g.call_function_with_name(function_value, args, name)
+                    }
+                    other => Err(function.operand_error("call", &other, _g)),
                 }
-                for ((kl, vr), (kr, vl)) in std::iter::zip(l.iter(), r.iter()) {
-                    if kl != kr || vr != vl {
-                        return false;
+            }
+            Expression::MethodCall(object, method, function_arguments, span) => {
+                let object_value = object.execute(_g)?;
+                _g.set_position(span.start);
+                let function_value =
+                    object.index(object_value.clone(), EvalValue::string(method.as_str()), _g)?;
+
+                let args = std::iter::once(object_value)
+                    .chain(execute_expression_list(function_arguments, _g)?)
+                    .collect();
+                _g.set_position(span.start);
+
+                let name = VariableName {
+                    kind: "method",
+                    name: method.clone(),
+                };
+                match function_value {
+                    EvalValue::NativeFunction(_) | EvalValue::DeclaredFunction(_) => {
+                        _g.call_function_with_name(function_value, args, Some(name))
                     }
+                    other => Err(LuaError::type_mismatch(format!(
+                        "attempt to call a {} value ({})",
+                        other.type_name(),
+                        name
+                    ))),
                 }
-                true
             }
-            // Compare BinaryExpression
-            (
-                Expression::BinaryExpression(l_l, l_op, l_r),
-                Expression::BinaryExpression(r_l, r_op, r_r),
-            ) => l_l == r_l && l_op == r_op && l_r == r_r,
-            // Compare FunctionCall
-            (
-                Expression::FunctionCall(l_name, l_args),
-                Expression::FunctionCall(r_name, r_args),
-            ) => l_name == r_name && l_args == r_args,
+            Expression::VarargExpression(_) => Ok(_g.varargs().to_vec()),
+            _ => Ok(vec![self.execute(_g)?]),
+        }
+    }
+
+    /// The variable or field this expression reads, if it is one.
+    fn variable_name(&self, _g: &VirtualMachine) -> Option<VariableName> {
+        match self {
+            Expression::IdentifierExpression(name, _) => Some(VariableName {
+                kind: _g.variable_kind(name),
+                name: name.clone(),
+            }),
+            Expression::IndexOperator(_, index, _) => match index.as_ref() {
+                Expression::StringLiteral(field, _) => Some(VariableName {
+                    kind: "field",
+                    name: field.to_string(),
+                }),
+                _ => None,
+            },
+            _ => None,
+        }
+    }
 
-            // Different types are not equal
-            _ => false,
+    /// Indexes `value`, the result of this expression, with `key`. Values that cannot be
+    /// indexed are reported with the variable or field they came from.
+    fn index(
+        &self,
+        value: EvalValue,
+        key: EvalValue,
+        _g: &mut VirtualMachine,
+    ) -> Result<EvalValue, LuaError> {
+        if !matches!(value, EvalValue::Table(_))
+            && _g.get_metamethod(&value, "__index") == EvalValue::Nil
+        {
+            return Err(self.operand_error("index", &value, _g));
         }
+
+        _g.index(value, key)
+    }
+
+    /// Builds the error for an operation that cannot be applied to `value`, the result of
+    /// this expression, naming the variable or field it came from like Lua does.
+    fn operand_error(&self, operation: &str, value: &EvalValue, _g: &VirtualMachine) -> LuaError {
+        let origin = match self.variable_name(_g) {
+            Some(name) => format!(" ({})", name),
+            None => String::new(),
+        };
+
+        LuaError::type_mismatch(format!(
+            "attempt to {} a {} value{}",
+            operation,
+            value.type_name(),
+            origin
+        ))
     }
 }
 
-impl Eq for Expression {}
+impl Expression {
+    /// Removes the subexpressions of the expression.
+    fn take_operands(&mut self) -> Vec<Expression> {
+        let take = |operand: &mut Box<Expression>| {
+            std::mem::replace(&mut **operand, Expression::NilLiteral(Span::default()))
+        };
 
-impl PartialOrd for Expression {
-    fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
-        match (self, other) {
-            (Expression::NumberLiteral(l), Expression::NumberLiteral(r)) => l.partial_cmp(r),
-            (Expression::BooleanLiteral(l), Expression::BooleanLiteral(r)) => l.partial_cmp(r), 
-            (Expression::StringLiteral(l), Expression::StringLiteral(r)) => l.partial_cmp(r),
-            (Expression::NilLiteral, Expression::NilLiteral) => Some(Ordering::Equal),
-            (Expression::IdentifierExpression(l), Expression::IdentifierExpression(r)) => l.partial_cmp(r),
-            (Expression::TableLiteral(l), Expression::TableLiteral(r)) => {
-                l.iter().partial_cmp(r.iter())
-            },
-            (Expression::BinaryExpression(l, _, r), Expression::BinaryExpression(ll, _, rr)) => {
-                l.partial_cmp(ll).and_then(|ord| Some(ord.then(r.partial_cmp(rr)?)))
-            },
-            (Expression::FunctionCall(l, _), Expression::FunctionCall(r, _)) => l.partial_cmp(r),
-            _ => None,
+        match self {
+            Expression::TableLiteral(fields, _) => std::mem::take(fields)
+                .into_iter()
+                .flat_map(|(key, value)| key.into_iter().chain([value]))
+                .collect(),
+            Expression::ParenthesizedExpression(operand, _)
+            | Expression::UnaryExpression(_, operand, _) => vec![take(operand)],
+            Expression::BinaryExpression(left, _, right, _)
+            | Expression::IndexOperator(left, right, _) => vec![take(left), take(right)],
+            Expression::FunctionCall(function, arguments, _)
+            | Expression::MethodCall(function, _, arguments, _) => {
+                let mut operands = std::mem::take(arguments);
+                operands.push(take(function));
+                operands
+            }
+            _ => Vec::new(),
         }
     }
 }
 
-impl Ord for Expression {
-    fn cmp(&self, other: &Self) -> Ordering {
-        self.partial_cmp(other).unwrap_or(Ordering::Equal)
+impl Drop for Expression {
+    /// Long chains like `1 + 1 + ... + 1` or `a.b. ... .z` are parsed without recursion,
+    /// so they are dropped without recursion too.
+    fn drop(&mut self) {
+        let mut operands = self.take_operands();
+        while let Some(mut operand) = operands.pop() {
+            operands.extend(operand.take_operands());
+        }
     }
 }
 
+/// Evaluates a comma separated list of expressions, where only the last one can expand
+/// to multiple values.
+pub fn execute_expression_list(
+    expressions: &[Expression],
+    _g: &mut VirtualMachine,
+) -> Result<Vec<EvalValue>, LuaError> {
+    let mut values = Vec::new();
+
+    if let Some((last, rest)) = expressions.split_last() {
+        for expression in rest {
+            values.push(expression.execute(_g)?);
+        }
+        values.extend(last.execute_multiple(_g)?);
+    }
+
+    Ok(values)
+}
+
 impl Statement {
-    pub fn execute(&self, _g: &mut VirtualMachine) -> Result<EvalValue, String> {
+    pub fn span(&self) -> Span {
         match self {
-            Statement::LocalVariableDeclaration(variable_name, expr) => {
-                let value = expr.execute(_g)?;
-                _g.declare_variable(variable_name.clone(), value);
-                Ok(EvalValue::Void)
+            Statement::LocalVariableDeclaration { span, .. }
+            | Statement::AssigmentStatement { span, .. }
+            | Statement::WhileLoop { span, .. }
+            | Statement::ForLoop { span, .. }
+            | Statement::GenericForLoop { span, .. }
+            | Statement::RepeatUntilLoop { span, .. }
+            | Statement::IfStatement { span, .. }
+            | Statement::DoBlock(_, span)
+            | Statement::ExpressionStatement(_, span)
+            | Statement::FunctionDeclaration { span, .. }
+            | Statement::LocalFunctionDeclaration { span, .. }
+            | Statement::ReturnStatement(_, span)
+            | Statement::BreakStatement(span) => *span,
+        }
+    }
+
+    pub fn execute(&self, _g: &mut VirtualMachine) -> Result<ControlFlow, LuaError> {
+        match self {
+            Statement::LocalVariableDeclaration {
+                variables, values, ..
+            } => {
+                let mut evaluated_values = execute_expression_list(values, _g)?.into_iter();
+
+                for (variable_name, attribute) in variables {
+                    let value = evaluated_values.next().unwrap_or(EvalValue::Nil);
+
+                    if *attribute == VariableAttribute::Close {
+                        _g.declare_to_be_closed(variable_name.clone(), value)?;
+                    } else {
+                        _g.declare_variable(variable_name.clone(), value);
+                    }
+                }
+                Ok(ControlFlow::Normal)
             }
-            Statement::AssigmentStatement(variable_name, expr) => {
-                let value = expr.execute(_g)?;
-                _g.change_or_create_value(variable_name.clone(), value);
-                Ok(EvalValue::Void)
+            Statement::AssigmentStatement {
+                targets, values, ..
+            } => {
+                let mut places = Vec::new();
+                for target in targets {
+                    places.push(match target {
+                        Expression::IdentifierExpression(variable_name, _) => {
+                            (None, EvalValue::string(variable_name.as_str()))
+                        }
+                        Expression::IndexOperator(table, index, span) => {
+                            let table_value = table.execute(_g)?;
+                            let index_value = index.execute(_g)?;
+                            _g.set_position(span.start);
+
+                            if !matches!(table_value, EvalValue::Table(_))
+                                && _g.get_metamethod(&table_value, "__newindex") == EvalValue::Nil
+                            {
+                                return Err(table.operand_error("index", &table_value, _g));
+                            }
+                            (Some(table_value), index_value)
+                        }
+                        _ => {
+                            return Err(LuaError::runtime(format!("Cannot assign to {:?}", target)))
+                        }
+                    });
+                }
+
+                let mut evaluated_values = execute_expression_list(values, _g)?.into_iter();
+
+                for (table, index) in places {
+                    let value = evaluated_values.next().unwrap_or(EvalValue::Nil);
+
+                    match (table, index) {
+                        (None, EvalValue::String(variable_name)) => {
+                            _g.change_or_create_value(variable_name.to_string(), value)?
+                        }
+                        (Some(table), index) => _g.set_index(table, index, value)?,
+                        _ => unreachable!(),
+                    }
+                }
+                Ok(ControlFlow::Normal)
             }
             Statement::WhileLoop {
                 loop_condition,
                 code_block,
+                ..
             } => {
-                _g.enter_scope();
-
                 while loop_condition.execute(_g)?.is_true() {
-                    for statement in code_block {
-                        let return_value = statement.execute(_g)?;
-
-                        if return_value != EvalValue::Void {
-                            _g.exit_scope();
-                            return Ok(return_value);
-                        }
+                    match _g.execute_block(code_block, Vec::new())? {
+                        ControlFlow::Normal => _g.jump_back(self.span().start.line)?,
+                        ControlFlow::Break => break,
+                        return_value => return Ok(return_value),
                     }
                 }
 
-                _g.exit_scope();
-                Ok(EvalValue::Void)
+                Ok(ControlFlow::Normal)
             }
             Statement::ForLoop {
                 iterator_identifier,
@@ -303,132 +1304,240 @@ impl Statement {
                 ending_value,
                 step_value,
                 code_block,
+                span,
             } => {
-                _g.enter_scope();
-
                 let starting_value = starting_value.execute(_g)?;
-                _g.declare_variable(iterator_identifier.clone(), starting_value);
+                let ending_value = ending_value.execute(_g)?;
+                let step_value = step_value.execute(_g)?;
+                _g.set_position(span.start);
 
-                let step_value = match step_value.execute(_g)? {
-                    EvalValue::Number(n) => n,
-                    _ => return Err("Invalid step value".to_string()),
-                };
-
-                while _g.lookup_variable(iterator_identifier).unwrap()
-                    <= ending_value.execute(_g)?
-                {
-                    for statement in code_block {
-                        let return_value = statement.execute(_g)?;
+                if !matches!(starting_value, EvalValue::Number(_) | EvalValue::Integer(_)) {
+                    return Err(LuaError::runtime("'for' initial value must be a number"));
+                }
+                if !matches!(ending_value, EvalValue::Number(_) | EvalValue::Integer(_)) {
+                    return Err(LuaError::runtime("'for' limit must be a number"));
+                }
+                if !matches!(step_value, EvalValue::Number(_) | EvalValue::Integer(_)) {
+                    return Err(LuaError::runtime("'for' step must be a number"));
+                }
+                if step_value == EvalValue::Integer(0) {
+                    return Err(LuaError::runtime("'for' step is zero"));
+                }
 
-                        if return_value != EvalValue::Void {
-                            _g.exit_scope();
-                            return Ok(return_value);
+                // The loop counts with integers if both the initial value and the step are
+                // integers, and with floats otherwise
+                let values: Box<dyn Iterator<Item = EvalValue>> =
+                    match (&starting_value, &step_value) {
+                        (EvalValue::Integer(start), EvalValue::Integer(step)) => {
+                            Box::new(integer_for_loop(*start, &ending_value, *step))
                         }
-                    }
+                        _ => Box::new(float_for_loop(
+                            starting_value.to_number().expect("Value is a number"),
+                            ending_value.to_number().expect("Value is a number"),
+                            step_value.to_number().expect("Value is a number"),
+                        )),
+                    };
 
-                    let current_value = match _g.lookup_variable(iterator_identifier) {
-                        Some(EvalValue::Number(n)) => n,
-                        _ => return Err(format!("Invalid {} value", iterator_identifier)),
-                    } + step_value;
+                for value in values {
+                    let iterator = vec![(iterator_identifier.clone(), value)];
 
-                    _g.change_or_create_value(
-                        iterator_identifier.clone(),
-                        EvalValue::Number(current_value),
-                    );
+                    match _g.execute_block(code_block, iterator)? {
+                        ControlFlow::Normal => _g.jump_back(self.span().start.line)?,
+                        ControlFlow::Break => break,
+                        return_value => return Ok(return_value),
+                    }
                 }
 
-                _g.exit_scope();
-                Ok(EvalValue::Void)
+                Ok(ControlFlow::Normal)
             }
-            Statement::IfStatement {
-                basic_condition,
+            Statement::GenericForLoop {
+                iterator_identifiers,
+                iterator_values,
                 code_block,
-                elseif_statements,
-                else_block,
+                span,
             } => {
+                let mut iterator_values = execute_expression_list(iterator_values, _g)?.into_iter();
+                let mut next_value = || iterator_values.next().unwrap_or(EvalValue::Nil);
+                let (function, state, mut control, closing) =
+                    (next_value(), next_value(), next_value(), next_value());
+
+                // The optional fourth value is closed when the loop ends
                 _g.enter_scope();
+                _g.set_position(span.start);
+                if let Err(err) = _g.declare_to_be_closed("(for state)".to_string(), closing) {
+                    return _g.exit_scope(Err(err));
+                }
 
-                if basic_condition.execute(_g)?.is_true() {
-                    for statement in code_block {
-                        let return_value = statement.execute(_g)?;
+                let result = loop {
+                    _g.set_position(span.start);
+                    let values =
+                        match _g.call_function(function.clone(), vec![state.clone(), control]) {
+                            Ok(values) => values,
+                            Err(err) => break Err(err),
+                        };
+                    let mut values = values.into_iter();
 
-                        if return_value != EvalValue::Void {
-                            _g.exit_scope();
-                            return Ok(return_value);
-                        }
+                    control = values.next().unwrap_or(EvalValue::Nil);
+                    if control == EvalValue::Nil {
+                        break Ok(ControlFlow::Normal);
                     }
-                } else {
-                    for (condition, block) in elseif_statements {
-                        if condition.execute(_g)?.is_true() {
-                            for statement in block {
-                                let return_value = statement.execute(_g)?;
-
-                                if return_value != EvalValue::Void {
-                                    _g.exit_scope();
-                                    return Ok(return_value);
-                                }
-                            }
-                            return Ok(EvalValue::Void);
-                        }
+
+                    let mut iterators = vec![(iterator_identifiers[0].clone(), control.clone())];
+                    for identifier in &iterator_identifiers[1..] {
+                        iterators
+                            .push((identifier.clone(), values.next().unwrap_or(EvalValue::Nil)));
                     }
-                    if let Some(block) = else_block {
-                        for statement in block {
-                            let return_value = statement.execute(_g)?;
 
-                            if return_value != EvalValue::Void {
-                                _g.exit_scope();
-                                return Ok(return_value);
+                    match _g.execute_block(code_block, iterators) {
+                        Ok(ControlFlow::Normal) => {
+                            if let Err(err) = _g.jump_back(span.start.line) {
+                                break Err(err);
                             }
                         }
+                        Ok(ControlFlow::Break) => break Ok(ControlFlow::Normal),
+                        other => break other,
+                    }
+                };
+
+                _g.exit_scope(result)
+            }
+            Statement::IfStatement {
+                basic_condition,
+                code_block,
+                elseif_statements,
+                else_block,
+                ..
+            } => {
+                if basic_condition.execute(_g)?.is_true() {
+                    return _g.execute_block(code_block, Vec::new());
+                }
+
+                for (condition, block) in elseif_statements {
+                    if condition.execute(_g)?.is_true() {
+                        return _g.execute_block(block, Vec::new());
                     }
                 }
 
-                _g.exit_scope();
-                Ok(EvalValue::Void)
+                match else_block {
+                    Some(block) => _g.execute_block(block, Vec::new()),
+                    None => Ok(ControlFlow::Normal),
+                }
             }
-            Statement::ExpressionStatement(expr) => {
+            Statement::DoBlock(code_block, _) => _g.execute_block(code_block, Vec::new()),
+            Statement::ExpressionStatement(expr, _) => {
                 expr.execute(_g)?;
-                Ok(EvalValue::Void)
+                Ok(ControlFlow::Normal)
             }
             Statement::FunctionDeclaration {
+                target, function, ..
+            } => {
+                let closure = _g.create_closure(function.clone());
+
+                match target.as_ref() {
+                    Expression::IdentifierExpression(name, _) => {
+                        _g.change_or_create_value(name.clone(), closure)?
+                    }
+                    Expression::IndexOperator(table, field, span) => {
+                        let table_value = table.execute(_g)?;
+                        let field = field.execute(_g)?;
+                        _g.set_position(span.start);
+
+                        if !matches!(table_value, EvalValue::Table(_))
+                            && _g.get_metamethod(&table_value, "__newindex") == EvalValue::Nil
+                        {
+                            return Err(table.operand_error("index", &table_value, _g));
+                        }
+                        _g.set_index(table_value, field, closure)?;
+                    }
+                    _ => unreachable!("Functions are declared as variables or fields"),
+                }
+                Ok(ControlFlow::Normal)
+            }
+            Statement::LocalFunctionDeclaration {
                 function_name,
-                function_arguments,
-                function_body,
+                function,
+                ..
             } => {
-                _g.declare_variable(
-                    function_name.clone(),
-                    EvalValue::DeclaredFunction {
-                        arguments: function_arguments.clone(),
-                        body: function_body.clone(),
-                    },
-                );
-                Ok(EvalValue::Void)
+                // Declared before the closure is created so the function can refer to itself
+                _g.declare_variable(function_name.clone(), EvalValue::Nil);
+                let closure = _g.create_closure(function.clone());
+                _g.change_or_create_value(function_name.clone(), closure)?;
+                Ok(ControlFlow::Normal)
             }
-            Statement::ReturnStatement(expression) => Ok(expression.execute(_g)?),
+            Statement::ReturnStatement(expressions, _) => Ok(ControlFlow::Return(
+                execute_expression_list(expressions, _g)?,
+            )),
+            Statement::BreakStatement(_) => Ok(ControlFlow::Break),
             Statement::RepeatUntilLoop {
                 code_block,
                 loop_condition,
+                ..
             } => {
-                _g.enter_scope();
-
                 loop {
-                    for statement in code_block {
-                        let return_value = statement.execute(_g)?;
-
-                        if return_value != EvalValue::Void {
-                            _g.exit_scope();
-                            return Ok(return_value);
-                        }
-                    }
+                    // The condition can see the locals declared inside the loop body
+                    _g.enter_scope();
+                    let result = match _g.execute_statements(code_block) {
+                        Ok(ControlFlow::Normal) => loop_condition.execute(_g).map(|condition| {
+                            if condition.is_true() {
+                                ControlFlow::Break
+                            } else {
+                                ControlFlow::Normal
+                            }
+                        }),
+                        other => other,
+                    };
 
-                    if loop_condition.execute(_g)?.is_true() {
-                        break;
+                    match _g.exit_scope(result)? {
+                        ControlFlow::Normal => _g.jump_back(self.span().start.line)?,
+                        ControlFlow::Break => break,
+                        return_value => return Ok(return_value),
                     }
                 }
 
-                _g.exit_scope();
-                Ok(EvalValue::Void)
+                Ok(ControlFlow::Normal)
             }
         }
     }
 }
+
+/// The values of a numeric `for` loop over integers. The number of iterations is computed
+/// up front, so the loop never overflows. A float limit is rounded towards the start.
+fn integer_for_loop(start: i64, limit: &EvalValue, step: i64) -> impl Iterator<Item = EvalValue> {
+    let limit = match *limit {
+        EvalValue::Integer(limit) => Some(limit),
+        EvalValue::Number(limit) if limit.is_nan() => None,
+        EvalValue::Number(limit) => {
+            let limit = if step > 0 {
+                limit.floor()
+            } else {
+                limit.ceil()
+            };
+            Some(limit.clamp(i64::MIN as f64, i64::MAX as f64) as i64)
+        }
+        _ => None,
+    };
+
+    let count = match limit {
+        Some(limit) if step > 0 && start <= limit => {
+            Some((limit as u64).wrapping_sub(start as u64) / step as u64)
+        }
+        Some(limit) if step < 0 && start >= limit => {
+            Some((start as u64).wrapping_sub(limit as u64) / (step as u64).wrapping_neg())
+        }
+        _ => None,
+    };
+
+    count
+        .into_iter()
+        .flat_map(move |count| {
+            (0..=count).map(move |i| start.wrapping_add((i as i64).wrapping_mul(step)))
+        })
+        .map(EvalValue::Integer)
+}
+
+/// The values of a numeric `for` loop over floats.
+fn float_for_loop(start: f64, limit: f64, step: f64) -> impl Iterator<Item = EvalValue> {
+    std::iter::successors(Some(start), move |value| Some(value + step))
+        .take_while(move |value| (step > 0.0 && *value <= limit) || (step < 0.0 && *value >= limit))
+        .map(EvalValue::Number)
+}
@@ -0,0 +1,219 @@
+use crate::{
+    ast::{EvalValue, LuaString},
+    error::LuaError,
+};
+
+/// Rust values that can be passed to Lua.
+pub trait IntoLua {
+    fn into_lua(self) -> EvalValue;
+}
+
+/// Rust values that can be made from a Lua value. Conversions follow the coercions of
+/// the Lua API: numeric strings convert to numbers, numbers to strings, and any value to
+/// a boolean by its truthiness.
+pub trait FromLua: Sized {
+    fn from_lua(value: EvalValue) -> Result<Self, LuaError>;
+}
+
+/// Rust values that stand for a list of Lua values, such as the arguments of a call.
+/// Tuples are their elements in order, and `()` is no values at all.
+pub trait IntoLuaMulti {
+    fn into_lua_multi(self) -> Vec<EvalValue>;
+}
+
+/// Rust values that can be made from a list of Lua values, such as the results of a call.
+/// Missing values are nil and extra values are dropped.
+pub trait FromLuaMulti: Sized {
+    fn from_lua_multi(values: Vec<EvalValue>) -> Result<Self, LuaError>;
+}
+
+fn expected(type_name: &str, value: &EvalValue) -> LuaError {
+    LuaError::type_mismatch(format!("{} expected, got {}", type_name, value.type_name()))
+}
+
+impl IntoLua for EvalValue {
+    fn into_lua(self) -> EvalValue {
+        self
+    }
+}
+
+impl FromLua for EvalValue {
+    fn from_lua(value: EvalValue) -> Result<Self, LuaError> {
+        Ok(value)
+    }
+}
+
+impl IntoLua for bool {
+    fn into_lua(self) -> EvalValue {
+        EvalValue::Boolean(self)
+    }
+}
+
+impl FromLua for bool {
+    fn from_lua(value: EvalValue) -> Result<Self, LuaError> {
+        Ok(value.is_true())
+    }
+}
+
+macro_rules! integer_conversions {
+    ($($integer:ty),+) => {$(
+        impl IntoLua for $integer {
+            /// Integers that do not fit in a Lua integer become floats.
+            fn into_lua(self) -> EvalValue {
+                match i64::try_from(self) {
+                    Ok(i) => EvalValue::Integer(i),
+                    Err(_) => EvalValue::Number(self as f64),
+                }
+            }
+        }
+
+        impl FromLua for $integer {
+            fn from_lua(value: EvalValue) -> Result<Self, LuaError> {
+                let integer = match value.to_numeric() {
+                    Some(_) => value
+                        .to_integer()
+                        .ok_or("number has no integer representation")?,
+                    None => return Err(expected("number", &value)),
+                };
+                <$integer>::try_from(integer).map_err(|_| {
+                    LuaError::runtime(format!(
+                        "integer {} out of range for {}",
+                        integer,
+                        stringify!($integer)
+                    ))
+                })
+            }
+        }
+    )+};
+}
+
+integer_conversions!(i8, i16, i32, i64, isize, u8, u16, u32, u64, usize);
+
+impl IntoLua for f64 {
+    fn into_lua(self) -> EvalValue {
+        EvalValue::Number(self)
+    }
+}
+
+impl FromLua for f64 {
+    fn from_lua(value: EvalValue) -> Result<Self, LuaError> {
+        value.to_number().ok_or_else(|| expected("number", &value))
+    }
+}
+
+impl IntoLua for f32 {
+    fn into_lua(self) -> EvalValue {
+        EvalValue::Number(self.into())
+    }
+}
+
+impl FromLua for f32 {
+    fn from_lua(value: EvalValue) -> Result<Self, LuaError> {
+        f64::from_lua(value).map(|n| n as f32)
+    }
+}
+
+impl IntoLua for LuaString {
+    fn into_lua(self) -> EvalValue {
+        EvalValue::String(self)
+    }
+}
+
+impl FromLua for LuaString {
+    fn from_lua(value: EvalValue) -> Result<Self, LuaError> {
+        value
+            .to_lua_string()
+            .ok_or_else(|| expected("string", &value))
+    }
+}
+
+impl IntoLua for &str {
+    fn into_lua(self) -> EvalValue {
+        EvalValue::string(self)
+    }
+}
+
+impl IntoLua for String {
+    fn into_lua(self) -> EvalValue {
+        EvalValue::string(self)
+    }
+}
+
+/// Strings that are not valid UTF-8 fail to convert; use `LuaString` for binary data.
+impl FromLua for String {
+    fn from_lua(value: EvalValue) -> Result<Self, LuaError> {
+        let string = LuaString::from_lua(value)?;
+        String::from_utf8(string.to_vec())
+            .map_err(|_| LuaError::runtime("string is not valid UTF-8"))
+    }
+}
+
+/// `None` is nil.
+impl<T: IntoLua> IntoLua for Option<T> {
+    fn into_lua(self) -> EvalValue {
+        self.map_or(EvalValue::Nil, IntoLua::into_lua)
+    }
+}
+
+impl<T: FromLua> FromLua for Option<T> {
+    fn from_lua(value: EvalValue) -> Result<Self, LuaError> {
+        match value {
+            EvalValue::Nil => Ok(None),
+            value => T::from_lua(value).map(Some),
+        }
+    }
+}
+
+impl<T: IntoLua> IntoLuaMulti for T {
+    fn into_lua_multi(self) -> Vec<EvalValue> {
+        vec![self.into_lua()]
+    }
+}
+
+impl<T: FromLua> FromLuaMulti for T {
+    fn from_lua_multi(values: Vec<EvalValue>) -> Result<Self, LuaError> {
+        T::from_lua(values.into_iter().next().unwrap_or(EvalValue::Nil))
+    }
+}
+
+impl IntoLuaMulti for Vec<EvalValue> {
+    fn into_lua_multi(self) -> Vec<EvalValue> {
+        self
+    }
+}
+
+impl FromLuaMulti for Vec<EvalValue> {
+    fn from_lua_multi(values: Vec<EvalValue>) -> Result<Self, LuaError> {
+        Ok(values)
+    }
+}
+
+macro_rules! tuple_conversions {
+    ($($name:ident),*) => {
+        impl<$($name: IntoLua),*> IntoLuaMulti for ($($name,)*) {
+            #[allow(non_snake_case)]
+            fn into_lua_multi(self) -> Vec<EvalValue> {
+                let ($($name,)*) = self;
+                vec![$($name.into_lua()),*]
+            }
+        }
+
+        impl<$($name: FromLua),*> FromLuaMulti for ($($name,)*) {
+            #[allow(unused_variables, unused_mut)]
+            fn from_lua_multi(values: Vec<EvalValue>) -> Result<Self, LuaError> {
+                let mut values = values.into_iter();
+                Ok(($($name::from_lua(values.next().unwrap_or(EvalValue::Nil))?,)*))
+            }
+        }
+    };
+}
+
+tuple_conversions!();
+tuple_conversions!(A);
+tuple_conversions!(A, B);
+tuple_conversions!(A, B, C);
+tuple_conversions!(A, B, C, D);
+tuple_conversions!(A, B, C, D, E);
+tuple_conversions!(A, B, C, D, E, F);
+tuple_conversions!(A, B, C, D, E, F, G);
+tuple_conversions!(A, B, C, D, E, F, G, H);
@@ -0,0 +1,228 @@
+use std::{cell::RefCell, rc::Rc};
+
+use corosensei::{
+    stack::{DefaultStack, Stack},
+    CoroutineResult, Yielder,
+};
+
+use crate::{
+    ast::EvalValue,
+    error::LuaError,
+    vm::{DebugHook, ParkedStack, VirtualMachine},
+};
+
+pub type ThreadRef = Rc<RefCell<Coroutine>>;
+pub type CoroutineYielder = Yielder<ResumeSignal, Vec<EvalValue>>;
+
+type Execution = corosensei::Coroutine<
+    ResumeSignal,
+    Vec<EvalValue>,
+    Result<Vec<EvalValue>, LuaError>,
+    DefaultStack,
+>;
+
+const COROUTINE_STACK_SIZE: usize = 16 * 1024 * 1024;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum CoroutineStatus {
+    Suspended,
+    Running,
+    Normal,
+    Dead,
+}
+
+impl CoroutineStatus {
+    pub fn name(self) -> &'static str {
+        match self {
+            CoroutineStatus::Suspended => "suspended",
+            CoroutineStatus::Running => "running",
+            CoroutineStatus::Normal => "normal",
+            CoroutineStatus::Dead => "dead",
+        }
+    }
+}
+
+/// What a suspended coroutine receives when it is switched back to.
+pub enum ResumeSignal {
+    Values(Vec<EvalValue>),
+    Close,
+}
+
+/// A Lua thread. Each coroutine runs on its own native stack, so it can yield from
+/// any depth of nested Lua and native calls.
+pub struct Coroutine {
+    is_main: bool,
+    status: CoroutineStatus,
+    execution: Option<Execution>,
+    /// The virtual machine and function of a coroutine that has not been resumed yet.
+    /// Its native stack is only allocated once it runs, so creating coroutines is cheap.
+    body: Option<(VirtualMachine, EvalValue)>,
+    error: Option<LuaError>,
+    /// The debug hook of the thread, shared with the virtual machine it runs on.
+    pub(crate) hook: Rc<DebugHook>,
+    /// The call stack of the thread while it is suspended or resuming another thread.
+    pub(crate) stack: ParkedStack,
+}
+
+impl Coroutine {
+    /// The thread the interpreter starts on. It is always running and cannot yield.
+    pub fn main() -> ThreadRef {
+        Rc::new(RefCell::new(Coroutine {
+            is_main: true,
+            status: CoroutineStatus::Running,
+            execution: None,
+            body: None,
+            error: None,
+            hook: Rc::default(),
+            stack: ParkedStack::default(),
+        }))
+    }
+
+    pub fn new(virtual_machine: &VirtualMachine, function: EvalValue) -> ThreadRef {
+        let thread = Rc::new(RefCell::new(Coroutine {
+            is_main: false,
+            status: CoroutineStatus::Suspended,
+            execution: None,
+            body: None,
+            error: None,
+            hook: Rc::default(),
+            stack: ParkedStack::default(),
+        }));
+
+        let thread_vm = virtual_machine.new_thread(&thread);
+        thread.borrow_mut().body = Some((thread_vm, function));
+        thread
+    }
+
+    /// The execution of a suspended coroutine, started on a new native stack if the
+    /// coroutine has not run yet.
+    fn take_execution(&mut self) -> Result<Execution, LuaError> {
+        if let Some(execution) = self.execution.take() {
+            return Ok(execution);
+        }
+
+        let stack = DefaultStack::new(COROUTINE_STACK_SIZE).map_err(|_| LuaError::memory())?;
+        let (mut thread_vm, function) = self.body.take().expect("Suspended coroutine has no body");
+        thread_vm.stack_limit = stack.limit().get();
+
+        Ok(Execution::with_stack(
+            stack,
+            move |yielder, signal| match signal {
+                ResumeSignal::Values(args) => {
+                    thread_vm.yielder = Some(yielder as *const CoroutineYielder);
+                    thread_vm.call_function(function, args)
+                }
+                ResumeSignal::Close => Ok(Vec::new()),
+            },
+        ))
+    }
+
+    pub fn status(&self) -> CoroutineStatus {
+        self.status
+    }
+
+    pub fn is_main(&self) -> bool {
+        self.is_main
+    }
+}
+
+/// Switches to `thread`, returning the values it yields or returns.
+pub fn resume(
+    virtual_machine: &mut VirtualMachine,
+    thread: &ThreadRef,
+    args: Vec<EvalValue>,
+) -> Result<Vec<EvalValue>, LuaError> {
+    let mut execution = {
+        let mut coroutine = thread.borrow_mut();
+
+        match coroutine.status {
+            CoroutineStatus::Suspended => {}
+            CoroutineStatus::Dead => return Err("cannot resume dead coroutine".into()),
+            _ => return Err("cannot resume non-suspended coroutine".into()),
+        }
+
+        let execution = coroutine.take_execution()?;
+        coroutine.status = CoroutineStatus::Running;
+        execution
+    };
+
+    let resumer = virtual_machine.current_thread();
+    {
+        let mut resumer = resumer.borrow_mut();
+        resumer.status = CoroutineStatus::Normal;
+        resumer.stack = virtual_machine.park_stack();
+    }
+
+    let result = execution.resume(ResumeSignal::Values(args));
+
+    {
+        let mut resumer = resumer.borrow_mut();
+        resumer.status = CoroutineStatus::Running;
+        virtual_machine.unpark_stack(std::mem::take(&mut resumer.stack));
+    }
+
+    let mut coroutine = thread.borrow_mut();
+    match result {
+        CoroutineResult::Yield(values) => {
+            coroutine.status = CoroutineStatus::Suspended;
+            coroutine.execution = Some(execution);
+            Ok(values)
+        }
+        CoroutineResult::Return(result) => {
+            coroutine.status = CoroutineStatus::Dead;
+            if let Err(err) = &result {
+                coroutine.error = Some(err.clone());
+            }
+            result
+        }
+    }
+}
+
+/// Suspends the coroutine running on `virtual_machine`, returning the values it is
+/// resumed with.
+pub fn yield_values(
+    virtual_machine: &mut VirtualMachine,
+    values: Vec<EvalValue>,
+) -> Result<Vec<EvalValue>, LuaError> {
+    let yielder = virtual_machine
+        .yielder
+        .ok_or_else(|| LuaError::from("attempt to yield from outside a coroutine"))?;
+
+    // The suspended stack must not keep its own thread alive, or the thread could never
+    // be freed
+    virtual_machine.current_thread().borrow_mut().stack = virtual_machine.park_stack();
+
+    // SAFETY: `yielder` is only set on the virtual machine owned by a coroutine's body,
+    // and that body is the only code that runs on the coroutine's stack, so the yielder
+    // is alive whenever this virtual machine is executing.
+    let signal = unsafe { &*yielder }.suspend(values);
+
+    let stack = std::mem::take(&mut virtual_machine.current_thread().borrow_mut().stack);
+    virtual_machine.unpark_stack(stack);
+    match signal {
+        ResumeSignal::Values(values) => Ok(values),
+        ResumeSignal::Close => Err(LuaError::CoroutineClosed),
+    }
+}
+
+/// Kills a suspended or dead coroutine, running the `__close` metamethods of its pending
+/// to-be-closed variables. Returns the error that killed the coroutine or that was raised
+/// while closing it, if any.
+pub fn close(thread: &ThreadRef) -> Option<LuaError> {
+    let mut coroutine = thread.borrow_mut();
+    coroutine.status = CoroutineStatus::Dead;
+
+    coroutine.body = None;
+    let execution = coroutine.execution.take();
+    let error = coroutine.error.take();
+    drop(coroutine);
+
+    match execution {
+        Some(mut execution) if execution.started() => match execution.resume(ResumeSignal::Close) {
+            CoroutineResult::Return(Err(LuaError::CoroutineClosed)) => None,
+            CoroutineResult::Return(Err(err)) => Some(err),
+            _ => None,
+        },
+        _ => error,
+    }
+}
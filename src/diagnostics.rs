@@ -0,0 +1,181 @@
+use std::io::IsTerminal;
+
+use clap::ValueEnum;
+
+use luir::{
+    error::{LuaError, SourceLocation},
+    EvalValue, Lua,
+};
+
+const BOLD: &str = "\x1b[1m";
+const RED: &str = "\x1b[1;31m";
+const BLUE: &str = "\x1b[1;34m";
+const RESET: &str = "\x1b[0m";
+
+/// When to colour the error messages printed by the interpreter.
+#[derive(Debug, Clone, Copy, PartialEq, ValueEnum)]
+pub enum ColorChoice {
+    /// Colour when standard error is a terminal and `NO_COLOR` is not set.
+    Auto,
+    Always,
+    Never,
+}
+
+/// Replaces an error object that is not a string but has a `__tostring` metamethod with
+/// the string it converts to, like the message handler of `lua`. Other error objects
+/// are reported by their type.
+pub fn convert_error_object(lua: &mut Lua, err: &mut LuaError) {
+    let Some(details) = err.details_mut() else {
+        return;
+    };
+    if matches!(
+        details.value,
+        EvalValue::String(_) | EvalValue::Number(_) | EvalValue::Integer(_)
+    ) || matches!(
+        lua.get_metamethod(&details.value, "__tostring"),
+        EvalValue::Nil
+    ) {
+        return;
+    }
+
+    if let Ok(message) = lua.tostring(&details.value) {
+        details.value = EvalValue::String(message);
+    }
+}
+
+/// Prints errors of a chunk together with the source lines they point at.
+pub struct Reporter<'a> {
+    chunk_name: &'a str,
+    source_code: &'a str,
+    color: bool,
+}
+
+impl<'a> Reporter<'a> {
+    pub fn new(chunk_name: &'a str, source_code: &'a str, color: ColorChoice) -> Self {
+        let color = match color {
+            ColorChoice::Auto => {
+                std::io::stderr().is_terminal() && std::env::var_os("NO_COLOR").is_none()
+            }
+            ColorChoice::Always => true,
+            ColorChoice::Never => false,
+        };
+
+        Reporter {
+            chunk_name,
+            source_code,
+            color,
+        }
+    }
+
+    pub fn report(&self, err: &LuaError) {
+        eprint!("{}", self.render(err));
+    }
+
+    /// Formats an error the way `lua` prints it, followed by the source line it was
+    /// raised at and, for runtime errors, the stack traceback.
+    pub fn render(&self, err: &LuaError) -> String {
+        let mut output = String::new();
+
+        match err {
+            LuaError::Syntax(errors) => {
+                for error in errors {
+                    let span = error.span;
+                    let width = if span.start.line == span.end.line {
+                        span.end.column.saturating_sub(span.start.column)
+                    } else {
+                        usize::MAX
+                    };
+
+                    output += &self.header(&error.to_string());
+                    output += &self.snippet(&error.location(), width, &error.expected.join(" or "));
+                }
+            }
+            err => {
+                output += &self.header(&err.message());
+                if let Some(location) = err.location() {
+                    output += &self.snippet(&location, 1, "");
+                }
+
+                if !err.traceback().is_empty() {
+                    output += "stack traceback:\n";
+                    for entry in err.traceback() {
+                        output += &format!("\t{}\n", entry);
+                    }
+                }
+            }
+        }
+
+        output
+    }
+
+    fn paint(&self, style: &str, text: &str) -> String {
+        if self.color {
+            format!("{}{}{}", style, text, RESET)
+        } else {
+            text.to_string()
+        }
+    }
+
+    fn header(&self, message: &str) -> String {
+        format!(
+            "{} {}\n",
+            self.paint(RED, "luir:"),
+            self.paint(BOLD, message)
+        )
+    }
+
+    /// Shows the line of `location` with `width` characters from its column underlined,
+    /// and what was expected there if known. Locations in other chunks are only named.
+    fn snippet(&self, location: &SourceLocation, width: usize, expected: &str) -> String {
+        let position = format!(
+            "{}:{}:{}",
+            location.chunk_name, location.line, location.column
+        );
+        let line = self
+            .source_code
+            .lines()
+            .nth(location.line.wrapping_sub(1))
+            .filter(|_| &*location.chunk_name == self.chunk_name);
+
+        let line_number = location.line.to_string();
+        let gutter = " ".repeat(line_number.len());
+        let mut output = format!("{}{} {}\n", gutter, self.paint(BLUE, "-->"), position);
+
+        let Some(line) = line else {
+            return output;
+        };
+
+        // Keep tabs in the indentation of the underline so it lines up with the source
+        let indentation: String = line
+            .chars()
+            .take(location.column.saturating_sub(1))
+            .map(|c| if c == '\t' { '\t' } else { ' ' })
+            .collect();
+        let remaining = line
+            .chars()
+            .count()
+            .saturating_sub(indentation.chars().count());
+        let underline = "^".repeat(width.min(remaining).max(1));
+        let label = if expected.is_empty() {
+            String::new()
+        } else {
+            format!(" {} expected", expected)
+        };
+
+        output += &format!("{} {}\n", gutter, self.paint(BLUE, "|"));
+        output += &format!(
+            "{} {}\n",
+            self.paint(BLUE, &format!("{} |", line_number)),
+            line
+        );
+        output += &format!(
+            "{} {} {}{}\n",
+            gutter,
+            self.paint(BLUE, "|"),
+            indentation,
+            self.paint(RED, &format!("{}{}", underline, label))
+        );
+
+        output
+    }
+}
@@ -0,0 +1,252 @@
+use std::{fmt, rc::Rc};
+
+use crate::{ast::EvalValue, lex::Span};
+
+/// A position in the source code of a chunk.
+#[derive(Debug, Clone, PartialEq)]
+pub struct SourceLocation {
+    pub chunk_name: Rc<str>,
+    pub line: usize,
+    pub column: usize,
+}
+
+impl fmt::Display for SourceLocation {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}:{}", self.chunk_name, self.line)
+    }
+}
+
+/// A problem found while parsing a chunk.
+#[derive(Debug, Clone)]
+pub struct SyntaxError {
+    pub chunk_name: Rc<str>,
+    pub message: String,
+    /// The source code the problem was found at, usually the offending token.
+    pub span: Span,
+    /// What the parser would have accepted instead, e.g. `'end'` or `<name>`.
+    pub expected: Vec<String>,
+}
+
+impl SyntaxError {
+    pub fn location(&self) -> SourceLocation {
+        SourceLocation {
+            chunk_name: self.chunk_name.clone(),
+            line: self.span.start.line,
+            column: self.span.start.column,
+        }
+    }
+}
+
+impl fmt::Display for SyntaxError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}: {}", self.location(), self.message)
+    }
+}
+
+/// A function that was on the call stack when an error was raised.
+#[derive(Debug, Clone)]
+pub struct TracebackEntry {
+    /// Where the function was executing, or `None` for native functions.
+    pub location: Option<SourceLocation>,
+    /// How the function is referred to, e.g. `local 'f'` or `main chunk`.
+    pub description: String,
+}
+
+impl fmt::Display for TracebackEntry {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match &self.location {
+            Some(location) => write!(f, "{}: in {}", location, self.description),
+            None => write!(f, "[C]: in {}", self.description),
+        }
+    }
+}
+
+/// The details of an error raised while running Lua code.
+#[derive(Debug, Clone)]
+pub struct RuntimeError {
+    /// The error object. For errors raised by the interpreter it is the message,
+    /// without the position.
+    pub value: EvalValue,
+    /// Where the error was raised, if it was raised in Lua code.
+    pub location: Option<SourceLocation>,
+    /// The call stack at the point of failure, innermost function first. It is `None`
+    /// until the error leaves the function that raised it.
+    pub traceback: Option<Vec<TracebackEntry>>,
+}
+
+/// An error raised while loading or running Lua code.
+#[derive(Debug, Clone)]
+pub enum LuaError {
+    /// The source code of a chunk is not valid Lua. Holds every problem the parser
+    /// found, in source order; there is always at least one.
+    Syntax(Vec<SyntaxError>),
+    /// An error raised by `error` or a failed runtime check.
+    Runtime(Box<RuntimeError>),
+    /// An operation was applied to a value of the wrong type.
+    Type(Box<RuntimeError>),
+    /// A function was called without a required argument.
+    Arity(Box<RuntimeError>),
+    /// A function was called with an invalid argument.
+    Argument(Box<RuntimeError>),
+    /// Calls were nested too deeply.
+    StackOverflow(Box<RuntimeError>),
+    /// Memory for a new object could not be allocated.
+    Memory(Box<RuntimeError>),
+    /// Unwinds the stack of a suspended coroutine that is being closed.
+    CoroutineClosed,
+}
+
+fn details(message: String) -> Box<RuntimeError> {
+    Box::new(RuntimeError {
+        value: EvalValue::string(message),
+        location: None,
+        traceback: None,
+    })
+}
+
+impl LuaError {
+    /// An error with an arbitrary Lua value as its error object.
+    pub fn from_value(value: EvalValue) -> Self {
+        LuaError::Runtime(Box::new(RuntimeError {
+            value,
+            location: None,
+            traceback: None,
+        }))
+    }
+
+    pub fn runtime(message: impl Into<String>) -> Self {
+        LuaError::Runtime(details(message.into()))
+    }
+
+    pub fn type_mismatch(message: impl Into<String>) -> Self {
+        LuaError::Type(details(message.into()))
+    }
+
+    pub fn arity(message: impl Into<String>) -> Self {
+        LuaError::Arity(details(message.into()))
+    }
+
+    pub fn argument(message: impl Into<String>) -> Self {
+        LuaError::Argument(details(message.into()))
+    }
+
+    pub fn stack_overflow() -> Self {
+        LuaError::StackOverflow(details("stack overflow".to_string()))
+    }
+
+    pub fn memory() -> Self {
+        LuaError::Memory(details("not enough memory".to_string()))
+    }
+
+    /// The details of errors raised while running code.
+    pub fn details(&self) -> Option<&RuntimeError> {
+        match self {
+            LuaError::Runtime(details)
+            | LuaError::Type(details)
+            | LuaError::Arity(details)
+            | LuaError::Argument(details)
+            | LuaError::StackOverflow(details)
+            | LuaError::Memory(details) => Some(details),
+            LuaError::Syntax(_) | LuaError::CoroutineClosed => None,
+        }
+    }
+
+    pub fn details_mut(&mut self) -> Option<&mut RuntimeError> {
+        match self {
+            LuaError::Runtime(details)
+            | LuaError::Type(details)
+            | LuaError::Arity(details)
+            | LuaError::Argument(details)
+            | LuaError::StackOverflow(details)
+            | LuaError::Memory(details) => Some(details),
+            LuaError::Syntax(_) | LuaError::CoroutineClosed => None,
+        }
+    }
+
+    /// Where the error was raised, or where the first syntax error was found.
+    pub fn location(&self) -> Option<SourceLocation> {
+        match self {
+            LuaError::Syntax(errors) => errors.first().map(SyntaxError::location),
+            _ => self.details()?.location.clone(),
+        }
+    }
+
+    pub fn traceback(&self) -> &[TracebackEntry] {
+        self.details()
+            .and_then(|details| details.traceback.as_deref())
+            .unwrap_or_default()
+    }
+
+    /// The value a protected call reports for this error: string error objects get the
+    /// position they were raised at prepended, like `error` does in Lua. Memory errors
+    /// have no position, as Lua reports them.
+    pub fn into_value(self) -> EvalValue {
+        let location = match self {
+            LuaError::Memory(_) => None,
+            _ => self.location(),
+        };
+        let value = match self {
+            LuaError::Syntax(mut errors) => EvalValue::string(errors.swap_remove(0).message),
+            LuaError::CoroutineClosed => return EvalValue::Nil,
+            err => err.details().expect("Error has details").value.clone(),
+        };
+
+        match (value, location) {
+            (EvalValue::String(message), Some(location)) => {
+                EvalValue::string(format!("{}: {}", location, message))
+            }
+            (value, _) => value,
+        }
+    }
+
+    /// The error as `lua` prints it, without the stack traceback. Syntax errors are
+    /// listed one per line.
+    pub fn message(&self) -> String {
+        match self {
+            LuaError::Syntax(errors) => errors
+                .iter()
+                .map(SyntaxError::to_string)
+                .collect::<Vec<_>>()
+                .join("\n"),
+            LuaError::CoroutineClosed => "coroutine is being closed".to_string(),
+            err => match err.clone().into_value() {
+                value @ (EvalValue::String(_) | EvalValue::Number(_) | EvalValue::Integer(_)) => {
+                    value
+                        .to_lua_string()
+                        .expect("Value is a string")
+                        .to_string()
+                }
+                value => format!("(error object is a {} value)", value.type_name()),
+            },
+        }
+    }
+}
+
+impl From<String> for LuaError {
+    fn from(message: String) -> Self {
+        LuaError::runtime(message)
+    }
+}
+
+impl From<&str> for LuaError {
+    fn from(message: &str) -> Self {
+        LuaError::runtime(message)
+    }
+}
+
+impl fmt::Display for LuaError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", self.message())?;
+
+        if !self.traceback().is_empty() {
+            write!(f, "\nstack traceback:")?;
+            for entry in self.traceback() {
+                write!(f, "\n\t{}", entry)?;
+            }
+        }
+
+        Ok(())
+    }
+}
+
+impl std::error::Error for LuaError {}
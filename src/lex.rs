@@ -1,10 +1,49 @@
-use std::str::Chars;
+use std::fmt;
+
+use crate::ast::{number_to_string, string_to_number, EvalValue};
+
+/// A point in the source code: the byte offset from the start of the chunk, and the
+/// line and column, both starting at 1.
+#[derive(Debug, Clone, Copy, PartialEq, Default)]
+pub struct Position {
+    pub offset: usize,
+    pub line: usize,
+    pub column: usize,
+}
+
+impl Position {
+    /// The position of the first character of a chunk.
+    pub const START: Position = Position {
+        offset: 0,
+        line: 1,
+        column: 1,
+    };
+}
+
+/// The part of the source code a token or syntax tree node was read from. `end` is the
+/// position right after its last character.
+#[derive(Debug, Clone, Copy, PartialEq, Default)]
+pub struct Span {
+    pub start: Position,
+    pub end: Position,
+}
+
+impl Span {
+    /// The span covering both `self` and `other`.
+    pub fn to(self, other: Span) -> Span {
+        Span {
+            start: self.start,
+            end: other.end,
+        }
+    }
+}
 
 #[derive(Debug, PartialEq, Clone)]
 pub enum LiteralType {
     Number(f64),
+    Integer(i64),
     Boolean(bool),
-    String(String),
+    String(Vec<u8>),
     Nil,
 }
 
@@ -14,6 +53,7 @@ pub enum Token {
     Local,
     Function,
     Return,
+    Break,
 
     Identifier(String),
 
@@ -25,6 +65,9 @@ pub enum Token {
     Minus,
     Asterisk,
     Slash,
+    DoubleSlash,
+    Percent,
+    Caret,
     LeftParen,
     RightParen,
     LeftBracket,
@@ -34,6 +77,9 @@ pub enum Token {
     Assigment,
     Dot,
     Comma,
+    Semicolon,
+    Colon,
+    Hash,
 
     Equal,
     NotEqual,
@@ -43,6 +89,7 @@ pub enum Token {
     GreaterThanOrEqual,
 
     Concatanation,
+    Ellipsis,
 
     If,
     Then,
@@ -53,55 +100,158 @@ pub enum Token {
     While,
     For,
     Do,
+    In,
 
     Repeat,
     Until,
+
+    And,
+    Or,
+    Not,
+
+    /// Source code that is not a token, with the message describing the problem.
+    Error(String),
+}
+
+impl fmt::Display for Token {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let text = match self {
+            Token::Identifier(name) => return write!(f, "{}", name),
+            Token::Error(message) => return write!(f, "{}", message),
+            Token::Literal(LiteralType::Number(number)) => {
+                return write!(f, "{}", number_to_string(*number))
+            }
+            Token::Literal(LiteralType::Integer(number)) => return write!(f, "{}", number),
+            Token::Literal(LiteralType::String(string)) => {
+                return write!(f, "\"{}\"", String::from_utf8_lossy(string))
+            }
+            Token::Literal(LiteralType::Boolean(true)) => "true",
+            Token::Literal(LiteralType::Boolean(false)) => "false",
+            Token::Literal(LiteralType::Nil) => "nil",
+            Token::Local => "local",
+            Token::Function => "function",
+            Token::Return => "return",
+            Token::Break => "break",
+            Token::Plus => "+",
+            Token::Minus => "-",
+            Token::Asterisk => "*",
+            Token::Slash => "/",
+            Token::DoubleSlash => "//",
+            Token::Percent => "%",
+            Token::Caret => "^",
+            Token::LeftParen => "(",
+            Token::RightParen => ")",
+            Token::LeftBracket => "{",
+            Token::RightBracket => "}",
+            Token::LeftSquareBracket => "[",
+            Token::RightSquareBracket => "]",
+            Token::Assigment => "=",
+            Token::Dot => ".",
+            Token::Comma => ",",
+            Token::Semicolon => ";",
+            Token::Colon => ":",
+            Token::Hash => "#",
+            Token::Equal => "==",
+            Token::NotEqual => "~=",
+            Token::LessThan => "<",
+            Token::LessThanOrEqual => "<=",
+            Token::GreaterThan => ">",
+            Token::GreaterThanOrEqual => ">=",
+            Token::Concatanation => "..",
+            Token::Ellipsis => "...",
+            Token::If => "if",
+            Token::Then => "then",
+            Token::Else => "else",
+            Token::ElseIf => "elseif",
+            Token::End => "end",
+            Token::While => "while",
+            Token::For => "for",
+            Token::Do => "do",
+            Token::In => "in",
+            Token::Repeat => "repeat",
+            Token::Until => "until",
+            Token::And => "and",
+            Token::Or => "or",
+            Token::Not => "not",
+        };
+
+        write!(f, "{}", text)
+    }
 }
 
+/// A token together with the part of the source code it was read from.
+#[derive(Debug, PartialEq, Clone)]
+pub struct SpannedToken {
+    pub token: Token,
+    pub span: Span,
+}
+
+/// Reads the tokens of source code, which like Lua strings is made of bytes and does not
+/// have to be valid UTF-8. Only string literals and comments may hold other than ASCII.
 pub struct Lexer<'a> {
-    input: Chars<'a>,
-    current: Option<char>,
+    input: &'a [u8],
+    current: Option<u8>,
+    position: Position,
 }
 
 impl<'a> Lexer<'a> {
-    pub fn new(input: &'a str) -> Lexer<'a> {
-        let mut chars = input.chars();
+    pub fn new(input: &'a [u8]) -> Lexer<'a> {
         Lexer {
-            current: chars.next(),
-            input: chars,
+            current: input.first().copied(),
+            input,
+            position: Position::START,
         }
     }
 
+    /// Where the next character is.
+    pub fn position(&self) -> Position {
+        self.position
+    }
+
+    /// The byte `distance` bytes after the current one.
+    fn peek(&self, distance: usize) -> Option<u8> {
+        self.input.get(self.position.offset + distance).copied()
+    }
+
     fn advance(&mut self) {
-        self.current = self.input.next();
+        let Some(current) = self.current else {
+            return;
+        };
+        self.position.offset += 1;
+        self.current = self.input.get(self.position.offset).copied();
+
+        if current == b'\n' {
+            self.position.line += 1;
+            self.position.column = 1;
+        } else if !self.current.is_some_and(is_utf8_continuation) {
+            // Columns count characters, so the bytes of a UTF-8 sequence take up one
+            self.position.column += 1;
+        }
     }
 
-    fn consume_while<F>(&mut self, test: F) -> String
+    fn consume_while<F>(&mut self, test: F) -> &'a [u8]
     where
-        F: Fn(char) -> bool,
+        F: Fn(u8) -> bool,
     {
-        let mut result = String::new();
-        while let Some(c) = self.current {
-            if test(c) {
-                result.push(c);
-                self.advance();
-            } else {
-                break;
-            }
+        let start = self.position.offset;
+        while self.current.is_some_and(&test) {
+            self.advance();
         }
-        result
+        &self.input[start..self.position.offset]
     }
 
     fn consume_whitespace(&mut self) {
-        self.consume_while(char::is_whitespace);
+        self.consume_while(is_space);
     }
 
     fn consume_identifier_or_keyword(&mut self) -> Token {
-        let id = self.consume_while(|c| c.is_alphanumeric() || c == '_');
-        match id.as_str() {
+        let id = self.consume_while(|c| c.is_ascii_alphanumeric() || c == b'_');
+        let id = String::from_utf8_lossy(id);
+        match id.as_ref() {
             "local" => Token::Local,
             "function" => Token::Function,
             "return" => Token::Return,
+            "break" => Token::Break,
             "nil" => Token::Literal(LiteralType::Nil),
             "true" => Token::Literal(LiteralType::Boolean(true)),
             "false" => Token::Literal(LiteralType::Boolean(false)),
@@ -113,71 +263,252 @@ impl<'a> Lexer<'a> {
             "while" => Token::While,
             "for" => Token::For,
             "do" => Token::Do,
+            "in" => Token::In,
             "repeat" => Token::Repeat,
             "until" => Token::Until,
-            _ => Token::Identifier(id),
+            "and" => Token::And,
+            "or" => Token::Or,
+            "not" => Token::Not,
+            _ => Token::Identifier(id.into_owned()),
         }
     }
 
+    /// Reads a decimal or hexadecimal numeral, with an optional fraction and exponent.
     fn consume_number(&mut self) -> Result<Token, String> {
-        let num_str = self.consume_while(|c| c.is_ascii_digit() || c == '.');
-        Ok(Token::Literal(LiteralType::Number(
-            num_str.parse().or(Err("Number conversion error"))?,
-        )))
+        let mut numeral = String::new();
+        let mut exponent_markers = ['e', 'E'];
+        if self.current == Some(b'0') && matches!(self.peek(1), Some(b'x' | b'X')) {
+            exponent_markers = ['p', 'P'];
+            numeral.extend(self.current.map(char::from));
+            self.advance();
+            numeral.extend(self.current.map(char::from));
+            self.advance();
+        }
+
+        while let Some(c) = self.current.map(char::from) {
+            if exponent_markers.contains(&c) {
+                numeral.push(c);
+                self.advance();
+                if let Some(sign @ (b'+' | b'-')) = self.current {
+                    numeral.push(char::from(sign));
+                    self.advance();
+                }
+            } else if c.is_ascii_hexdigit() || c == '.' {
+                numeral.push(c);
+                self.advance();
+            } else {
+                break;
+            }
+        }
+
+        // A letter right after the numeral makes it malformed, as in `3x`
+        if let Some(c) = self
+            .current
+            .filter(|c| c.is_ascii_alphanumeric() || *c == b'_')
+        {
+            numeral.push(char::from(c));
+            self.advance();
+        }
+
+        match string_to_number(&numeral) {
+            Some(EvalValue::Integer(number)) => Ok(Token::Literal(LiteralType::Integer(number))),
+            Some(EvalValue::Number(number)) => Ok(Token::Literal(LiteralType::Number(number))),
+            _ => Err(format!("malformed number near '{}'", numeral)),
+        }
+    }
+
+    /// Reads a string literal delimited by `quote`, resolving its escape sequences.
+    fn consume_string(&mut self, quote: u8) -> Result<Vec<u8>, String> {
+        let mut string = Vec::new();
+        self.advance();
+
+        loop {
+            match self.current {
+                None => return Err("unfinished string near <eof>".to_string()),
+                Some(b'\n') => {
+                    return Err(format!(
+                        "unfinished string near '{}{}'",
+                        char::from(quote),
+                        String::from_utf8_lossy(&string)
+                    ))
+                }
+                Some(c) if c == quote => break,
+                Some(b'\\') => {
+                    self.advance();
+                    if let Err(message) = self.consume_escape_sequence(&mut string) {
+                        // Skip the rest of the string, so it is not read as code
+                        self.consume_while(|c| c != quote && c != b'\n');
+                        if self.current == Some(quote) {
+                            self.advance();
+                        }
+                        return Err(message);
+                    }
+                }
+                Some(c) => {
+                    string.push(c);
+                    self.advance();
+                }
+            }
+        }
+
+        self.advance();
+        Ok(string)
+    }
+
+    /// Reads the escape sequence after a backslash in a string literal into `string`.
+    fn consume_escape_sequence(&mut self, string: &mut Vec<u8>) -> Result<(), String> {
+        let Some(c) = self.current else {
+            return Err("unfinished string near <eof>".to_string());
+        };
+
+        let byte = match c {
+            b'a' => 0x07,
+            b'b' => 0x08,
+            b'f' => 0x0c,
+            b'n' => b'\n',
+            b'r' => b'\r',
+            b't' => b'\t',
+            b'v' => 0x0b,
+            b'\\' | b'"' | b'\'' | b'\n' => c,
+            // `\z` skips the following whitespace, including line breaks
+            b'z' => {
+                self.advance();
+                self.consume_whitespace();
+                return Ok(());
+            }
+            b'x' => {
+                self.advance();
+                let mut value = 0;
+                for _ in 0..2 {
+                    let digit = self
+                        .current
+                        .and_then(|c| char::from(c).to_digit(16))
+                        .ok_or("hexadecimal digit expected")?;
+                    value = value * 16 + digit;
+                    self.advance();
+                }
+                string.push(value as u8);
+                return Ok(());
+            }
+            b'u' => {
+                self.advance();
+                if self.current != Some(b'{') {
+                    return Err("missing '{' in \\u{xxxx}".to_string());
+                }
+                self.advance();
+
+                let digits = self.consume_while(|c| c.is_ascii_hexdigit());
+                let value = std::str::from_utf8(digits)
+                    .ok()
+                    .and_then(|digits| u32::from_str_radix(digits, 16).ok())
+                    .filter(|value| *value <= 0x7fff_ffff)
+                    .ok_or(if digits.is_empty() {
+                        "hexadecimal digit expected"
+                    } else {
+                        "UTF-8 value too large"
+                    })?;
+                if self.current != Some(b'}') {
+                    return Err("missing '}' in \\u{xxxx}".to_string());
+                }
+                self.advance();
+
+                encode_utf8(value, string);
+                return Ok(());
+            }
+            _ if c.is_ascii_digit() => {
+                let mut value = 0;
+                for _ in 0..3 {
+                    match self.current.and_then(|c| char::from(c).to_digit(10)) {
+                        Some(digit) => value = value * 10 + digit,
+                        None => break,
+                    }
+                    self.advance();
+                }
+                if value > 255 {
+                    return Err("decimal escape too large".to_string());
+                }
+                string.push(value as u8);
+                return Ok(());
+            }
+            _ => return Err(format!("invalid escape sequence '\\{}'", byte_to_string(c))),
+        };
+
+        string.push(byte);
+        self.advance();
+        Ok(())
     }
 
-    pub fn tokenize(&mut self) -> Result<Vec<Token>, String> {
+    /// Splits the source code into tokens. Invalid source code becomes `Token::Error`
+    /// and the rest of the code is still read, so the parser can report every problem.
+    pub fn tokenize(&mut self) -> Vec<SpannedToken> {
         let mut tokens = Vec::new();
+        let mut spans = Vec::new();
         self.consume_whitespace();
         while let Some(c) = self.current {
+            let position = self.position;
+
             match c {
-                '+' => {
+                b'+' => {
                     tokens.push(Token::Plus);
                     self.advance();
                 }
-                '-' => {
-                    if Some('-') == self.input.clone().next() {
-                        self.consume_while(|c| c != '\n');
+                b'-' => {
+                    if Some(b'-') == self.peek(1) {
+                        self.consume_while(|c| c != b'\n');
                     } else {
                         tokens.push(Token::Minus);
                     }
 
                     self.advance();
                 }
-                '*' => {
+                b'*' => {
                     tokens.push(Token::Asterisk);
                     self.advance();
                 }
-                '/' => {
-                    tokens.push(Token::Slash);
+                b'/' => {
+                    if Some(b'/') == self.peek(1) {
+                        tokens.push(Token::DoubleSlash);
+                        self.advance();
+                    } else {
+                        tokens.push(Token::Slash);
+                    }
+
+                    self.advance();
+                }
+                b'%' => {
+                    tokens.push(Token::Percent);
                     self.advance();
                 }
-                '(' => {
+                b'^' => {
+                    tokens.push(Token::Caret);
+                    self.advance();
+                }
+                b'(' => {
                     tokens.push(Token::LeftParen);
                     self.advance();
                 }
-                ')' => {
+                b')' => {
                     tokens.push(Token::RightParen);
                     self.advance();
                 }
-                '{' => {
+                b'{' => {
                     tokens.push(Token::LeftBracket);
                     self.advance();
                 }
-                '}' => {
+                b'}' => {
                     tokens.push(Token::RightBracket);
                     self.advance();
                 }
-                '[' => {
+                b'[' => {
                     tokens.push(Token::LeftSquareBracket);
                     self.advance();
                 }
-                ']' => {
+                b']' => {
                     tokens.push(Token::RightSquareBracket);
                     self.advance();
                 }
-                '<' => {
-                    if Some('=') == self.input.clone().next() {
+                b'<' => {
+                    if Some(b'=') == self.peek(1) {
                         tokens.push(Token::LessThanOrEqual);
                         self.advance();
                     } else {
@@ -186,8 +517,8 @@ impl<'a> Lexer<'a> {
 
                     self.advance();
                 }
-                '>' => {
-                    if Some('=') == self.input.clone().next() {
+                b'>' => {
+                    if Some(b'=') == self.peek(1) {
                         tokens.push(Token::GreaterThanOrEqual);
                         self.advance();
                     } else {
@@ -196,8 +527,8 @@ impl<'a> Lexer<'a> {
 
                     self.advance();
                 }
-                '=' => {
-                    if Some('=') == self.input.clone().next() {
+                b'=' => {
+                    if Some(b'=') == self.peek(1) {
                         tokens.push(Token::Equal);
                         self.advance();
                     } else {
@@ -207,20 +538,28 @@ impl<'a> Lexer<'a> {
                     self.advance();
                 }
 
-                '~' => {
-                    if Some('=') == self.input.clone().next() {
+                b'~' => {
+                    if Some(b'=') == self.peek(1) {
                         tokens.push(Token::NotEqual);
                         self.advance();
                     } else {
-                        return Err(String::from("Unexpected char after ~ expected ="));
+                        tokens.push(Token::Error("unexpected symbol near '~'".to_string()));
                     }
 
                     self.advance();
                 }
 
-                '.' => {
-                    if Some('.') == self.input.clone().next() {
-                        tokens.push(Token::Concatanation);
+                b'.' if self.peek(1).is_some_and(|c| c.is_ascii_digit()) => {
+                    tokens.push(self.consume_number().unwrap_or_else(Token::Error));
+                }
+                b'.' => {
+                    if Some(b'.') == self.peek(1) {
+                        if Some(b'.') == self.peek(2) {
+                            tokens.push(Token::Ellipsis);
+                            self.advance();
+                        } else {
+                            tokens.push(Token::Concatanation);
+                        }
                         self.advance();
                     } else {
                         tokens.push(Token::Dot);
@@ -229,29 +568,104 @@ impl<'a> Lexer<'a> {
                     self.advance();
                 }
 
-                ',' => {
+                b',' => {
                     tokens.push(Token::Comma);
                     self.advance();
                 }
 
-                _ if c.is_whitespace() => {
+                b';' => {
+                    tokens.push(Token::Semicolon);
+                    self.advance();
+                }
+
+                b':' => {
+                    tokens.push(Token::Colon);
+                    self.advance();
+                }
+
+                b'#' => {
+                    tokens.push(Token::Hash);
+                    self.advance();
+                }
+
+                _ if is_space(c) => {
                     self.consume_whitespace();
                 }
                 _ if c.is_ascii_digit() => {
-                    tokens.push(self.consume_number()?);
+                    tokens.push(self.consume_number().unwrap_or_else(Token::Error));
                 }
-                _ if c.is_ascii_alphabetic() => {
+                _ if c.is_ascii_alphabetic() || c == b'_' => {
                     tokens.push(self.consume_identifier_or_keyword());
                 }
-                '"' => {
-                    self.advance();
-                    let string = self.consume_while(|c| c != '"');
+                b'"' | b'\'' => {
+                    tokens.push(
+                        self.consume_string(c)
+                            .map(|string| Token::Literal(LiteralType::String(string)))
+                            .unwrap_or_else(Token::Error),
+                    );
+                }
+                _ => {
+                    tokens.push(Token::Error(format!(
+                        "unexpected symbol near '{}'",
+                        byte_to_string(c)
+                    )));
                     self.advance();
-                    tokens.push(Token::Literal(LiteralType::String(string)));
                 }
-                _ => Err(format!("Unexpected character: {}", c))?,
+            }
+
+            if tokens.len() > spans.len() {
+                spans.push(Span {
+                    start: position,
+                    end: self.position,
+                });
             }
         }
-        Ok(tokens)
+
+        tokens
+            .into_iter()
+            .zip(spans)
+            .map(|(token, span)| SpannedToken { token, span })
+            .collect()
     }
 }
+
+/// Whether `c` is white space, as C's `isspace` has it.
+fn is_space(c: u8) -> bool {
+    c.is_ascii_whitespace() || c == 0x0b
+}
+
+fn is_utf8_continuation(c: u8) -> bool {
+    c & 0xc0 == 0x80
+}
+
+/// `byte` as Lua shows it in error messages: itself if it is printable, otherwise its
+/// value in angle brackets, as in `<\233>`.
+fn byte_to_string(byte: u8) -> String {
+    if byte.is_ascii_graphic() || byte == b' ' {
+        char::from(byte).to_string()
+    } else {
+        format!("<\\{}>", byte)
+    }
+}
+
+/// Encodes `value` like UTF-8, but allowing values up to 2^31 as Lua's `\u{...}` escapes do.
+pub fn encode_utf8(value: u32, output: &mut Vec<u8>) {
+    if value < 0x80 {
+        output.push(value as u8);
+        return;
+    }
+
+    // Continuation bytes hold 6 bits each, the first byte holds what is left
+    let mut continuation = Vec::new();
+    let mut value = value;
+    let mut first_byte_limit = 0x3f;
+    while value > first_byte_limit {
+        continuation.push(0x80 | (value & 0x3f) as u8);
+        value >>= 6;
+        first_byte_limit >>= 1;
+    }
+
+    let prefix = !(first_byte_limit << 1) as u8 & 0xfe;
+    output.push(prefix | value as u8);
+    output.extend(continuation.iter().rev());
+}
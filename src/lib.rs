@@ -0,0 +1,29 @@
+//! Luir is a Lua interpreter. [`Lua`] runs Lua code from Rust and exchanges values with
+//! it; the modules below it are the interpreter itself.
+//!
+//! ```
+//! let mut lua = luir::Lua::new();
+//! lua.load("greeting = 'hello ' .. ...").call::<_, ()>("world")?;
+//!
+//! let greeting: String = lua.globals().get("greeting")?;
+//! assert_eq!(greeting, "hello world");
+//! assert_eq!(lua.load("1 + 2").eval::<i64>()?, 3);
+//! # Ok::<(), luir::LuaError>(())
+//! ```
+
+pub mod ast;
+mod conversion;
+mod coroutine;
+pub mod error;
+pub mod lex;
+pub mod loader;
+mod lua;
+pub mod parser;
+mod stdlib;
+mod vm;
+
+pub use ast::{EvalValue, LuaString};
+pub use conversion::{FromLua, FromLuaMulti, IntoLua, IntoLuaMulti};
+pub use error::LuaError;
+pub use lua::{Chunk, Function, Lua, Table};
+pub use vm::LuaOptions;
@@ -0,0 +1,72 @@
+use std::path::Path;
+
+const BYTE_ORDER_MARK: &[u8] = b"\xef\xbb\xbf";
+
+/// Reads the source code of the Lua file at `path` the way `lua` loads files. Like Lua
+/// strings, the code is made of bytes and does not have to be valid UTF-8.
+pub fn read_source_file(path: impl AsRef<Path>) -> std::io::Result<Vec<u8>> {
+    std::fs::read(path).map(|source_code| skip_file_header(&source_code).to_vec())
+}
+
+/// Skips what may precede the code of a file: a UTF-8 byte order mark, and a first line
+/// starting with `#` such as a Unix shebang. The line break of that line is kept so line
+/// numbers stay the same.
+pub fn skip_file_header(source_code: &[u8]) -> &[u8] {
+    let source_code = source_code
+        .strip_prefix(BYTE_ORDER_MARK)
+        .unwrap_or(source_code);
+
+    if !source_code.starts_with(b"#") {
+        return source_code;
+    }
+
+    match source_code.iter().position(|&byte| byte == b'\n') {
+        Some(line_end) => &source_code[line_end..],
+        None => &[],
+    }
+}
+
+/// Longest chunk name shown in messages, like `LUA_IDSIZE` minus its terminator.
+const MAX_CHUNK_ID_LENGTH: usize = 59;
+
+/// The name messages use for a chunk loaded from `source`, the way Lua shows chunk
+/// names: `=name` is shown as is, `@file` as the file name, and other sources as
+/// `[string "first line..."]`.
+pub fn chunk_id(source: &str) -> String {
+    fn truncate(text: &str, length: usize) -> &str {
+        let mut end = length.min(text.len());
+        while !text.is_char_boundary(end) {
+            end -= 1;
+        }
+        &text[..end]
+    }
+
+    if let Some(name) = source.strip_prefix('=') {
+        return truncate(name, MAX_CHUNK_ID_LENGTH).to_string();
+    }
+
+    if let Some(filename) = source.strip_prefix('@') {
+        if filename.len() <= MAX_CHUNK_ID_LENGTH {
+            return filename.to_string();
+        }
+        // The end of a long path is the most telling part
+        let mut start = filename.len() - (MAX_CHUNK_ID_LENGTH - 3);
+        while !filename.is_char_boundary(start) {
+            start += 1;
+        }
+        return format!("...{}", &filename[start..]);
+    }
+
+    // Room for the source within `[string "..."]`
+    const MAX_SOURCE_LENGTH: usize = MAX_CHUNK_ID_LENGTH - 14;
+    match source.find('\n') {
+        None if source.len() < MAX_SOURCE_LENGTH => format!("[string \"{}\"]", source),
+        line_end => {
+            let first_line = &source[..line_end.unwrap_or(source.len())];
+            format!(
+                "[string \"{}...\"]",
+                truncate(first_line, MAX_SOURCE_LENGTH)
+            )
+        }
+    }
+}
@@ -0,0 +1,297 @@
+use std::{borrow::Cow, cell::RefCell, rc::Rc};
+
+use crate::{
+    ast::{self, EvalValue, FunctionDefinition, LuaString, TableRef},
+    conversion::{FromLua, FromLuaMulti, IntoLua, IntoLuaMulti},
+    error::LuaError,
+    parser::Parser,
+    vm::{LuaOptions, VirtualMachine},
+};
+
+/// A Lua state with the standard libraries loaded, for running Lua code from Rust.
+///
+/// ```
+/// let mut lua = luir::Lua::new();
+/// lua.globals().set("width", 6)?;
+/// lua.load("function area(height) return width * height end").exec()?;
+///
+/// let area: luir::Function = lua.globals().get("area")?;
+/// let result: i64 = area.call(&mut lua, 7)?;
+/// assert_eq!(result, 42);
+/// # Ok::<(), luir::LuaError>(())
+/// ```
+// Transparent so native functions can be given the virtual machine calling them as a
+// `Lua`
+#[repr(transparent)]
+pub struct Lua {
+    virtual_machine: VirtualMachine,
+}
+
+impl Lua {
+    pub fn new() -> Self {
+        Lua::with_options(LuaOptions::default())
+    }
+
+    /// Creates a state set up according to `options`.
+    pub fn with_options(options: LuaOptions) -> Self {
+        Lua {
+            virtual_machine: VirtualMachine::with_options(options),
+        }
+    }
+
+    /// Prepares the chunk `source_code` to be compiled and run. Like Lua strings, source
+    /// code is made of bytes and does not have to be valid UTF-8.
+    pub fn load<'a, S>(&'a mut self, source_code: &'a S) -> Chunk<'a>
+    where
+        S: AsRef<[u8]> + ?Sized,
+    {
+        Chunk {
+            lua: self,
+            source_code: source_code.as_ref(),
+            source: None,
+            environment: None,
+        }
+    }
+
+    /// The table holding the global variables.
+    pub fn globals(&self) -> Table {
+        Table(self.virtual_machine.globals())
+    }
+
+    pub fn create_table(&self) -> Table {
+        Table(Rc::new(RefCell::new(ast::Table::default())))
+    }
+
+    /// Creates a function implemented by `function`, which may capture state. It is
+    /// called with the state running it and its arguments converted to `A`, and returns
+    /// its results or raises an error.
+    ///
+    /// ```
+    /// let mut lua = luir::Lua::new();
+    /// let prefix = String::from("> ");
+    /// let quote = lua.create_function(move |lua, (text, times): (String, usize)| {
+    ///     let lines = lua.create_table();
+    ///     for line in 1..=times {
+    ///         lines.set(line, format!("{}{}", prefix, text))?;
+    ///     }
+    ///     Ok((lines, times))
+    /// });
+    /// lua.globals().set("quote", quote)?;
+    ///
+    /// let line: String = lua.load("quote('hi', 2)[2]").eval()?;
+    /// assert_eq!(line, "> hi");
+    /// # Ok::<(), luir::LuaError>(())
+    /// ```
+    pub fn create_function<A, R, F>(&self, function: F) -> Function
+    where
+        A: FromLuaMulti,
+        R: IntoLuaMulti,
+        F: Fn(&mut Lua, A) -> Result<R, LuaError> + 'static,
+    {
+        Function(EvalValue::native_function(move |virtual_machine, args| {
+            let args = A::from_lua_multi(args)?;
+            let lua = Lua::from_virtual_machine(virtual_machine);
+            Ok(function(lua, args)?.into_lua_multi())
+        }))
+    }
+
+    /// Like [`create_function`](Lua::create_function), for functions that change their
+    /// captured state. Calling the function again while it runs is an error.
+    pub fn create_function_mut<A, R, F>(&self, function: F) -> Function
+    where
+        A: FromLuaMulti,
+        R: IntoLuaMulti,
+        F: FnMut(&mut Lua, A) -> Result<R, LuaError> + 'static,
+    {
+        let function = RefCell::new(function);
+        self.create_function(move |lua, args| {
+            let mut function = function
+                .try_borrow_mut()
+                .map_err(|_| LuaError::runtime("function called while it is running"))?;
+            function(lua, args)
+        })
+    }
+
+    /// The state of `virtual_machine`, for native functions called by it.
+    fn from_virtual_machine(virtual_machine: &mut VirtualMachine) -> &mut Lua {
+        // SAFETY: `Lua` is a transparent wrapper of `VirtualMachine`
+        unsafe { &mut *(virtual_machine as *mut VirtualMachine as *mut Lua) }
+    }
+
+    /// Whether `warn` prints its messages, like the `-W` option of `lua`.
+    pub fn set_warnings_enabled(&mut self, enabled: bool) {
+        self.virtual_machine.set_warnings_enabled(enabled);
+    }
+
+    /// Converts `value` to a string like the `tostring` function does, calling its
+    /// `__tostring` metamethod.
+    pub fn tostring(&mut self, value: &EvalValue) -> Result<LuaString, LuaError> {
+        self.virtual_machine.tostring(value)
+    }
+
+    /// The metamethod `event` of `value`, e.g. `__tostring`, or nil if it has none.
+    pub fn get_metamethod(&self, value: &EvalValue, event: &str) -> EvalValue {
+        self.virtual_machine.get_metamethod(value, event)
+    }
+}
+
+impl Default for Lua {
+    fn default() -> Self {
+        Lua::new()
+    }
+}
+
+/// A chunk of Lua code loaded with [`Lua::load`], run by [`exec`](Chunk::exec),
+/// [`eval`](Chunk::eval) or [`call`](Chunk::call).
+pub struct Chunk<'a> {
+    lua: &'a mut Lua,
+    source_code: &'a [u8],
+    source: Option<String>,
+    environment: Option<EvalValue>,
+}
+
+impl<'a> Chunk<'a> {
+    /// Names the chunk like the `chunkname` argument of `load`, e.g. `@file.lua` for a
+    /// file or `=name` for a name shown as is. By default the chunk is named after its
+    /// source code.
+    pub fn set_name(mut self, source: impl Into<String>) -> Self {
+        self.source = Some(source.into());
+        self
+    }
+
+    /// Runs the chunk with `environment` as its `_ENV` instead of the global table.
+    pub fn set_environment(mut self, environment: Table) -> Self {
+        self.environment = Some(EvalValue::Table(environment.0));
+        self
+    }
+
+    /// Compiles the chunk into its main function.
+    pub fn into_function(mut self) -> Result<Function, LuaError> {
+        self.compile(Parser::parse)
+    }
+
+    /// Runs the chunk, discarding the values it returns.
+    pub fn exec(self) -> Result<(), LuaError> {
+        self.call(())
+    }
+
+    /// Evaluates the chunk as an expression, or runs it as statements if it is not one,
+    /// like the REPL of `lua` does. Returns the values of the expression or the values
+    /// the statements return.
+    pub fn eval<R: FromLuaMulti>(mut self) -> Result<R, LuaError> {
+        let function = match self.compile(Parser::parse_expressions) {
+            Ok(function) => function,
+            Err(_) => self.compile(Parser::parse)?,
+        };
+
+        function.call(self.lua, ())
+    }
+
+    /// Runs the chunk with `args` as its varargs, returning the values it returns.
+    pub fn call<A: IntoLuaMulti, R: FromLuaMulti>(mut self, args: A) -> Result<R, LuaError> {
+        let function = self.compile(Parser::parse)?;
+        function.call(self.lua, args)
+    }
+
+    /// Compiles the chunk into its main function with `parse`, a parsing method of
+    /// [`Parser`].
+    fn compile(
+        &mut self,
+        parse: fn(&mut Parser<'a>) -> Result<Rc<FunctionDefinition>, LuaError>,
+    ) -> Result<Function, LuaError> {
+        let source = match &self.source {
+            Some(source) => Cow::Borrowed(source.as_str()),
+            None => String::from_utf8_lossy(self.source_code),
+        };
+        // Parsing is recursive too, so it needs the stack Lua code runs on
+        let chunk = self
+            .lua
+            .virtual_machine
+            .on_interpreter_stack(|_| parse(&mut Parser::new(self.source_code, &source)))?;
+        Ok(Function(
+            self.lua
+                .virtual_machine
+                .load(chunk, self.environment.clone()),
+        ))
+    }
+}
+
+/// A Lua table. Its fields are read and written without invoking metamethods, like
+/// `rawget` and `rawset` do.
+#[derive(Clone)]
+pub struct Table(TableRef);
+
+impl Table {
+    pub fn get<K: IntoLua, V: FromLua>(&self, key: K) -> Result<V, LuaError> {
+        V::from_lua(self.0.borrow().get(&key.into_lua()))
+    }
+
+    /// Fails for nil and NaN keys, which Lua tables cannot hold.
+    pub fn set<K: IntoLua, V: IntoLua>(&self, key: K, value: V) -> Result<(), LuaError> {
+        Ok(self.0.borrow_mut().set(key.into_lua(), value.into_lua())?)
+    }
+
+    /// The length of the sequence in the table, a border like the `#` operator gives
+    /// without `__len`.
+    pub fn len(&self) -> usize {
+        self.0.borrow().border()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.0.borrow().next(&EvalValue::Nil).is_none()
+    }
+}
+
+impl IntoLua for Table {
+    fn into_lua(self) -> EvalValue {
+        EvalValue::Table(self.0)
+    }
+}
+
+impl FromLua for Table {
+    fn from_lua(value: EvalValue) -> Result<Self, LuaError> {
+        match value {
+            EvalValue::Table(table) => Ok(Table(table)),
+            value => Err(LuaError::type_mismatch(format!(
+                "table expected, got {}",
+                value.type_name()
+            ))),
+        }
+    }
+}
+
+/// A Lua function, either declared in Lua or native.
+#[derive(Clone)]
+pub struct Function(EvalValue);
+
+impl Function {
+    /// Calls the function with `args`, returning the values it returns.
+    pub fn call<A: IntoLuaMulti, R: FromLuaMulti>(
+        &self,
+        lua: &mut Lua,
+        args: A,
+    ) -> Result<R, LuaError> {
+        let values = lua
+            .virtual_machine
+            .call(self.0.clone(), args.into_lua_multi())?;
+        R::from_lua_multi(values)
+    }
+}
+
+impl IntoLua for Function {
+    fn into_lua(self) -> EvalValue {
+        self.0
+    }
+}
+
+impl FromLua for Function {
+    fn from_lua(value: EvalValue) -> Result<Self, LuaError> {
+        match value {
+            EvalValue::NativeFunction(_) | EvalValue::DeclaredFunction(_) => Ok(Function(value)),
+            value => Err(LuaError::type_mismatch(format!(
+                "function expected, got {}",
+                value.type_name()
+            ))),
+        }
+    }
+}
@@ -1,44 +1,369 @@
-use core::panic;
+use std::io::{IsTerminal, Read};
 
-use clap::Parser;
+use clap::{CommandFactory, FromArgMatches, Parser};
+use luir::{loader, EvalValue, Function, Lua, LuaError, LuaOptions, Table};
 
-mod ast;
-mod lex;
-mod parser;
+mod diagnostics;
+mod repl;
 
-mod vm;
+use diagnostics::{convert_error_object, ColorChoice, Reporter};
 
-use vm::VirtualMachine;
+/// Exit status for runtime errors and unreadable scripts, the same `lua` uses.
+const EXIT_FAILURE: i32 = 1;
+/// Exit status for scripts that do not compile. Status 2 is taken by usage errors.
+const EXIT_SYNTAX_ERROR: i32 = 3;
+
+/// Chunk name of the code given with `-e` and `-l`.
+const COMMAND_LINE_CHUNK: &str = "(command line)";
 
 #[derive(Parser, Debug)]
 #[clap(version, author = "Lukasz <luki446@gmail.com> Burchard", about)]
 /// Lua interpreter
 struct CliOptions {
-    filename: String,
+    #[arg(short, value_name = "STAT", help = "Execute string 'STAT'")]
+    execute: Vec<String>,
+    #[arg(
+        short,
+        value_name = "MOD",
+        help = "Require library 'MOD' into global 'MOD', or 'G=MOD' into global 'G'"
+    )]
+    library: Vec<String>,
+    #[arg(
+        short,
+        long,
+        help = "Enter interactive mode after executing the script"
+    )]
+    interactive: bool,
+    #[arg(short = 'v', help = "Show version information")]
+    show_version: bool,
+    #[arg(short = 'E', help = "Ignore environment variables")]
+    ignore_environment: bool,
+    #[arg(short = 'W', help = "Turn warnings on")]
+    warnings: bool,
     #[arg(short, long, help = "Print AST")]
     print_ast: bool,
+    #[arg(
+        long,
+        value_enum,
+        default_value_t = ColorChoice::Auto,
+        help = "When to colour error messages"
+    )]
+    color: ColorChoice,
+    #[arg(
+        value_name = "SCRIPT [ARGS]",
+        trailing_var_arg = true,
+        allow_hyphen_values = true,
+        help = "Script to run, '-' for standard input, followed by its arguments"
+    )]
+    script: Vec<String>,
+}
+
+/// An action of the command line that runs in the order it was given.
+enum CommandLineAction {
+    Execute(String),
+    Require(String),
 }
 
 fn main() {
-    let options = CliOptions::parse();
+    let matches = CliOptions::command().get_matches_from(split_attached_values(std::env::args()));
+    let options = CliOptions::from_arg_matches(&matches).unwrap_or_else(|err| err.exit());
+    reject_unknown_option(&options);
+    let mut lua = Lua::with_options(LuaOptions {
+        ignore_environment: options.ignore_environment,
+    });
 
-    let source_code = std::fs::read_to_string(options.filename).unwrap();
+    if let Err(exit_code) = run(&mut lua, &options, &matches) {
+        std::process::exit(exit_code);
+    }
+}
 
-    let mut parser = parser::Parser::new(&source_code);
-    let mut global_map = VirtualMachine::new();
+/// Splits `-eSTAT` and `-lMOD` into an option and its value, which clap would otherwise
+/// take for the script because `lua` options can be grouped. Stops at the script, as
+/// everything after it belongs to the script.
+fn split_attached_values(mut argv: impl Iterator<Item = String>) -> Vec<String> {
+    let mut split: Vec<String> = argv.next().into_iter().collect();
 
-    let ast = match parser.parse() {
-        Ok(ast) => ast,
-        Err(err) => {
-            panic!("Error: {}", err);
+    while let Some(arg) = argv.next() {
+        if arg == "-" || arg == "--" || !arg.starts_with('-') {
+            split.push(arg);
+            break;
+        }
+
+        match arg.split_at_checked(2) {
+            Some((option @ ("-e" | "-l"), value)) if !value.is_empty() => {
+                split.push(option.to_string());
+                split.push(value.to_string());
+            }
+            _ => {
+                let takes_value = arg == "-e" || arg == "-l";
+                split.push(arg);
+                if takes_value {
+                    split.extend(argv.next());
+                }
+            }
         }
+    }
+
+    split.extend(argv);
+    split
+}
+
+/// Exits with a usage error if the script position holds an option that is not known,
+/// which the script arguments accept. Like in `lua`, `-` and options after `--` are
+/// scripts.
+fn reject_unknown_option(options: &CliOptions) {
+    let argv: Vec<String> = std::env::args().collect();
+    let script_index = argv.len() - options.script.len();
+    let Some(script) = options.script.first() else {
+        return;
     };
 
-    if options.print_ast {
-        for statement in &ast {
-            println!("{:#?}", statement);
+    if script.starts_with('-') && script != "-" && argv[script_index - 1] != "--" {
+        CliOptions::command()
+            .error(
+                clap::error::ErrorKind::UnknownArgument,
+                format!("unrecognized option '{}'", script),
+            )
+            .exit();
+    }
+}
+
+/// Does what the command line asks for, in the order `lua` does it. Returns the exit
+/// status on failure.
+fn run(lua: &mut Lua, options: &CliOptions, matches: &clap::ArgMatches) -> Result<(), i32> {
+    let argv: Vec<String> = std::env::args().collect();
+    // The script and its arguments are always the last ones on the command line
+    let script_index = argv.len() - options.script.len();
+    lua.globals()
+        .set("arg", create_arg_table(lua, &argv, script_index))
+        .expect("Global names are valid table keys");
+
+    let starts_repl = options.interactive
+        || (options.script.is_empty()
+            && options.execute.is_empty()
+            && options.library.is_empty()
+            && !options.show_version
+            && std::io::stdin().is_terminal());
+
+    if options.show_version || starts_repl {
+        println!("luir {}", env!("CARGO_PKG_VERSION"));
+    }
+
+    if options.warnings {
+        lua.set_warnings_enabled(true);
+    }
+
+    if !options.ignore_environment {
+        run_lua_init(lua, options)?;
+    }
+
+    for action in command_line_actions(options, matches) {
+        match action {
+            CommandLineAction::Execute(statement) => run_chunk(
+                lua,
+                &format!("={}", COMMAND_LINE_CHUNK),
+                statement.as_bytes(),
+                Vec::new(),
+                options,
+            )?,
+            CommandLineAction::Require(library) => require_library(lua, &library, options)?,
+        }
+    }
+
+    match options.script.split_first() {
+        Some((script, args)) => {
+            let args = args
+                .iter()
+                .map(|arg| EvalValue::string(arg.as_str()))
+                .collect();
+            run_file(lua, script, args, options)?;
+        }
+        // Like `lua`, run piped standard input when there is nothing else to do
+        None if options.execute.is_empty()
+            && options.library.is_empty()
+            && !options.show_version
+            && !starts_repl =>
+        {
+            run_file(lua, "-", Vec::new(), options)?;
         }
+        None => {}
+    }
+
+    if starts_repl {
+        if let Err(err) = repl::run(lua, options.color) {
+            eprintln!("luir: {}", err);
+            return Err(EXIT_FAILURE);
+        }
+    }
+
+    Ok(())
+}
+
+/// Builds the global `arg` table: the script name at index 0, its arguments at positive
+/// indices and the interpreter with its options at negative ones. Without a script the
+/// interpreter takes index 0.
+fn create_arg_table(lua: &Lua, argv: &[String], script_index: usize) -> Table {
+    let script_index = if script_index == argv.len() {
+        0
     } else {
-        global_map.execute(&ast).unwrap();
+        script_index
+    };
+    let table = lua.create_table();
+
+    for (index, value) in argv.iter().enumerate() {
+        table
+            .set(index as i64 - script_index as i64, value.as_str())
+            .expect("Numbers are valid table keys");
+    }
+
+    table
+}
+
+/// The `-e` and `-l` options in the order they appear on the command line.
+fn command_line_actions(
+    options: &CliOptions,
+    matches: &clap::ArgMatches,
+) -> Vec<CommandLineAction> {
+    let indices = |id: &str| -> Vec<usize> {
+        matches
+            .indices_of(id)
+            .map(|indices| indices.collect())
+            .unwrap_or_default()
+    };
+
+    let mut actions: Vec<(usize, CommandLineAction)> = indices("execute")
+        .into_iter()
+        .zip(
+            options
+                .execute
+                .iter()
+                .cloned()
+                .map(CommandLineAction::Execute),
+        )
+        .chain(
+            indices("library").into_iter().zip(
+                options
+                    .library
+                    .iter()
+                    .cloned()
+                    .map(CommandLineAction::Require),
+            ),
+        )
+        .collect();
+    actions.sort_by_key(|(index, _)| *index);
+
+    actions.into_iter().map(|(_, action)| action).collect()
+}
+
+/// Runs the code in `LUA_INIT_5_4` or `LUA_INIT`, or the file it names after an `@`.
+fn run_lua_init(lua: &mut Lua, options: &CliOptions) -> Result<(), i32> {
+    let Some((name, value)) = ["LUA_INIT_5_4", "LUA_INIT"]
+        .into_iter()
+        .find_map(|name| std::env::var(name).ok().map(|value| (name, value)))
+    else {
+        return Ok(());
+    };
+
+    match value.strip_prefix('@') {
+        Some(filename) => run_file(lua, filename, Vec::new(), options),
+        None => run_chunk(
+            lua,
+            &format!("={}", name),
+            value.as_bytes(),
+            Vec::new(),
+            options,
+        ),
+    }
+}
+
+/// Calls `require` for `-l MOD` or `-l G=MOD` and stores the result in global `G`,
+/// which defaults to the module name.
+fn require_library(lua: &mut Lua, library: &str, options: &CliOptions) -> Result<(), i32> {
+    let (global, module) = library.split_once('=').unwrap_or((library, library));
+    let result = lua
+        .globals()
+        .get::<_, Function>("require")
+        .and_then(|require| require.call::<_, EvalValue>(lua, module))
+        .and_then(|value| lua.globals().set(global, value));
+
+    match result {
+        Ok(()) => Ok(()),
+        Err(mut err) => {
+            convert_error_object(lua, &mut err);
+            Reporter::new(COMMAND_LINE_CHUNK, "", options.color).report(&err);
+            Err(EXIT_FAILURE)
+        }
     }
 }
+
+/// Runs the script in `filename`, or standard input for `-`, with `args` as `...`.
+/// A shebang line or byte order mark at the start is skipped.
+fn run_file(
+    lua: &mut Lua,
+    filename: &str,
+    args: Vec<EvalValue>,
+    options: &CliOptions,
+) -> Result<(), i32> {
+    let (chunk_name, source_code) = if filename == "-" {
+        let mut source_code = Vec::new();
+        let result = std::io::stdin()
+            .read_to_end(&mut source_code)
+            .map(|_| source_code);
+        (
+            "stdin",
+            result.map(|source_code| loader::skip_file_header(&source_code).to_vec()),
+        )
+    } else {
+        (filename, loader::read_source_file(filename))
+    };
+
+    let source_code = match source_code {
+        Ok(source_code) => source_code,
+        Err(err) => {
+            eprintln!("luir: cannot open {}: {}", chunk_name, err);
+            return Err(EXIT_FAILURE);
+        }
+    };
+
+    let source = match filename {
+        "-" => "=stdin".to_string(),
+        filename => format!("@{}", filename),
+    };
+    run_chunk(lua, &source, &source_code, args, options)
+}
+
+/// Compiles and runs `source_code` loaded from `source`, reporting its errors. Returns
+/// the exit status on failure.
+fn run_chunk(
+    lua: &mut Lua,
+    source: &str,
+    source_code: &[u8],
+    args: Vec<EvalValue>,
+    options: &CliOptions,
+) -> Result<(), i32> {
+    let chunk_name = loader::chunk_id(source);
+    let source_text = String::from_utf8_lossy(source_code);
+    let reporter = Reporter::new(&chunk_name, &source_text, options.color);
+    let result = if options.print_ast {
+        luir::parser::Parser::new(source_code, source)
+            .parse()
+            .map(|chunk| {
+                for statement in &chunk.body {
+                    println!("{:#?}", statement);
+                }
+            })
+    } else {
+        lua.load(source_code).set_name(source).call(args)
+    };
+
+    if let Err(mut err) = result {
+        convert_error_object(lua, &mut err);
+        reporter.report(&err);
+        return Err(match err {
+            LuaError::Syntax(_) => EXIT_SYNTAX_ERROR,
+            _ => EXIT_FAILURE,
+        });
+    }
+
+    Ok(())
+}
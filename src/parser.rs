@@ -1,12 +1,40 @@
-use std::collections::BTreeMap;
+use std::rc::Rc;
 
 use crate::{
-    ast::{Expression, Statement},
-    lex::{self, Lexer, LiteralType},
+    ast::{Expression, FunctionDefinition, Statement, VariableAttribute},
+    error::{LuaError, SyntaxError},
+    lex::{self, Lexer, LiteralType, Position, Span},
+    loader::chunk_id,
 };
 
+/// How deeply statements and expressions can nest. Parsing them is recursive, so
+/// deeper nesting could exhaust the native stack.
+const MAX_NESTING_LEVEL: usize = 200;
+
+/// A function whose body is being parsed.
+struct FunctionState {
+    line_defined: usize,
+    /// Index in `Parser::scopes` of the scope holding the parameters.
+    first_scope: usize,
+    upvalues: Vec<String>,
+}
+
 pub struct Parser<'a> {
     lexer: Lexer<'a>,
+    source: Rc<str>,
+    chunk_name: Rc<str>,
+    token_spans: Vec<Span>,
+    scopes: Vec<Vec<(String, VariableAttribute)>>,
+    /// The functions being parsed, innermost last. The main chunk is not among them.
+    functions: Vec<FunctionState>,
+    loop_depth: usize,
+    /// How many nested statements and expressions are being parsed.
+    nesting_level: usize,
+    /// Set when the nesting limit is reached, after which the rest of the chunk is
+    /// skipped, as it would only produce follow-on errors.
+    gave_up: bool,
+    is_variadic: bool,
+    diagnostics: Vec<SyntaxError>,
 }
 
 macro_rules! create_binary_expression {
@@ -18,7 +46,8 @@ macro_rules! create_binary_expression {
                 $( $op => {
                     $tokens.next();
                     let right = $parse_next_level_expression($parser, $tokens)?;
-                    left = Expression::BinaryExpression(Box::new(left), $op_str.to_string(), Box::new(right));
+                    let span = left.span().to(right.span());
+                    left = Expression::BinaryExpression(Box::new(left), $op_str.to_string(), Box::new(right), span);
                 }, )+
                 _ => break,
             }
@@ -29,81 +58,436 @@ macro_rules! create_binary_expression {
 }
 
 impl<'a> Parser<'a> {
-    pub fn new(source_code: &'a str) -> Self {
+    /// Creates a parser for the chunk `source_code` loaded from `source`, which names
+    /// the chunk like the `chunkname` argument of `load`, e.g. `@file.lua`.
+    pub fn new(source_code: &'a [u8], source: &str) -> Self {
         Self {
             lexer: Lexer::new(source_code),
+            source: Rc::from(source),
+            chunk_name: Rc::from(chunk_id(source)),
+            token_spans: Vec::new(),
+            scopes: Vec::new(),
+            functions: Vec::new(),
+            loop_depth: 0,
+            nesting_level: 0,
+            gave_up: false,
+            // The main chunk is a vararg function
+            is_variadic: true,
+            diagnostics: Vec::new(),
         }
     }
 
-    pub fn parse(&mut self) -> Result<Vec<Statement>, String> {
-        let mut statements = Vec::new();
+    /// Parses the source code into the main function of the chunk. The parser keeps going
+    /// after a syntax error, so the error lists every problem found in the chunk.
+    pub fn parse(&mut self) -> Result<Rc<FunctionDefinition>, LuaError> {
+        let mut tokens = self.tokenize();
+        let statements = self.parse_block_until(&mut tokens, &[]);
+
+        self.finish(tokens, statements)
+    }
+
+    /// Parses the source code as a list of expressions, into a main function that
+    /// returns their values. This is how the REPL evaluates its input, without the
+    /// positions in error messages moving as they would if `return` were prepended.
+    pub fn parse_expressions(&mut self) -> Result<Rc<FunctionDefinition>, LuaError> {
+        let mut tokens = self.tokenize();
+        let start = self.current_position(&tokens);
+
+        self.enter_scope();
+        let statements = match self.parse_expression_list(&mut tokens) {
+            Ok(expressions) => {
+                let span = self.span_from(start, &tokens);
+                vec![Statement::ReturnStatement(expressions, span)]
+            }
+            Err(error) => {
+                self.report(error);
+                Vec::new()
+            }
+        };
+        self.exit_scope();
+
+        self.finish(tokens, statements)
+    }
 
-        let tokens = self.lexer.tokenize()?;
-        let mut tokens = tokens.into_iter().peekable();
+    fn tokenize(&mut self) -> std::iter::Peekable<std::vec::IntoIter<lex::Token>> {
+        let (tokens, spans): (Vec<_>, Vec<_>) = self
+            .lexer
+            .tokenize()
+            .into_iter()
+            .map(|spanned| (spanned.token, spanned.span))
+            .unzip();
+        self.token_spans = spans;
 
-        while tokens.peek().is_some() {
-            statements.push(self.parse_single_statement(&mut tokens)?);
+        tokens.into_iter().peekable()
+    }
+
+    /// Builds the main function of the chunk out of its `statements`, or fails with
+    /// the syntax errors found. `tokens` must have been consumed up to the end.
+    fn finish(
+        &mut self,
+        mut tokens: std::iter::Peekable<std::vec::IntoIter<lex::Token>>,
+        statements: Vec<Statement>,
+    ) -> Result<Rc<FunctionDefinition>, LuaError> {
+        if tokens.peek().is_some() {
+            let error = self.expected_error(&mut tokens, &["'<eof>'"]);
+            self.report(error);
+        }
+
+        if !self.diagnostics.is_empty() {
+            let mut diagnostics = std::mem::take(&mut self.diagnostics);
+            diagnostics.sort_by_key(|diagnostic| diagnostic.span.start.offset);
+
+            return Err(LuaError::Syntax(diagnostics));
         }
 
-        Ok(statements)
+        Ok(Rc::new(FunctionDefinition {
+            chunk_name: self.chunk_name.clone(),
+            source: self.source.clone(),
+            line_defined: 0,
+            last_line_defined: 0,
+            arguments: Vec::new(),
+            is_variadic: true,
+            // Every chunk is a closure over `_ENV`, whether it uses globals or not
+            upvalues: vec!["_ENV".to_string()],
+            body: statements,
+        }))
+    }
+
+    fn error_at(&self, span: Span, message: String) -> SyntaxError {
+        SyntaxError {
+            chunk_name: self.chunk_name.clone(),
+            message,
+            span,
+            expected: Vec::new(),
+        }
+    }
+
+    /// An error at the next token, which the parser cannot accept there.
+    fn error_near(
+        &self,
+        tokens: &mut std::iter::Peekable<std::vec::IntoIter<lex::Token>>,
+        message: &str,
+        expected: &[&str],
+    ) -> SyntaxError {
+        let index = self.token_spans.len() - tokens.len();
+        // The lexer already knows what is wrong with source code that is not a token
+        if let Some(lex::Token::Error(message)) = tokens.peek() {
+            return self.error_at(self.token_spans[index], message.clone());
+        }
+
+        let (near, span) = match tokens.peek() {
+            Some(token) => (format!("'{}'", token), self.token_spans[index]),
+            None => {
+                let end = self.lexer.position();
+                ("<eof>".to_string(), Span { start: end, end })
+            }
+        };
+
+        SyntaxError {
+            expected: expected.iter().map(|item| item.to_string()).collect(),
+            ..self.error_at(span, format!("{} near {}", message, near))
+        }
+    }
+
+    fn expected_error(
+        &self,
+        tokens: &mut std::iter::Peekable<std::vec::IntoIter<lex::Token>>,
+        expected: &[&str],
+    ) -> SyntaxError {
+        self.error_near(
+            tokens,
+            &format!("{} expected", expected.join(" or ")),
+            expected,
+        )
+    }
+
+    /// Runs `parse` one nesting level deeper, failing if that is too deep.
+    fn nested<T>(
+        &mut self,
+        tokens: &mut std::iter::Peekable<std::vec::IntoIter<lex::Token>>,
+        parse: impl FnOnce(
+            &mut Self,
+            &mut std::iter::Peekable<std::vec::IntoIter<lex::Token>>,
+        ) -> Result<T, SyntaxError>,
+    ) -> Result<T, SyntaxError> {
+        if self.nesting_level == MAX_NESTING_LEVEL {
+            let function = match self.functions.last() {
+                Some(function) => format!("function at line {}", function.line_defined),
+                None => "main function".to_string(),
+            };
+            let message = format!(
+                "too many C levels (limit is {}) in {}",
+                MAX_NESTING_LEVEL, function
+            );
+
+            let error = self.error_near(tokens, &message, &[]);
+            self.report(error.clone());
+            self.gave_up = true;
+            tokens.for_each(drop);
+
+            return Err(error);
+        }
+
+        self.nesting_level += 1;
+        let result = parse(self, tokens);
+        self.nesting_level -= 1;
+
+        result
+    }
+
+    /// Records an error that does not stop the statement from being parsed.
+    fn report(&mut self, error: SyntaxError) {
+        if !self.gave_up {
+            self.diagnostics.push(error);
+        }
+    }
+
+    fn starts_statement(token: &lex::Token) -> bool {
+        matches!(
+            token,
+            lex::Token::Local
+                | lex::Token::Function
+                | lex::Token::Return
+                | lex::Token::Break
+                | lex::Token::If
+                | lex::Token::While
+                | lex::Token::For
+                | lex::Token::Do
+                | lex::Token::Repeat
+                | lex::Token::Semicolon
+        )
+    }
+
+    /// Whether the next token is the first one on its line.
+    fn starts_line(&self, tokens: &std::iter::Peekable<std::vec::IntoIter<lex::Token>>) -> bool {
+        let index = self.token_spans.len() - tokens.len();
+
+        index == 0 || self.token_spans[index - 1].end.line < self.token_spans[index].start.line
+    }
+
+    /// Skips the tokens of a statement that failed to parse, up to one that can start the
+    /// next statement or ends the current block. `remaining` is the number of tokens left
+    /// when the statement started.
+    fn synchronize(
+        &self,
+        tokens: &mut std::iter::Peekable<std::vec::IntoIter<lex::Token>>,
+        end_tokens: &[lex::Token],
+        remaining: usize,
+    ) {
+        // The statement is never parsed again from the same token
+        if tokens.len() == remaining {
+            tokens.next();
+        }
+
+        while let Some(token) = tokens.peek() {
+            let starts_assignment_or_call = matches!(token, lex::Token::Identifier(_));
+
+            if end_tokens.contains(token)
+                || Self::starts_statement(token)
+                || (starts_assignment_or_call && self.starts_line(tokens))
+            {
+                break;
+            }
+
+            tokens.next();
+        }
+    }
+
+    /// Parses the header of a compound statement followed by `terminator`, like the
+    /// condition of `if ... then`. If the header is invalid, the error is recorded and
+    /// the tokens up to `terminator` are skipped, so the body of the statement can
+    /// still be checked; `None` is returned then.
+    fn parse_header<T>(
+        &mut self,
+        tokens: &mut std::iter::Peekable<std::vec::IntoIter<lex::Token>>,
+        terminator: lex::Token,
+        parse: impl FnOnce(
+            &mut Self,
+            &mut std::iter::Peekable<std::vec::IntoIter<lex::Token>>,
+        ) -> Result<T, SyntaxError>,
+    ) -> Result<Option<T>, SyntaxError> {
+        let error = match parse(self, tokens) {
+            Ok(header) => {
+                self.expect(tokens, terminator)?;
+                return Ok(Some(header));
+            }
+            Err(error) => error,
+        };
+
+        while let Some(token) = tokens.peek() {
+            if *token == terminator {
+                tokens.next();
+                self.report(error);
+                return Ok(None);
+            }
+
+            if *token == lex::Token::End || Self::starts_statement(token) {
+                break;
+            }
+
+            tokens.next();
+        }
+
+        Err(error)
+    }
+
+    /// Where the next token starts, or the end of the input if there are no tokens left.
+    fn current_position(
+        &self,
+        tokens: &std::iter::Peekable<std::vec::IntoIter<lex::Token>>,
+    ) -> Position {
+        let index = self.token_spans.len() - tokens.len();
+
+        match self.token_spans.get(index) {
+            Some(span) => span.start,
+            None => self.lexer.position(),
+        }
+    }
+
+    /// The span from `start` to the end of the last consumed token.
+    fn span_from(
+        &self,
+        start: Position,
+        tokens: &std::iter::Peekable<std::vec::IntoIter<lex::Token>>,
+    ) -> Span {
+        let consumed = self.token_spans.len() - tokens.len();
+        let end = self.token_spans[..consumed]
+            .last()
+            .map_or(start, |span| span.end);
+
+        Span { start, end }
+    }
+
+    fn enter_scope(&mut self) {
+        self.scopes.push(Vec::new());
+    }
+
+    fn exit_scope(&mut self) {
+        self.scopes.pop();
+    }
+
+    fn declare_local(&mut self, name: String, attribute: VariableAttribute) {
+        self.scopes
+            .last_mut()
+            .expect("No scope found")
+            .push((name, attribute));
+    }
+
+    /// Records that the variable `name` is used in the current function, which makes it
+    /// an upvalue of the functions between the one that declares it and the current one.
+    /// Global variables are fields of `_ENV`, so using one uses `_ENV`.
+    fn use_variable(&mut self, name: &str) {
+        let declaring_scope = self
+            .scopes
+            .iter()
+            .rposition(|scope| scope.iter().any(|(local_name, _)| local_name == name));
+        if declaring_scope.is_none() && name != "_ENV" {
+            return self.use_variable("_ENV");
+        }
+
+        for function in self.functions.iter_mut().rev() {
+            if declaring_scope.is_some_and(|scope| scope >= function.first_scope) {
+                break;
+            }
+            if !function.upvalues.iter().any(|upvalue| upvalue == name) {
+                function.upvalues.push(name.to_string());
+            }
+        }
+    }
+
+    /// Rejects assignments to `<const>` and `<close>` locals visible from the current scope.
+    fn check_assignable(&mut self, name: &str, span: Span) {
+        let attribute = self
+            .scopes
+            .iter()
+            .rev()
+            .flat_map(|scope| scope.iter().rev())
+            .find(|(local_name, _)| local_name == name)
+            .map(|(_, attribute)| *attribute);
+
+        match attribute {
+            Some(VariableAttribute::Const) | Some(VariableAttribute::Close) => {
+                self.report(self.error_at(
+                    span,
+                    format!("attempt to assign to const variable '{}'", name),
+                ))
+            }
+            _ => {}
+        }
     }
 
     fn parse_single_statement(
         &mut self,
         tokens: &mut std::iter::Peekable<std::vec::IntoIter<lex::Token>>,
-    ) -> Result<Statement, String> {
+    ) -> Result<Statement, SyntaxError> {
         let token = tokens.peek();
 
         match token {
             Some(lex::Token::Local) => self.parse_local_variable_declaration(tokens),
-            Some(lex::Token::Identifier(_)) => {
-                let mut future = tokens.clone();
-                future.next();
-                if future.peek() == Some(&lex::Token::Assigment) {
-                    Ok(self.parse_assigment_statement(tokens)?)
-                } else {
-                    let expression = self.parse_expression(tokens)?;
-                    Ok(Statement::ExpressionStatement(Box::new(expression)))
+            Some(lex::Token::Identifier(_)) | Some(lex::Token::LeftParen) => {
+                let expression = self.parse_suffixed_expression(tokens)?;
+
+                match (tokens.peek(), expression) {
+                    (Some(lex::Token::Assigment), target) | (Some(lex::Token::Comma), target) => {
+                        self.parse_assigment_statement(tokens, target)
+                    }
+                    (
+                        _,
+                        expression @ (Expression::FunctionCall(..) | Expression::MethodCall(..)),
+                    ) => {
+                        let span = expression.span();
+                        Ok(Statement::ExpressionStatement(Box::new(expression), span))
+                    }
+                    _ => Err(self.error_near(tokens, "syntax error", &["'='"])),
                 }
             }
             Some(lex::Token::If) => self.parse_if_statement(tokens),
             Some(lex::Token::While) => self.parse_while_loop(tokens),
             Some(lex::Token::For) => self.parse_for_loop(tokens),
+            Some(lex::Token::Do) => self.parse_do_block(tokens),
             Some(lex::Token::Function) => self.parse_function_declaration(tokens),
             Some(lex::Token::Return) => self.parse_return_statement(tokens),
+            Some(lex::Token::Break) => self.parse_break_statement(tokens),
             Some(lex::Token::Repeat) => self.parse_repeat_statement(tokens),
-            _ => Err(format!("Unexpected top-level token '{:?}'", token)),
+            _ => Err(self.error_near(tokens, "unexpected symbol", &[])),
         }
     }
 
     fn parse_while_loop(
         &mut self,
         tokens: &mut std::iter::Peekable<std::vec::IntoIter<lex::Token>>,
-    ) -> Result<Statement, String> {
+    ) -> Result<Statement, SyntaxError> {
+        let start = self.current_position(tokens);
         tokens.next();
 
-        let loop_condition = self.parse_expression(tokens)?;
+        let loop_condition = self
+            .parse_header(tokens, lex::Token::Do, Self::parse_expression)?
+            .unwrap_or_else(|| Expression::NilLiteral(self.span_from(start, tokens)));
 
-        self.expect(tokens, lex::Token::Do)?;
-
-        let loop_block = self.parse_block_until(tokens, &[lex::Token::End])?;
+        let loop_block = self.parse_loop_block_until(tokens, &[lex::Token::End]);
 
         self.expect(tokens, lex::Token::End)?;
 
         Ok(Statement::WhileLoop {
             loop_condition: Box::new(loop_condition),
             code_block: loop_block,
+            span: self.span_from(start, tokens),
         })
     }
 
     fn parse_for_loop(
         &mut self,
         tokens: &mut std::iter::Peekable<std::vec::IntoIter<lex::Token>>,
-    ) -> Result<Statement, String> {
+    ) -> Result<Statement, SyntaxError> {
+        let start = self.current_position(tokens);
         tokens.next();
 
         let loop_variable = self.parse_identifier(tokens)?;
 
+        if tokens.peek() != Some(&lex::Token::Assigment) {
+            return self.parse_generic_for_loop(tokens, loop_variable, start);
+        }
+
         self.expect(tokens, lex::Token::Assigment)?;
 
         let start_value = self.parse_expression(tokens)?;
@@ -111,16 +495,21 @@ impl<'a> Parser<'a> {
 
         let end_value = self.parse_expression(tokens)?;
 
-        let mut step_value: Expression = Expression::NumberLiteral(1.0);
-
-        if tokens.peek() == Some(&lex::Token::Comma) {
+        let step_value = if tokens.peek() == Some(&lex::Token::Comma) {
             tokens.next();
-            step_value = self.parse_expression(tokens)?;
-        }
+            self.parse_expression(tokens)?
+        } else {
+            // An omitted step is an implicit `1` at the end of the limit
+            let end = end_value.span().end;
+            Expression::IntegerLiteral(1, Span { start: end, end })
+        };
 
         self.expect(tokens, lex::Token::Do)?;
 
-        let loop_block = self.parse_block_until(tokens, &[lex::Token::End])?;
+        self.enter_scope();
+        self.declare_local(loop_variable.clone(), VariableAttribute::Regular);
+        let loop_block = self.parse_loop_block_until(tokens, &[lex::Token::End]);
+        self.exit_scope();
 
         self.expect(tokens, lex::Token::End)?;
 
@@ -130,45 +519,84 @@ impl<'a> Parser<'a> {
             ending_value: Box::new(end_value),
             step_value: Box::new(step_value),
             code_block: loop_block,
+            span: self.span_from(start, tokens),
+        })
+    }
+
+    fn parse_generic_for_loop(
+        &mut self,
+        tokens: &mut std::iter::Peekable<std::vec::IntoIter<lex::Token>>,
+        first_variable: String,
+        start: Position,
+    ) -> Result<Statement, SyntaxError> {
+        let mut iterator_identifiers = vec![first_variable];
+
+        while let Some(lex::Token::Comma) = tokens.peek() {
+            tokens.next();
+            iterator_identifiers.push(self.parse_identifier(tokens)?);
+        }
+
+        self.expect(tokens, lex::Token::In)?;
+
+        let iterator_values = self.parse_expression_list(tokens)?;
+
+        self.expect(tokens, lex::Token::Do)?;
+
+        self.enter_scope();
+        for identifier in &iterator_identifiers {
+            self.declare_local(identifier.clone(), VariableAttribute::Regular);
+        }
+        let loop_block = self.parse_loop_block_until(tokens, &[lex::Token::End]);
+        self.exit_scope();
+
+        self.expect(tokens, lex::Token::End)?;
+
+        Ok(Statement::GenericForLoop {
+            iterator_identifiers,
+            iterator_values,
+            code_block: loop_block,
+            span: self.span_from(start, tokens),
         })
     }
 
     fn parse_if_statement(
         &mut self,
         tokens: &mut std::iter::Peekable<std::vec::IntoIter<lex::Token>>,
-    ) -> Result<Statement, String> {
+    ) -> Result<Statement, SyntaxError> {
+        let start = self.current_position(tokens);
         tokens.next();
 
-        let condition = self.parse_expression(tokens)?;
-
-        self.expect(tokens, lex::Token::Then)?;
+        let condition = self
+            .parse_header(tokens, lex::Token::Then, Self::parse_expression)?
+            .unwrap_or_else(|| Expression::NilLiteral(self.span_from(start, tokens)));
 
         let main_block = self.parse_block_until(
             tokens,
             &[lex::Token::End, lex::Token::ElseIf, lex::Token::Else],
-        )?;
+        );
 
         let mut elseif_statements = Vec::new();
 
         while let Some(lex::Token::ElseIf) = tokens.peek() {
+            let elseif_start = self.current_position(tokens);
             tokens.next();
 
-            let condition = Box::new(self.parse_expression(tokens)?);
-
-            self.expect(tokens, lex::Token::Then)?;
+            let condition = self
+                .parse_header(tokens, lex::Token::Then, Self::parse_expression)?
+                .unwrap_or_else(|| Expression::NilLiteral(self.span_from(elseif_start, tokens)));
 
             let block = self.parse_block_until(
                 tokens,
                 &[lex::Token::End, lex::Token::ElseIf, lex::Token::Else],
-            )?;
+            );
 
-            elseif_statements.push((condition, block));
+            elseif_statements.push((Box::new(condition), block));
         }
 
         let else_block = if let Some(lex::Token::Else) = tokens.peek() {
             tokens.next();
 
-            Some(self.parse_block_until(tokens, &[lex::Token::End])?)
+            Some(self.parse_block_until(tokens, &[lex::Token::End]))
         } else {
             None
         };
@@ -180,65 +608,241 @@ impl<'a> Parser<'a> {
             code_block: main_block,
             elseif_statements,
             else_block,
+            span: self.span_from(start, tokens),
         })
     }
 
+    fn parse_do_block(
+        &mut self,
+        tokens: &mut std::iter::Peekable<std::vec::IntoIter<lex::Token>>,
+    ) -> Result<Statement, SyntaxError> {
+        let start = self.current_position(tokens);
+        tokens.next();
+
+        let code_block = self.parse_block_until(tokens, &[lex::Token::End]);
+
+        self.expect(tokens, lex::Token::End)?;
+
+        Ok(Statement::DoBlock(
+            code_block,
+            self.span_from(start, tokens),
+        ))
+    }
+
     fn parse_block_until(
         &mut self,
         tokens: &mut std::iter::Peekable<std::vec::IntoIter<lex::Token>>,
         end_tokens: &[lex::Token],
-    ) -> Result<Vec<Statement>, String> {
+    ) -> Vec<Statement> {
+        self.enter_scope();
+        let statements = self.parse_statements_until(tokens, end_tokens);
+        self.exit_scope();
+
+        statements
+    }
+
+    fn parse_loop_block_until(
+        &mut self,
+        tokens: &mut std::iter::Peekable<std::vec::IntoIter<lex::Token>>,
+        end_tokens: &[lex::Token],
+    ) -> Vec<Statement> {
+        self.loop_depth += 1;
+        let statements = self.parse_block_until(tokens, end_tokens);
+        self.loop_depth -= 1;
+
+        statements
+    }
+
+    /// Parses statements up to one of `end_tokens` or the end of the input. Statements
+    /// with syntax errors are reported and skipped.
+    fn parse_statements_until(
+        &mut self,
+        tokens: &mut std::iter::Peekable<std::vec::IntoIter<lex::Token>>,
+        end_tokens: &[lex::Token],
+    ) -> Vec<Statement> {
         let mut statements = Vec::new();
 
         while let Some(token) = tokens.peek() {
             if end_tokens.contains(token) {
-                // tokens.next();
                 break;
             }
 
-            statements.push(self.parse_single_statement(tokens)?);
+            if token == &lex::Token::Semicolon {
+                tokens.next();
+                continue;
+            }
+
+            let remaining = tokens.len();
+
+            match self.nested(tokens, Self::parse_single_statement) {
+                // `return` can only be the last statement of a block
+                Ok(statement @ Statement::ReturnStatement(..)) => {
+                    statements.push(statement);
+                    break;
+                }
+                Ok(statement) => statements.push(statement),
+                Err(error) => {
+                    self.report(error);
+                    self.synchronize(tokens, end_tokens, remaining);
+                }
+            }
         }
 
-        Ok(statements)
+        statements
     }
 
     fn parse_local_variable_declaration(
         &mut self,
         tokens: &mut std::iter::Peekable<std::vec::IntoIter<lex::Token>>,
-    ) -> Result<Statement, String> {
+    ) -> Result<Statement, SyntaxError> {
+        let start = self.current_position(tokens);
         tokens.next();
 
-        let local_variable_identifier = self.parse_identifier(tokens)?;
+        if tokens.peek() == Some(&lex::Token::Function) {
+            let function_start = self.current_position(tokens);
+            tokens.next();
 
-        self.expect(tokens, lex::Token::Assigment)?;
+            let function_name = self.parse_identifier(tokens)?;
+            self.declare_local(function_name.clone(), VariableAttribute::Regular);
+            let function = self.parse_function_body(tokens, function_start, false)?;
 
-        let expression = self.parse_expression(tokens)?;
+            return Ok(Statement::LocalFunctionDeclaration {
+                function_name,
+                function,
+                span: self.span_from(start, tokens),
+            });
+        }
 
-        Ok(Statement::LocalVariableDeclaration(
-            local_variable_identifier,
-            Box::new(expression),
-        ))
+        let mut variables = Vec::new();
+
+        loop {
+            let local_variable_identifier = self.parse_identifier(tokens)?;
+            let attribute = self.parse_attribute(tokens)?;
+
+            variables.push((local_variable_identifier, attribute));
+
+            if let Some(lex::Token::Comma) = tokens.peek() {
+                tokens.next();
+            } else {
+                break;
+            }
+        }
+
+        let to_be_closed_count = variables
+            .iter()
+            .filter(|(_, attribute)| *attribute == VariableAttribute::Close)
+            .count();
+
+        if to_be_closed_count > 1 {
+            self.report(self.error_at(
+                self.span_from(start, tokens),
+                "multiple to-be-closed variables in local list".to_string(),
+            ));
+        }
+
+        let values = if tokens.peek() == Some(&lex::Token::Assigment) {
+            tokens.next();
+            self.parse_expression_list(tokens)?
+        } else {
+            Vec::new()
+        };
+
+        for (name, attribute) in &variables {
+            self.declare_local(name.clone(), *attribute);
+        }
+
+        Ok(Statement::LocalVariableDeclaration {
+            variables,
+            values,
+            span: self.span_from(start, tokens),
+        })
+    }
+
+    fn parse_attribute(
+        &mut self,
+        tokens: &mut std::iter::Peekable<std::vec::IntoIter<lex::Token>>,
+    ) -> Result<VariableAttribute, SyntaxError> {
+        if tokens.peek() != Some(&lex::Token::LessThan) {
+            return Ok(VariableAttribute::Regular);
+        }
+
+        tokens.next();
+
+        let start = self.current_position(tokens);
+        let attribute = match self.parse_identifier(tokens)?.as_str() {
+            "const" => VariableAttribute::Const,
+            "close" => VariableAttribute::Close,
+            unknown => {
+                return Err(self.error_at(
+                    self.span_from(start, tokens),
+                    format!("unknown attribute '{}'", unknown),
+                ))
+            }
+        };
+
+        self.expect(tokens, lex::Token::GreaterThan)?;
+
+        Ok(attribute)
     }
 
     fn parse_identifier(
         &mut self,
         tokens: &mut std::iter::Peekable<std::vec::IntoIter<lex::Token>>,
-    ) -> Result<String, String> {
-        if let Some(lex::Token::Identifier(identifier)) = tokens.next() {
-            Ok(identifier)
-        } else {
-            Err("Expected identifier".to_string())
+    ) -> Result<String, SyntaxError> {
+        match tokens.next_if(|token| matches!(token, lex::Token::Identifier(_))) {
+            Some(lex::Token::Identifier(identifier)) => Ok(identifier),
+            _ => Err(self.expected_error(tokens, &["<name>"])),
         }
     }
 
+    fn parse_expression_list(
+        &mut self,
+        tokens: &mut std::iter::Peekable<std::vec::IntoIter<lex::Token>>,
+    ) -> Result<Vec<Expression>, SyntaxError> {
+        let mut expressions = vec![self.parse_expression(tokens)?];
+
+        while let Some(lex::Token::Comma) = tokens.peek() {
+            tokens.next();
+            expressions.push(self.parse_expression(tokens)?);
+        }
+
+        Ok(expressions)
+    }
+
     fn parse_expression(
         &mut self,
         tokens: &mut std::iter::Peekable<std::vec::IntoIter<lex::Token>>,
-    ) -> Result<Expression, String> {
+    ) -> Result<Expression, SyntaxError> {
+        self.nested(tokens, |parser, tokens| {
+            create_binary_expression!(
+                parser,
+                tokens,
+                Self::parse_and_expression,
+                [(lex::Token::Or, "or")]
+            )
+        })
+    }
+
+    fn parse_and_expression(
+        &mut self,
+        tokens: &mut std::iter::Peekable<std::vec::IntoIter<lex::Token>>,
+    ) -> Result<Expression, SyntaxError> {
         create_binary_expression!(
             self,
             tokens,
-            Self::parse_2_level_expression,
+            Self::parse_comparison_expression,
+            [(lex::Token::And, "and")]
+        )
+    }
+
+    fn parse_comparison_expression(
+        &mut self,
+        tokens: &mut std::iter::Peekable<std::vec::IntoIter<lex::Token>>,
+    ) -> Result<Expression, SyntaxError> {
+        create_binary_expression!(
+            self,
+            tokens,
+            Self::parse_concatenation_expression,
             [
                 (lex::Token::NotEqual, "~="),
                 (lex::Token::Equal, "=="),
@@ -250,10 +854,33 @@ impl<'a> Parser<'a> {
         )
     }
 
+    /// Concatenation is right associative, so `a .. b .. c` is `a .. (b .. c)`.
+    fn parse_concatenation_expression(
+        &mut self,
+        tokens: &mut std::iter::Peekable<std::vec::IntoIter<lex::Token>>,
+    ) -> Result<Expression, SyntaxError> {
+        let left = self.parse_2_level_expression(tokens)?;
+
+        if tokens.peek() != Some(&lex::Token::Concatanation) {
+            return Ok(left);
+        }
+
+        tokens.next();
+        let right = self.nested(tokens, Self::parse_concatenation_expression)?;
+        let span = left.span().to(right.span());
+
+        Ok(Expression::BinaryExpression(
+            Box::new(left),
+            "..".to_string(),
+            Box::new(right),
+            span,
+        ))
+    }
+
     fn parse_2_level_expression(
         &mut self,
         tokens: &mut std::iter::Peekable<std::vec::IntoIter<lex::Token>>,
-    ) -> Result<Expression, String> {
+    ) -> Result<Expression, SyntaxError> {
         create_binary_expression!(
             self,
             tokens,
@@ -265,87 +892,254 @@ impl<'a> Parser<'a> {
     fn parse_3_level_expression(
         &mut self,
         tokens: &mut std::iter::Peekable<std::vec::IntoIter<lex::Token>>,
-    ) -> Result<Expression, String> {
+    ) -> Result<Expression, SyntaxError> {
         create_binary_expression!(
             self,
             tokens,
-            Self::parse_4_level_expression,
-            [(lex::Token::Asterisk, "*"), (lex::Token::Slash, "/")]
+            Self::parse_unary_expression,
+            [
+                (lex::Token::Asterisk, "*"),
+                (lex::Token::Slash, "/"),
+                (lex::Token::DoubleSlash, "//"),
+                (lex::Token::Percent, "%")
+            ]
         )
     }
 
+    fn parse_unary_expression(
+        &mut self,
+        tokens: &mut std::iter::Peekable<std::vec::IntoIter<lex::Token>>,
+    ) -> Result<Expression, SyntaxError> {
+        let operator = match tokens.peek() {
+            Some(lex::Token::Not) => "not",
+            Some(lex::Token::Minus) => "-",
+            Some(lex::Token::Hash) => "#",
+            _ => return self.parse_power_expression(tokens),
+        };
+
+        let start = self.current_position(tokens);
+        tokens.next();
+        let operand = self.nested(tokens, Self::parse_unary_expression)?;
+
+        Ok(Expression::UnaryExpression(
+            operator.to_string(),
+            Box::new(operand),
+            self.span_from(start, tokens),
+        ))
+    }
+
+    /// Exponentiation binds tighter than unary operators on its left and is right
+    /// associative, so `-2 ^ -3 ^ 2` is `-(2 ^ (-(3 ^ 2)))`.
+    fn parse_power_expression(
+        &mut self,
+        tokens: &mut std::iter::Peekable<std::vec::IntoIter<lex::Token>>,
+    ) -> Result<Expression, SyntaxError> {
+        let left = self.parse_4_level_expression(tokens)?;
+
+        if tokens.peek() != Some(&lex::Token::Caret) {
+            return Ok(left);
+        }
+
+        tokens.next();
+        let right = self.nested(tokens, Self::parse_unary_expression)?;
+        let span = left.span().to(right.span());
+
+        Ok(Expression::BinaryExpression(
+            Box::new(left),
+            "^".to_string(),
+            Box::new(right),
+            span,
+        ))
+    }
+
     fn parse_4_level_expression(
         &mut self,
         tokens: &mut std::iter::Peekable<std::vec::IntoIter<lex::Token>>,
-    ) -> Result<Expression, String> {
-        if let Some(token) = tokens.next() {
-            match token {
-                lex::Token::LeftParen => {
-                    let expression = self.parse_expression(tokens)?;
+    ) -> Result<Expression, SyntaxError> {
+        match tokens.peek() {
+            Some(lex::Token::Identifier(_)) | Some(lex::Token::LeftParen) => {
+                return self.parse_suffixed_expression(tokens)
+            }
+            Some(lex::Token::Function) => {
+                let start = self.current_position(tokens);
+                tokens.next();
+                let function = self.parse_function_body(tokens, start, false)?;
 
-                    if let Some(lex::Token::RightParen) = tokens.next() {
-                        Ok(expression)
-                    } else {
-                        Err("Expected ')'".to_string())
-                    }
-                }
-                lex::Token::LeftBracket => {
-                    let table_literal = self.parse_table(tokens)?;
+                return Ok(Expression::FunctionLiteral(
+                    function,
+                    self.span_from(start, tokens),
+                ));
+            }
+            Some(lex::Token::Ellipsis) => {
+                let start = self.current_position(tokens);
+                tokens.next();
 
-                    if let Some(lex::Token::RightBracket) = tokens.next() {
-                        Ok(table_literal)
-                    } else {
-                        Err("Expected '}'".to_string())
-                    }
-                }
-                lex::Token::Literal(LiteralType::Number(number)) => {
-                    Ok(Expression::NumberLiteral(number))
+                let span = self.span_from(start, tokens);
+                if !self.is_variadic {
+                    self.report(self.error_at(
+                        span,
+                        "cannot use '...' outside a vararg function near '...'".to_string(),
+                    ));
                 }
-                lex::Token::Identifier(identifier) => {
-                    if tokens.peek() == Some(&lex::Token::LeftParen) {
-                        tokens.next();
 
-                        let mut arguments = Vec::new();
+                return Ok(Expression::VarargExpression(span));
+            }
+            _ => {}
+        }
 
-                        while let Ok(expression) = self.parse_expression(tokens) {
-                            arguments.push(expression);
+        let start = self.current_position(tokens);
 
-                            if let Some(lex::Token::Comma) = tokens.peek() {
-                                tokens.next();
-                            } else {
-                                break;
-                            }
-                        }
+        match tokens.peek() {
+            Some(lex::Token::LeftBracket) => {
+                tokens.next();
+                let fields = self.parse_table(tokens)?;
+                self.expect(tokens, lex::Token::RightBracket)?;
 
-                        if tokens.peek() != Some(&lex::Token::RightParen) {
-                            return Err("Expected ')'".to_string());
-                        }
+                Ok(Expression::TableLiteral(
+                    fields,
+                    self.span_from(start, tokens),
+                ))
+            }
+            Some(lex::Token::Literal(_)) => {
+                let Some(lex::Token::Literal(literal)) = tokens.next() else {
+                    unreachable!()
+                };
+                let span = self.span_from(start, tokens);
+
+                Ok(match literal {
+                    LiteralType::Number(number) => Expression::NumberLiteral(number, span),
+                    LiteralType::Integer(number) => Expression::IntegerLiteral(number, span),
+                    LiteralType::Boolean(value) => Expression::BooleanLiteral(value, span),
+                    LiteralType::Nil => Expression::NilLiteral(span),
+                    LiteralType::String(value) => Expression::StringLiteral(value.into(), span),
+                })
+            }
+            _ => Err(self.error_near(tokens, "unexpected symbol", &["expression"])),
+        }
+    }
 
-                        tokens.next();
+    fn parse_primary_expression(
+        &mut self,
+        tokens: &mut std::iter::Peekable<std::vec::IntoIter<lex::Token>>,
+    ) -> Result<Expression, SyntaxError> {
+        let start = self.current_position(tokens);
 
-                        Ok(Expression::FunctionCall(identifier, arguments))
-                    } else if tokens.peek() == Some(&lex::Token::LeftSquareBracket) {
-                        tokens.next();
-                        let index = self.parse_expression(tokens)?;
-                        
-                        self.expect(tokens, lex::Token::RightSquareBracket)?;
+        match tokens.peek() {
+            Some(lex::Token::Identifier(_)) => {
+                let identifier = self.parse_identifier(tokens)?;
+                self.use_variable(&identifier);
 
-                        Ok(Expression::IndexOperator(Box::new(Expression::IdentifierExpression(identifier)), Box::new(index)))
-                    } else {
-                        Ok(Expression::IdentifierExpression(identifier))
-                    }
+                Ok(Expression::IdentifierExpression(
+                    identifier,
+                    self.span_from(start, tokens),
+                ))
+            }
+            Some(lex::Token::LeftParen) => {
+                tokens.next();
+                let expression = self.parse_expression(tokens)?;
+                self.expect(tokens, lex::Token::RightParen)?;
+
+                // Parentheses truncate calls and `...` to a single value
+                match expression {
+                    Expression::FunctionCall(..)
+                    | Expression::MethodCall(..)
+                    | Expression::VarargExpression(_) => Ok(Expression::ParenthesizedExpression(
+                        Box::new(expression),
+                        self.span_from(start, tokens),
+                    )),
+                    expression => Ok(expression),
                 }
-                lex::Token::Literal(LiteralType::Boolean(value)) => {
-                    Ok(Expression::BooleanLiteral(value))
+            }
+            _ => Err(self.error_near(tokens, "unexpected symbol", &["<name>", "'('"])),
+        }
+    }
+
+    /// Parses a name or parenthesized expression followed by any number of field
+    /// accesses, index operations and calls.
+    fn parse_suffixed_expression(
+        &mut self,
+        tokens: &mut std::iter::Peekable<std::vec::IntoIter<lex::Token>>,
+    ) -> Result<Expression, SyntaxError> {
+        let mut expression = self.parse_primary_expression(tokens)?;
+        let start = expression.span().start;
+
+        loop {
+            expression = match tokens.peek() {
+                Some(lex::Token::Dot) => {
+                    tokens.next();
+                    let field_start = self.current_position(tokens);
+                    let field = self.parse_identifier(tokens)?;
+                    let field_span = self.span_from(field_start, tokens);
+
+                    Expression::IndexOperator(
+                        Box::new(expression),
+                        Box::new(Expression::StringLiteral(field.into(), field_span)),
+                        self.span_from(start, tokens),
+                    )
                 }
-                lex::Token::Literal(LiteralType::Nil) => Ok(Expression::NilLiteral {}),
-                lex::Token::Literal(LiteralType::String(value)) => {
-                    Ok(Expression::StringLiteral(value))
+                Some(lex::Token::LeftSquareBracket) => {
+                    tokens.next();
+                    let index = self.parse_expression(tokens)?;
+
+                    self.expect(tokens, lex::Token::RightSquareBracket)?;
+
+                    Expression::IndexOperator(
+                        Box::new(expression),
+                        Box::new(index),
+                        self.span_from(start, tokens),
+                    )
+                }
+                Some(lex::Token::Colon) => {
+                    tokens.next();
+                    let method = self.parse_identifier(tokens)?;
+                    let arguments = self.parse_call_arguments(tokens)?;
+
+                    Expression::MethodCall(
+                        Box::new(expression),
+                        method,
+                        arguments,
+                        self.span_from(start, tokens),
+                    )
+                }
+                Some(lex::Token::LeftParen)
+                | Some(lex::Token::Literal(LiteralType::String(_)))
+                | Some(lex::Token::LeftBracket) => {
+                    let arguments = self.parse_call_arguments(tokens)?;
+
+                    Expression::FunctionCall(
+                        Box::new(expression),
+                        arguments,
+                        self.span_from(start, tokens),
+                    )
                 }
-                _ => Err(format!("Unexpected token '{:?}'", token)),
+                _ => return Ok(expression),
+            };
+        }
+    }
+
+    /// Parses the arguments of a call: a parenthesized list, a string literal or a
+    /// table constructor.
+    fn parse_call_arguments(
+        &mut self,
+        tokens: &mut std::iter::Peekable<std::vec::IntoIter<lex::Token>>,
+    ) -> Result<Vec<Expression>, SyntaxError> {
+        match tokens.peek() {
+            Some(lex::Token::LeftParen) => {
+                tokens.next();
+
+                let arguments = if tokens.peek() == Some(&lex::Token::RightParen) {
+                    Vec::new()
+                } else {
+                    self.parse_expression_list(tokens)?
+                };
+
+                self.expect(tokens, lex::Token::RightParen)?;
+                Ok(arguments)
             }
-        } else {
-            Err("Expected factor".to_string())
+            Some(lex::Token::Literal(LiteralType::String(_))) | Some(lex::Token::LeftBracket) => {
+                Ok(vec![self.parse_4_level_expression(tokens)?])
+            }
+            _ => Err(self.expected_error(tokens, &["function arguments"])),
         }
     }
 
@@ -353,119 +1147,280 @@ impl<'a> Parser<'a> {
         &mut self,
         tokens: &mut std::iter::Peekable<std::vec::IntoIter<lex::Token>>,
         expected: lex::Token,
-    ) -> Result<(), String> {
-        let next_token = tokens.next();
-        if next_token == Some(expected.clone()) {
-            Ok(())
-        } else {
-            Err(format!("Expected '{:?}'", expected))
+    ) -> Result<(), SyntaxError> {
+        match tokens.next_if_eq(&expected) {
+            Some(_) => Ok(()),
+            None => Err(self.expected_error(tokens, &[&format!("'{}'", expected)])),
         }
     }
 
     fn parse_assigment_statement(
         &mut self,
         tokens: &mut std::iter::Peekable<std::vec::IntoIter<lex::Token>>,
-    ) -> Result<Statement, String> {
-        let identifier = self.parse_identifier(tokens)?;
+        first_target: Expression,
+    ) -> Result<Statement, SyntaxError> {
+        let start = first_target.span().start;
+        let mut targets = vec![first_target];
+
+        while let Some(lex::Token::Comma) = tokens.peek() {
+            tokens.next();
+            targets.push(self.parse_suffixed_expression(tokens)?);
+        }
+
+        for target in &targets {
+            match target {
+                Expression::IdentifierExpression(identifier, span) => {
+                    self.check_assignable(identifier, *span)
+                }
+                Expression::IndexOperator(..) => {}
+                _ => return Err(self.error_near(tokens, "syntax error", &[])),
+            }
+        }
 
         self.expect(tokens, lex::Token::Assigment)?;
 
-        let expression = self.parse_expression(tokens)?;
+        let values = self.parse_expression_list(tokens)?;
 
-        Ok(Statement::AssigmentStatement(
-            identifier,
-            Box::new(expression),
-        ))
+        Ok(Statement::AssigmentStatement {
+            targets,
+            values,
+            span: self.span_from(start, tokens),
+        })
     }
 
     fn parse_function_declaration(
         &mut self,
         tokens: &mut std::iter::Peekable<std::vec::IntoIter<lex::Token>>,
-    ) -> Result<Statement, String> {
+    ) -> Result<Statement, SyntaxError> {
+        let start = self.current_position(tokens);
         tokens.next();
 
+        let name_start = self.current_position(tokens);
         let function_name = self.parse_identifier(tokens)?;
+        let name_span = self.span_from(name_start, tokens);
+        self.use_variable(&function_name);
+        let mut target = Expression::IdentifierExpression(function_name, name_span);
+        let mut is_method = false;
+
+        // `function a.b.c:m()` stores the function in a field, `:` makes it a method
+        while let Some(lex::Token::Dot | lex::Token::Colon) = tokens.peek() {
+            is_method = tokens.next() == Some(lex::Token::Colon);
+
+            let field_start = self.current_position(tokens);
+            let field = self.parse_identifier(tokens)?;
+            let field_span = self.span_from(field_start, tokens);
+
+            target = Expression::IndexOperator(
+                Box::new(target),
+                Box::new(Expression::StringLiteral(field.into(), field_span)),
+                self.span_from(name_start, tokens),
+            );
+
+            if is_method {
+                break;
+            }
+        }
+
+        if let Expression::IdentifierExpression(function_name, span) = &target {
+            self.check_assignable(function_name, *span);
+        }
+
+        let function = self.parse_function_body(tokens, start, is_method)?;
+
+        Ok(Statement::FunctionDeclaration {
+            target: Box::new(target),
+            function,
+            span: self.span_from(start, tokens),
+        })
+    }
 
+    /// Parses the parameter list and body of a function, up to and including `end`.
+    /// `start` is the position of the `function` keyword. Methods get an implicit
+    /// first parameter `self`.
+    fn parse_function_body(
+        &mut self,
+        tokens: &mut std::iter::Peekable<std::vec::IntoIter<lex::Token>>,
+        start: Position,
+        is_method: bool,
+    ) -> Result<Rc<FunctionDefinition>, SyntaxError> {
+        let line_defined = start.line;
         self.expect(tokens, lex::Token::LeftParen)?;
 
-        let mut function_arguments = Vec::new();
+        let (mut arguments, is_variadic) = self
+            .parse_header(tokens, lex::Token::RightParen, Self::parse_parameter_list)?
+            .unwrap_or_default();
+        if is_method {
+            arguments.insert(0, "self".to_string());
+        }
+
+        self.functions.push(FunctionState {
+            line_defined,
+            first_scope: self.scopes.len(),
+            upvalues: Vec::new(),
+        });
+        self.enter_scope();
+        for argument in &arguments {
+            self.declare_local(argument.clone(), VariableAttribute::Regular);
+        }
+
+        // `break` cannot jump out of a function body into an enclosing loop
+        let enclosing_loop_depth = std::mem::take(&mut self.loop_depth);
+        let enclosing_is_variadic = std::mem::replace(&mut self.is_variadic, is_variadic);
+        let body = self.parse_block_until(tokens, &[lex::Token::End]);
+        self.loop_depth = enclosing_loop_depth;
+        self.is_variadic = enclosing_is_variadic;
+        self.exit_scope();
+        let function = self.functions.pop().expect("No function found");
 
-        while let Ok(identifier) = self.parse_identifier(tokens) {
-            function_arguments.push(identifier);
+        let last_line_defined = self.current_position(tokens).line;
+        self.expect(tokens, lex::Token::End)?;
+
+        Ok(Rc::new(FunctionDefinition {
+            chunk_name: self.chunk_name.clone(),
+            source: self.source.clone(),
+            line_defined,
+            last_line_defined,
+            arguments,
+            is_variadic,
+            upvalues: function.upvalues,
+            body,
+        }))
+    }
+
+    /// Parses the names of the parameters of a function and whether it ends with `...`.
+    fn parse_parameter_list(
+        &mut self,
+        tokens: &mut std::iter::Peekable<std::vec::IntoIter<lex::Token>>,
+    ) -> Result<(Vec<String>, bool), SyntaxError> {
+        let mut arguments = Vec::new();
+
+        if tokens.peek() == Some(&lex::Token::RightParen) {
+            return Ok((arguments, false));
+        }
+
+        loop {
+            if tokens.peek() == Some(&lex::Token::Ellipsis) {
+                tokens.next();
+                return Ok((arguments, true));
+            }
+
+            arguments.push(self.parse_identifier(tokens)?);
 
             if let Some(lex::Token::Comma) = tokens.peek() {
                 tokens.next();
             } else {
-                break;
+                return Ok((arguments, false));
             }
         }
+    }
 
-        self.expect(tokens, lex::Token::RightParen)?;
-
-        let function_body = self.parse_block_until(tokens, &[lex::Token::End])?;
+    fn parse_return_statement(
+        &mut self,
+        tokens: &mut std::iter::Peekable<std::vec::IntoIter<lex::Token>>,
+    ) -> Result<Statement, SyntaxError> {
+        let start = self.current_position(tokens);
+        tokens.next();
 
-        self.expect(tokens, lex::Token::End)?;
+        let expressions = match tokens.peek() {
+            None
+            | Some(lex::Token::Semicolon)
+            | Some(lex::Token::End)
+            | Some(lex::Token::Else)
+            | Some(lex::Token::ElseIf)
+            | Some(lex::Token::Until) => Vec::new(),
+            _ => self.parse_expression_list(tokens)?,
+        };
+        let span = self.span_from(start, tokens);
+        tokens.next_if_eq(&lex::Token::Semicolon);
 
-        Ok(Statement::FunctionDeclaration {
-            function_name,
-            function_arguments,
-            function_body,
-        })
+        Ok(Statement::ReturnStatement(expressions, span))
     }
 
-    fn parse_return_statement(
+    fn parse_break_statement(
         &mut self,
         tokens: &mut std::iter::Peekable<std::vec::IntoIter<lex::Token>>,
-    ) -> Result<Statement, String> {
+    ) -> Result<Statement, SyntaxError> {
+        let start = self.current_position(tokens);
         tokens.next();
+        let span = self.span_from(start, tokens);
 
-        let expression = self.parse_expression(tokens)?;
+        if self.loop_depth == 0 {
+            self.report(self.error_at(span, "break outside a loop".to_string()));
+        }
 
-        Ok(Statement::ReturnStatement(Box::new(expression)))
+        Ok(Statement::BreakStatement(span))
     }
 
     fn parse_repeat_statement(
         &mut self,
         tokens: &mut std::iter::Peekable<std::vec::IntoIter<lex::Token>>,
-    ) -> Result<Statement, String> {
+    ) -> Result<Statement, SyntaxError> {
+        let start = self.current_position(tokens);
         tokens.next();
 
-        let code_block = self.parse_block_until(tokens, &[lex::Token::Until])?;
+        // The `until` condition is parsed inside the scope of the loop body
+        self.enter_scope();
+        self.loop_depth += 1;
+        let code_block = self.parse_statements_until(tokens, &[lex::Token::Until]);
+        self.loop_depth -= 1;
 
-        self.expect(tokens, lex::Token::Until)?;
-
-        let loop_condition = Box::new(self.parse_expression(tokens)?);
+        let loop_condition = self
+            .expect(tokens, lex::Token::Until)
+            .and_then(|_| self.parse_expression(tokens));
+        self.exit_scope();
+        let loop_condition = Box::new(loop_condition?);
 
         Ok(Statement::RepeatUntilLoop {
             code_block,
             loop_condition,
+            span: self.span_from(start, tokens),
         })
     }
 
     fn parse_table(
         &mut self,
         tokens: &mut std::iter::Peekable<std::vec::IntoIter<lex::Token>>,
-    ) -> Result<Expression, String> {
-
-        let mut table_structure = BTreeMap::new();
-        let mut in_table_index = 1;
+    ) -> Result<Vec<(Option<Expression>, Expression)>, SyntaxError> {
+        let mut fields = Vec::new();
 
         while let Some(token) = tokens.peek() {
             match *token {
                 lex::Token::RightBracket => {
                     break;
                 }
-                lex::Token::Comma => {
+                lex::Token::LeftSquareBracket => {
                     tokens.next();
-                } // Skip comma
+                    let key = self.parse_expression(tokens)?;
+                    self.expect(tokens, lex::Token::RightSquareBracket)?;
+                    self.expect(tokens, lex::Token::Assigment)?;
+                    let value = self.parse_expression(tokens)?;
+                    fields.push((Some(key), value));
+                }
                 _ => {
                     let element = self.parse_expression(tokens)?;
-                    table_structure.insert(Expression::NumberLiteral(in_table_index as f64), element);
-                    in_table_index += 1;
+
+                    match &element {
+                        Expression::IdentifierExpression(name, span)
+                            if tokens.peek() == Some(&lex::Token::Assigment) =>
+                        {
+                            let key = Expression::StringLiteral(name.as_str().into(), *span);
+                            tokens.next();
+                            let value = self.parse_expression(tokens)?;
+                            fields.push((Some(key), value));
+                        }
+                        _ => fields.push((None, element)),
+                    }
                 }
             }
+
+            match tokens.peek() {
+                Some(lex::Token::Comma) | Some(lex::Token::Semicolon) => {
+                    tokens.next();
+                }
+                _ => break,
+            }
         }
 
-        Ok(Expression::TableLiteral(table_structure))
+        Ok(fields)
     }
 }
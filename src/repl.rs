@@ -0,0 +1,103 @@
+use rustyline::{error::ReadlineError, DefaultEditor};
+
+use luir::{EvalValue, Function, Lua, LuaError};
+
+use crate::diagnostics::{convert_error_object, ColorChoice, Reporter};
+
+const PROMPT: &str = "> ";
+const CONTINUATION_PROMPT: &str = ">> ";
+const CHUNK_NAME: &str = "stdin";
+const SOURCE: &str = "=stdin";
+
+/// Reads statements from the terminal and runs them on `lua` until the end of input.
+/// Globals persist between statements, and the values of expressions are printed.
+pub fn run(lua: &mut Lua, color: ColorChoice) -> rustyline::Result<()> {
+    let mut editor = DefaultEditor::new()?;
+    let mut input = String::new();
+
+    loop {
+        let prompt = if input.is_empty() {
+            PROMPT
+        } else {
+            CONTINUATION_PROMPT
+        };
+
+        let line = match editor.readline(prompt) {
+            Ok(line) => line,
+            // Ctrl-C discards the statement being typed
+            Err(ReadlineError::Interrupted) => {
+                input.clear();
+                continue;
+            }
+            Err(ReadlineError::Eof) => return Ok(()),
+            Err(err) => return Err(err),
+        };
+
+        if !input.is_empty() {
+            input.push('\n');
+        }
+        input.push_str(&line);
+
+        if execute_input(lua, &input, color) == Input::Incomplete {
+            continue;
+        }
+
+        editor.add_history_entry(input.as_str())?;
+        input.clear();
+    }
+}
+
+#[derive(PartialEq)]
+enum Input {
+    Complete,
+    /// The input ends in the middle of a statement, e.g. inside an open block.
+    Incomplete,
+}
+
+/// Runs one input of the REPL, reporting errors to standard error. Like in `lua`, the
+/// input is first tried as an expression whose values are printed, and `=expr` is
+/// shorthand for `return expr`.
+fn execute_input(lua: &mut Lua, input: &str, color: ColorChoice) -> Input {
+    // Blanking out `=` keeps the positions in error messages those of the typed input
+    let source_code = match input.strip_prefix('=') {
+        Some(expression) => format!(" {}", expression),
+        None => input.to_string(),
+    };
+
+    let result = lua
+        .load(&source_code)
+        .set_name(SOURCE)
+        .eval::<Vec<EvalValue>>()
+        .and_then(|values| {
+            if values.is_empty() {
+                return Ok(());
+            }
+
+            let print: Function = lua.globals().get("print")?;
+            print.call(lua, values)
+        });
+
+    match result {
+        Err(err) if is_incomplete(lua, &source_code, &err) => Input::Incomplete,
+        Err(mut err) => {
+            convert_error_object(lua, &mut err);
+            Reporter::new(CHUNK_NAME, input, color).report(&err);
+            Input::Complete
+        }
+        Ok(()) => Input::Complete,
+    }
+}
+
+/// Whether `err`, raised by evaluating `source_code`, means that the code ends in the
+/// middle of a statement. Syntax errors in other chunks, e.g. from `dofile`, also come
+/// out of evaluating it, so the input itself is checked.
+fn is_incomplete(lua: &mut Lua, source_code: &str, err: &LuaError) -> bool {
+    let ends_early = |err: &LuaError| matches!(err, LuaError::Syntax(errors) if errors[0].message.ends_with("<eof>"));
+
+    ends_early(err)
+        && lua
+            .load(source_code)
+            .set_name(SOURCE)
+            .into_function()
+            .is_err_and(|err| ends_early(&err))
+}
@@ -0,0 +1,697 @@
+use std::{
+    cell::RefCell,
+    io::{Read, Write},
+};
+
+use crate::{
+    ast::{string_to_number, EvalValue, LuaString, TableRef},
+    error::LuaError,
+    loader::{chunk_id, read_source_file, skip_file_header},
+    parser::Parser,
+    vm::VirtualMachine,
+};
+
+use super::{
+    argument_error, check_integer, check_option, check_string, io::error_message, missing_argument,
+    opt_integer, tostring, type_error,
+};
+
+/// First byte of precompiled chunks.
+const BINARY_CHUNK_SIGNATURE: u8 = 0x1b;
+
+pub fn open(virtual_machine: &mut VirtualMachine) -> EvalValue {
+    virtual_machine.set_global("print", EvalValue::native_function(print));
+    virtual_machine.set_global("type", EvalValue::native_function(lua_type));
+    virtual_machine.set_global("tostring", EvalValue::native_function(lua_tostring));
+    virtual_machine.set_global("tonumber", EvalValue::native_function(tonumber));
+    virtual_machine.set_global("setmetatable", EvalValue::native_function(setmetatable));
+    virtual_machine.set_global("getmetatable", EvalValue::native_function(getmetatable));
+    virtual_machine.set_global("rawget", EvalValue::native_function(rawget));
+    virtual_machine.set_global("rawset", EvalValue::native_function(rawset));
+    virtual_machine.set_global("rawequal", EvalValue::native_function(rawequal));
+    virtual_machine.set_global("rawlen", EvalValue::native_function(rawlen));
+    virtual_machine.set_global("next", EvalValue::native_function(next));
+    virtual_machine.set_global("pairs", EvalValue::native_function(pairs));
+    virtual_machine.set_global("ipairs", EvalValue::native_function(ipairs));
+    virtual_machine.set_global("select", EvalValue::native_function(select));
+    virtual_machine.set_global("error", EvalValue::native_function(error));
+    virtual_machine.set_global("pcall", EvalValue::native_function(pcall));
+    virtual_machine.set_global("xpcall", EvalValue::native_function(xpcall));
+    virtual_machine.set_global("assert", EvalValue::native_function(assert));
+    virtual_machine.set_global("warn", EvalValue::native_function(warn));
+    virtual_machine.set_global("load", EvalValue::native_function(load));
+    virtual_machine.set_global("loadfile", EvalValue::native_function(loadfile));
+    virtual_machine.set_global("dofile", EvalValue::native_function(dofile));
+    virtual_machine.set_global("collectgarbage", collectgarbage());
+    virtual_machine.set_global("_VERSION", EvalValue::string("Lua 5.4"));
+
+    EvalValue::Table(virtual_machine.globals())
+}
+
+fn print(
+    virtual_machine: &mut VirtualMachine,
+    args: Vec<EvalValue>,
+) -> Result<Vec<EvalValue>, LuaError> {
+    let mut line = Vec::new();
+    for (position, arg) in args.iter().enumerate() {
+        if position > 0 {
+            line.push(b'\t');
+        }
+        // Strings are written as is, they do not have to be valid UTF-8
+        line.extend_from_slice(&tostring(virtual_machine, arg)?);
+    }
+    line.push(b'\n');
+
+    // Like in Lua, a failed write to standard output is not an error
+    let _ = std::io::stdout().write_all(&line);
+    Ok(Vec::new())
+}
+
+fn lua_type(_: &mut VirtualMachine, args: Vec<EvalValue>) -> Result<Vec<EvalValue>, LuaError> {
+    match args.first() {
+        Some(value) => Ok(vec![EvalValue::string(value.type_name())]),
+        None => Err(missing_argument(1, "type")),
+    }
+}
+
+fn lua_tostring(
+    virtual_machine: &mut VirtualMachine,
+    args: Vec<EvalValue>,
+) -> Result<Vec<EvalValue>, LuaError> {
+    match args.first() {
+        Some(value) => Ok(vec![EvalValue::String(tostring(virtual_machine, value)?)]),
+        None => Err(missing_argument(1, "tostring")),
+    }
+}
+
+/// Converts a numeral in `base` to an integer, ignoring surrounding whitespace. Like
+/// in Lua, numerals that do not fit wrap around.
+fn string_to_integer(string: &[u8], base: u32) -> Option<i64> {
+    let is_space = |byte: &u8| matches!(byte, b' ' | b'\t'..=b'\r');
+    let start = string.iter().position(|byte| !is_space(byte))?;
+    let end = string.iter().rposition(|byte| !is_space(byte))? + 1;
+    let string = &string[start..end];
+
+    let (negative, digits) = match string.split_first() {
+        Some((b'-', digits)) => (true, digits),
+        Some((b'+', digits)) => (false, digits),
+        _ => (false, string),
+    };
+    if digits.is_empty() {
+        return None;
+    }
+
+    let mut value: u64 = 0;
+    for digit in digits {
+        let digit = (*digit as char)
+            .to_digit(36)
+            .filter(|digit| *digit < base)?;
+        value = value.wrapping_mul(base as u64).wrapping_add(digit as u64);
+    }
+
+    let value = value as i64;
+    Some(if negative {
+        value.wrapping_neg()
+    } else {
+        value
+    })
+}
+
+fn tonumber(_: &mut VirtualMachine, args: Vec<EvalValue>) -> Result<Vec<EvalValue>, LuaError> {
+    if let None | Some(EvalValue::Nil) = args.get(1) {
+        return match args.first() {
+            Some(value @ (EvalValue::Number(_) | EvalValue::Integer(_))) => Ok(vec![value.clone()]),
+            Some(EvalValue::String(string)) => Ok(vec![std::str::from_utf8(string)
+                .ok()
+                .and_then(string_to_number)
+                .unwrap_or(EvalValue::Nil)]),
+            Some(_) => Ok(vec![EvalValue::Nil]),
+            None => Err(missing_argument(1, "tonumber")),
+        };
+    }
+
+    let base = check_integer(&args, 2, "tonumber")?;
+    let string = match args.first() {
+        Some(EvalValue::String(string)) => string,
+        other => return Err(type_error(1, "tonumber", "string", other)),
+    };
+    if !(2..=36).contains(&base) {
+        return Err(argument_error(2, "tonumber", "base out of range"));
+    }
+
+    Ok(vec![
+        string_to_integer(string, base as u32).map_or(EvalValue::Nil, EvalValue::Integer)
+    ])
+}
+
+fn setmetatable(_: &mut VirtualMachine, args: Vec<EvalValue>) -> Result<Vec<EvalValue>, LuaError> {
+    let mut args = args.into_iter();
+
+    let table = match args.next() {
+        Some(EvalValue::Table(table)) => table,
+        other => return Err(type_error(1, "setmetatable", "table", other.as_ref())),
+    };
+    let metatable = match args.next() {
+        Some(EvalValue::Table(metatable)) => Some(metatable),
+        Some(EvalValue::Nil) => None,
+        _ => return Err(argument_error(2, "setmetatable", "nil or table expected")),
+    };
+
+    let protected = table.borrow().metatable().is_some_and(|current| {
+        current.borrow().get(&EvalValue::string("__metatable")) != EvalValue::Nil
+    });
+
+    if protected {
+        return Err("cannot change a protected metatable".into());
+    }
+
+    table.borrow_mut().set_metatable(metatable);
+    Ok(vec![EvalValue::Table(table)])
+}
+
+fn getmetatable(
+    virtual_machine: &mut VirtualMachine,
+    args: Vec<EvalValue>,
+) -> Result<Vec<EvalValue>, LuaError> {
+    let metatable = match args.first() {
+        Some(value) => virtual_machine.metatable(value),
+        None => return Err(missing_argument(1, "getmetatable")),
+    };
+
+    match metatable {
+        Some(metatable) => match metatable.borrow().get(&EvalValue::string("__metatable")) {
+            EvalValue::Nil => Ok(vec![EvalValue::Table(metatable.clone())]),
+            protected => Ok(vec![protected]),
+        },
+        None => Ok(vec![EvalValue::Nil]),
+    }
+}
+
+/// Argument 1 as a table.
+fn check_table(args: &[EvalValue], function_name: &str) -> Result<TableRef, LuaError> {
+    match args.first() {
+        Some(EvalValue::Table(table)) => Ok(table.clone()),
+        other => Err(type_error(1, function_name, "table", other)),
+    }
+}
+
+fn rawget(_: &mut VirtualMachine, args: Vec<EvalValue>) -> Result<Vec<EvalValue>, LuaError> {
+    let table = check_table(&args, "rawget")?;
+    let key = args.get(1).ok_or_else(|| missing_argument(2, "rawget"))?;
+    let value = table.borrow().get(key);
+    Ok(vec![value])
+}
+
+fn rawset(_: &mut VirtualMachine, args: Vec<EvalValue>) -> Result<Vec<EvalValue>, LuaError> {
+    let table = check_table(&args, "rawset")?;
+    let key = args.get(1).ok_or_else(|| missing_argument(2, "rawset"))?;
+    let value = args.get(2).ok_or_else(|| missing_argument(3, "rawset"))?;
+    table.borrow_mut().set(key.clone(), value.clone())?;
+    Ok(vec![EvalValue::Table(table)])
+}
+
+fn rawequal(_: &mut VirtualMachine, args: Vec<EvalValue>) -> Result<Vec<EvalValue>, LuaError> {
+    match (args.first(), args.get(1)) {
+        (Some(left), Some(right)) => Ok(vec![EvalValue::Boolean(left == right)]),
+        (None, _) => Err(missing_argument(1, "rawequal")),
+        (Some(_), None) => Err(missing_argument(2, "rawequal")),
+    }
+}
+
+fn rawlen(_: &mut VirtualMachine, args: Vec<EvalValue>) -> Result<Vec<EvalValue>, LuaError> {
+    let length = match args.first() {
+        Some(EvalValue::Table(table)) => table.borrow().border(),
+        Some(EvalValue::String(string)) => string.len(),
+        _ => return Err(argument_error(1, "rawlen", "table or string expected")),
+    };
+    Ok(vec![EvalValue::Integer(length as i64)])
+}
+
+fn next(_: &mut VirtualMachine, args: Vec<EvalValue>) -> Result<Vec<EvalValue>, LuaError> {
+    let table = check_table(&args, "next")?;
+    let key = args.get(1).cloned().unwrap_or(EvalValue::Nil);
+    if let EvalValue::Number(n) = key {
+        if n.is_nan() {
+            return Err("invalid key to 'next'".into());
+        }
+    }
+
+    let entry = table.borrow().next(&key);
+    Ok(match entry {
+        Some((key, value)) => vec![key, value],
+        None => vec![EvalValue::Nil],
+    })
+}
+
+/// Returns `next`, the table and nil for a generic for, unless the value has a
+/// `__pairs` metamethod that provides them.
+fn pairs(
+    virtual_machine: &mut VirtualMachine,
+    args: Vec<EvalValue>,
+) -> Result<Vec<EvalValue>, LuaError> {
+    let value = args.first().ok_or_else(|| missing_argument(1, "pairs"))?;
+
+    match virtual_machine.get_metamethod(value, "__pairs") {
+        EvalValue::Nil => Ok(vec![
+            EvalValue::native_function(next),
+            value.clone(),
+            EvalValue::Nil,
+        ]),
+        handler => {
+            let mut values = virtual_machine.call_function(handler, vec![value.clone()])?;
+            values.resize(3, EvalValue::Nil);
+            Ok(values)
+        }
+    }
+}
+
+fn ipairs(_: &mut VirtualMachine, args: Vec<EvalValue>) -> Result<Vec<EvalValue>, LuaError> {
+    let value = args.first().ok_or_else(|| missing_argument(1, "ipairs"))?;
+    Ok(vec![
+        EvalValue::native_function(ipairs_iterator),
+        value.clone(),
+        EvalValue::Integer(0),
+    ])
+}
+
+/// The iterator of `ipairs`, which reads the fields with metamethods and stops at the
+/// first nil.
+fn ipairs_iterator(
+    virtual_machine: &mut VirtualMachine,
+    args: Vec<EvalValue>,
+) -> Result<Vec<EvalValue>, LuaError> {
+    let index = check_integer(&args, 2, "ipairs")?.wrapping_add(1);
+    let object = args.first().cloned().unwrap_or(EvalValue::Nil);
+
+    match virtual_machine.index(object, EvalValue::Integer(index))? {
+        EvalValue::Nil => Ok(vec![EvalValue::Nil]),
+        value => Ok(vec![EvalValue::Integer(index), value]),
+    }
+}
+
+/// With `'#'` the number of extra arguments, otherwise the arguments after the one at
+/// the given index. Negative indices count from the end.
+fn select(_: &mut VirtualMachine, args: Vec<EvalValue>) -> Result<Vec<EvalValue>, LuaError> {
+    let count = args.len() as i64;
+
+    if let Some(EvalValue::String(string)) = args.first() {
+        if string.first() == Some(&b'#') {
+            return Ok(vec![EvalValue::Integer(count - 1)]);
+        }
+    }
+
+    let index = match check_integer(&args, 1, "select")? {
+        index if index < 0 => count + index,
+        index => index.min(count),
+    };
+    if index < 1 {
+        return Err(argument_error(1, "select", "index out of range"));
+    }
+    Ok(args.into_iter().skip(index as usize).collect())
+}
+
+fn error(
+    virtual_machine: &mut VirtualMachine,
+    args: Vec<EvalValue>,
+) -> Result<Vec<EvalValue>, LuaError> {
+    let mut args = args.into_iter();
+    let value = args.next().unwrap_or(EvalValue::Nil);
+    let level = match args.next() {
+        None | Some(EvalValue::Nil) => 1.0,
+        Some(level) => match level.to_number() {
+            Some(level) => level,
+            None => return Err(type_error(2, "error", "number", Some(&level))),
+        },
+    };
+
+    let mut err = LuaError::from_value(value);
+    if let Some(details) = err.details_mut() {
+        // Level 1 is the function that called `error`, right below its own frame
+        if matches!(details.value, EvalValue::String(_)) && level >= 1.0 {
+            details.location = virtual_machine.location_at(level as usize);
+        }
+        details.traceback = Some(virtual_machine.traceback());
+    }
+
+    Err(err)
+}
+
+fn pcall(
+    virtual_machine: &mut VirtualMachine,
+    args: Vec<EvalValue>,
+) -> Result<Vec<EvalValue>, LuaError> {
+    let mut args = args.into_iter();
+    let function = args.next().ok_or_else(|| missing_argument(1, "pcall"))?;
+
+    match virtual_machine.call_function(function, args.collect()) {
+        Ok(values) => Ok(std::iter::once(EvalValue::Boolean(true))
+            .chain(values)
+            .collect()),
+        Err(LuaError::CoroutineClosed) => Err(LuaError::CoroutineClosed),
+        Err(err) => Ok(vec![EvalValue::Boolean(false), err.into_value()]),
+    }
+}
+
+fn xpcall(
+    virtual_machine: &mut VirtualMachine,
+    args: Vec<EvalValue>,
+) -> Result<Vec<EvalValue>, LuaError> {
+    let mut args = args.into_iter();
+    let function = args.next().unwrap_or(EvalValue::Nil);
+    let handler = match args.next() {
+        Some(handler @ (EvalValue::NativeFunction(_) | EvalValue::DeclaredFunction(_))) => handler,
+        other => return Err(type_error(2, "xpcall", "function", other.as_ref())),
+    };
+
+    let err = match virtual_machine.call_function(function, args.collect()) {
+        Ok(values) => {
+            return Ok(std::iter::once(EvalValue::Boolean(true))
+                .chain(values)
+                .collect())
+        }
+        Err(LuaError::CoroutineClosed) => return Err(LuaError::CoroutineClosed),
+        Err(err) => err,
+    };
+
+    let handled = match virtual_machine.call_function(handler, vec![err.into_value()]) {
+        Ok(values) => values.into_iter().next().unwrap_or(EvalValue::Nil),
+        Err(LuaError::CoroutineClosed) => return Err(LuaError::CoroutineClosed),
+        Err(err) => err.into_value(),
+    };
+
+    Ok(vec![EvalValue::Boolean(false), handled])
+}
+
+fn assert(
+    virtual_machine: &mut VirtualMachine,
+    args: Vec<EvalValue>,
+) -> Result<Vec<EvalValue>, LuaError> {
+    match args.first() {
+        None => Err(missing_argument(1, "assert")),
+        Some(condition) if condition.is_true() => Ok(args),
+        Some(_) => match args.into_iter().nth(1) {
+            // The message is raised as is, without a position
+            Some(message) => {
+                let mut err = LuaError::from_value(message);
+                if let Some(details) = err.details_mut() {
+                    details.traceback = Some(virtual_machine.traceback());
+                }
+                Err(err)
+            }
+            None => Err("assertion failed!".into()),
+        },
+    }
+}
+
+/// Prints a warning made of its string arguments to standard error if warnings are on.
+/// A single argument starting with `@` is a control message: `@on` and `@off` switch
+/// warnings on and off, others are ignored.
+fn warn(
+    virtual_machine: &mut VirtualMachine,
+    args: Vec<EvalValue>,
+) -> Result<Vec<EvalValue>, LuaError> {
+    if args.is_empty() {
+        return Err(type_error(1, "warn", "string", None));
+    }
+
+    let mut message = Vec::new();
+    for (position, arg) in args.iter().enumerate() {
+        match arg.to_lua_string() {
+            Some(piece) => message.extend_from_slice(&piece),
+            None => return Err(type_error(position + 1, "warn", "string", Some(arg))),
+        }
+    }
+
+    match message.strip_prefix(b"@") {
+        Some(b"on") if args.len() == 1 => virtual_machine.set_warnings_enabled(true),
+        Some(b"off") if args.len() == 1 => virtual_machine.set_warnings_enabled(false),
+        Some(_) if args.len() == 1 => {}
+        _ if virtual_machine.warnings_enabled() => {
+            let mut stderr = std::io::stderr().lock();
+            // Like `print`, a failed write to a standard stream is not an error
+            let _ = stderr
+                .write_all(b"Lua warning: ")
+                .and_then(|_| stderr.write_all(&message))
+                .and_then(|_| stderr.write_all(b"\n"));
+        }
+        _ => {}
+    }
+
+    Ok(Vec::new())
+}
+
+/// Compiles a chunk loaded from `source` into a function whose `_ENV` is `environment`,
+/// or the global table if it is `None`. `mode` tells whether text and binary chunks are
+/// accepted; binary chunks are recognized but cannot be loaded.
+fn load_chunk(
+    virtual_machine: &mut VirtualMachine,
+    source_code: &[u8],
+    source: &str,
+    mode: &[u8],
+    environment: Option<EvalValue>,
+) -> Result<EvalValue, LuaError> {
+    let is_binary = source_code.first() == Some(&BINARY_CHUNK_SIGNATURE);
+    let (kind, mode_letter) = if is_binary {
+        ("binary", b'b')
+    } else {
+        ("text", b't')
+    };
+    if !mode.contains(&mode_letter) {
+        return Err(LuaError::runtime(format!(
+            "attempt to load a {} chunk (mode is '{}')",
+            kind,
+            String::from_utf8_lossy(mode)
+        )));
+    }
+    if is_binary {
+        return Err(LuaError::runtime(format!(
+            "{}: bad binary format (precompiled chunks are not supported)",
+            chunk_id(source)
+        )));
+    }
+
+    let chunk = Parser::new(source_code, source).parse()?;
+    Ok(virtual_machine.load(chunk, environment))
+}
+
+/// Compiles the file `filename`, or standard input if it is `None`.
+pub(super) fn load_file(
+    virtual_machine: &mut VirtualMachine,
+    filename: Option<&str>,
+    mode: &[u8],
+    environment: Option<EvalValue>,
+) -> Result<EvalValue, LuaError> {
+    let (source, source_code) = match filename {
+        Some(filename) => (
+            format!("@{}", filename),
+            read_source_file(filename)
+                .map_err(|err| format!("cannot open {}: {}", filename, error_message(&err))),
+        ),
+        None => {
+            let mut source_code = Vec::new();
+            let source_code = std::io::stdin()
+                .read_to_end(&mut source_code)
+                .map(|_| skip_file_header(&source_code).to_vec())
+                .map_err(|err| format!("cannot read stdin: {}", error_message(&err)));
+            ("=stdin".to_string(), source_code)
+        }
+    };
+
+    load_chunk(virtual_machine, &source_code?, &source, mode, environment)
+}
+
+/// The values `load` and `loadfile` return: the function, or nil and the error message.
+fn load_result(result: Result<EvalValue, LuaError>) -> Vec<EvalValue> {
+    match result {
+        Ok(function) => vec![function],
+        Err(err) => vec![EvalValue::Nil, err.into_value()],
+    }
+}
+
+/// Compiles a chunk given as a string, or as a function returning its pieces until it
+/// returns nil or an empty string.
+fn load(
+    virtual_machine: &mut VirtualMachine,
+    args: Vec<EvalValue>,
+) -> Result<Vec<EvalValue>, LuaError> {
+    let mode = match args.get(2) {
+        None | Some(EvalValue::Nil) => "bt".into(),
+        Some(_) => check_string(&args, 3, "load")?,
+    };
+    let environment = args.get(3).cloned();
+    let chunk_name = |default: &LuaString| -> Result<String, LuaError> {
+        Ok(match args.get(1) {
+            None | Some(EvalValue::Nil) => default.to_str_lossy().into_owned(),
+            Some(_) => check_string(&args, 2, "load")?.to_str_lossy().into_owned(),
+        })
+    };
+
+    let (chunk_name, source_code) = match args.first().and_then(EvalValue::to_lua_string) {
+        Some(source_code) => (chunk_name(&source_code)?, Ok(source_code.to_vec())),
+        None => {
+            let chunk_name = chunk_name(&"=(load)".into())?;
+            let reader = match args.first() {
+                Some(reader @ (EvalValue::NativeFunction(_) | EvalValue::DeclaredFunction(_))) => {
+                    reader.clone()
+                }
+                other => return Err(type_error(1, "load", "function", other)),
+            };
+            (chunk_name, read_chunk(virtual_machine, reader))
+        }
+    };
+
+    Ok(load_result(source_code.and_then(|source_code| {
+        load_chunk(
+            virtual_machine,
+            &source_code,
+            &chunk_name,
+            &mode,
+            environment,
+        )
+    })))
+}
+
+/// Calls the reader function of `load` until it signals the end of the chunk.
+fn read_chunk(
+    virtual_machine: &mut VirtualMachine,
+    reader: EvalValue,
+) -> Result<Vec<u8>, LuaError> {
+    let mut source_code = Vec::new();
+
+    loop {
+        let piece = virtual_machine
+            .call_function(reader.clone(), Vec::new())?
+            .into_iter()
+            .next()
+            .unwrap_or(EvalValue::Nil);
+        match piece {
+            EvalValue::Nil => return Ok(source_code),
+            EvalValue::String(piece) if piece.is_empty() => return Ok(source_code),
+            EvalValue::String(piece) => source_code.extend_from_slice(&piece),
+            _ => return Err("reader function must return a string".into()),
+        }
+    }
+}
+
+fn loadfile(
+    virtual_machine: &mut VirtualMachine,
+    args: Vec<EvalValue>,
+) -> Result<Vec<EvalValue>, LuaError> {
+    let filename = match args.first() {
+        None | Some(EvalValue::Nil) => None,
+        Some(_) => Some(check_string(&args, 1, "loadfile")?),
+    };
+    let mode = match args.get(1) {
+        None | Some(EvalValue::Nil) => "bt".into(),
+        Some(_) => check_string(&args, 2, "loadfile")?,
+    };
+    let environment = args.get(2).cloned();
+
+    let filename = filename.map(|filename| filename.to_str_lossy().into_owned());
+    Ok(load_result(load_file(
+        virtual_machine,
+        filename.as_deref(),
+        &mode,
+        environment,
+    )))
+}
+
+/// Runs the file `filename`, or standard input if it is not given, returning what the
+/// chunk returns. Errors are raised, not returned.
+fn dofile(
+    virtual_machine: &mut VirtualMachine,
+    args: Vec<EvalValue>,
+) -> Result<Vec<EvalValue>, LuaError> {
+    let filename = match args.first() {
+        None | Some(EvalValue::Nil) => None,
+        Some(_) => Some(
+            check_string(&args, 1, "dofile")?
+                .to_str_lossy()
+                .into_owned(),
+        ),
+    };
+
+    let function = match load_file(virtual_machine, filename.as_deref(), b"bt", None) {
+        Ok(function) => function,
+        Err(LuaError::Syntax(errors)) => return Err(LuaError::Syntax(errors)),
+        // Like syntax errors, the message already tells where the problem is
+        Err(err) => {
+            let mut err = LuaError::from_value(err.into_value());
+            if let Some(details) = err.details_mut() {
+                details.traceback = Some(virtual_machine.traceback());
+            }
+            return Err(err);
+        }
+    };
+    virtual_machine.call_function(function, Vec::new())
+}
+
+/// The state `collectgarbage` reports. Values are freed as soon as they are no longer
+/// referenced, so there is no collector to control and the options only record what
+/// they were set to. A full collection has nothing left to reclaim, except for values
+/// that refer to each other in a cycle, which are never freed.
+struct CollectorState {
+    running: bool,
+    generational: bool,
+    pause: i64,
+    step_multiplier: i64,
+}
+
+fn collectgarbage() -> EvalValue {
+    const OPTIONS: [&str; 10] = [
+        "stop",
+        "restart",
+        "collect",
+        "count",
+        "step",
+        "setpause",
+        "setstepmul",
+        "isrunning",
+        "generational",
+        "incremental",
+    ];
+
+    let state = RefCell::new(CollectorState {
+        running: true,
+        generational: false,
+        pause: 200,
+        step_multiplier: 100,
+    });
+
+    EvalValue::native_function(move |virtual_machine, args| {
+        let option = check_option(&args, 1, "collectgarbage", Some("collect"), &OPTIONS)?;
+        let mut state = state.borrow_mut();
+
+        let result = match OPTIONS[option] {
+            "stop" | "restart" => {
+                state.running = OPTIONS[option] == "restart";
+                EvalValue::Integer(0)
+            }
+            // Memory use is not tracked, so this is the size of what the running code can
+            // reach, in kilobytes
+            "count" => EvalValue::Number(virtual_machine.memory_in_use() as f64 / 1024.0),
+            "step" => EvalValue::Boolean(true),
+            "setpause" => EvalValue::Integer(std::mem::replace(
+                &mut state.pause,
+                opt_integer(&args, 2, "collectgarbage", 0)?,
+            )),
+            "setstepmul" => EvalValue::Integer(std::mem::replace(
+                &mut state.step_multiplier,
+                opt_integer(&args, 2, "collectgarbage", 0)?,
+            )),
+            "isrunning" => EvalValue::Boolean(state.running),
+            mode @ ("generational" | "incremental") => {
+                let previous = if state.generational {
+                    "generational"
+                } else {
+                    "incremental"
+                };
+                state.generational = mode == "generational";
+                EvalValue::string(previous)
+            }
+            _ => EvalValue::Integer(0),
+        };
+
+        Ok(vec![result])
+    })
+}
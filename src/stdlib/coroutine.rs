@@ -0,0 +1,140 @@
+use crate::{
+    ast::EvalValue,
+    coroutine::{self, Coroutine, CoroutineStatus, ThreadRef},
+    error::LuaError,
+    vm::VirtualMachine,
+};
+
+use super::{create_library, type_error};
+
+pub fn open(_virtual_machine: &mut VirtualMachine) -> EvalValue {
+    create_library(&[
+        ("create", create),
+        ("resume", resume),
+        ("yield", coroutine_yield),
+        ("status", status),
+        ("wrap", wrap),
+        ("isyieldable", isyieldable),
+        ("running", running),
+        ("close", close),
+    ])
+}
+
+fn check_thread(args: &[EvalValue], function_name: &str) -> Result<ThreadRef, LuaError> {
+    match args.first() {
+        Some(EvalValue::Thread(thread)) => Ok(thread.clone()),
+        other => Err(type_error(1, function_name, "coroutine", other)),
+    }
+}
+
+fn create_thread(
+    virtual_machine: &VirtualMachine,
+    args: Vec<EvalValue>,
+    function_name: &str,
+) -> Result<ThreadRef, LuaError> {
+    match args.into_iter().next() {
+        Some(function @ (EvalValue::DeclaredFunction(_) | EvalValue::NativeFunction(_))) => {
+            Ok(Coroutine::new(virtual_machine, function))
+        }
+        other => Err(type_error(1, function_name, "function", other.as_ref())),
+    }
+}
+
+fn create(
+    virtual_machine: &mut VirtualMachine,
+    args: Vec<EvalValue>,
+) -> Result<Vec<EvalValue>, LuaError> {
+    Ok(vec![EvalValue::Thread(create_thread(
+        virtual_machine,
+        args,
+        "create",
+    )?)])
+}
+
+fn resume(
+    virtual_machine: &mut VirtualMachine,
+    args: Vec<EvalValue>,
+) -> Result<Vec<EvalValue>, LuaError> {
+    let thread = check_thread(&args, "resume")?;
+
+    match coroutine::resume(virtual_machine, &thread, args.into_iter().skip(1).collect()) {
+        Ok(values) => Ok(std::iter::once(EvalValue::Boolean(true))
+            .chain(values)
+            .collect()),
+        Err(err) => Ok(vec![EvalValue::Boolean(false), err.into_value()]),
+    }
+}
+
+fn coroutine_yield(
+    virtual_machine: &mut VirtualMachine,
+    args: Vec<EvalValue>,
+) -> Result<Vec<EvalValue>, LuaError> {
+    coroutine::yield_values(virtual_machine, args)
+}
+
+fn status(_: &mut VirtualMachine, args: Vec<EvalValue>) -> Result<Vec<EvalValue>, LuaError> {
+    let thread = check_thread(&args, "status")?;
+    let status = thread.borrow().status();
+
+    Ok(vec![EvalValue::string(status.name())])
+}
+
+fn wrap(
+    virtual_machine: &mut VirtualMachine,
+    args: Vec<EvalValue>,
+) -> Result<Vec<EvalValue>, LuaError> {
+    let thread = create_thread(virtual_machine, args, "wrap")?;
+
+    Ok(vec![EvalValue::native_function(
+        move |virtual_machine, args| {
+            // String errors get the position of the caller prepended, like other errors
+            // raised from native functions
+            coroutine::resume(virtual_machine, &thread, args).map_err(|err| {
+                match err.details().map(|details| &details.value) {
+                    Some(EvalValue::String(_)) => LuaError::runtime(
+                        err.into_value()
+                            .to_lua_string()
+                            .expect("Error object is a string")
+                            .to_string(),
+                    ),
+                    _ => err,
+                }
+            })
+        },
+    )])
+}
+
+fn isyieldable(
+    virtual_machine: &mut VirtualMachine,
+    args: Vec<EvalValue>,
+) -> Result<Vec<EvalValue>, LuaError> {
+    let is_yieldable = match args.first() {
+        Some(EvalValue::Thread(thread)) => !thread.borrow().is_main(),
+        _ => !virtual_machine.is_main_thread(),
+    };
+
+    Ok(vec![EvalValue::Boolean(is_yieldable)])
+}
+
+fn running(
+    virtual_machine: &mut VirtualMachine,
+    _: Vec<EvalValue>,
+) -> Result<Vec<EvalValue>, LuaError> {
+    Ok(vec![
+        EvalValue::Thread(virtual_machine.current_thread()),
+        EvalValue::Boolean(virtual_machine.is_main_thread()),
+    ])
+}
+
+fn close(_: &mut VirtualMachine, args: Vec<EvalValue>) -> Result<Vec<EvalValue>, LuaError> {
+    let thread = check_thread(&args, "close")?;
+    let status = thread.borrow().status();
+
+    match status {
+        CoroutineStatus::Suspended | CoroutineStatus::Dead => match coroutine::close(&thread) {
+            None => Ok(vec![EvalValue::Boolean(true)]),
+            Some(err) => Ok(vec![EvalValue::Boolean(false), err.into_value()]),
+        },
+        status => Err(format!("cannot close a {} coroutine", status.name()).into()),
+    }
+}
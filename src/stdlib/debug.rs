@@ -0,0 +1,520 @@
+use std::{cell::RefCell, collections::BTreeSet, rc::Rc};
+
+use crate::{
+    ast::{EvalValue, FunctionDefinition, Statement, Table, Variable},
+    coroutine::ThreadRef,
+    error::{LuaError, TracebackEntry},
+    vm::{Activation, VirtualMachine, HOOK_CALL, HOOK_COUNT, HOOK_LINE, HOOK_RETURN},
+};
+
+use super::{
+    argument_error, check_integer, check_string, create_library, missing_argument, opt_integer,
+    type_error,
+};
+
+/// Levels a traceback shows before and after the levels it skips on deep stacks.
+const TRACEBACK_HEAD_LEVELS: usize = 10;
+const TRACEBACK_TAIL_LEVELS: usize = 11;
+
+/// Options of `getinfo` and the fields they select.
+const INFO_OPTIONS: &str = "SlunrtLf";
+
+pub fn open(_virtual_machine: &mut VirtualMachine) -> EvalValue {
+    create_library(&[
+        ("gethook", gethook),
+        ("getinfo", getinfo),
+        ("getlocal", getlocal),
+        ("getmetatable", getmetatable),
+        ("getupvalue", getupvalue),
+        ("sethook", sethook),
+        ("setlocal", setlocal),
+        ("setmetatable", setmetatable),
+        ("setupvalue", setupvalue),
+        ("traceback", traceback),
+        ("upvalueid", upvalueid),
+    ])
+}
+
+/// The thread most debug functions optionally take as their first argument, and the
+/// position of the argument after it.
+fn thread_argument(args: &[EvalValue]) -> (Option<ThreadRef>, usize) {
+    match args.first() {
+        Some(EvalValue::Thread(thread)) => (Some(thread.clone()), 2),
+        _ => (None, 1),
+    }
+}
+
+/// Calls `f` with the function at `level` of the call stack of `thread`, or of the
+/// running thread if it is `None`.
+fn with_activation<R>(
+    virtual_machine: &VirtualMachine,
+    thread: Option<&ThreadRef>,
+    level: i64,
+    f: impl FnOnce(Option<Activation<'_>>) -> R,
+) -> R {
+    let Ok(level) = usize::try_from(level) else {
+        return f(None);
+    };
+
+    match thread {
+        Some(thread) if !Rc::ptr_eq(thread, &virtual_machine.current_thread()) => {
+            f(thread.borrow().stack.activation(level))
+        }
+        _ => f(virtual_machine.activation(level)),
+    }
+}
+
+fn is_function(value: Option<&EvalValue>) -> bool {
+    matches!(
+        value,
+        Some(EvalValue::NativeFunction(_) | EvalValue::DeclaredFunction(_))
+    )
+}
+
+fn set_field(table: &mut Table, name: &str, value: EvalValue) {
+    table
+        .set(EvalValue::string(name), value)
+        .expect("Field names are valid table keys");
+}
+
+fn traceback(
+    virtual_machine: &mut VirtualMachine,
+    args: Vec<EvalValue>,
+) -> Result<Vec<EvalValue>, LuaError> {
+    let (thread, arg) = thread_argument(&args);
+    let message = match args.get(arg - 1) {
+        None | Some(EvalValue::Nil) => None,
+        Some(message) => match message.to_lua_string() {
+            Some(message) => Some(message),
+            None => return Ok(vec![message.clone()]),
+        },
+    };
+
+    let is_running = thread
+        .as_ref()
+        .is_none_or(|thread| Rc::ptr_eq(thread, &virtual_machine.current_thread()));
+    let level = opt_integer(&args, arg + 1, "traceback", if is_running { 1 } else { 0 })?;
+    let entries = match &thread {
+        Some(thread) if !is_running => thread.borrow().stack.traceback(),
+        _ => virtual_machine.traceback(),
+    };
+
+    let mut output = Vec::new();
+    if let Some(message) = message {
+        output.extend_from_slice(&message);
+        output.push(b'\n');
+    }
+    output.extend_from_slice(b"stack traceback:");
+
+    let entries = entries
+        .get(usize::try_from(level).unwrap_or(0)..)
+        .unwrap_or_default();
+    let mut lines: Vec<String> = entries.iter().map(TracebackEntry::to_string).collect();
+    if lines.len() > TRACEBACK_HEAD_LEVELS + TRACEBACK_TAIL_LEVELS + 1 {
+        let skipped = lines.len() - TRACEBACK_HEAD_LEVELS - TRACEBACK_TAIL_LEVELS;
+        lines.splice(
+            TRACEBACK_HEAD_LEVELS..lines.len() - TRACEBACK_TAIL_LEVELS,
+            [format!("...\t(skipping {} levels)", skipped)],
+        );
+    }
+    for line in lines {
+        output.extend_from_slice(format!("\n\t{}", line).as_bytes());
+    }
+
+    Ok(vec![EvalValue::string(output)])
+}
+
+fn getinfo(
+    virtual_machine: &mut VirtualMachine,
+    args: Vec<EvalValue>,
+) -> Result<Vec<EvalValue>, LuaError> {
+    let (thread, arg) = thread_argument(&args);
+    let options = match args.get(arg) {
+        None | Some(EvalValue::Nil) => "flnSrtu".into(),
+        Some(_) => check_string(&args, arg + 1, "getinfo")?,
+    };
+    if options.first() == Some(&b'>') {
+        return Err(argument_error(arg + 1, "getinfo", "invalid option '>'"));
+    }
+    if !options
+        .iter()
+        .all(|option| INFO_OPTIONS.as_bytes().contains(option))
+    {
+        return Err(argument_error(arg + 1, "getinfo", "invalid option"));
+    }
+
+    let info = if is_function(args.get(arg - 1)) {
+        Some(function_info(&args[arg - 1], None, &options))
+    } else {
+        let level = check_integer(&args, arg, "getinfo")?;
+        with_activation(virtual_machine, thread.as_ref(), level, |activation| {
+            activation.map(|activation| {
+                function_info(&activation.frame.function, Some(&activation), &options)
+            })
+        })
+    };
+
+    Ok(vec![info.map_or(EvalValue::Nil, |info| {
+        EvalValue::Table(Rc::new(RefCell::new(info)))
+    })])
+}
+
+/// The table `getinfo` returns for `function`, running as `activation` if it is on the
+/// call stack, with the fields selected by `options`.
+fn function_info(
+    function: &EvalValue,
+    activation: Option<&Activation<'_>>,
+    options: &[u8],
+) -> Table {
+    let definition = match function {
+        EvalValue::DeclaredFunction(function) => Some(&function.definition),
+        _ => None,
+    };
+    let mut info = Table::default();
+
+    for option in options {
+        match option {
+            b'S' => {
+                let (source, short_source, line_defined, last_line_defined, what) = match definition
+                {
+                    Some(definition) => (
+                        EvalValue::string(&*definition.source),
+                        EvalValue::string(&*definition.chunk_name),
+                        EvalValue::Integer(definition.line_defined as i64),
+                        EvalValue::Integer(definition.last_line_defined as i64),
+                        if definition.line_defined == 0 {
+                            "main"
+                        } else {
+                            "Lua"
+                        },
+                    ),
+                    None => (
+                        EvalValue::string("=[C]"),
+                        EvalValue::string("[C]"),
+                        EvalValue::Integer(-1),
+                        EvalValue::Integer(-1),
+                        "C",
+                    ),
+                };
+                set_field(&mut info, "source", source);
+                set_field(&mut info, "short_src", short_source);
+                set_field(&mut info, "linedefined", line_defined);
+                set_field(&mut info, "lastlinedefined", last_line_defined);
+                set_field(&mut info, "what", EvalValue::string(what));
+            }
+            b'l' => {
+                let line = activation
+                    .and_then(|activation| activation.frame.location())
+                    .map_or(-1, |location| location.line as i64);
+                set_field(&mut info, "currentline", EvalValue::Integer(line));
+            }
+            b'u' => {
+                let (upvalues, parameters, is_variadic) = match definition {
+                    Some(definition) => (
+                        definition.upvalues.len(),
+                        definition.arguments.len(),
+                        definition.is_variadic,
+                    ),
+                    None => (0, 0, true),
+                };
+                set_field(&mut info, "nups", EvalValue::Integer(upvalues as i64));
+                set_field(&mut info, "nparams", EvalValue::Integer(parameters as i64));
+                set_field(&mut info, "isvararg", EvalValue::Boolean(is_variadic));
+            }
+            b'n' => {
+                let name = activation.and_then(|activation| activation.frame.name.as_ref());
+                let (name, kind) = match name {
+                    Some(name) => (EvalValue::string(name.name.as_str()), name.kind),
+                    None => (EvalValue::Nil, ""),
+                };
+                set_field(&mut info, "name", name);
+                set_field(&mut info, "namewhat", EvalValue::string(kind));
+            }
+            b'r' => {
+                set_field(&mut info, "ftransfer", EvalValue::Integer(0));
+                set_field(&mut info, "ntransfer", EvalValue::Integer(0));
+            }
+            b't' => set_field(&mut info, "istailcall", EvalValue::Boolean(false)),
+            b'L' => {
+                if let Some(definition) = definition {
+                    let mut lines = Table::default();
+                    for line in active_lines(definition) {
+                        lines
+                            .set(EvalValue::Integer(line as i64), EvalValue::Boolean(true))
+                            .expect("Integers are valid table keys");
+                    }
+                    set_field(
+                        &mut info,
+                        "activelines",
+                        EvalValue::Table(Rc::new(RefCell::new(lines))),
+                    );
+                }
+            }
+            b'f' => set_field(&mut info, "func", function.clone()),
+            _ => unreachable!("Options were checked"),
+        }
+    }
+
+    info
+}
+
+/// The lines of a function that have statements, and the line of its `end`.
+fn active_lines(definition: &FunctionDefinition) -> BTreeSet<usize> {
+    fn add_lines(block: &[Statement], lines: &mut BTreeSet<usize>) {
+        for statement in block {
+            lines.insert(statement.span().start.line);
+
+            match statement {
+                Statement::WhileLoop { code_block, .. }
+                | Statement::ForLoop { code_block, .. }
+                | Statement::GenericForLoop { code_block, .. }
+                | Statement::RepeatUntilLoop { code_block, .. }
+                | Statement::DoBlock(code_block, _) => add_lines(code_block, lines),
+                Statement::IfStatement {
+                    code_block,
+                    elseif_statements,
+                    else_block,
+                    ..
+                } => {
+                    add_lines(code_block, lines);
+                    for (_, block) in elseif_statements {
+                        add_lines(block, lines);
+                    }
+                    if let Some(block) = else_block {
+                        add_lines(block, lines);
+                    }
+                }
+                _ => {}
+            }
+        }
+    }
+
+    let mut lines = BTreeSet::new();
+    add_lines(&definition.body, &mut lines);
+    if definition.last_line_defined > 0 {
+        lines.insert(definition.last_line_defined);
+    }
+
+    lines
+}
+
+/// The local variable `n` of `activation`, counting from 1 in declaration order, or
+/// vararg `-n` if `n` is negative.
+fn local_variable(activation: &Activation<'_>, n: i64) -> Option<(String, Variable)> {
+    if n < 0 {
+        let value = activation.varargs.get(usize::try_from(-n - 1).ok()?)?;
+        return Some(("(vararg)".to_string(), Rc::new(RefCell::new(value.clone()))));
+    }
+
+    activation
+        .locals()
+        .nth(usize::try_from(n - 1).ok()?)
+        .cloned()
+}
+
+fn getlocal(
+    virtual_machine: &mut VirtualMachine,
+    args: Vec<EvalValue>,
+) -> Result<Vec<EvalValue>, LuaError> {
+    let (thread, arg) = thread_argument(&args);
+    let n = check_integer(&args, arg + 1, "getlocal")?;
+
+    // Functions that are not running only have their parameters
+    if is_function(args.get(arg - 1)) {
+        let name = match &args[arg - 1] {
+            EvalValue::DeclaredFunction(function) => usize::try_from(n - 1)
+                .ok()
+                .and_then(|index| function.definition.arguments.get(index)),
+            _ => None,
+        };
+        return Ok(vec![
+            name.map_or(EvalValue::Nil, |name| EvalValue::string(name.as_str()))
+        ]);
+    }
+
+    let level = check_integer(&args, arg, "getlocal")?;
+    let local = with_activation(virtual_machine, thread.as_ref(), level, |activation| {
+        activation.map(|activation| local_variable(&activation, n))
+    })
+    .ok_or_else(|| argument_error(arg, "getlocal", "level out of range"))?;
+
+    Ok(match local {
+        Some((name, variable)) => vec![EvalValue::string(name), variable.borrow().clone()],
+        None => vec![EvalValue::Nil],
+    })
+}
+
+/// Varargs cannot be changed, only local variables.
+fn setlocal(
+    virtual_machine: &mut VirtualMachine,
+    args: Vec<EvalValue>,
+) -> Result<Vec<EvalValue>, LuaError> {
+    let (thread, arg) = thread_argument(&args);
+    let level = check_integer(&args, arg, "setlocal")?;
+    let n = check_integer(&args, arg + 1, "setlocal")?;
+
+    let local = with_activation(virtual_machine, thread.as_ref(), level, |activation| {
+        activation.map(|activation| local_variable(&activation, n.max(0)))
+    })
+    .ok_or_else(|| argument_error(arg, "setlocal", "level out of range"))?;
+    let value = args
+        .get(arg + 1)
+        .cloned()
+        .ok_or_else(|| missing_argument(arg + 2, "setlocal"))?;
+
+    Ok(match local {
+        Some((name, variable)) => {
+            *variable.borrow_mut() = value;
+            vec![EvalValue::string(name)]
+        }
+        None => vec![EvalValue::Nil],
+    })
+}
+
+/// The upvalue at argument 2 of the function at argument 1, with its name. Native
+/// functions have no upvalues.
+fn check_upvalue(
+    args: &[EvalValue],
+    function_name: &str,
+) -> Result<Option<(String, Variable)>, LuaError> {
+    let n = check_integer(args, 2, function_name)?;
+    let function = match args.first() {
+        Some(EvalValue::DeclaredFunction(function)) => function,
+        Some(EvalValue::NativeFunction(_)) => return Ok(None),
+        other => return Err(type_error(1, function_name, "function", other)),
+    };
+
+    let name = usize::try_from(n - 1)
+        .ok()
+        .and_then(|index| function.definition.upvalues.get(index));
+    Ok(name.and_then(|name| {
+        let variable = function.upvalue(name)?;
+        Some((name.clone(), variable))
+    }))
+}
+
+fn getupvalue(_: &mut VirtualMachine, args: Vec<EvalValue>) -> Result<Vec<EvalValue>, LuaError> {
+    Ok(match check_upvalue(&args, "getupvalue")? {
+        Some((name, variable)) => vec![EvalValue::string(name), variable.borrow().clone()],
+        None => Vec::new(),
+    })
+}
+
+fn setupvalue(_: &mut VirtualMachine, args: Vec<EvalValue>) -> Result<Vec<EvalValue>, LuaError> {
+    let value = args
+        .get(2)
+        .cloned()
+        .ok_or_else(|| missing_argument(3, "setupvalue"))?;
+
+    Ok(match check_upvalue(&args, "setupvalue")? {
+        Some((name, variable)) => {
+            *variable.borrow_mut() = value;
+            vec![EvalValue::string(name)]
+        }
+        None => Vec::new(),
+    })
+}
+
+/// Identifies the variable an upvalue refers to. There is no light userdata, so the
+/// identifier is the address of the variable as an integer.
+fn upvalueid(_: &mut VirtualMachine, args: Vec<EvalValue>) -> Result<Vec<EvalValue>, LuaError> {
+    Ok(vec![match check_upvalue(&args, "upvalueid")? {
+        Some((_, variable)) => EvalValue::Integer(Rc::as_ptr(&variable) as i64),
+        None => EvalValue::Nil,
+    }])
+}
+
+fn sethook(
+    virtual_machine: &mut VirtualMachine,
+    args: Vec<EvalValue>,
+) -> Result<Vec<EvalValue>, LuaError> {
+    let (thread, arg) = thread_argument(&args);
+    let thread = thread.unwrap_or_else(|| virtual_machine.current_thread());
+
+    let (function, mask, count) = match args.get(arg - 1) {
+        None | Some(EvalValue::Nil) => (EvalValue::Nil, 0, 0),
+        Some(_) => {
+            let events = check_string(&args, arg + 1, "sethook")?;
+            if !is_function(args.get(arg - 1)) {
+                return Err(type_error(arg, "sethook", "function", args.get(arg - 1)));
+            }
+            let count = usize::try_from(opt_integer(&args, arg + 2, "sethook", 0)?).unwrap_or(0);
+
+            let mut mask = 0;
+            for (event, event_mask) in [(b'c', HOOK_CALL), (b'r', HOOK_RETURN), (b'l', HOOK_LINE)] {
+                if events.contains(&event) {
+                    mask |= event_mask;
+                }
+            }
+            if count > 0 {
+                mask |= HOOK_COUNT;
+            }
+            (args[arg - 1].clone(), mask, count)
+        }
+    };
+
+    let hook = thread.borrow().hook.clone();
+    hook.set(function, mask, count);
+    Ok(Vec::new())
+}
+
+fn gethook(
+    virtual_machine: &mut VirtualMachine,
+    args: Vec<EvalValue>,
+) -> Result<Vec<EvalValue>, LuaError> {
+    let (thread, _) = thread_argument(&args);
+    let thread = thread.unwrap_or_else(|| virtual_machine.current_thread());
+
+    let hook = thread.borrow().hook.clone();
+    let (function, mask, count) = hook.get();
+    if mask == 0 {
+        return Ok(vec![EvalValue::Nil]);
+    }
+
+    let events: String = [('c', HOOK_CALL), ('r', HOOK_RETURN), ('l', HOOK_LINE)]
+        .into_iter()
+        .filter(|(_, event_mask)| mask & event_mask != 0)
+        .map(|(event, _)| event)
+        .collect();
+    Ok(vec![
+        function,
+        EvalValue::string(events),
+        EvalValue::Integer(count as i64),
+    ])
+}
+
+/// The metatable of a value, ignoring `__metatable`.
+fn getmetatable(
+    virtual_machine: &mut VirtualMachine,
+    args: Vec<EvalValue>,
+) -> Result<Vec<EvalValue>, LuaError> {
+    let value = args
+        .first()
+        .ok_or_else(|| missing_argument(1, "getmetatable"))?;
+
+    Ok(vec![virtual_machine
+        .metatable(value)
+        .map_or(EvalValue::Nil, EvalValue::Table)])
+}
+
+/// Sets the metatable of a value of any type, even a protected one. Values other than
+/// tables and userdata share the metatable of their type.
+fn setmetatable(
+    virtual_machine: &mut VirtualMachine,
+    args: Vec<EvalValue>,
+) -> Result<Vec<EvalValue>, LuaError> {
+    let metatable = match args.get(1) {
+        Some(EvalValue::Table(metatable)) => Some(metatable.clone()),
+        Some(EvalValue::Nil) => None,
+        _ => return Err(argument_error(2, "setmetatable", "nil or table expected")),
+    };
+
+    let value = args[0].clone();
+    match &value {
+        EvalValue::Table(table) => table.borrow_mut().set_metatable(metatable),
+        EvalValue::UserData(data) => data.set_metatable(metatable),
+        value => virtual_machine.set_type_metatable(value.type_name(), metatable),
+    }
+
+    Ok(vec![value])
+}
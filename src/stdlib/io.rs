@@ -0,0 +1,1044 @@
+use std::{
+    cell::{RefCell, RefMut},
+    collections::hash_map::RandomState,
+    fs::{self, File, OpenOptions},
+    hash::{BuildHasher, Hasher},
+    io::{self, Read, Seek, SeekFrom, Write},
+    iter,
+    path::PathBuf,
+    process::{Child, Command, ExitStatus, Stdio},
+    rc::Rc,
+};
+
+use crate::{
+    ast::{
+        format_general, string_to_number, EvalValue, LuaString, TableRef, UserData, UserDataRef,
+    },
+    error::LuaError,
+    vm::VirtualMachine,
+};
+
+use super::{
+    argument_error, check_integer, check_option, check_string, create_library, missing_argument,
+    opt_integer, type_error,
+};
+
+/// Default size of the buffer `setvbuf` gives a file, the same as Lua's.
+const BUFFER_SIZE: usize = 1024;
+/// How many bytes are read from a stream at a time.
+const READ_CHUNK_SIZE: usize = 8192;
+/// Longest numeral `read("n")` accepts.
+const MAX_NUMERAL_LENGTH: usize = 200;
+/// Most formats `lines` can be given.
+const MAX_LINES_FORMATS: usize = 250;
+
+const BAD_FILE_DESCRIPTOR: i32 = 9;
+const INVALID_ARGUMENT: i32 = 22;
+const ILLEGAL_SEEK: i32 = 29;
+
+pub fn open(_virtual_machine: &mut VirtualMachine) -> EvalValue {
+    let methods = create_library(&[
+        ("close", file_close),
+        ("flush", file_flush),
+        ("lines", file_lines),
+        ("read", file_read),
+        ("seek", file_seek),
+        ("setvbuf", file_setvbuf),
+        ("write", file_write),
+    ]);
+    let metatable = create_library(&[
+        ("__close", file_gc),
+        ("__gc", file_gc),
+        ("__tostring", file_tostring),
+    ]);
+    let EvalValue::Table(metatable) = metatable else {
+        unreachable!("Libraries are tables")
+    };
+    {
+        let mut metatable = metatable.borrow_mut();
+        let fields = [("__index", methods), ("__name", EvalValue::string("FILE*"))];
+        for (name, value) in fields {
+            metatable
+                .set(EvalValue::string(name), value)
+                .expect("Metatable field names are valid table keys");
+        }
+    }
+
+    let stdin = new_file(&metatable, Stream::Stdin);
+    let stdout = new_file(&metatable, Stream::Stdout);
+    let stderr = new_file(&metatable, Stream::Stderr);
+    let io = Rc::new(IoLibrary {
+        metatable,
+        input: RefCell::new(stdin.clone()),
+        output: RefCell::new(stdout.clone()),
+    });
+
+    let library = create_library(&[("type", io_type)]);
+    if let EvalValue::Table(table) = &library {
+        let mut table = table.borrow_mut();
+        let fields = [
+            ("close", bind(&io, io_close)),
+            ("flush", bind(&io, io_flush)),
+            ("input", bind(&io, io_input)),
+            ("lines", bind(&io, io_lines)),
+            ("open", bind(&io, io_open)),
+            ("output", bind(&io, io_output)),
+            ("popen", bind(&io, io_popen)),
+            ("read", bind(&io, io_read)),
+            ("tmpfile", bind(&io, io_tmpfile)),
+            ("write", bind(&io, io_write)),
+            ("stdin", stdin),
+            ("stdout", stdout),
+            ("stderr", stderr),
+        ];
+        for (name, value) in fields {
+            table
+                .set(EvalValue::string(name), value)
+                .expect("Library field names are valid table keys");
+        }
+    }
+
+    library
+}
+
+/// The operating system object behind a file handle.
+enum Stream {
+    Stdin,
+    Stdout,
+    Stderr,
+    File(File),
+    /// A program started by `popen`, read from or written to through its standard
+    /// output or input.
+    Process(Child),
+}
+
+impl Stream {
+    fn read(&mut self, buffer: &mut [u8]) -> io::Result<usize> {
+        match self {
+            Stream::Stdin => io::stdin().read(buffer),
+            Stream::File(file) => file.read(buffer),
+            Stream::Process(Child {
+                stdout: Some(stdout),
+                ..
+            }) => stdout.read(buffer),
+            _ => Err(io::Error::from_raw_os_error(BAD_FILE_DESCRIPTOR)),
+        }
+    }
+
+    fn write_all(&mut self, bytes: &[u8]) -> io::Result<()> {
+        match self {
+            Stream::Stdout => io::stdout().write_all(bytes),
+            Stream::Stderr => io::stderr().write_all(bytes),
+            Stream::File(file) => file.write_all(bytes),
+            Stream::Process(Child {
+                stdin: Some(stdin), ..
+            }) => stdin.write_all(bytes),
+            _ => Err(io::Error::from_raw_os_error(BAD_FILE_DESCRIPTOR)),
+        }
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        match self {
+            Stream::Stdout => io::stdout().flush(),
+            Stream::Stderr => io::stderr().flush(),
+            Stream::File(file) => file.flush(),
+            Stream::Process(Child {
+                stdin: Some(stdin), ..
+            }) => stdin.flush(),
+            _ => Ok(()),
+        }
+    }
+
+    fn seek(&mut self, position: SeekFrom) -> io::Result<u64> {
+        match self {
+            Stream::File(file) => file.seek(position),
+            _ => Err(io::Error::from_raw_os_error(ILLEGAL_SEEK)),
+        }
+    }
+}
+
+#[derive(Clone, Copy)]
+enum Buffering {
+    No,
+    Full,
+    Line,
+}
+
+/// An open or closed file handle. Reads go through a buffer so that `read("n")` can
+/// look ahead, while writes reach the stream right away unless `setvbuf` asks for
+/// buffering.
+struct LuaFile {
+    /// `None` once the file is closed.
+    stream: Option<Stream>,
+    read_buffer: Vec<u8>,
+    /// How much of `read_buffer` was already consumed.
+    read_position: usize,
+    write_buffer: Vec<u8>,
+    buffering: Buffering,
+    buffer_size: usize,
+}
+
+impl LuaFile {
+    fn new(stream: Stream) -> Self {
+        LuaFile {
+            stream: Some(stream),
+            read_buffer: Vec::new(),
+            read_position: 0,
+            write_buffer: Vec::new(),
+            buffering: Buffering::No,
+            buffer_size: BUFFER_SIZE,
+        }
+    }
+
+    fn is_closed(&self) -> bool {
+        self.stream.is_none()
+    }
+
+    fn is_standard(&self) -> bool {
+        matches!(
+            self.stream,
+            Some(Stream::Stdin | Stream::Stdout | Stream::Stderr)
+        )
+    }
+
+    fn stream(&mut self) -> &mut Stream {
+        self.stream.as_mut().expect("File is open")
+    }
+
+    /// Makes sure there are unread bytes in the read buffer, reading more from the
+    /// stream if needed. Returns false at the end of the stream.
+    fn fill_buffer(&mut self) -> io::Result<bool> {
+        if self.read_position < self.read_buffer.len() {
+            return Ok(true);
+        }
+        self.flush()?;
+        if let Some(Stream::Stdin) = self.stream {
+            // Prompts written before reading are shown, like C does for terminals
+            let _ = io::stdout().flush();
+        }
+
+        let mut buffer = std::mem::take(&mut self.read_buffer);
+        buffer.resize(READ_CHUNK_SIZE, 0);
+        let count = loop {
+            match self.stream().read(&mut buffer) {
+                Err(err) if err.kind() == io::ErrorKind::Interrupted => continue,
+                result => break result,
+            }
+        };
+        buffer.truncate(*count.as_ref().unwrap_or(&0));
+        self.read_buffer = buffer;
+        self.read_position = 0;
+        Ok(count? > 0)
+    }
+
+    fn peek(&mut self) -> io::Result<Option<u8>> {
+        Ok(if self.fill_buffer()? {
+            Some(self.read_buffer[self.read_position])
+        } else {
+            None
+        })
+    }
+
+    /// Reads a line, with or without its newline. Returns `None` at the end of the
+    /// stream.
+    fn read_line(&mut self, keep_newline: bool) -> io::Result<Option<Vec<u8>>> {
+        let mut line = Vec::new();
+
+        while self.fill_buffer()? {
+            let available = &self.read_buffer[self.read_position..];
+            match available.iter().position(|byte| *byte == b'\n') {
+                Some(end) => {
+                    line.extend_from_slice(&available[..end + keep_newline as usize]);
+                    self.read_position += end + 1;
+                    return Ok(Some(line));
+                }
+                None => {
+                    line.extend_from_slice(available);
+                    self.read_position = self.read_buffer.len();
+                }
+            }
+        }
+
+        Ok((!line.is_empty()).then_some(line))
+    }
+
+    /// Reads up to `count` bytes. Returns `None` if nothing could be read.
+    fn read_bytes(&mut self, count: usize) -> io::Result<Option<Vec<u8>>> {
+        let mut bytes = Vec::new();
+
+        while bytes.len() < count && self.fill_buffer()? {
+            let available = &self.read_buffer[self.read_position..];
+            let length = available.len().min(count - bytes.len());
+            bytes.extend_from_slice(&available[..length]);
+            self.read_position += length;
+        }
+
+        Ok((!bytes.is_empty()).then_some(bytes))
+    }
+
+    fn read_all(&mut self) -> io::Result<Vec<u8>> {
+        let mut bytes = Vec::new();
+
+        while self.fill_buffer()? {
+            let read = &self.read_buffer[self.read_position..];
+            bytes
+                .try_reserve(read.len())
+                .map_err(|_| io::Error::from(io::ErrorKind::OutOfMemory))?;
+            bytes.extend_from_slice(read);
+            self.read_position = self.read_buffer.len();
+        }
+
+        Ok(bytes)
+    }
+
+    /// Consumes the next byte if it is one of `set`, adding it to `numeral`.
+    fn accept(&mut self, numeral: &mut Vec<u8>, set: &[u8]) -> io::Result<bool> {
+        match self.peek()? {
+            Some(byte) if set.contains(&byte) && numeral.len() < MAX_NUMERAL_LENGTH => {
+                numeral.push(byte);
+                self.read_position += 1;
+                Ok(true)
+            }
+            _ => Ok(false),
+        }
+    }
+
+    fn accept_digits(&mut self, numeral: &mut Vec<u8>, hexadecimal: bool) -> io::Result<usize> {
+        let mut count = 0;
+        loop {
+            match self.peek()? {
+                Some(byte)
+                    if (hexadecimal && byte.is_ascii_hexdigit()) || byte.is_ascii_digit() =>
+                {
+                    if !self.accept(numeral, &[byte])? {
+                        return Ok(count);
+                    }
+                    count += 1;
+                }
+                _ => return Ok(count),
+            }
+        }
+    }
+
+    /// Reads the longest prefix that could start a numeral and converts it, like Lua's
+    /// `read("n")`. Returns `None` if the prefix is not a valid number.
+    fn read_number(&mut self) -> io::Result<Option<EvalValue>> {
+        while let Some(b' ' | b'\t'..=b'\r') = self.peek()? {
+            self.read_position += 1;
+        }
+
+        let mut numeral = Vec::new();
+        let mut count = 0;
+        let mut hexadecimal = false;
+        self.accept(&mut numeral, b"-+")?;
+        if self.accept(&mut numeral, b"0")? {
+            if self.accept(&mut numeral, b"xX")? {
+                hexadecimal = true;
+            } else {
+                count = 1;
+            }
+        }
+        count += self.accept_digits(&mut numeral, hexadecimal)?;
+        if self.accept(&mut numeral, b".")? {
+            count += self.accept_digits(&mut numeral, hexadecimal)?;
+        }
+        let exponent: &[u8] = if hexadecimal { b"pP" } else { b"eE" };
+        if count > 0 && self.accept(&mut numeral, exponent)? {
+            self.accept(&mut numeral, b"-+")?;
+            self.accept_digits(&mut numeral, false)?;
+        }
+
+        Ok(std::str::from_utf8(&numeral)
+            .ok()
+            .and_then(string_to_number))
+    }
+
+    /// Gives back bytes that were read ahead but not consumed, so that the stream is
+    /// positioned where the script expects it.
+    fn discard_read_ahead(&mut self) -> io::Result<()> {
+        let unread = self.read_buffer.len() - self.read_position;
+        if unread > 0 {
+            self.stream().seek(SeekFrom::Current(-(unread as i64)))?;
+        }
+        self.read_buffer.clear();
+        self.read_position = 0;
+        Ok(())
+    }
+
+    fn write(&mut self, bytes: &[u8]) -> io::Result<()> {
+        if let Some(Stream::File(_)) = self.stream {
+            self.discard_read_ahead()?;
+        }
+
+        match self.buffering {
+            Buffering::No => self.stream().write_all(bytes),
+            Buffering::Full => {
+                self.write_buffer.extend_from_slice(bytes);
+                if self.write_buffer.len() >= self.buffer_size {
+                    self.flush()?;
+                }
+                Ok(())
+            }
+            Buffering::Line => {
+                self.write_buffer.extend_from_slice(bytes);
+                if self.write_buffer.len() >= self.buffer_size || bytes.contains(&b'\n') {
+                    self.flush()?;
+                }
+                Ok(())
+            }
+        }
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        if !self.write_buffer.is_empty() {
+            let bytes = std::mem::take(&mut self.write_buffer);
+            self.stream().write_all(&bytes)?;
+        }
+        self.stream().flush()
+    }
+
+    fn seek(&mut self, position: SeekFrom) -> io::Result<u64> {
+        self.flush()?;
+
+        // The stream is ahead of the script by the unread bytes
+        let unread = self.read_buffer.len() - self.read_position;
+        let position = match position {
+            SeekFrom::Current(offset) => SeekFrom::Current(offset - unread as i64),
+            position => position,
+        };
+        let offset = self.stream().seek(position)?;
+        self.read_buffer.clear();
+        self.read_position = 0;
+        Ok(offset)
+    }
+
+    fn set_buffering(&mut self, buffering: Buffering, size: usize) -> io::Result<()> {
+        self.flush()?;
+        self.buffering = buffering;
+        self.buffer_size = size;
+        Ok(())
+    }
+
+    /// Closes the stream, returning the exit status for programs started by `popen`.
+    fn close(&mut self) -> io::Result<Option<ExitStatus>> {
+        let flushed = self.flush();
+
+        match self.stream.take() {
+            Some(Stream::Process(mut child)) => {
+                // Closing its input lets the program finish
+                drop(child.stdin.take());
+                let status = child.wait()?;
+                flushed.map(|_| Some(status))
+            }
+            _ => flushed.map(|_| None),
+        }
+    }
+}
+
+impl Drop for LuaFile {
+    fn drop(&mut self) {
+        if !self.is_closed() {
+            let _ = self.close();
+        }
+    }
+}
+
+/// The state shared by the functions of the library: the metatable of file handles and
+/// the default input and output files.
+struct IoLibrary {
+    metatable: TableRef,
+    input: RefCell<EvalValue>,
+    output: RefCell<EvalValue>,
+}
+
+type IoFunction =
+    fn(&IoLibrary, &mut VirtualMachine, Vec<EvalValue>) -> Result<Vec<EvalValue>, LuaError>;
+
+fn bind(io: &Rc<IoLibrary>, function: IoFunction) -> EvalValue {
+    let io = io.clone();
+    EvalValue::native_function(move |virtual_machine, args| function(&io, virtual_machine, args))
+}
+
+fn new_file(metatable: &TableRef, stream: Stream) -> EvalValue {
+    EvalValue::UserData(UserData::new(LuaFile::new(stream), Some(metatable.clone())))
+}
+
+impl IoLibrary {
+    fn open_file(&self, filename: &str, mode: &[u8]) -> io::Result<EvalValue> {
+        let mut options = OpenOptions::new();
+        let update = mode.get(1) == Some(&b'+');
+        match mode[0] {
+            b'r' => options.read(true).write(update),
+            b'w' => options.write(true).create(true).truncate(true).read(update),
+            _ => options.append(true).create(true).read(update),
+        };
+
+        Ok(new_file(
+            &self.metatable,
+            Stream::File(options.open(filename)?),
+        ))
+    }
+
+    /// Like `open_file`, but a failure is an error.
+    fn open_checked(&self, filename: &LuaString, mode: &[u8]) -> Result<EvalValue, LuaError> {
+        let filename = filename.to_str_lossy();
+        self.open_file(&filename, mode).map_err(|err| {
+            LuaError::runtime(format!(
+                "cannot open file '{}' ({})",
+                filename,
+                error_message(&err)
+            ))
+        })
+    }
+
+    /// The default input or output file, which must still be open.
+    fn default_file(&self, output: bool) -> Result<(EvalValue, UserDataRef), LuaError> {
+        let (file, kind) = match output {
+            false => (self.input.borrow().clone(), "input"),
+            true => (self.output.borrow().clone(), "output"),
+        };
+        let handle = file_handle(Some(&file)).expect("Default files are file handles");
+        if lua_file(&handle).is_closed() {
+            return Err(LuaError::runtime(format!(
+                "default {} file is closed",
+                kind
+            )));
+        }
+        Ok((file, handle))
+    }
+}
+
+/// The message of an operating system error, without the error number Rust adds.
+pub(super) fn error_message(error: &io::Error) -> String {
+    let message = error.to_string();
+    match error.raw_os_error() {
+        Some(code) => message
+            .trim_end_matches(&format!(" (os error {})", code))
+            .to_string(),
+        None => message,
+    }
+}
+
+/// The values a failed operation returns: nil, a message that mentions `filename` if
+/// given, and the error number.
+pub(super) fn file_error(error: io::Error, filename: Option<&str>) -> Vec<EvalValue> {
+    let message = match filename {
+        Some(filename) => format!("{}: {}", filename, error_message(&error)),
+        None => error_message(&error),
+    };
+    vec![
+        EvalValue::Nil,
+        EvalValue::string(message),
+        EvalValue::Integer(error.raw_os_error().unwrap_or(0) as i64),
+    ]
+}
+
+/// The values describing how a program ended: whether it succeeded, `"exit"` or
+/// `"signal"`, and the exit status or signal number.
+pub(super) fn execute_result(status: ExitStatus) -> Vec<EvalValue> {
+    #[cfg(unix)]
+    let signal = std::os::unix::process::ExitStatusExt::signal(&status);
+    #[cfg(not(unix))]
+    let signal = None;
+
+    let (what, code) = match (status.code(), signal) {
+        (Some(code), _) => ("exit", code),
+        (None, Some(signal)) => ("signal", signal),
+        (None, None) => ("exit", -1),
+    };
+    vec![
+        if what == "exit" && code == 0 {
+            EvalValue::Boolean(true)
+        } else {
+            EvalValue::Nil
+        },
+        EvalValue::string(what),
+        EvalValue::Integer(code as i64),
+    ]
+}
+
+/// Creates a new file with a unique name in the temporary directory, like `mkstemp`.
+pub(super) fn temporary_file() -> io::Result<(PathBuf, File)> {
+    const CHARACTERS: &[u8] = b"abcdefghijklmnopqrstuvwxyzABCDEFGHIJKLMNOPQRSTUVWXYZ0123456789";
+
+    loop {
+        // Every `RandomState` has different keys, so the hash of nothing is random
+        let mut random = RandomState::new().build_hasher().finish();
+        let suffix: String = (0..6)
+            .map(|_| {
+                let character = CHARACTERS[(random % CHARACTERS.len() as u64) as usize];
+                random /= CHARACTERS.len() as u64;
+                character as char
+            })
+            .collect();
+        let path = std::env::temp_dir().join(format!("lua_{}", suffix));
+
+        let file = OpenOptions::new()
+            .read(true)
+            .write(true)
+            .create_new(true)
+            .open(&path);
+        match file {
+            Ok(file) => return Ok((path, file)),
+            Err(err) if err.kind() == io::ErrorKind::AlreadyExists => continue,
+            Err(err) => return Err(err),
+        }
+    }
+}
+
+/// A command that runs `command` with the system shell.
+pub(super) fn shell_command(command: &str) -> Command {
+    let (shell, flag) = if cfg!(windows) {
+        ("cmd", "/C")
+    } else {
+        ("/bin/sh", "-c")
+    };
+    let mut process = Command::new(shell);
+    process.arg(flag).arg(command);
+    process
+}
+
+fn file_handle(value: Option<&EvalValue>) -> Option<UserDataRef> {
+    match value {
+        Some(EvalValue::UserData(data)) if data.is::<LuaFile>() => Some(data.clone()),
+        _ => None,
+    }
+}
+
+fn lua_file(handle: &UserData) -> RefMut<'_, LuaFile> {
+    handle.borrow_mut::<LuaFile>().expect("Handle is a file")
+}
+
+/// Argument 1 as a file handle, which must still be open.
+fn check_file(args: &[EvalValue], function_name: &str) -> Result<UserDataRef, LuaError> {
+    let handle = file_handle(args.first())
+        .ok_or_else(|| type_error(1, function_name, "FILE*", args.first()))?;
+    if lua_file(&handle).is_closed() {
+        return Err(LuaError::runtime("attempt to use a closed file"));
+    }
+    Ok(handle)
+}
+
+/// Reads the values the formats starting at argument `first` ask for. Reading stops at
+/// the first format that fails, which produces nil.
+fn read_values(
+    file: &mut LuaFile,
+    args: &[EvalValue],
+    first: usize,
+    function_name: &str,
+) -> Result<Vec<EvalValue>, LuaError> {
+    let to_value = |bytes: Option<Vec<u8>>| bytes.map(EvalValue::string);
+
+    if args.len() < first {
+        return Ok(match file.read_line(false) {
+            Ok(line) => vec![to_value(line).unwrap_or(EvalValue::Nil)],
+            Err(err) => file_error(err, None),
+        });
+    }
+
+    let mut values = Vec::new();
+    for position in first..=args.len() {
+        let value = match &args[position - 1] {
+            EvalValue::Number(_) | EvalValue::Integer(_) => {
+                let count = check_integer(args, position, function_name)?;
+                if count == 0 {
+                    // Reading nothing succeeds unless at the end of the file
+                    file.fill_buffer()
+                        .map(|more| more.then(|| EvalValue::string("")))
+                } else {
+                    file.read_bytes(count.max(0) as usize).map(to_value)
+                }
+            }
+            _ => {
+                let format = check_string(args, position, function_name)?;
+                let format = format.strip_prefix(b"*").unwrap_or(&format);
+                match format.first() {
+                    Some(b'n') => file.read_number(),
+                    Some(b'l') => file.read_line(false).map(to_value),
+                    Some(b'L') => file.read_line(true).map(to_value),
+                    Some(b'a') => file.read_all().map(|bytes| Some(EvalValue::string(bytes))),
+                    _ => return Err(argument_error(position, function_name, "invalid format")),
+                }
+            }
+        };
+
+        match value {
+            Ok(Some(value)) => values.push(value),
+            Ok(None) => {
+                values.push(EvalValue::Nil);
+                break;
+            }
+            Err(err) if err.kind() == io::ErrorKind::OutOfMemory => return Err(LuaError::memory()),
+            Err(err) => return Ok(file_error(err, None)),
+        }
+    }
+
+    Ok(values)
+}
+
+/// Writes the strings and numbers starting at argument `first`, returning `file_value`
+/// on success.
+fn write_values(
+    file: &mut LuaFile,
+    args: &[EvalValue],
+    first: usize,
+    file_value: EvalValue,
+) -> Result<Vec<EvalValue>, LuaError> {
+    for position in first..=args.len() {
+        let result = match &args[position - 1] {
+            EvalValue::Integer(i) => file.write(i.to_string().as_bytes()),
+            EvalValue::Number(n) => file.write(format_general(*n).as_bytes()),
+            _ => file.write(&check_string(args, position, "write")?),
+        };
+        if let Err(err) = result {
+            return Ok(file_error(err, None));
+        }
+    }
+
+    Ok(vec![file_value])
+}
+
+/// Closes a file. The standard files stay open.
+fn close_file(handle: &UserData) -> Vec<EvalValue> {
+    let mut file = lua_file(handle);
+    if file.is_standard() {
+        return vec![
+            EvalValue::Nil,
+            EvalValue::string("cannot close standard file"),
+        ];
+    }
+
+    match file.close() {
+        Ok(None) => vec![EvalValue::Boolean(true)],
+        Ok(Some(status)) => execute_result(status),
+        Err(err) => file_error(err, None),
+    }
+}
+
+/// The iterator `lines` returns, reading with `formats` on every call and closing the
+/// file at its end if `close` is set.
+fn lines_iterator(
+    handle: UserDataRef,
+    formats: Vec<EvalValue>,
+    close: bool,
+) -> Result<EvalValue, LuaError> {
+    if formats.len() > MAX_LINES_FORMATS {
+        return Err(argument_error(
+            MAX_LINES_FORMATS + 2,
+            "lines",
+            "too many arguments",
+        ));
+    }
+    // The formats keep the argument positions they had in the call to `lines`
+    let args: Vec<EvalValue> = iter::once(EvalValue::Nil).chain(formats).collect();
+
+    Ok(EvalValue::native_function(move |_, _| {
+        let mut file = lua_file(&handle);
+        if file.is_closed() {
+            return Err(LuaError::runtime("file is already closed"));
+        }
+
+        let values = read_values(&mut file, &args, 2, "lines")?;
+        if values[0].is_true() {
+            return Ok(values);
+        }
+        if let Some(message) = values.get(1) {
+            let message = message.to_lua_string().unwrap_or_default();
+            return Err(LuaError::runtime(message.to_str_lossy()));
+        }
+        if close {
+            drop(file);
+            close_file(&handle);
+        }
+        Ok(Vec::new())
+    }))
+}
+
+fn file_close(_: &mut VirtualMachine, args: Vec<EvalValue>) -> Result<Vec<EvalValue>, LuaError> {
+    let handle = check_file(&args, "close")?;
+    Ok(close_file(&handle))
+}
+
+fn file_flush(_: &mut VirtualMachine, args: Vec<EvalValue>) -> Result<Vec<EvalValue>, LuaError> {
+    let handle = check_file(&args, "flush")?;
+    let result = lua_file(&handle).flush();
+    Ok(match result {
+        Ok(()) => vec![EvalValue::Boolean(true)],
+        Err(err) => file_error(err, None),
+    })
+}
+
+fn file_lines(_: &mut VirtualMachine, args: Vec<EvalValue>) -> Result<Vec<EvalValue>, LuaError> {
+    let handle = check_file(&args, "lines")?;
+    let formats = args.into_iter().skip(1).collect();
+    Ok(vec![lines_iterator(handle, formats, false)?])
+}
+
+fn file_read(_: &mut VirtualMachine, args: Vec<EvalValue>) -> Result<Vec<EvalValue>, LuaError> {
+    let handle = check_file(&args, "read")?;
+    let mut file = lua_file(&handle);
+    read_values(&mut file, &args, 2, "read")
+}
+
+fn file_seek(_: &mut VirtualMachine, args: Vec<EvalValue>) -> Result<Vec<EvalValue>, LuaError> {
+    let handle = check_file(&args, "seek")?;
+    let whence = check_option(&args, 2, "seek", Some("cur"), &["set", "cur", "end"])?;
+    let offset = opt_integer(&args, 3, "seek", 0)?;
+
+    let position = match whence {
+        0 if offset < 0 => {
+            return Ok(file_error(
+                io::Error::from_raw_os_error(INVALID_ARGUMENT),
+                None,
+            ))
+        }
+        0 => SeekFrom::Start(offset as u64),
+        1 => SeekFrom::Current(offset),
+        _ => SeekFrom::End(offset),
+    };
+    let result = lua_file(&handle).seek(position);
+    Ok(match result {
+        Ok(offset) => vec![EvalValue::Integer(offset as i64)],
+        Err(err) => file_error(err, None),
+    })
+}
+
+fn file_setvbuf(_: &mut VirtualMachine, args: Vec<EvalValue>) -> Result<Vec<EvalValue>, LuaError> {
+    let handle = check_file(&args, "setvbuf")?;
+    let mode = check_option(&args, 2, "setvbuf", None, &["no", "full", "line"])?;
+    let size = opt_integer(&args, 3, "setvbuf", BUFFER_SIZE as i64)?;
+
+    let buffering = [Buffering::No, Buffering::Full, Buffering::Line][mode];
+    let result = lua_file(&handle).set_buffering(buffering, size.max(1) as usize);
+    Ok(match result {
+        Ok(()) => vec![EvalValue::Boolean(true)],
+        Err(err) => file_error(err, None),
+    })
+}
+
+fn file_write(_: &mut VirtualMachine, args: Vec<EvalValue>) -> Result<Vec<EvalValue>, LuaError> {
+    let handle = check_file(&args, "write")?;
+    let mut file = lua_file(&handle);
+    write_values(&mut file, &args, 2, args[0].clone())
+}
+
+/// Closes a file that is no longer used, ignoring errors.
+fn file_gc(_: &mut VirtualMachine, args: Vec<EvalValue>) -> Result<Vec<EvalValue>, LuaError> {
+    if let Some(handle) = file_handle(args.first()) {
+        if !lua_file(&handle).is_closed() {
+            close_file(&handle);
+        }
+    }
+    Ok(Vec::new())
+}
+
+fn file_tostring(_: &mut VirtualMachine, args: Vec<EvalValue>) -> Result<Vec<EvalValue>, LuaError> {
+    let handle = file_handle(args.first())
+        .ok_or_else(|| type_error(1, "tostring", "FILE*", args.first()))?;
+    let text = if lua_file(&handle).is_closed() {
+        "file (closed)".to_string()
+    } else {
+        format!("file ({:p})", Rc::as_ptr(&handle))
+    };
+    Ok(vec![EvalValue::string(text)])
+}
+
+fn io_type(_: &mut VirtualMachine, args: Vec<EvalValue>) -> Result<Vec<EvalValue>, LuaError> {
+    let value = args.first().ok_or_else(|| missing_argument(1, "type"))?;
+    Ok(vec![match file_handle(Some(value)) {
+        Some(handle) if lua_file(&handle).is_closed() => EvalValue::string("closed file"),
+        Some(_) => EvalValue::string("file"),
+        None => EvalValue::Nil,
+    }])
+}
+
+fn io_close(
+    io: &IoLibrary,
+    _: &mut VirtualMachine,
+    args: Vec<EvalValue>,
+) -> Result<Vec<EvalValue>, LuaError> {
+    let handle = match args.first() {
+        None => io.default_file(true)?.1,
+        Some(_) => check_file(&args, "close")?,
+    };
+    Ok(close_file(&handle))
+}
+
+fn io_flush(
+    io: &IoLibrary,
+    _: &mut VirtualMachine,
+    _: Vec<EvalValue>,
+) -> Result<Vec<EvalValue>, LuaError> {
+    let (_, handle) = io.default_file(true)?;
+    let result = lua_file(&handle).flush();
+    Ok(match result {
+        Ok(()) => vec![EvalValue::Boolean(true)],
+        Err(err) => file_error(err, None),
+    })
+}
+
+/// `io.input` and `io.output`: sets the default file from a file name or handle, and
+/// returns the current one.
+fn set_default_file(
+    io: &IoLibrary,
+    args: &[EvalValue],
+    output: bool,
+) -> Result<Vec<EvalValue>, LuaError> {
+    let (default, mode, function_name): (_, &[u8], _) = match output {
+        false => (&io.input, b"r", "input"),
+        true => (&io.output, b"w", "output"),
+    };
+
+    match args.first() {
+        None | Some(EvalValue::Nil) => {}
+        Some(value) => {
+            let file = match value.to_lua_string() {
+                Some(filename) => io.open_checked(&filename, mode)?,
+                None => {
+                    check_file(args, function_name)?;
+                    value.clone()
+                }
+            };
+            *default.borrow_mut() = file;
+        }
+    }
+
+    Ok(vec![default.borrow().clone()])
+}
+
+fn io_input(
+    io: &IoLibrary,
+    _: &mut VirtualMachine,
+    args: Vec<EvalValue>,
+) -> Result<Vec<EvalValue>, LuaError> {
+    set_default_file(io, &args, false)
+}
+
+fn io_output(
+    io: &IoLibrary,
+    _: &mut VirtualMachine,
+    args: Vec<EvalValue>,
+) -> Result<Vec<EvalValue>, LuaError> {
+    set_default_file(io, &args, true)
+}
+
+fn io_lines(
+    io: &IoLibrary,
+    _: &mut VirtualMachine,
+    args: Vec<EvalValue>,
+) -> Result<Vec<EvalValue>, LuaError> {
+    let formats = args.iter().skip(1).cloned().collect();
+
+    match args.first() {
+        None | Some(EvalValue::Nil) => {
+            let file = io.input.borrow().clone();
+            let handle = check_file(&[file], "lines")?;
+            Ok(vec![lines_iterator(handle, formats, false)?])
+        }
+        Some(_) => {
+            let filename = check_string(&args, 1, "lines")?;
+            let file = io.open_checked(&filename, b"r")?;
+            let handle = file_handle(Some(&file)).expect("Opened file is a file handle");
+            // The file is also the to-be-closed value of a generic for
+            Ok(vec![
+                lines_iterator(handle, formats, true)?,
+                EvalValue::Nil,
+                EvalValue::Nil,
+                file,
+            ])
+        }
+    }
+}
+
+/// Whether `mode` is a valid mode for `open`: `r`, `w` or `a`, an optional `+` and any
+/// number of `b`.
+fn is_valid_mode(mode: &[u8]) -> bool {
+    let rest = match mode.split_first() {
+        Some((b'r' | b'w' | b'a', rest)) => rest,
+        _ => return false,
+    };
+    let rest = rest.strip_prefix(b"+").unwrap_or(rest);
+    rest.iter().all(|byte| *byte == b'b')
+}
+
+fn io_open(
+    io: &IoLibrary,
+    _: &mut VirtualMachine,
+    args: Vec<EvalValue>,
+) -> Result<Vec<EvalValue>, LuaError> {
+    let filename = check_string(&args, 1, "open")?;
+    let mode = match args.get(1) {
+        None | Some(EvalValue::Nil) => "r".into(),
+        Some(_) => check_string(&args, 2, "open")?,
+    };
+    if !is_valid_mode(&mode) {
+        return Err(argument_error(2, "open", "invalid mode"));
+    }
+
+    let filename = filename.to_str_lossy();
+    Ok(match io.open_file(&filename, &mode) {
+        Ok(file) => vec![file],
+        Err(err) => file_error(err, Some(&filename)),
+    })
+}
+
+fn io_popen(
+    io: &IoLibrary,
+    _: &mut VirtualMachine,
+    args: Vec<EvalValue>,
+) -> Result<Vec<EvalValue>, LuaError> {
+    let program = check_string(&args, 1, "popen")?;
+    let mode = match args.get(1) {
+        None | Some(EvalValue::Nil) => "r".into(),
+        Some(_) => check_string(&args, 2, "popen")?,
+    };
+
+    let mut command = shell_command(&program.to_str_lossy());
+    match &*mode {
+        b"r" => command.stdout(Stdio::piped()),
+        b"w" => command.stdin(Stdio::piped()),
+        _ => return Err(argument_error(2, "popen", "invalid mode")),
+    };
+
+    Ok(match command.spawn() {
+        Ok(child) => vec![new_file(&io.metatable, Stream::Process(child))],
+        Err(err) => file_error(err, Some(&program.to_str_lossy())),
+    })
+}
+
+fn io_read(
+    io: &IoLibrary,
+    _: &mut VirtualMachine,
+    args: Vec<EvalValue>,
+) -> Result<Vec<EvalValue>, LuaError> {
+    let (_, handle) = io.default_file(false)?;
+    let mut file = lua_file(&handle);
+    read_values(&mut file, &args, 1, "read")
+}
+
+/// Creates a file that is removed when closed. On Unix its name is removed right away,
+/// so it is also removed if the program ends before closing it.
+fn io_tmpfile(
+    io: &IoLibrary,
+    _: &mut VirtualMachine,
+    _: Vec<EvalValue>,
+) -> Result<Vec<EvalValue>, LuaError> {
+    let file = temporary_file();
+    Ok(match file {
+        Ok((path, file)) => {
+            let _ = fs::remove_file(path);
+            vec![new_file(&io.metatable, Stream::File(file))]
+        }
+        Err(err) => file_error(err, None),
+    })
+}
+
+fn io_write(
+    io: &IoLibrary,
+    _: &mut VirtualMachine,
+    args: Vec<EvalValue>,
+) -> Result<Vec<EvalValue>, LuaError> {
+    let (output, handle) = io.default_file(true)?;
+    let mut file = lua_file(&handle);
+    write_values(&mut file, &args, 1, output)
+}
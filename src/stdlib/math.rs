@@ -0,0 +1,353 @@
+use std::{cell::RefCell, f64::consts::PI, rc::Rc, time::SystemTime};
+
+use crate::{
+    ast::{compare_numbers, float_to_integer, EvalValue},
+    error::LuaError,
+    vm::VirtualMachine,
+};
+
+use super::{
+    argument_error, check_integer, check_number, create_library, missing_argument, opt_integer,
+    type_error,
+};
+
+pub fn open(_virtual_machine: &mut VirtualMachine) -> EvalValue {
+    let library = create_library(&[
+        ("abs", abs),
+        ("ceil", ceil),
+        ("floor", floor),
+        ("sqrt", sqrt),
+        ("sin", sin),
+        ("cos", cos),
+        ("tan", tan),
+        ("asin", asin),
+        ("acos", acos),
+        ("atan", atan),
+        ("exp", exp),
+        ("log", log),
+        ("fmod", fmod),
+        ("modf", modf),
+        ("tointeger", tointeger),
+        ("type", math_type),
+        ("ult", ult),
+        ("max", max),
+        ("min", min),
+    ]);
+
+    // The generator is shared by `random` and `randomseed`, and seeded differently on
+    // every run until a script seeds it
+    let generator = Rc::new(RefCell::new(Xoshiro256::default()));
+    generator
+        .borrow_mut()
+        .seed(random_seed(), Rc::as_ptr(&generator) as u64);
+
+    if let EvalValue::Table(table) = &library {
+        let mut table = table.borrow_mut();
+        let fields = [
+            ("pi", EvalValue::Number(PI)),
+            ("huge", EvalValue::Number(f64::INFINITY)),
+            ("maxinteger", EvalValue::Integer(i64::MAX)),
+            ("mininteger", EvalValue::Integer(i64::MIN)),
+            ("random", random(generator.clone())),
+            ("randomseed", randomseed(generator)),
+        ];
+        for (name, value) in fields {
+            table
+                .set(EvalValue::string(name), value)
+                .expect("Library field names are valid table keys");
+        }
+    }
+
+    library
+}
+
+/// Argument `position` as a number, keeping integers and floats apart.
+fn check_numeric(
+    args: &[EvalValue],
+    position: usize,
+    function_name: &str,
+) -> Result<EvalValue, LuaError> {
+    let arg = args.get(position - 1);
+    arg.and_then(EvalValue::to_numeric)
+        .ok_or_else(|| type_error(position, function_name, "number", arg))
+}
+
+/// A float with an integer value as an integer if it fits, and as a float otherwise.
+fn float_or_integer(n: f64) -> EvalValue {
+    float_to_integer(n).map_or(EvalValue::Number(n), EvalValue::Integer)
+}
+
+fn abs(_: &mut VirtualMachine, args: Vec<EvalValue>) -> Result<Vec<EvalValue>, LuaError> {
+    Ok(vec![match check_numeric(&args, 1, "abs")? {
+        EvalValue::Integer(i) => EvalValue::Integer(i.wrapping_abs()),
+        n => EvalValue::Number(n.to_number().expect("Value is a number").abs()),
+    }])
+}
+
+fn ceil(_: &mut VirtualMachine, args: Vec<EvalValue>) -> Result<Vec<EvalValue>, LuaError> {
+    Ok(vec![match check_numeric(&args, 1, "ceil")? {
+        EvalValue::Integer(i) => EvalValue::Integer(i),
+        n => float_or_integer(n.to_number().expect("Value is a number").ceil()),
+    }])
+}
+
+fn floor(_: &mut VirtualMachine, args: Vec<EvalValue>) -> Result<Vec<EvalValue>, LuaError> {
+    Ok(vec![match check_numeric(&args, 1, "floor")? {
+        EvalValue::Integer(i) => EvalValue::Integer(i),
+        n => float_or_integer(n.to_number().expect("Value is a number").floor()),
+    }])
+}
+
+fn sqrt(_: &mut VirtualMachine, args: Vec<EvalValue>) -> Result<Vec<EvalValue>, LuaError> {
+    Ok(vec![EvalValue::Number(
+        check_number(&args, 1, "sqrt")?.sqrt(),
+    )])
+}
+
+fn sin(_: &mut VirtualMachine, args: Vec<EvalValue>) -> Result<Vec<EvalValue>, LuaError> {
+    Ok(vec![EvalValue::Number(
+        check_number(&args, 1, "sin")?.sin(),
+    )])
+}
+
+fn cos(_: &mut VirtualMachine, args: Vec<EvalValue>) -> Result<Vec<EvalValue>, LuaError> {
+    Ok(vec![EvalValue::Number(
+        check_number(&args, 1, "cos")?.cos(),
+    )])
+}
+
+fn tan(_: &mut VirtualMachine, args: Vec<EvalValue>) -> Result<Vec<EvalValue>, LuaError> {
+    Ok(vec![EvalValue::Number(
+        check_number(&args, 1, "tan")?.tan(),
+    )])
+}
+
+fn asin(_: &mut VirtualMachine, args: Vec<EvalValue>) -> Result<Vec<EvalValue>, LuaError> {
+    Ok(vec![EvalValue::Number(
+        check_number(&args, 1, "asin")?.asin(),
+    )])
+}
+
+fn acos(_: &mut VirtualMachine, args: Vec<EvalValue>) -> Result<Vec<EvalValue>, LuaError> {
+    Ok(vec![EvalValue::Number(
+        check_number(&args, 1, "acos")?.acos(),
+    )])
+}
+
+fn atan(_: &mut VirtualMachine, args: Vec<EvalValue>) -> Result<Vec<EvalValue>, LuaError> {
+    let y = check_number(&args, 1, "atan")?;
+    let x = match args.get(1) {
+        None | Some(EvalValue::Nil) => 1.0,
+        Some(_) => check_number(&args, 2, "atan")?,
+    };
+    Ok(vec![EvalValue::Number(y.atan2(x))])
+}
+
+fn exp(_: &mut VirtualMachine, args: Vec<EvalValue>) -> Result<Vec<EvalValue>, LuaError> {
+    Ok(vec![EvalValue::Number(
+        check_number(&args, 1, "exp")?.exp(),
+    )])
+}
+
+fn log(_: &mut VirtualMachine, args: Vec<EvalValue>) -> Result<Vec<EvalValue>, LuaError> {
+    let x = check_number(&args, 1, "log")?;
+    let result = match args.get(1) {
+        None | Some(EvalValue::Nil) => x.ln(),
+        Some(_) => match check_number(&args, 2, "log")? {
+            2.0 => x.log2(),
+            10.0 => x.log10(),
+            base => x.ln() / base.ln(),
+        },
+    };
+    Ok(vec![EvalValue::Number(result)])
+}
+
+fn fmod(_: &mut VirtualMachine, args: Vec<EvalValue>) -> Result<Vec<EvalValue>, LuaError> {
+    let result = match (
+        check_numeric(&args, 1, "fmod")?,
+        check_numeric(&args, 2, "fmod")?,
+    ) {
+        (EvalValue::Integer(_), EvalValue::Integer(0)) => {
+            return Err(argument_error(2, "fmod", "zero"))
+        }
+        // The remainder truncates like C's `%`, unlike the `%` operator
+        (EvalValue::Integer(a), EvalValue::Integer(b)) => EvalValue::Integer(a.wrapping_rem(b)),
+        (a, b) => EvalValue::Number(
+            a.to_number().expect("Value is a number") % b.to_number().expect("Value is a number"),
+        ),
+    };
+    Ok(vec![result])
+}
+
+fn modf(_: &mut VirtualMachine, args: Vec<EvalValue>) -> Result<Vec<EvalValue>, LuaError> {
+    match check_numeric(&args, 1, "modf")? {
+        EvalValue::Integer(i) => Ok(vec![EvalValue::Integer(i), EvalValue::Number(0.0)]),
+        n => {
+            let n = n.to_number().expect("Value is a number");
+            let integral = n.trunc();
+            // Infinities have no fractional part
+            let fractional = if n == integral { 0.0 } else { n - integral };
+            Ok(vec![
+                EvalValue::Number(integral),
+                EvalValue::Number(fractional),
+            ])
+        }
+    }
+}
+
+fn tointeger(_: &mut VirtualMachine, args: Vec<EvalValue>) -> Result<Vec<EvalValue>, LuaError> {
+    match args.first() {
+        None => Err(missing_argument(1, "tointeger")),
+        Some(value) => Ok(vec![value
+            .to_integer()
+            .map_or(EvalValue::Nil, EvalValue::Integer)]),
+    }
+}
+
+fn math_type(_: &mut VirtualMachine, args: Vec<EvalValue>) -> Result<Vec<EvalValue>, LuaError> {
+    match args.first() {
+        None => Err(missing_argument(1, "type")),
+        Some(EvalValue::Integer(_)) => Ok(vec![EvalValue::string("integer")]),
+        Some(EvalValue::Number(_)) => Ok(vec![EvalValue::string("float")]),
+        Some(_) => Ok(vec![EvalValue::Nil]),
+    }
+}
+
+fn ult(_: &mut VirtualMachine, args: Vec<EvalValue>) -> Result<Vec<EvalValue>, LuaError> {
+    let a = check_integer(&args, 1, "ult")?;
+    let b = check_integer(&args, 2, "ult")?;
+    Ok(vec![EvalValue::Boolean((a as u64) < (b as u64))])
+}
+
+/// The argument of `max` or `min` that wins every comparison with `replaces`, which
+/// tells whether a candidate replaces the current best one.
+fn extreme(
+    args: &[EvalValue],
+    function_name: &str,
+    replaces: fn(std::cmp::Ordering) -> bool,
+) -> Result<Vec<EvalValue>, LuaError> {
+    if args.is_empty() {
+        return Err(argument_error(1, function_name, "number expected"));
+    }
+
+    let mut best = check_numeric(args, 1, function_name)?;
+    for position in 2..=args.len() {
+        let candidate = check_numeric(args, position, function_name)?;
+        if compare_numbers(&candidate, &best).is_some_and(replaces) {
+            best = candidate;
+        }
+    }
+    Ok(vec![best])
+}
+
+fn max(_: &mut VirtualMachine, args: Vec<EvalValue>) -> Result<Vec<EvalValue>, LuaError> {
+    extreme(&args, "max", std::cmp::Ordering::is_gt)
+}
+
+fn min(_: &mut VirtualMachine, args: Vec<EvalValue>) -> Result<Vec<EvalValue>, LuaError> {
+    extreme(&args, "min", std::cmp::Ordering::is_lt)
+}
+
+/// The xoshiro256** generator used by Lua 5.4, so seeded sequences match the ones of
+/// the reference implementation.
+#[derive(Default)]
+struct Xoshiro256 {
+    state: [u64; 4],
+}
+
+impl Xoshiro256 {
+    fn next(&mut self) -> u64 {
+        let state = &mut self.state;
+        let result = state[1].wrapping_mul(5).rotate_left(7).wrapping_mul(9);
+        let shifted = state[1] << 17;
+
+        state[2] ^= state[0];
+        state[3] ^= state[1];
+        state[1] ^= state[2];
+        state[0] ^= state[3];
+        state[2] ^= shifted;
+        state[3] = state[3].rotate_left(45);
+
+        result
+    }
+
+    fn seed(&mut self, n1: u64, n2: u64) {
+        // The constant avoids an all-zero state
+        self.state = [n1, 0xff, n2, 0];
+        // Discard the first values to spread the seed over the state
+        for _ in 0..16 {
+            self.next();
+        }
+    }
+
+    /// An integer in `[0, n]` without bias: values are drawn with as many bits as `n`
+    /// has until one fits.
+    fn project(&mut self, random: u64, n: u64) -> u64 {
+        if n & n.wrapping_add(1) == 0 {
+            return random & n;
+        }
+
+        let limit = u64::MAX >> n.leading_zeros();
+        let mut random = random & limit;
+        while random > n {
+            random = self.next() & limit;
+        }
+        random
+    }
+}
+
+/// A float in `[0, 1)`, made from the 53 highest bits of a random value.
+fn to_float(random: u64) -> f64 {
+    (random >> 11) as f64 * 2f64.powi(-53)
+}
+
+/// A seed from the clock, for runs that do not choose one.
+fn random_seed() -> u64 {
+    SystemTime::now()
+        .duration_since(SystemTime::UNIX_EPOCH)
+        .map_or(0, |duration| duration.as_nanos() as u64)
+}
+
+fn random(generator: Rc<RefCell<Xoshiro256>>) -> EvalValue {
+    EvalValue::native_function(move |_, args| {
+        let mut generator = generator.borrow_mut();
+        let random = generator.next();
+
+        let (low, up) = match args.len() {
+            0 => return Ok(vec![EvalValue::Number(to_float(random))]),
+            1 => match check_integer(&args, 1, "random")? {
+                // `random(0)` produces an integer with all bits random
+                0 => return Ok(vec![EvalValue::Integer(random as i64)]),
+                up => (1, up),
+            },
+            2 => (
+                check_integer(&args, 1, "random")?,
+                check_integer(&args, 2, "random")?,
+            ),
+            _ => return Err(LuaError::runtime("wrong number of arguments")),
+        };
+
+        if low > up {
+            return Err(argument_error(1, "random", "interval is empty"));
+        }
+        let offset = generator.project(random, (up as u64).wrapping_sub(low as u64));
+        Ok(vec![EvalValue::Integer(
+            offset.wrapping_add(low as u64) as i64
+        )])
+    })
+}
+
+fn randomseed(generator: Rc<RefCell<Xoshiro256>>) -> EvalValue {
+    EvalValue::native_function(move |_, args| {
+        let (n1, n2) = match args.first() {
+            None => (random_seed() as i64, Rc::as_ptr(&generator) as i64),
+            Some(_) => (
+                check_integer(&args, 1, "randomseed")?,
+                opt_integer(&args, 2, "randomseed", 0)?,
+            ),
+        };
+
+        generator.borrow_mut().seed(n1 as u64, n2 as u64);
+        Ok(vec![EvalValue::Integer(n1), EvalValue::Integer(n2)])
+    })
+}
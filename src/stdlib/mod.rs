@@ -0,0 +1,235 @@
+use std::{cell::RefCell, rc::Rc};
+
+use crate::{
+    ast::{EvalValue, LuaString, Table},
+    error::LuaError,
+    vm::VirtualMachine,
+};
+
+mod base;
+mod coroutine;
+mod debug;
+mod io;
+mod math;
+mod os;
+mod pack;
+mod package;
+mod pattern;
+mod string;
+mod table;
+mod utf8;
+
+type LibraryFunction = fn(&mut VirtualMachine, Vec<EvalValue>) -> Result<Vec<EvalValue>, LuaError>;
+
+/// Creates a library and returns its table.
+type LibraryOpener = fn(&mut VirtualMachine) -> EvalValue;
+
+/// The standard libraries loaded as modules, by module name.
+const LIBRARIES: [(&str, LibraryOpener); 9] = [
+    ("_G", base::open),
+    ("coroutine", coroutine::open),
+    ("table", table::open),
+    ("io", io::open),
+    ("os", os::open),
+    ("string", string::open),
+    ("math", math::open),
+    ("utf8", utf8::open),
+    ("debug", debug::open),
+];
+
+/// Registers the standard library in the globals of `virtual_machine`. The libraries
+/// are native modules, so they are also found in `package.loaded`.
+pub fn open_libs(virtual_machine: &mut VirtualMachine) {
+    let package = package::open(virtual_machine);
+    virtual_machine.set_global("package", package.clone());
+
+    for (name, open) in LIBRARIES {
+        virtual_machine.register_module(name, move |virtual_machine, _| {
+            Ok(vec![open(virtual_machine)])
+        });
+        let library = package::load_native_module(virtual_machine, &package, name)
+            .expect("Standard libraries load");
+        virtual_machine.set_global(name, library);
+    }
+}
+
+fn create_library(functions: &[(&str, LibraryFunction)]) -> EvalValue {
+    let mut library = Table::default();
+
+    for (name, function) in functions {
+        library
+            .set(
+                EvalValue::string(*name),
+                EvalValue::native_function(*function),
+            )
+            .expect("Library function names are valid table keys");
+    }
+
+    EvalValue::Table(Rc::new(RefCell::new(library)))
+}
+
+fn argument_message(position: usize, function_name: &str, message: &str) -> String {
+    format!(
+        "bad argument #{} to '{}' ({})",
+        position, function_name, message
+    )
+}
+
+fn argument_error(position: usize, function_name: &str, message: &str) -> LuaError {
+    LuaError::argument(argument_message(position, function_name, message))
+}
+
+/// The error for a required argument that was not passed.
+fn missing_argument(position: usize, function_name: &str) -> LuaError {
+    LuaError::arity(argument_message(position, function_name, "value expected"))
+}
+
+fn type_error(
+    position: usize,
+    function_name: &str,
+    expected: &str,
+    got: Option<&EvalValue>,
+) -> LuaError {
+    match got {
+        Some(got) => LuaError::type_mismatch(argument_message(
+            position,
+            function_name,
+            &format!("{} expected, got {}", expected, got.type_name()),
+        )),
+        None => LuaError::arity(argument_message(
+            position,
+            function_name,
+            &format!("{} expected, got no value", expected),
+        )),
+    }
+}
+
+/// Argument `position` of a library function as a string, converting numbers.
+fn check_string(
+    args: &[EvalValue],
+    position: usize,
+    function_name: &str,
+) -> Result<LuaString, LuaError> {
+    let arg = args.get(position - 1);
+    arg.and_then(EvalValue::to_lua_string)
+        .ok_or_else(|| type_error(position, function_name, "string", arg))
+}
+
+fn check_number(args: &[EvalValue], position: usize, function_name: &str) -> Result<f64, LuaError> {
+    let arg = args.get(position - 1);
+    arg.and_then(EvalValue::to_number)
+        .ok_or_else(|| type_error(position, function_name, "number", arg))
+}
+
+/// Argument `position` of a library function as an integer. Numbers with a fractional
+/// part are rejected like in Lua 5.4.
+fn check_integer(
+    args: &[EvalValue],
+    position: usize,
+    function_name: &str,
+) -> Result<i64, LuaError> {
+    let arg = args.get(position - 1);
+    match arg.map(|arg| (arg.to_integer(), arg.to_number())) {
+        Some((Some(integer), _)) => Ok(integer),
+        Some((None, Some(_))) => Err(argument_error(
+            position,
+            function_name,
+            "number has no integer representation",
+        )),
+        _ => Err(type_error(position, function_name, "number", arg)),
+    }
+}
+
+/// Like `check_integer`, but an absent or nil argument is `default`.
+fn opt_integer(
+    args: &[EvalValue],
+    position: usize,
+    function_name: &str,
+    default: i64,
+) -> Result<i64, LuaError> {
+    match args.get(position - 1) {
+        None | Some(EvalValue::Nil) => Ok(default),
+        Some(_) => check_integer(args, position, function_name),
+    }
+}
+
+/// Argument `position` as one of the names in `options`, returning its index. An
+/// absent or nil argument is `default`.
+fn check_option(
+    args: &[EvalValue],
+    position: usize,
+    function_name: &str,
+    default: Option<&str>,
+    options: &[&str],
+) -> Result<usize, LuaError> {
+    let name = match (args.get(position - 1), default) {
+        (None | Some(EvalValue::Nil), Some(default)) => default.into(),
+        _ => check_string(args, position, function_name)?,
+    };
+    options
+        .iter()
+        .position(|option| option.as_bytes() == &*name)
+        .ok_or_else(|| {
+            argument_error(
+                position,
+                function_name,
+                &format!("invalid option '{}'", name),
+            )
+        })
+}
+
+/// The address that identifies a table, function, thread or userdata.
+fn value_address(value: &EvalValue) -> Option<*const ()> {
+    match value {
+        EvalValue::NativeFunction(function) => Some(Rc::as_ptr(function) as *const ()),
+        EvalValue::DeclaredFunction(function) => Some(Rc::as_ptr(function) as *const ()),
+        EvalValue::Table(table) => Some(Rc::as_ptr(table) as *const ()),
+        EvalValue::Thread(thread) => Some(Rc::as_ptr(thread) as *const ()),
+        EvalValue::UserData(data) => Some(Rc::as_ptr(data) as *const ()),
+        _ => None,
+    }
+}
+
+/// The string `tostring` converts a value to: the result of its `__tostring`
+/// metamethod, or for references the `__name` metafield and the address.
+pub(crate) fn tostring(
+    virtual_machine: &mut VirtualMachine,
+    value: &EvalValue,
+) -> Result<LuaString, LuaError> {
+    match virtual_machine.get_metamethod(value, "__tostring") {
+        EvalValue::Nil => {}
+        handler => {
+            let result = virtual_machine.call_function(handler, vec![value.clone()])?;
+            return result
+                .first()
+                .and_then(EvalValue::to_lua_string)
+                .ok_or_else(|| "'__tostring' must return a string".into());
+        }
+    }
+
+    match (
+        value_address(value),
+        virtual_machine.get_metamethod(value, "__name"),
+    ) {
+        (Some(address), EvalValue::String(name)) => Ok(format!("{}: {:p}", name, address).into()),
+        _ => Ok(display_string(value)),
+    }
+}
+
+/// The string a value is shown as without metamethods. Values without a natural text
+/// are shown as their type and address.
+fn display_string(value: &EvalValue) -> LuaString {
+    match value {
+        EvalValue::Nil => "nil".into(),
+        EvalValue::Boolean(b) => b.to_string().into(),
+        EvalValue::Number(_) | EvalValue::Integer(_) | EvalValue::String(_) => value
+            .to_lua_string()
+            .expect("Value is a string or a number"),
+        _ => format!(
+            "{}: {:p}",
+            value.type_name(),
+            value_address(value).expect("Value is a reference")
+        )
+        .into(),
+    }
+}
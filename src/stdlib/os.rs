@@ -0,0 +1,408 @@
+use std::{cell::RefCell, ffi::OsString, fs, io::Write, mem, path::Path, rc::Rc, time::SystemTime};
+
+#[cfg(unix)]
+use libc as c_runtime;
+#[cfg(unix)]
+use std::{
+    ffi::OsStr,
+    os::unix::ffi::{OsStrExt, OsStringExt},
+};
+
+use crate::{
+    ast::{EvalValue, Table},
+    error::LuaError,
+    vm::VirtualMachine,
+};
+
+use super::{
+    argument_error, check_integer, check_string, create_library,
+    io::{execute_result, file_error, shell_command, temporary_file},
+    opt_integer, type_error,
+};
+
+/// Largest result a single `strftime` conversion can produce.
+const MAX_CONVERSION_SIZE: usize = 250;
+/// The conversions `date` accepts after a `%`, as in C99.
+#[cfg(unix)]
+const SINGLE_CONVERSIONS: &[u8] = b"aAbBcCdDeFgGhHIjmMnprRStTuUVwWxXyYzZ%";
+#[cfg(unix)]
+const DOUBLE_CONVERSIONS: &[u8] = b"EcECExEXEyEYOdOeOHOIOmOMOSOuOUOVOwOWOy";
+/// The C runtime of Windows only has the C89 conversions, and stops the program on
+/// others.
+#[cfg(windows)]
+const SINGLE_CONVERSIONS: &[u8] = b"aAbBcdHIjmMpSUwWxXyYZ%";
+#[cfg(windows)]
+const DOUBLE_CONVERSIONS: &[u8] = b"";
+
+/// Units of `clock` per second in the C runtime of Windows.
+#[cfg(windows)]
+const CLOCKS_PER_SEC: f64 = 1000.0;
+
+/// Functions of the C runtime of Windows that `libc` does not declare.
+#[cfg(windows)]
+mod c_runtime {
+    extern "C" {
+        #[link_name = "_mktime64"]
+        pub fn mktime(fields: *mut libc::tm) -> i64;
+        pub fn strftime(
+            buffer: *mut libc::c_char,
+            size: libc::size_t,
+            format: *const libc::c_char,
+            fields: *const libc::tm,
+        ) -> libc::size_t;
+    }
+}
+
+pub fn open(_virtual_machine: &mut VirtualMachine) -> EvalValue {
+    create_library(&[
+        ("clock", clock),
+        ("date", date),
+        ("difftime", difftime),
+        ("execute", execute),
+        ("exit", exit),
+        ("getenv", getenv),
+        ("remove", remove),
+        ("rename", rename),
+        ("time", time),
+        ("tmpname", tmpname),
+    ])
+}
+
+/// A file name or environment variable from the bytes of a Lua string. Windows names
+/// are Unicode, so bytes that are not UTF-8 are replaced there.
+fn os_string(bytes: &[u8]) -> OsString {
+    #[cfg(unix)]
+    let string = OsStr::from_bytes(bytes).to_os_string();
+    #[cfg(not(unix))]
+    let string = String::from_utf8_lossy(bytes).into_owned().into();
+    string
+}
+
+fn os_string_bytes(string: OsString) -> Vec<u8> {
+    #[cfg(unix)]
+    let bytes = string.into_vec();
+    #[cfg(not(unix))]
+    let bytes = string.to_string_lossy().into_owned().into_bytes();
+    bytes
+}
+
+fn current_time() -> i64 {
+    match SystemTime::now().duration_since(SystemTime::UNIX_EPOCH) {
+        Ok(duration) => duration.as_secs() as i64,
+        Err(err) => -(err.duration().as_secs() as i64),
+    }
+}
+
+/// Splits `time` into its date and time fields, in UTC or in the local time zone.
+fn broken_down_time(time: i64, utc: bool) -> Option<libc::tm> {
+    let time = time as libc::time_t;
+    // SAFETY: `tm` is plain data, and the functions only write to it
+    unsafe {
+        let mut fields: libc::tm = mem::zeroed();
+        #[cfg(unix)]
+        let converted = if utc {
+            !libc::gmtime_r(&time, &mut fields).is_null()
+        } else {
+            !libc::localtime_r(&time, &mut fields).is_null()
+        };
+        #[cfg(windows)]
+        let converted = if utc {
+            libc::gmtime_s(&mut fields, &time) == 0
+        } else {
+            libc::localtime_s(&mut fields, &time) == 0
+        };
+        converted.then_some(fields)
+    }
+}
+
+/// Stores the fields of a date in `table`, as `date("*t")` returns them.
+fn set_date_fields(
+    virtual_machine: &mut VirtualMachine,
+    table: &EvalValue,
+    fields: &libc::tm,
+) -> Result<(), LuaError> {
+    let values = [
+        ("year", fields.tm_year, 1900),
+        ("month", fields.tm_mon, 1),
+        ("day", fields.tm_mday, 0),
+        ("hour", fields.tm_hour, 0),
+        ("min", fields.tm_min, 0),
+        ("sec", fields.tm_sec, 0),
+        ("yday", fields.tm_yday, 1),
+        ("wday", fields.tm_wday, 1),
+    ];
+    for (name, value, delta) in values {
+        virtual_machine.set_index(
+            table.clone(),
+            EvalValue::string(name),
+            EvalValue::Integer(value as i64 + delta),
+        )?;
+    }
+
+    // A negative flag means the information is not available
+    if fields.tm_isdst >= 0 {
+        virtual_machine.set_index(
+            table.clone(),
+            EvalValue::string("isdst"),
+            EvalValue::Boolean(fields.tm_isdst != 0),
+        )?;
+    }
+    Ok(())
+}
+
+/// Reads field `key` of a date table, minus `delta`. Absent fields are `default`, or
+/// an error without one.
+fn date_field(
+    virtual_machine: &mut VirtualMachine,
+    table: &EvalValue,
+    key: &str,
+    default: Option<i32>,
+    delta: i64,
+) -> Result<i32, LuaError> {
+    let value = virtual_machine.index(table.clone(), EvalValue::string(key))?;
+
+    match (value.to_integer(), value) {
+        (Some(integer), _) => integer
+            .checked_sub(delta)
+            .and_then(|integer| i32::try_from(integer).ok())
+            .ok_or_else(|| format!("field '{}' is out-of-bound", key).into()),
+        (None, EvalValue::Nil) => {
+            default.ok_or_else(|| format!("field '{}' missing in date table", key).into())
+        }
+        (None, _) => Err(format!("field '{}' is not an integer", key).into()),
+    }
+}
+
+/// The conversion specifier at the start of `format`, which follows a `%`.
+fn conversion(format: &[u8]) -> Option<&[u8]> {
+    let first = *format.first()?;
+    if SINGLE_CONVERSIONS.contains(&first) {
+        return Some(&format[..1]);
+    }
+
+    let specifier = format.get(..2)?;
+    DOUBLE_CONVERSIONS
+        .chunks(2)
+        .any(|conversion| conversion == specifier)
+        .then_some(specifier)
+}
+
+fn strftime(conversion: &[u8], fields: &libc::tm) -> Vec<u8> {
+    let mut format = vec![b'%'];
+    format.extend_from_slice(conversion);
+    format.push(0);
+
+    let mut buffer = [0u8; MAX_CONVERSION_SIZE];
+    // SAFETY: the format is a valid conversion followed by a terminating zero, and
+    // `strftime` writes at most `buffer.len()` bytes
+    let length = unsafe {
+        c_runtime::strftime(
+            buffer.as_mut_ptr().cast(),
+            buffer.len(),
+            format.as_ptr().cast(),
+            fields,
+        )
+    };
+    buffer[..length].to_vec()
+}
+
+#[cfg(unix)]
+fn clock(_: &mut VirtualMachine, _: Vec<EvalValue>) -> Result<Vec<EvalValue>, LuaError> {
+    // SAFETY: `timespec` is plain data, and `clock_gettime` only writes to it
+    let time = unsafe {
+        let mut time: libc::timespec = mem::zeroed();
+        libc::clock_gettime(libc::CLOCK_PROCESS_CPUTIME_ID, &mut time);
+        time
+    };
+
+    Ok(vec![EvalValue::Number(
+        time.tv_sec as f64 + time.tv_nsec as f64 / 1e9,
+    )])
+}
+
+/// On Windows `clock` measures the time since the program started, like in `lua`
+/// there.
+#[cfg(windows)]
+fn clock(_: &mut VirtualMachine, _: Vec<EvalValue>) -> Result<Vec<EvalValue>, LuaError> {
+    // SAFETY: `clock` has no preconditions
+    let time = unsafe { libc::clock() };
+    Ok(vec![EvalValue::Number(time as f64 / CLOCKS_PER_SEC)])
+}
+
+fn date(
+    virtual_machine: &mut VirtualMachine,
+    args: Vec<EvalValue>,
+) -> Result<Vec<EvalValue>, LuaError> {
+    let format = match args.first() {
+        None | Some(EvalValue::Nil) => "%c".into(),
+        Some(_) => check_string(&args, 1, "date")?,
+    };
+    let time = match args.get(1) {
+        None | Some(EvalValue::Nil) => current_time(),
+        Some(_) => check_integer(&args, 2, "date")?,
+    };
+
+    let (utc, mut format) = match format.strip_prefix(b"!") {
+        Some(format) => (true, format),
+        None => (false, &format[..]),
+    };
+    let fields = broken_down_time(time, utc).ok_or_else(|| {
+        LuaError::runtime("date result cannot be represented in this installation")
+    })?;
+
+    if format == b"*t" {
+        let table = EvalValue::Table(Rc::new(RefCell::new(Table::default())));
+        set_date_fields(virtual_machine, &table, &fields)?;
+        return Ok(vec![table]);
+    }
+
+    let mut output = Vec::new();
+    while let Some((&byte, rest)) = format.split_first() {
+        if byte != b'%' {
+            output.push(byte);
+            format = rest;
+            continue;
+        }
+
+        let conversion = conversion(rest).ok_or_else(|| {
+            argument_error(
+                1,
+                "date",
+                &format!(
+                    "invalid conversion specifier '%{}'",
+                    String::from_utf8_lossy(rest)
+                ),
+            )
+        })?;
+        output.extend(strftime(conversion, &fields));
+        format = &rest[conversion.len()..];
+    }
+
+    Ok(vec![EvalValue::string(output)])
+}
+
+fn difftime(_: &mut VirtualMachine, args: Vec<EvalValue>) -> Result<Vec<EvalValue>, LuaError> {
+    let end = check_integer(&args, 1, "difftime")?;
+    let start = check_integer(&args, 2, "difftime")?;
+    Ok(vec![EvalValue::Number(end as f64 - start as f64)])
+}
+
+fn execute(_: &mut VirtualMachine, args: Vec<EvalValue>) -> Result<Vec<EvalValue>, LuaError> {
+    // Without a command, tells whether a shell is available
+    if let None | Some(EvalValue::Nil) = args.first() {
+        let status = shell_command("exit 0").status();
+        return Ok(vec![EvalValue::Boolean(status.is_ok())]);
+    }
+
+    let command = check_string(&args, 1, "execute")?;
+    // Output written so far comes before the output of the command
+    let _ = std::io::stdout().flush();
+    Ok(match shell_command(&command.to_str_lossy()).status() {
+        Ok(status) => execute_result(status),
+        Err(err) => file_error(err, None),
+    })
+}
+
+fn exit(
+    virtual_machine: &mut VirtualMachine,
+    args: Vec<EvalValue>,
+) -> Result<Vec<EvalValue>, LuaError> {
+    let status = match args.first() {
+        Some(EvalValue::Boolean(success)) => !success as i32,
+        _ => opt_integer(&args, 1, "exit", 0)? as i32,
+    };
+    if args.get(1).is_some_and(EvalValue::is_true) {
+        virtual_machine.close_scopes();
+    }
+
+    let _ = std::io::stdout().flush();
+    let _ = std::io::stderr().flush();
+    std::process::exit(status)
+}
+
+fn getenv(_: &mut VirtualMachine, args: Vec<EvalValue>) -> Result<Vec<EvalValue>, LuaError> {
+    let name = check_string(&args, 1, "getenv")?;
+    // Such names cannot be in the environment
+    if name.is_empty() || name.contains(&b'=') || name.contains(&0) {
+        return Ok(vec![EvalValue::Nil]);
+    }
+
+    Ok(vec![match std::env::var_os(os_string(&name)) {
+        Some(value) => EvalValue::string(os_string_bytes(value)),
+        None => EvalValue::Nil,
+    }])
+}
+
+/// Removes a file or an empty directory.
+fn remove(_: &mut VirtualMachine, args: Vec<EvalValue>) -> Result<Vec<EvalValue>, LuaError> {
+    let filename = check_string(&args, 1, "remove")?;
+    let path = os_string(&filename);
+    let path = Path::new(&path);
+
+    let result = match fs::symlink_metadata(path) {
+        Ok(metadata) if metadata.is_dir() => fs::remove_dir(path),
+        _ => fs::remove_file(path),
+    };
+    Ok(match result {
+        Ok(()) => vec![EvalValue::Boolean(true)],
+        Err(err) => file_error(err, Some(&filename.to_str_lossy())),
+    })
+}
+
+fn rename(_: &mut VirtualMachine, args: Vec<EvalValue>) -> Result<Vec<EvalValue>, LuaError> {
+    let from = check_string(&args, 1, "rename")?;
+    let to = check_string(&args, 2, "rename")?;
+
+    Ok(match fs::rename(os_string(&from), os_string(&to)) {
+        Ok(()) => vec![EvalValue::Boolean(true)],
+        Err(err) => file_error(err, Some(&from.to_str_lossy())),
+    })
+}
+
+/// Without arguments the current time, otherwise the time of the date in the table
+/// argument. The fields of the table are normalized, e.g. a day of 32 becomes the first
+/// of the next month.
+fn time(
+    virtual_machine: &mut VirtualMachine,
+    args: Vec<EvalValue>,
+) -> Result<Vec<EvalValue>, LuaError> {
+    let table = match args.first() {
+        None | Some(EvalValue::Nil) => return Ok(vec![EvalValue::Integer(current_time())]),
+        Some(table @ EvalValue::Table(_)) => table,
+        other => return Err(type_error(1, "time", "table", other)),
+    };
+
+    // SAFETY: `tm` is plain data
+    let mut fields: libc::tm = unsafe { mem::zeroed() };
+    fields.tm_year = date_field(virtual_machine, table, "year", None, 1900)?;
+    fields.tm_mon = date_field(virtual_machine, table, "month", None, 1)?;
+    fields.tm_mday = date_field(virtual_machine, table, "day", None, 0)?;
+    fields.tm_hour = date_field(virtual_machine, table, "hour", Some(12), 0)?;
+    fields.tm_min = date_field(virtual_machine, table, "min", Some(0), 0)?;
+    fields.tm_sec = date_field(virtual_machine, table, "sec", Some(0), 0)?;
+    fields.tm_isdst = match virtual_machine.index(table.clone(), EvalValue::string("isdst"))? {
+        // Whether daylight saving time is in effect is left for `mktime` to find out
+        EvalValue::Nil => -1,
+        isdst => isdst.is_true() as i32,
+    };
+
+    // SAFETY: `mktime` only reads and normalizes the fields
+    let time = unsafe { c_runtime::mktime(&mut fields) } as i64;
+    set_date_fields(virtual_machine, table, &fields)?;
+
+    if time == -1 {
+        return Err(LuaError::runtime(
+            "time result cannot be represented in this installation",
+        ));
+    }
+    Ok(vec![EvalValue::Integer(time)])
+}
+
+/// A name for a temporary file. The file is created so that the name stays unique.
+fn tmpname(_: &mut VirtualMachine, _: Vec<EvalValue>) -> Result<Vec<EvalValue>, LuaError> {
+    let (path, _) =
+        temporary_file().map_err(|_| LuaError::runtime("unable to generate a unique filename"))?;
+    Ok(vec![EvalValue::string(os_string_bytes(
+        path.into_os_string(),
+    ))])
+}
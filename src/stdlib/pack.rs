@@ -0,0 +1,447 @@
+//! Binary packing, as used by `string.pack`, `unpack` and `packsize`. Formats are read
+//! option by option like in the reference implementation, so the produced bytes,
+//! alignment and error messages are the same.
+
+use std::{
+    iter::{Copied, Peekable},
+    slice::Iter,
+};
+
+use crate::{ast::EvalValue, error::LuaError, vm::VirtualMachine};
+
+use super::{argument_error, check_integer, check_number, check_string, opt_integer};
+
+/// Size in bytes of a Lua integer.
+const INTEGER_SIZE: usize = 8;
+/// Largest size of an integer option such as `i16`.
+const MAX_INTEGER_SIZE: usize = 16;
+/// Largest size or count read from a format.
+const MAX_SIZE: usize = i32::MAX as usize;
+/// Alignment used by `!` without a size: the alignment of the largest native type.
+const NATIVE_ALIGNMENT: usize = 8;
+/// Byte used for padding.
+const PADDING_BYTE: u8 = 0;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum FormatOption {
+    Integer,
+    Unsigned,
+    Float,
+    Double,
+    /// A string of fixed size.
+    Char,
+    /// A string preceded by its length.
+    String,
+    /// A zero-terminated string.
+    ZeroTerminated,
+    Padding,
+    /// Padding up to the alignment of the next option.
+    PaddingAlignment,
+    /// An option that only changes the state of the format, such as `<`.
+    NoOperation,
+}
+
+/// Reads a format string, keeping track of the endianness and maximum alignment set by
+/// its options.
+struct Format<'a> {
+    options: Peekable<Copied<Iter<'a, u8>>>,
+    little_endian: bool,
+    max_alignment: usize,
+}
+
+impl<'a> Format<'a> {
+    fn new(format: &'a [u8]) -> Self {
+        Format {
+            options: format.iter().copied().peekable(),
+            little_endian: cfg!(target_endian = "little"),
+            max_alignment: 1,
+        }
+    }
+
+    fn is_finished(&mut self) -> bool {
+        self.options.peek().is_none()
+    }
+
+    /// Reads the number following an option, or returns `default` if there is none.
+    fn number(&mut self, default: usize) -> usize {
+        if !self.options.peek().is_some_and(u8::is_ascii_digit) {
+            return default;
+        }
+
+        let mut number = 0;
+        while let Some(digit) = self.options.next_if(u8::is_ascii_digit) {
+            number = number * 10 + (digit - b'0') as usize;
+            if number > (MAX_SIZE - 9) / 10 {
+                break;
+            }
+        }
+        number
+    }
+
+    /// Reads the size of an integer option, which has to fit the supported integers.
+    fn integer_size(&mut self, default: usize) -> Result<usize, LuaError> {
+        match self.number(default) {
+            size @ 1..=MAX_INTEGER_SIZE => Ok(size),
+            size => Err(format!(
+                "integral size ({}) out of limits [1,{}]",
+                size, MAX_INTEGER_SIZE
+            )
+            .into()),
+        }
+    }
+
+    /// Reads the next option and its size.
+    fn option(&mut self) -> Result<(FormatOption, usize), LuaError> {
+        let option = self.options.next().expect("Format is not finished");
+        Ok(match option {
+            b'b' => (FormatOption::Integer, 1),
+            b'B' => (FormatOption::Unsigned, 1),
+            b'h' => (FormatOption::Integer, 2),
+            b'H' => (FormatOption::Unsigned, 2),
+            b'i' => (FormatOption::Integer, self.integer_size(4)?),
+            b'I' => (FormatOption::Unsigned, self.integer_size(4)?),
+            b'l' | b'j' => (FormatOption::Integer, 8),
+            b'L' | b'J' | b'T' => (FormatOption::Unsigned, 8),
+            b'f' => (FormatOption::Float, 4),
+            b'd' | b'n' => (FormatOption::Double, 8),
+            b's' => (FormatOption::String, self.integer_size(8)?),
+            b'c' => match self.number(usize::MAX) {
+                usize::MAX => {
+                    return Err("missing size for format option 'c'".into());
+                }
+                size => (FormatOption::Char, size),
+            },
+            b'z' => (FormatOption::ZeroTerminated, 0),
+            b'x' => (FormatOption::Padding, 1),
+            b'X' => (FormatOption::PaddingAlignment, 0),
+            b' ' => (FormatOption::NoOperation, 0),
+            b'<' => {
+                self.little_endian = true;
+                (FormatOption::NoOperation, 0)
+            }
+            b'>' => {
+                self.little_endian = false;
+                (FormatOption::NoOperation, 0)
+            }
+            b'=' => {
+                self.little_endian = cfg!(target_endian = "little");
+                (FormatOption::NoOperation, 0)
+            }
+            b'!' => {
+                self.max_alignment = self.integer_size(NATIVE_ALIGNMENT)?;
+                (FormatOption::NoOperation, 0)
+            }
+            _ => {
+                return Err(format!("invalid format option '{}'", option as char).into());
+            }
+        })
+    }
+
+    /// Reads the next option, its size and the padding needed before it so it is aligned
+    /// when it starts at `offset`.
+    fn option_details(
+        &mut self,
+        offset: usize,
+        function_name: &str,
+    ) -> Result<(FormatOption, usize, usize), LuaError> {
+        let (option, size) = self.option()?;
+
+        // `X` aligns to the option that follows it, which is otherwise ignored
+        let mut alignment = size;
+        if option == FormatOption::PaddingAlignment {
+            let next = if self.is_finished() {
+                None
+            } else {
+                Some(self.option()?)
+            };
+            match next {
+                Some((next_option, next_size))
+                    if next_option != FormatOption::Char && next_size != 0 =>
+                {
+                    alignment = next_size;
+                }
+                _ => {
+                    return Err(argument_error(
+                        1,
+                        function_name,
+                        "invalid next option for option 'X'",
+                    ))
+                }
+            }
+        }
+
+        if alignment <= 1 || option == FormatOption::Char {
+            return Ok((option, size, 0));
+        }
+
+        let alignment = alignment.min(self.max_alignment);
+        if !alignment.is_power_of_two() {
+            return Err(argument_error(
+                1,
+                function_name,
+                "format asks for alignment not power of 2",
+            ));
+        }
+        let padding = (alignment - (offset & (alignment - 1))) & (alignment - 1);
+
+        Ok((option, size, padding))
+    }
+}
+
+/// Appends the `size` lowest bytes of `value`. Sizes beyond an integer are filled with
+/// ones for negative values and zeros otherwise.
+fn pack_integer(
+    output: &mut Vec<u8>,
+    value: u64,
+    negative: bool,
+    little_endian: bool,
+    size: usize,
+) {
+    let extension = if negative { 0xff } else { 0 };
+    let mut bytes: Vec<u8> = (0..size)
+        .map(|i| match i {
+            0..INTEGER_SIZE => (value >> (i * 8)) as u8,
+            _ => extension,
+        })
+        .collect();
+
+    if !little_endian {
+        bytes.reverse();
+    }
+    output.extend_from_slice(&bytes);
+}
+
+/// Reads an integer of `size` bytes, which has to fit in a Lua integer.
+fn unpack_integer(bytes: &[u8], little_endian: bool, signed: bool) -> Result<i64, LuaError> {
+    let size = bytes.len();
+    let byte = |i: usize| bytes[if little_endian { i } else { size - 1 - i }];
+
+    let limit = size.min(INTEGER_SIZE);
+    let mut value = (0..limit)
+        .rev()
+        .fold(0u64, |value, i| (value << 8) | byte(i) as u64);
+
+    if size < INTEGER_SIZE {
+        if signed {
+            let mask = 1u64 << (size * 8 - 1);
+            value = (value ^ mask).wrapping_sub(mask);
+        }
+    } else if size > INTEGER_SIZE {
+        let extension = if !signed || (value as i64) >= 0 {
+            0
+        } else {
+            0xff
+        };
+        if (limit..size).any(|i| byte(i) != extension) {
+            return Err(format!("{}-byte integer does not fit into Lua Integer", size).into());
+        }
+    }
+
+    Ok(value as i64)
+}
+
+/// Appends the bytes of a float in the given byte order.
+fn pack_bytes<const N: usize>(output: &mut Vec<u8>, little_endian: bool, bytes: [u8; N]) {
+    let mut bytes = bytes;
+    if little_endian != cfg!(target_endian = "little") {
+        bytes.reverse();
+    }
+    output.extend_from_slice(&bytes);
+}
+
+/// Reads the bytes of a float in the given byte order.
+fn unpack_bytes<const N: usize>(bytes: &[u8], little_endian: bool) -> [u8; N] {
+    let mut bytes: [u8; N] = bytes.try_into().expect("Size matches the option");
+    if little_endian != cfg!(target_endian = "little") {
+        bytes.reverse();
+    }
+    bytes
+}
+
+pub fn pack(_: &mut VirtualMachine, args: Vec<EvalValue>) -> Result<Vec<EvalValue>, LuaError> {
+    let format = check_string(&args, 1, "pack")?;
+    let mut format = Format::new(&format);
+    let mut output = Vec::new();
+    let mut argument = 1;
+
+    while !format.is_finished() {
+        let (option, size, padding) = format.option_details(output.len(), "pack")?;
+        output.resize(output.len() + padding, PADDING_BYTE);
+
+        argument += 1;
+        match option {
+            FormatOption::Integer => {
+                let value = check_integer(&args, argument, "pack")?;
+                if size < INTEGER_SIZE {
+                    let limit = 1i64 << (size * 8 - 1);
+                    if !(-limit..limit).contains(&value) {
+                        return Err(argument_error(argument, "pack", "integer overflow"));
+                    }
+                }
+                pack_integer(
+                    &mut output,
+                    value as u64,
+                    value < 0,
+                    format.little_endian,
+                    size,
+                );
+            }
+            FormatOption::Unsigned => {
+                let value = check_integer(&args, argument, "pack")?;
+                if size < INTEGER_SIZE && (value as u64) >= 1u64 << (size * 8) {
+                    return Err(argument_error(argument, "pack", "unsigned overflow"));
+                }
+                pack_integer(&mut output, value as u64, false, format.little_endian, size);
+            }
+            FormatOption::Float => {
+                let value = check_number(&args, argument, "pack")? as f32;
+                pack_bytes(&mut output, format.little_endian, value.to_ne_bytes());
+            }
+            FormatOption::Double => {
+                let value = check_number(&args, argument, "pack")?;
+                pack_bytes(&mut output, format.little_endian, value.to_ne_bytes());
+            }
+            FormatOption::Char => {
+                let string = check_string(&args, argument, "pack")?;
+                if string.len() > size {
+                    return Err(argument_error(
+                        argument,
+                        "pack",
+                        "string longer than given size",
+                    ));
+                }
+                output.extend_from_slice(&string);
+                output.resize(output.len() + size - string.len(), PADDING_BYTE);
+            }
+            FormatOption::String => {
+                let string = check_string(&args, argument, "pack")?;
+                if size < INTEGER_SIZE && string.len() as u64 >= 1u64 << (size * 8) {
+                    return Err(argument_error(
+                        argument,
+                        "pack",
+                        "string length does not fit in given size",
+                    ));
+                }
+                pack_integer(
+                    &mut output,
+                    string.len() as u64,
+                    false,
+                    format.little_endian,
+                    size,
+                );
+                output.extend_from_slice(&string);
+            }
+            FormatOption::ZeroTerminated => {
+                let string = check_string(&args, argument, "pack")?;
+                if string.contains(&0) {
+                    return Err(argument_error(argument, "pack", "string contains zeros"));
+                }
+                output.extend_from_slice(&string);
+                output.push(0);
+            }
+            FormatOption::Padding => {
+                output.push(PADDING_BYTE);
+                argument -= 1;
+            }
+            FormatOption::PaddingAlignment | FormatOption::NoOperation => argument -= 1,
+        }
+    }
+
+    Ok(vec![EvalValue::string(output)])
+}
+
+pub fn packsize(_: &mut VirtualMachine, args: Vec<EvalValue>) -> Result<Vec<EvalValue>, LuaError> {
+    let format = check_string(&args, 1, "packsize")?;
+    let mut format = Format::new(&format);
+    let mut total_size = 0;
+
+    while !format.is_finished() {
+        let (option, size, padding) = format.option_details(total_size, "packsize")?;
+        if matches!(option, FormatOption::String | FormatOption::ZeroTerminated) {
+            return Err(argument_error(1, "packsize", "variable-length format"));
+        }
+
+        let size = size + padding;
+        if total_size > MAX_SIZE - size {
+            return Err(argument_error(1, "packsize", "format result too large"));
+        }
+        total_size += size;
+    }
+
+    Ok(vec![EvalValue::Integer(total_size as i64)])
+}
+
+pub fn unpack(_: &mut VirtualMachine, args: Vec<EvalValue>) -> Result<Vec<EvalValue>, LuaError> {
+    let format = check_string(&args, 1, "unpack")?;
+    let mut format = Format::new(&format);
+    let data = check_string(&args, 2, "unpack")?;
+
+    let start = opt_integer(&args, 3, "unpack", 1)?;
+    let mut position = match start {
+        1.. => start as usize - 1,
+        0 => 0,
+        _ if start.unsigned_abs() > data.len() as u64 => 0,
+        _ => data.len() - start.unsigned_abs() as usize,
+    };
+    if position > data.len() {
+        return Err(argument_error(
+            3,
+            "unpack",
+            "initial position out of string",
+        ));
+    }
+
+    let too_short = || argument_error(2, "unpack", "data string too short");
+    let mut values = Vec::new();
+
+    while !format.is_finished() {
+        let (option, size, padding) = format.option_details(position, "unpack")?;
+        if padding + size > data.len() - position {
+            return Err(too_short());
+        }
+        position += padding;
+
+        let bytes = &data[position..position + size];
+        match option {
+            FormatOption::Integer | FormatOption::Unsigned => {
+                let value =
+                    unpack_integer(bytes, format.little_endian, option == FormatOption::Integer)?;
+                values.push(EvalValue::Integer(value));
+            }
+            FormatOption::Float => {
+                let value = f32::from_ne_bytes(unpack_bytes(bytes, format.little_endian));
+                values.push(EvalValue::Number(value as f64));
+            }
+            FormatOption::Double => {
+                let value = f64::from_ne_bytes(unpack_bytes(bytes, format.little_endian));
+                values.push(EvalValue::Number(value));
+            }
+            FormatOption::Char => values.push(EvalValue::string(bytes)),
+            FormatOption::String => {
+                let length = unpack_integer(bytes, format.little_endian, false)? as u64;
+                if length > (data.len() - position - size) as u64 {
+                    return Err(too_short());
+                }
+                let start = position + size;
+                values.push(EvalValue::string(&data[start..start + length as usize]));
+                position += length as usize;
+            }
+            FormatOption::ZeroTerminated => {
+                let Some(length) = data[position..].iter().position(|byte| *byte == 0) else {
+                    return Err(argument_error(
+                        2,
+                        "unpack",
+                        "unfinished string for format 'z'",
+                    ));
+                };
+                values.push(EvalValue::string(&data[position..position + length]));
+                position += length + 1;
+            }
+            FormatOption::Padding | FormatOption::PaddingAlignment | FormatOption::NoOperation => {}
+        }
+        position += size;
+    }
+
+    values.push(EvalValue::Integer(position as i64 + 1));
+    Ok(values)
+}
@@ -0,0 +1,375 @@
+use std::{cell::RefCell, env, fs::File, rc::Rc};
+
+use crate::{
+    ast::{EvalValue, LuaString, Table, TableRef},
+    error::LuaError,
+    vm::VirtualMachine,
+};
+
+use super::{base::load_file, check_string, create_library};
+
+/// Separates the templates of a path.
+const PATH_SEPARATOR: &str = ";";
+
+/// Stands for the module name in the templates of a path.
+const PATH_MARK: &str = "?";
+
+/// Directory separator used for the dots in module names.
+const DIRECTORY_SEPARATOR: &str = "/";
+
+/// Where `require` looks for Lua modules when `LUA_PATH` is not set.
+const DEFAULT_PATH: &str = concat!(
+    "/usr/local/share/lua/5.4/?.lua;",
+    "/usr/local/share/lua/5.4/?/init.lua;",
+    "/usr/local/lib/lua/5.4/?.lua;",
+    "/usr/local/lib/lua/5.4/?/init.lua;",
+    "./?.lua;",
+    "./?/init.lua"
+);
+
+/// The default of `package.cpath`. Shared libraries are never loaded, the path is only
+/// kept for scripts that read or extend it.
+const DEFAULT_CPATH: &str = "/usr/local/lib/lua/5.4/?.so;/usr/local/lib/lua/5.4/loadall.so;./?.so";
+
+/// The message of `package.loadlib`, which cannot load shared libraries.
+const DYNAMIC_LIBRARIES_MESSAGE: &str =
+    "dynamic libraries not enabled; check your Lua installation";
+
+pub fn open(virtual_machine: &mut VirtualMachine) -> EvalValue {
+    let loaded = Rc::new(RefCell::new(Table::default()));
+    let preload = Rc::new(RefCell::new(Table::default()));
+    let library = create_library(&[("loadlib", loadlib), ("searchpath", searchpath)]);
+    let EvalValue::Table(package) = &library else {
+        unreachable!("Libraries are tables")
+    };
+
+    let searchers = {
+        let preload = preload.clone();
+        let package = package.clone();
+        let mut searchers = Table::default();
+        let functions = [
+            EvalValue::native_function(move |_, args| search_preload(&preload, args)),
+            EvalValue::native_function(move |virtual_machine, args| {
+                search_lua(virtual_machine, &package, args)
+            }),
+            EvalValue::native_function(search_native),
+        ];
+        for (index, searcher) in functions.into_iter().enumerate() {
+            searchers
+                .set(EvalValue::Integer(index as i64 + 1), searcher)
+                .expect("Integers are valid table keys");
+        }
+        Rc::new(RefCell::new(searchers))
+    };
+
+    {
+        let mut package = package.borrow_mut();
+        let fields = [
+            ("config", EvalValue::string("/\n;\n?\n!\n-\n")),
+            (
+                "cpath",
+                EvalValue::string(search_path(virtual_machine, "LUA_CPATH", DEFAULT_CPATH)),
+            ),
+            ("loaded", EvalValue::Table(loaded.clone())),
+            (
+                "path",
+                EvalValue::string(search_path(virtual_machine, "LUA_PATH", DEFAULT_PATH)),
+            ),
+            ("preload", EvalValue::Table(preload)),
+            ("searchers", EvalValue::Table(searchers)),
+        ];
+        for (name, value) in fields {
+            package
+                .set(EvalValue::string(name), value)
+                .expect("Library field names are valid table keys");
+        }
+    }
+    loaded
+        .borrow_mut()
+        .set(EvalValue::string("package"), library.clone())
+        .expect("Module names are valid table keys");
+
+    let package = library.clone();
+    virtual_machine.set_global(
+        "require",
+        EvalValue::native_function(move |virtual_machine, args| {
+            require(virtual_machine, &package, &loaded, args)
+        }),
+    );
+
+    library
+}
+
+/// Loads the module `name` registered with `register_module` into `package.loaded`,
+/// without running the searchers, like `luaL_requiref` does for the standard libraries.
+pub(super) fn load_native_module(
+    virtual_machine: &mut VirtualMachine,
+    package: &EvalValue,
+    name: &str,
+) -> Result<EvalValue, LuaError> {
+    let loaded = virtual_machine.index(package.clone(), EvalValue::string("loaded"))?;
+    let module = match virtual_machine.index(loaded.clone(), EvalValue::string(name))? {
+        EvalValue::Nil | EvalValue::Boolean(false) => {
+            let loader = virtual_machine
+                .native_module(&name.into())
+                .ok_or_else(|| format!("module '{}' not found", name))?;
+            let args = vec![EvalValue::string(name), EvalValue::string(":native:")];
+            let module = match virtual_machine
+                .call_function(loader, args)?
+                .into_iter()
+                .next()
+            {
+                None | Some(EvalValue::Nil) => EvalValue::Boolean(true),
+                Some(module) => module,
+            };
+            virtual_machine.set_index(loaded, EvalValue::string(name), module.clone())?;
+            module
+        }
+        module => module,
+    };
+
+    Ok(module)
+}
+
+/// The value of a path: the environment variable `name` with a version suffix, or
+/// without one, where ";;" stands for `default`. Otherwise, or when the virtual machine
+/// ignores the environment, `default`.
+fn search_path(virtual_machine: &VirtualMachine, name: &str, default: &str) -> String {
+    if virtual_machine.ignores_environment() {
+        return default.to_string();
+    }
+
+    let Some(path) = env::var(format!("{}_5_4", name))
+        .or_else(|_| env::var(name))
+        .ok()
+    else {
+        return default.to_string();
+    };
+
+    let separators = format!("{}{}", PATH_SEPARATOR, PATH_SEPARATOR);
+    match path.split_once(&separators) {
+        None => path,
+        Some((prefix, suffix)) => {
+            let mut path = String::new();
+            if !prefix.is_empty() {
+                path.push_str(prefix);
+                path.push_str(PATH_SEPARATOR);
+            }
+            path.push_str(default);
+            if !suffix.is_empty() {
+                path.push_str(PATH_SEPARATOR);
+                path.push_str(suffix);
+            }
+            path
+        }
+    }
+}
+
+/// Finds the first readable file among the templates of `path` with `name` in place of
+/// the marks, after replacing `separator` in `name` with `directory_separator`. Fails
+/// with the list of files tried.
+fn find_file(
+    name: &str,
+    path: &str,
+    separator: &str,
+    directory_separator: &str,
+) -> Result<String, String> {
+    let name = match separator {
+        "" => name.to_string(),
+        separator => name.replace(separator, directory_separator),
+    };
+    let path = path.replace(PATH_MARK, &name);
+
+    let filenames = path
+        .split(PATH_SEPARATOR)
+        .filter(|filename| !filename.is_empty());
+    for filename in filenames {
+        if File::open(filename).is_ok() {
+            return Ok(filename.to_string());
+        }
+    }
+
+    Err(format!(
+        "no file '{}'",
+        path.replace(PATH_SEPARATOR, "'\n\tno file '")
+    ))
+}
+
+fn require(
+    virtual_machine: &mut VirtualMachine,
+    package: &EvalValue,
+    loaded: &TableRef,
+    args: Vec<EvalValue>,
+) -> Result<Vec<EvalValue>, LuaError> {
+    let module_name = check_string(&args, 1, "require")?;
+    let name = EvalValue::String(module_name.clone());
+    let module = loaded.borrow().get(&name);
+    if module.is_true() {
+        return Ok(vec![module]);
+    }
+
+    let (loader, data) = find_loader(virtual_machine, package, &module_name)?;
+    let result = virtual_machine.call_function(loader, vec![name.clone(), data.clone()])?;
+    if let Some(module) = result
+        .into_iter()
+        .next()
+        .filter(|module| *module != EvalValue::Nil)
+    {
+        loaded.borrow_mut().set(name.clone(), module)?;
+    }
+
+    let module = loaded.borrow().get(&name);
+    let module = match module {
+        EvalValue::Nil => {
+            loaded.borrow_mut().set(name, EvalValue::Boolean(true))?;
+            EvalValue::Boolean(true)
+        }
+        module => module,
+    };
+
+    Ok(vec![module, data])
+}
+
+/// Asks each of `package.searchers` in turn for a loader of the module `name`. Returns
+/// the loader and the value the searcher passes to it.
+fn find_loader(
+    virtual_machine: &mut VirtualMachine,
+    package: &EvalValue,
+    name: &LuaString,
+) -> Result<(EvalValue, EvalValue), LuaError> {
+    let EvalValue::Table(searchers) =
+        virtual_machine.index(package.clone(), EvalValue::string("searchers"))?
+    else {
+        return Err("'package.searchers' must be a table".into());
+    };
+
+    let mut messages = String::new();
+    for index in 1.. {
+        let searcher = searchers.borrow().get(&EvalValue::Integer(index));
+        if searcher == EvalValue::Nil {
+            break;
+        }
+
+        let mut result = virtual_machine
+            .call_function(searcher, vec![EvalValue::String(name.clone())])?
+            .into_iter();
+        match (result.next(), result.next()) {
+            (
+                Some(loader @ (EvalValue::NativeFunction(_) | EvalValue::DeclaredFunction(_))),
+                data,
+            ) => {
+                return Ok((loader, data.unwrap_or(EvalValue::Nil)));
+            }
+            (
+                Some(
+                    message @ (EvalValue::String(_) | EvalValue::Number(_) | EvalValue::Integer(_)),
+                ),
+                _,
+            ) => {
+                messages.push_str("\n\t");
+                messages.push_str(
+                    &message
+                        .to_lua_string()
+                        .expect("Value is a string")
+                        .to_str_lossy(),
+                );
+            }
+            _ => {}
+        }
+    }
+
+    Err(format!("module '{}' not found:{}", name, messages).into())
+}
+
+fn search_preload(preload: &TableRef, args: Vec<EvalValue>) -> Result<Vec<EvalValue>, LuaError> {
+    let name = check_string(&args, 1, "searcher")?;
+    Ok(
+        match preload.borrow().get(&EvalValue::String(name.clone())) {
+            EvalValue::Nil => vec![EvalValue::string(format!(
+                "no field package.preload['{}']",
+                name
+            ))],
+            loader => vec![loader, EvalValue::string(":preload:")],
+        },
+    )
+}
+
+fn search_lua(
+    virtual_machine: &mut VirtualMachine,
+    package: &TableRef,
+    args: Vec<EvalValue>,
+) -> Result<Vec<EvalValue>, LuaError> {
+    let name = check_string(&args, 1, "searcher")?;
+    let path = virtual_machine
+        .index(EvalValue::Table(package.clone()), EvalValue::string("path"))?
+        .to_lua_string()
+        .ok_or("'package.path' must be a string")?;
+
+    let filename = match find_file(
+        &name.to_str_lossy(),
+        &path.to_str_lossy(),
+        ".",
+        DIRECTORY_SEPARATOR,
+    ) {
+        Ok(filename) => filename,
+        Err(message) => return Ok(vec![EvalValue::string(message)]),
+    };
+
+    match load_file(virtual_machine, Some(&filename), b"bt", None) {
+        Ok(loader) => Ok(vec![loader, EvalValue::string(filename)]),
+        Err(err) => Err(format!(
+            "error loading module '{}' from file '{}':\n\t{}",
+            name,
+            filename,
+            err.message()
+        )
+        .into()),
+    }
+}
+
+/// Searches the modules registered from Rust with `register_module`.
+fn search_native(
+    virtual_machine: &mut VirtualMachine,
+    args: Vec<EvalValue>,
+) -> Result<Vec<EvalValue>, LuaError> {
+    let name = check_string(&args, 1, "searcher")?;
+    Ok(match virtual_machine.native_module(&name) {
+        Some(loader) => vec![loader, EvalValue::string(":native:")],
+        None => vec![EvalValue::string(format!("no native module '{}'", name))],
+    })
+}
+
+fn searchpath(_: &mut VirtualMachine, args: Vec<EvalValue>) -> Result<Vec<EvalValue>, LuaError> {
+    let name = check_string(&args, 1, "searchpath")?;
+    let path = check_string(&args, 2, "searchpath")?;
+    let separator = match args.get(2) {
+        None | Some(EvalValue::Nil) => ".".into(),
+        Some(_) => check_string(&args, 3, "searchpath")?,
+    };
+    let directory_separator = match args.get(3) {
+        None | Some(EvalValue::Nil) => DIRECTORY_SEPARATOR.into(),
+        Some(_) => check_string(&args, 4, "searchpath")?,
+    };
+
+    Ok(
+        match find_file(
+            &name.to_str_lossy(),
+            &path.to_str_lossy(),
+            &separator.to_str_lossy(),
+            &directory_separator.to_str_lossy(),
+        ) {
+            Ok(filename) => vec![EvalValue::string(filename)],
+            Err(message) => vec![EvalValue::Nil, EvalValue::string(message)],
+        },
+    )
+}
+
+fn loadlib(_: &mut VirtualMachine, args: Vec<EvalValue>) -> Result<Vec<EvalValue>, LuaError> {
+    check_string(&args, 1, "loadlib")?;
+    check_string(&args, 2, "loadlib")?;
+    Ok(vec![
+        EvalValue::Nil,
+        EvalValue::string(DYNAMIC_LIBRARIES_MESSAGE),
+        EvalValue::string("absent"),
+    ])
+}
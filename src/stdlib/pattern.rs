@@ -0,0 +1,415 @@
+//! Lua patterns, as used by `string.find`, `match`, `gmatch` and `gsub`. The matcher
+//! backtracks over the bytes of the subject like the one of the reference
+//! implementation, so patterns behave the same way, including their corner cases.
+
+use std::ops::Range;
+
+/// Maximum number of captures in a pattern.
+const MAX_CAPTURES: usize = 32;
+/// Maximum nesting of the matcher, which recurses on every quantifier and capture.
+const MAX_MATCH_DEPTH: usize = 200;
+
+const ESCAPE: u8 = b'%';
+const SPECIALS: &[u8] = b"^$*+?.([%-";
+
+/// A successful match: the range of the subject it covers and its captures.
+#[derive(Debug)]
+pub struct Match {
+    pub start: usize,
+    pub end: usize,
+    pub captures: Vec<Capture>,
+}
+
+#[derive(Debug, Clone)]
+pub enum Capture {
+    Substring(Range<usize>),
+    /// A `()` capture, which captures the position in the subject it is at.
+    Position(usize),
+}
+
+/// Whether `pattern` contains characters with a special meaning, i.e. it cannot be
+/// searched for as plain text.
+pub fn has_specials(pattern: &[u8]) -> bool {
+    pattern.iter().any(|c| SPECIALS.contains(c))
+}
+
+/// Finds the first match of `pattern` in `source` starting at `init` or later. A
+/// pattern starting with `^` only matches at `init`.
+pub fn find(source: &[u8], pattern: &[u8], init: usize) -> Result<Option<Match>, String> {
+    let (anchored, pattern) = match pattern.strip_prefix(b"^") {
+        Some(pattern) => (true, pattern),
+        None => (false, pattern),
+    };
+
+    for start in init..=source.len() {
+        if let Some(found) = match_at(source, pattern, start)? {
+            return Ok(Some(found));
+        }
+        if anchored {
+            break;
+        }
+    }
+
+    Ok(None)
+}
+
+/// Matches `pattern` against `source` at exactly `start`. A leading `^` has no special
+/// meaning here.
+pub fn match_at(source: &[u8], pattern: &[u8], start: usize) -> Result<Option<Match>, String> {
+    let mut state = MatchState {
+        source,
+        pattern,
+        depth: 0,
+        captures: Vec::new(),
+    };
+
+    match state.do_match(start, 0)? {
+        Some(end) => Ok(Some(Match {
+            start,
+            end,
+            captures: state.finish_captures()?,
+        })),
+        None => Ok(None),
+    }
+}
+
+#[derive(Debug, Clone, Copy)]
+enum CaptureEnd {
+    Unclosed,
+    Closed(usize),
+    Position,
+}
+
+struct MatchState<'a> {
+    source: &'a [u8],
+    pattern: &'a [u8],
+    depth: usize,
+    /// Start of every capture opened so far and where it ends, if it is closed.
+    captures: Vec<(usize, CaptureEnd)>,
+}
+
+impl MatchState<'_> {
+    fn finish_captures(&self) -> Result<Vec<Capture>, String> {
+        self.captures
+            .iter()
+            .map(|(start, end)| match end {
+                CaptureEnd::Closed(end) => Ok(Capture::Substring(*start..*end)),
+                CaptureEnd::Position => Ok(Capture::Position(*start)),
+                CaptureEnd::Unclosed => Err("unfinished capture".to_string()),
+            })
+            .collect()
+    }
+
+    /// Matches the pattern from `p` against the subject from `s`, returning where the
+    /// match ends.
+    fn do_match(&mut self, s: usize, p: usize) -> Result<Option<usize>, String> {
+        if self.depth == MAX_MATCH_DEPTH {
+            return Err("pattern too complex".to_string());
+        }
+
+        self.depth += 1;
+        let result = self.match_here(s, p);
+        self.depth -= 1;
+
+        result
+    }
+
+    fn match_here(&mut self, mut s: usize, mut p: usize) -> Result<Option<usize>, String> {
+        loop {
+            if p == self.pattern.len() {
+                return Ok(Some(s));
+            }
+
+            match self.pattern[p] {
+                b'(' if self.pattern.get(p + 1) == Some(&b')') => {
+                    return self.start_capture(s, p + 2, CaptureEnd::Position)
+                }
+                b'(' => return self.start_capture(s, p + 1, CaptureEnd::Unclosed),
+                b')' => return self.end_capture(s, p + 1),
+                b'$' if p + 1 == self.pattern.len() => {
+                    return Ok((s == self.source.len()).then_some(s));
+                }
+                ESCAPE if self.pattern.get(p + 1) == Some(&b'b') => {
+                    match self.match_balance(s, p + 2)? {
+                        Some(end) => {
+                            s = end;
+                            p += 4;
+                        }
+                        None => return Ok(None),
+                    }
+                }
+                ESCAPE if self.pattern.get(p + 1) == Some(&b'f') => {
+                    p += 2;
+                    if self.pattern.get(p) != Some(&b'[') {
+                        return Err("missing '[' after '%f' in pattern".to_string());
+                    }
+
+                    // The frontier is where the previous character is not in the set
+                    // and the current one is, with the subject bounded by '\0's
+                    let ep = self.class_end(p)?;
+                    let previous = if s == 0 { 0 } else { self.source[s - 1] };
+                    let current = self.source.get(s).copied().unwrap_or(0);
+                    if self.match_bracket_class(previous, p, ep - 1)
+                        || !self.match_bracket_class(current, p, ep - 1)
+                    {
+                        return Ok(None);
+                    }
+                    p = ep;
+                }
+                ESCAPE if self.pattern.get(p + 1).is_some_and(u8::is_ascii_digit) => {
+                    match self.match_capture(s, self.pattern[p + 1])? {
+                        Some(end) => {
+                            s = end;
+                            p += 2;
+                        }
+                        None => return Ok(None),
+                    }
+                }
+                _ => {
+                    let ep = self.class_end(p)?;
+                    let matches = s < self.source.len() && self.single_match(self.source[s], p, ep);
+
+                    match self.pattern.get(ep) {
+                        Some(b'?') => {
+                            if matches {
+                                if let Some(end) = self.do_match(s + 1, ep + 1)? {
+                                    return Ok(Some(end));
+                                }
+                            }
+                            p = ep + 1;
+                        }
+                        Some(b'+') if matches => return self.max_expand(s + 1, p, ep),
+                        Some(b'+') => return Ok(None),
+                        Some(b'*') => return self.max_expand(s, p, ep),
+                        Some(b'-') => return self.min_expand(s, p, ep),
+                        _ if matches => {
+                            s += 1;
+                            p = ep;
+                        }
+                        _ => return Ok(None),
+                    }
+                }
+            }
+        }
+    }
+
+    /// Matches as many repetitions of the single character class at `p` as possible,
+    /// giving them back one at a time until the rest of the pattern matches.
+    fn max_expand(&mut self, s: usize, p: usize, ep: usize) -> Result<Option<usize>, String> {
+        let mut count = 0;
+        while s + count < self.source.len() && self.single_match(self.source[s + count], p, ep) {
+            count += 1;
+        }
+
+        loop {
+            if let Some(end) = self.do_match(s + count, ep + 1)? {
+                return Ok(Some(end));
+            }
+            if count == 0 {
+                return Ok(None);
+            }
+            count -= 1;
+        }
+    }
+
+    /// Matches as few repetitions of the single character class at `p` as possible.
+    fn min_expand(&mut self, mut s: usize, p: usize, ep: usize) -> Result<Option<usize>, String> {
+        loop {
+            if let Some(end) = self.do_match(s, ep + 1)? {
+                return Ok(Some(end));
+            }
+            if s < self.source.len() && self.single_match(self.source[s], p, ep) {
+                s += 1;
+            } else {
+                return Ok(None);
+            }
+        }
+    }
+
+    fn start_capture(
+        &mut self,
+        s: usize,
+        p: usize,
+        end: CaptureEnd,
+    ) -> Result<Option<usize>, String> {
+        if self.captures.len() == MAX_CAPTURES {
+            return Err("too many captures".to_string());
+        }
+
+        self.captures.push((s, end));
+        let result = self.do_match(s, p)?;
+        if result.is_none() {
+            self.captures.pop();
+        }
+
+        Ok(result)
+    }
+
+    fn end_capture(&mut self, s: usize, p: usize) -> Result<Option<usize>, String> {
+        let index = self
+            .captures
+            .iter()
+            .rposition(|(_, end)| matches!(end, CaptureEnd::Unclosed))
+            .ok_or("invalid pattern capture")?;
+
+        self.captures[index].1 = CaptureEnd::Closed(s);
+        let result = self.do_match(s, p)?;
+        if result.is_none() {
+            self.captures[index].1 = CaptureEnd::Unclosed;
+        }
+
+        Ok(result)
+    }
+
+    /// Matches `%bxy` at `s`, with `x` and `y` at `p`: a string starting with `x` and
+    /// ending with the `y` that balances it.
+    fn match_balance(&self, s: usize, p: usize) -> Result<Option<usize>, String> {
+        let (open, close) = match self.pattern.get(p..p + 2) {
+            Some(&[open, close]) => (open, close),
+            _ => return Err("malformed pattern (missing arguments to '%b')".to_string()),
+        };
+
+        if self.source.get(s) != Some(&open) {
+            return Ok(None);
+        }
+
+        let mut depth = 1;
+        for (i, c) in self.source.iter().enumerate().skip(s + 1) {
+            if *c == close {
+                depth -= 1;
+                if depth == 0 {
+                    return Ok(Some(i + 1));
+                }
+            } else if *c == open {
+                depth += 1;
+            }
+        }
+
+        Ok(None)
+    }
+
+    /// Matches the back-reference `%1` to `%9` at `s`, i.e. the same text again.
+    fn match_capture(&self, s: usize, digit: u8) -> Result<Option<usize>, String> {
+        let index = (digit as usize).wrapping_sub(b'1' as usize);
+        let captured = match self.captures.get(index) {
+            Some((start, CaptureEnd::Closed(end))) => &self.source[*start..*end],
+            // A position capture never matches as it has no text
+            Some((_, CaptureEnd::Position)) => return Ok(None),
+            _ => {
+                return Err(format!(
+                    "invalid capture index %{} in pattern",
+                    index.wrapping_add(1) as isize
+                ))
+            }
+        };
+
+        Ok(self.source[s..]
+            .starts_with(captured)
+            .then_some(s + captured.len()))
+    }
+
+    /// Returns the end of the single character class starting at `p`.
+    fn class_end(&self, p: usize) -> Result<usize, String> {
+        let pattern = self.pattern;
+        let mut p = p;
+        let c = pattern[p];
+        p += 1;
+
+        match c {
+            ESCAPE => {
+                if p == pattern.len() {
+                    return Err("malformed pattern (ends with '%')".to_string());
+                }
+                Ok(p + 1)
+            }
+            b'[' => {
+                if pattern.get(p) == Some(&b'^') {
+                    p += 1;
+                }
+
+                // The first character of a set is never its end, so `[]]` matches ']'
+                loop {
+                    if p == pattern.len() {
+                        return Err("malformed pattern (missing ']')".to_string());
+                    }
+                    let c = pattern[p];
+                    p += 1;
+                    if c == ESCAPE && p < pattern.len() {
+                        p += 1;
+                    }
+                    match pattern.get(p) {
+                        Some(b']') => return Ok(p + 1),
+                        Some(_) => {}
+                        None => return Err("malformed pattern (missing ']')".to_string()),
+                    }
+                }
+            }
+            _ => Ok(p),
+        }
+    }
+
+    /// Whether `c` matches the single character class between `p` and `ep`.
+    fn single_match(&self, c: u8, p: usize, ep: usize) -> bool {
+        match self.pattern[p] {
+            b'.' => true,
+            ESCAPE => match_class(c, self.pattern[p + 1]),
+            b'[' => self.match_bracket_class(c, p, ep - 1),
+            literal => literal == c,
+        }
+    }
+
+    /// Whether `c` is in the set between the `[` at `p` and the `]` at `end`.
+    fn match_bracket_class(&self, c: u8, mut p: usize, end: usize) -> bool {
+        let pattern = self.pattern;
+        let mut found = true;
+        if pattern[p + 1] == b'^' {
+            found = false;
+            p += 1;
+        }
+
+        p += 1;
+        while p < end {
+            if pattern[p] == ESCAPE {
+                p += 1;
+                if match_class(c, pattern[p]) {
+                    return found;
+                }
+            } else if pattern[p + 1] == b'-' && p + 2 < end {
+                if pattern[p] <= c && c <= pattern[p + 2] {
+                    return found;
+                }
+                p += 2;
+            } else if pattern[p] == c {
+                return found;
+            }
+            p += 1;
+        }
+
+        !found
+    }
+}
+
+/// Whether `c` is in the class `%class`. Upper case classes are the complement of
+/// the lower case ones, and other characters stand for themselves.
+fn match_class(c: u8, class: u8) -> bool {
+    let matches = match class.to_ascii_lowercase() {
+        b'a' => c.is_ascii_alphabetic(),
+        b'c' => c.is_ascii_control(),
+        b'd' => c.is_ascii_digit(),
+        b'g' => c.is_ascii_graphic(),
+        b'l' => c.is_ascii_lowercase(),
+        b'p' => c.is_ascii_punctuation(),
+        // Unlike `is_ascii_whitespace`, C's `isspace` includes the vertical tab
+        b's' => c.is_ascii_whitespace() || c == 0x0b,
+        b'u' => c.is_ascii_uppercase(),
+        b'w' => c.is_ascii_alphanumeric(),
+        b'x' => c.is_ascii_hexdigit(),
+        _ => return class == c,
+    };
+
+    if class.is_ascii_uppercase() {
+        !matches
+    } else {
+        matches
+    }
+}
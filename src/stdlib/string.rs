@@ -0,0 +1,883 @@
+use std::{
+    borrow::Cow,
+    cell::{Cell, RefCell},
+    rc::Rc,
+};
+
+use crate::{
+    ast::{EvalValue, LuaString, Table},
+    error::LuaError,
+    vm::VirtualMachine,
+};
+
+use super::{
+    argument_error, check_integer, check_number, check_string, create_library, display_string,
+    opt_integer, pack, pattern, tostring, type_error, value_address,
+};
+
+/// Longest string `rep` and `format` are allowed to build.
+const MAX_STRING_SIZE: usize = i32::MAX as usize;
+
+pub fn open(virtual_machine: &mut VirtualMachine) -> EvalValue {
+    let library = create_library(&[
+        ("len", len),
+        ("sub", sub),
+        ("upper", upper),
+        ("lower", lower),
+        ("rep", rep),
+        ("reverse", reverse),
+        ("byte", byte),
+        ("char", char),
+        ("format", format),
+        ("find", find),
+        ("match", string_match),
+        ("gmatch", gmatch),
+        ("gsub", gsub),
+        ("pack", pack::pack),
+        ("packsize", pack::packsize),
+        ("unpack", pack::unpack),
+    ]);
+
+    // All strings share a metatable, so `s:upper()` finds the functions of the library
+    let mut metatable = Table::default();
+    metatable
+        .set(EvalValue::string("__index"), library.clone())
+        .expect("Metamethod names are valid table keys");
+    virtual_machine.set_type_metatable("string", Some(Rc::new(RefCell::new(metatable))));
+
+    library
+}
+
+/// Converts a possibly negative string index to a position counting from 1, for the
+/// start of a range. Indices before the start of the string are clamped to 1.
+fn start_position(index: i64, length: usize) -> usize {
+    match index {
+        1.. => index as usize,
+        0 => 1,
+        _ if index.unsigned_abs() > length as u64 => 1,
+        _ => length - index.unsigned_abs() as usize + 1,
+    }
+}
+
+/// Like `start_position`, for the end of a range, which is clamped to the string.
+fn end_position(index: i64, length: usize) -> usize {
+    match index {
+        _ if index > length as i64 => length,
+        0.. => index as usize,
+        _ if index.unsigned_abs() > length as u64 => 0,
+        _ => length - index.unsigned_abs() as usize + 1,
+    }
+}
+
+fn len(_: &mut VirtualMachine, args: Vec<EvalValue>) -> Result<Vec<EvalValue>, LuaError> {
+    let string = check_string(&args, 1, "len")?;
+    Ok(vec![EvalValue::Integer(string.len() as i64)])
+}
+
+fn sub(_: &mut VirtualMachine, args: Vec<EvalValue>) -> Result<Vec<EvalValue>, LuaError> {
+    let string = check_string(&args, 1, "sub")?;
+    let start = start_position(opt_integer(&args, 2, "sub", 1)?, string.len());
+    let end = end_position(opt_integer(&args, 3, "sub", -1)?, string.len());
+
+    if start > end {
+        return Ok(vec![EvalValue::string("")]);
+    }
+    Ok(vec![EvalValue::string(&string[start - 1..end])])
+}
+
+fn upper(_: &mut VirtualMachine, args: Vec<EvalValue>) -> Result<Vec<EvalValue>, LuaError> {
+    let string = check_string(&args, 1, "upper")?;
+    Ok(vec![EvalValue::string(string.to_ascii_uppercase())])
+}
+
+fn lower(_: &mut VirtualMachine, args: Vec<EvalValue>) -> Result<Vec<EvalValue>, LuaError> {
+    let string = check_string(&args, 1, "lower")?;
+    Ok(vec![EvalValue::string(string.to_ascii_lowercase())])
+}
+
+fn rep(_: &mut VirtualMachine, args: Vec<EvalValue>) -> Result<Vec<EvalValue>, LuaError> {
+    let string = check_string(&args, 1, "rep")?;
+    let count = check_integer(&args, 2, "rep")?;
+    let separator = match args.get(2) {
+        None | Some(EvalValue::Nil) => LuaString::default(),
+        Some(_) => check_string(&args, 3, "rep")?,
+    };
+
+    if count <= 0 {
+        return Ok(vec![EvalValue::string("")]);
+    }
+
+    let count = count as usize;
+    let size = (string.len() + separator.len())
+        .checked_mul(count)
+        .filter(|size| *size <= MAX_STRING_SIZE)
+        .ok_or("resulting string too large")?;
+
+    let mut result = Vec::new();
+    LuaString::reserve(&mut result, size)?;
+    for i in 0..count {
+        if i > 0 {
+            result.extend_from_slice(&separator);
+        }
+        result.extend_from_slice(&string);
+    }
+
+    Ok(vec![EvalValue::string(result)])
+}
+
+fn reverse(_: &mut VirtualMachine, args: Vec<EvalValue>) -> Result<Vec<EvalValue>, LuaError> {
+    let mut string = check_string(&args, 1, "reverse")?.to_vec();
+    string.reverse();
+    Ok(vec![EvalValue::string(string)])
+}
+
+fn byte(_: &mut VirtualMachine, args: Vec<EvalValue>) -> Result<Vec<EvalValue>, LuaError> {
+    let string = check_string(&args, 1, "byte")?;
+    let start_index = opt_integer(&args, 2, "byte", 1)?;
+    let start = start_position(start_index, string.len());
+    let end = end_position(opt_integer(&args, 3, "byte", start as i64)?, string.len());
+
+    if start > end {
+        return Ok(Vec::new());
+    }
+
+    Ok(string[start - 1..end]
+        .iter()
+        .map(|byte| EvalValue::Integer(*byte as i64))
+        .collect())
+}
+
+fn char(_: &mut VirtualMachine, args: Vec<EvalValue>) -> Result<Vec<EvalValue>, LuaError> {
+    let mut string = Vec::with_capacity(args.len());
+
+    for position in 1..=args.len() {
+        let code = check_integer(&args, position, "char")?;
+        let byte = u8::try_from(code)
+            .map_err(|_| argument_error(position, "char", "value out of range"))?;
+        string.push(byte);
+    }
+
+    Ok(vec![EvalValue::string(string)])
+}
+
+/// A conversion specification of `string.format`, e.g. `%-08.3f`.
+#[derive(Debug, Default)]
+struct FormatSpec {
+    left_align: bool,
+    plus_sign: bool,
+    space_sign: bool,
+    alternate: bool,
+    zero_pad: bool,
+    width: usize,
+    precision: Option<usize>,
+}
+
+impl FormatSpec {
+    /// Reads the flags, width and precision at the start of `format`, returning the
+    /// specification and the number of bytes it takes. Like in Lua, the width and the
+    /// precision have at most two digits.
+    fn parse(format: &[u8]) -> (FormatSpec, usize) {
+        let mut spec = FormatSpec::default();
+        let mut length = 0;
+
+        while let Some(flag) = format.get(length) {
+            match flag {
+                b'-' => spec.left_align = true,
+                b'+' => spec.plus_sign = true,
+                b' ' => spec.space_sign = true,
+                b'#' => spec.alternate = true,
+                b'0' => spec.zero_pad = true,
+                _ => break,
+            }
+            length += 1;
+        }
+
+        let read_number = |length: &mut usize| {
+            let mut value = 0;
+            for _ in 0..2 {
+                match format.get(*length) {
+                    Some(digit @ b'0'..=b'9') => {
+                        value = value * 10 + (digit - b'0') as usize;
+                        *length += 1;
+                    }
+                    _ => break,
+                }
+            }
+            value
+        };
+
+        spec.width = read_number(&mut length);
+        if format.get(length) == Some(&b'.') {
+            length += 1;
+            spec.precision = Some(read_number(&mut length));
+        }
+
+        (spec, length)
+    }
+
+    fn has_flags(&self) -> bool {
+        self.left_align || self.plus_sign || self.space_sign || self.alternate || self.zero_pad
+    }
+
+    /// Whether the specification only uses the flags in `allowed`, and a precision if
+    /// `precision` is allowed.
+    fn is_valid(&self, allowed: &str, precision: bool) -> bool {
+        let flags = [
+            (self.left_align, '-'),
+            (self.plus_sign, '+'),
+            (self.space_sign, ' '),
+            (self.alternate, '#'),
+            (self.zero_pad, '0'),
+        ];
+
+        flags
+            .iter()
+            .all(|(set, flag)| !set || allowed.contains(*flag))
+            && (precision || self.precision.is_none())
+    }
+
+    fn sign(&self, negative: bool) -> &'static str {
+        if negative {
+            "-"
+        } else if self.plus_sign {
+            "+"
+        } else if self.space_sign {
+            " "
+        } else {
+            ""
+        }
+    }
+
+    /// Pads `prefix` (a sign or a base) followed by `digits` to the width. Zeros go
+    /// between the two when zero padding applies.
+    fn pad_number(&self, prefix: &str, digits: &str, zero_pad: bool) -> Vec<u8> {
+        let length = prefix.len() + digits.len();
+        let padding = self.width.saturating_sub(length);
+
+        let text = if self.left_align {
+            format!("{}{}{}", prefix, digits, " ".repeat(padding))
+        } else if zero_pad && self.zero_pad {
+            format!("{}{}{}", prefix, "0".repeat(padding), digits)
+        } else {
+            format!("{}{}{}", " ".repeat(padding), prefix, digits)
+        };
+
+        text.into_bytes()
+    }
+
+    fn pad<'a>(&self, text: &'a [u8]) -> Cow<'a, [u8]> {
+        let padding = vec![b' '; self.width.saturating_sub(text.len())];
+
+        if padding.is_empty() {
+            Cow::Borrowed(text)
+        } else if self.left_align {
+            Cow::Owned([text, &padding].concat())
+        } else {
+            Cow::Owned([&padding, text].concat())
+        }
+    }
+
+    fn format_integer(&self, value: i64) -> Vec<u8> {
+        let digits = self.apply_integer_precision(value.unsigned_abs().to_string());
+        self.pad_number(self.sign(value < 0), &digits, self.precision.is_none())
+    }
+
+    /// Formats the bits of `value` as an unsigned number in `radix`, for `%u`, `%o`,
+    /// `%x` and `%X`.
+    fn format_unsigned(&self, value: i64, conversion: u8) -> Vec<u8> {
+        let value = value as u64;
+        let digits = match conversion {
+            b'o' => format!("{:o}", value),
+            b'x' => format!("{:x}", value),
+            b'X' => format!("{:X}", value),
+            _ => value.to_string(),
+        };
+
+        let mut digits = self.apply_integer_precision(digits);
+        let prefix = match conversion {
+            b'x' if self.alternate && value != 0 => "0x",
+            b'X' if self.alternate && value != 0 => "0X",
+            _ => "",
+        };
+        if conversion == b'o' && self.alternate && !digits.starts_with('0') {
+            digits.insert(0, '0');
+        }
+
+        self.pad_number(prefix, &digits, self.precision.is_none())
+    }
+
+    /// A precision on an integer conversion is its minimum number of digits.
+    fn apply_integer_precision(&self, digits: String) -> String {
+        match self.precision {
+            Some(0) if digits == "0" => String::new(),
+            Some(precision) if digits.len() < precision => {
+                format!("{}{}", "0".repeat(precision - digits.len()), digits)
+            }
+            _ => digits,
+        }
+    }
+
+    fn format_float(&self, value: f64, conversion: u8) -> Vec<u8> {
+        let uppercase = conversion.is_ascii_uppercase();
+        let sign = self.sign(value.is_sign_negative() && !value.is_nan());
+
+        if !value.is_finite() {
+            let text = match (value.is_nan(), uppercase) {
+                (true, false) => "nan",
+                (true, true) => "NAN",
+                (false, false) => "inf",
+                (false, true) => "INF",
+            };
+            return self.pad_number(sign, text, false);
+        }
+
+        let value = value.abs();
+        let digits = match conversion.to_ascii_lowercase() {
+            b'f' => self.fixed(value, self.precision.unwrap_or(6)),
+            b'e' => self.exponential(value, self.precision.unwrap_or(6)),
+            b'g' => self.general(value),
+            _ => self.hexadecimal(value),
+        };
+        let digits = if uppercase {
+            digits.to_ascii_uppercase()
+        } else {
+            digits
+        };
+
+        self.pad_number(sign, &digits, true)
+    }
+
+    fn fixed(&self, value: f64, precision: usize) -> String {
+        let mut digits = format!("{:.*}", precision, value);
+        if self.alternate && precision == 0 {
+            digits.push('.');
+        }
+        digits
+    }
+
+    fn exponential(&self, value: f64, precision: usize) -> String {
+        let formatted = format!("{:.*e}", precision, value);
+        let (mantissa, exponent) = formatted
+            .split_once('e')
+            .expect("Exponent is always present");
+        let exponent: i32 = exponent.parse().expect("Exponent is a valid integer");
+
+        let point = if self.alternate && precision == 0 {
+            "."
+        } else {
+            ""
+        };
+        let exponent_sign = if exponent < 0 { '-' } else { '+' };
+
+        format!(
+            "{}{}e{}{:02}",
+            mantissa,
+            point,
+            exponent_sign,
+            exponent.abs()
+        )
+    }
+
+    /// `%g`: the shorter of `%e` and `%f` for the precision, without trailing zeros
+    /// unless the `#` flag is given.
+    fn general(&self, value: f64) -> String {
+        let precision = self.precision.unwrap_or(6).max(1);
+        let formatted = format!("{:.*e}", precision - 1, value);
+        let (_, exponent) = formatted
+            .split_once('e')
+            .expect("Exponent is always present");
+        let exponent: i64 = exponent.parse().expect("Exponent is a valid integer");
+
+        let digits = if exponent < -4 || exponent >= precision as i64 {
+            self.exponential(value, precision - 1)
+        } else {
+            self.fixed(value, (precision as i64 - 1 - exponent) as usize)
+        };
+
+        if self.alternate {
+            if !digits.contains('.') {
+                return match digits.split_once('e') {
+                    Some((mantissa, exponent)) => format!("{}.e{}", mantissa, exponent),
+                    None => digits + ".",
+                };
+            }
+            return digits;
+        }
+
+        let (mantissa, exponent) = match digits.split_once('e') {
+            Some((mantissa, exponent)) => (mantissa.to_string(), format!("e{}", exponent)),
+            None => (digits, String::new()),
+        };
+        let mantissa = if mantissa.contains('.') {
+            mantissa.trim_end_matches('0').trim_end_matches('.')
+        } else {
+            &mantissa
+        };
+
+        format!("{}{}", mantissa, exponent)
+    }
+
+    /// `%a`: the value in hexadecimal scientific notation, like C's `printf`.
+    fn hexadecimal(&self, value: f64) -> String {
+        let bits = value.to_bits();
+        let biased_exponent = ((bits >> 52) & 0x7ff) as i64;
+        let mut mantissa = bits & ((1 << 52) - 1);
+
+        let (mut leading, exponent) = match (biased_exponent, mantissa) {
+            (0, 0) => (0, 0),
+            // Subnormal numbers are written with a leading 0 and the minimum exponent
+            (0, _) => (0, -1022),
+            _ => (1, biased_exponent - 1023),
+        };
+
+        // The 52 bits of the mantissa are 13 hexadecimal digits
+        let digits = match self.precision {
+            Some(precision) if precision < 13 => {
+                let dropped_bits = 52 - 4 * precision as u32;
+                let remainder = mantissa & ((1 << dropped_bits) - 1);
+                let half = 1 << (dropped_bits - 1);
+                mantissa >>= dropped_bits;
+
+                // Round half to even, carrying into the leading digit
+                if remainder > half || (remainder == half && mantissa & 1 == 1) {
+                    mantissa += 1;
+                    if mantissa >> (4 * precision) != 0 {
+                        mantissa &= (1 << (4 * precision)) - 1;
+                        leading += 1;
+                    }
+                }
+
+                if precision == 0 {
+                    String::new()
+                } else {
+                    format!("{:0width$x}", mantissa, width = precision)
+                }
+            }
+            Some(precision) => format!("{:013x}{}", mantissa, "0".repeat(precision - 13)),
+            None => format!("{:013x}", mantissa)
+                .trim_end_matches('0')
+                .to_string(),
+        };
+
+        let point = if !digits.is_empty() || self.alternate {
+            "."
+        } else {
+            ""
+        };
+        let exponent_sign = if exponent < 0 { '-' } else { '+' };
+
+        format!(
+            "0x{}{}{}p{}{}",
+            leading,
+            point,
+            digits,
+            exponent_sign,
+            exponent.abs()
+        )
+    }
+}
+
+/// Writes `value` as Lua source code that reads back as the same value, for `%q`.
+fn quote_value(value: &EvalValue, argument: usize, output: &mut Vec<u8>) -> Result<(), LuaError> {
+    match value {
+        EvalValue::String(string) => {
+            LuaString::reserve(output, string.len() + 2)?;
+            output.push(b'"');
+            for (i, byte) in string.iter().enumerate() {
+                match byte {
+                    b'"' | b'\\' | b'\n' => output.extend_from_slice(&[b'\\', *byte]),
+                    byte if byte.is_ascii_control() => {
+                        // A following digit would be read as part of a short escape
+                        if string.get(i + 1).is_some_and(u8::is_ascii_digit) {
+                            output.extend_from_slice(format!("\\{:03}", byte).as_bytes());
+                        } else {
+                            output.extend_from_slice(format!("\\{}", byte).as_bytes());
+                        }
+                    }
+                    byte => output.push(*byte),
+                }
+            }
+            output.push(b'"');
+        }
+        EvalValue::Number(n) => {
+            let text = if n.is_nan() {
+                "(0/0)".to_string()
+            } else if n.is_infinite() {
+                if *n > 0.0 { "1e9999" } else { "-1e9999" }.to_string()
+            } else {
+                String::from_utf8(FormatSpec::default().format_float(*n, b'a'))
+                    .expect("Formatted numbers are ASCII")
+            };
+            output.extend_from_slice(text.as_bytes());
+        }
+        // The smallest integer has no decimal literal, as its negation overflows
+        EvalValue::Integer(i64::MIN) => output.extend_from_slice(b"0x8000000000000000"),
+        EvalValue::Integer(i) => output.extend_from_slice(i.to_string().as_bytes()),
+        EvalValue::Nil | EvalValue::Boolean(_) => {
+            output.extend_from_slice(&display_string(value));
+        }
+        _ => {
+            return Err(argument_error(
+                argument,
+                "format",
+                "value has no literal form",
+            ))
+        }
+    }
+
+    Ok(())
+}
+
+fn format(
+    virtual_machine: &mut VirtualMachine,
+    args: Vec<EvalValue>,
+) -> Result<Vec<EvalValue>, LuaError> {
+    let format = check_string(&args, 1, "format")?;
+    let mut output = Vec::new();
+    let mut argument = 1;
+    let mut i = 0;
+
+    while i < format.len() {
+        if format[i] != b'%' {
+            output.push(format[i]);
+            i += 1;
+            continue;
+        }
+        if format.get(i + 1) == Some(&b'%') {
+            output.push(b'%');
+            i += 2;
+            continue;
+        }
+
+        let (spec, length) = FormatSpec::parse(&format[i + 1..]);
+        let conversion = format.get(i + 1 + length).copied();
+        let specification = &format[i..(i + 2 + length).min(format.len())];
+        i += 2 + length;
+
+        let invalid = || {
+            LuaError::runtime(format!(
+                "invalid conversion '{}' to 'format'",
+                String::from_utf8_lossy(specification)
+            ))
+        };
+        let valid = match conversion {
+            Some(b'c' | b'p') => spec.is_valid("-", false),
+            Some(b'd' | b'i') => spec.is_valid("-+ 0", true),
+            Some(b'u') => spec.is_valid("-0", true),
+            Some(b'o' | b'x' | b'X') => spec.is_valid("-#0", true),
+            Some(b'a' | b'A' | b'e' | b'E' | b'f' | b'F' | b'g' | b'G') => {
+                spec.is_valid("-+ #0", true)
+            }
+            Some(b's') => spec.is_valid("-", true),
+            Some(b'q') => {
+                if spec.has_flags() || spec.width != 0 || spec.precision.is_some() {
+                    return Err("specifier '%q' cannot have modifiers".into());
+                }
+                true
+            }
+            _ => false,
+        };
+        if !valid {
+            return Err(invalid());
+        }
+
+        argument += 1;
+        if args.len() < argument {
+            return Err(argument_error(argument, "format", "no value"));
+        }
+
+        match conversion.expect("Conversion is valid") {
+            b'c' => {
+                let code = check_integer(&args, argument, "format")?;
+                output.extend_from_slice(&spec.pad(&[code as u8]));
+            }
+            b'd' | b'i' => {
+                let value = check_integer(&args, argument, "format")?;
+                output.extend(spec.format_integer(value));
+            }
+            conversion @ (b'u' | b'o' | b'x' | b'X') => {
+                let value = check_integer(&args, argument, "format")?;
+                output.extend(spec.format_unsigned(value, conversion));
+            }
+            conversion @ (b'a' | b'A' | b'e' | b'E' | b'f' | b'F' | b'g' | b'G') => {
+                let value = check_number(&args, argument, "format")?;
+                output.extend(spec.format_float(value, conversion));
+            }
+            b'p' => {
+                let pointer = match value_address(&args[argument - 1]) {
+                    Some(address) => format!("{:p}", address),
+                    None => "(null)".to_string(),
+                };
+                output.extend_from_slice(&spec.pad(pointer.as_bytes()));
+            }
+            b'q' => quote_value(&args[argument - 1], argument, &mut output)?,
+            _ => {
+                let string = tostring(virtual_machine, &args[argument - 1])?;
+                let string = match spec.precision {
+                    Some(precision) => &string[..precision.min(string.len())],
+                    None => &string[..],
+                };
+                LuaString::reserve(&mut output, string.len().max(spec.width))?;
+                output.extend_from_slice(&spec.pad(string));
+            }
+        }
+
+        if output.len() > MAX_STRING_SIZE {
+            return Err("resulting string too large".into());
+        }
+    }
+
+    Ok(vec![EvalValue::string(output)])
+}
+
+/// The value of a capture: the captured text, or the position counting from 1.
+fn capture_value(source: &[u8], capture: &pattern::Capture) -> EvalValue {
+    match capture {
+        pattern::Capture::Substring(range) => EvalValue::string(&source[range.clone()]),
+        pattern::Capture::Position(position) => EvalValue::Integer((position + 1) as i64),
+    }
+}
+
+/// The values a match produces: its captures, or the whole match if the pattern has
+/// none and `whole_if_none` is set.
+fn capture_values(source: &[u8], found: &pattern::Match, whole_if_none: bool) -> Vec<EvalValue> {
+    if found.captures.is_empty() && whole_if_none {
+        return vec![EvalValue::string(&source[found.start..found.end])];
+    }
+
+    found
+        .captures
+        .iter()
+        .map(|capture| capture_value(source, capture))
+        .collect()
+}
+
+/// Shared implementation of `find` and `match`, which differ in what they return.
+fn find_or_match(
+    args: &[EvalValue],
+    function_name: &str,
+    find: bool,
+) -> Result<Vec<EvalValue>, LuaError> {
+    let source = check_string(args, 1, function_name)?;
+    let pattern = check_string(args, 2, function_name)?;
+    let init = start_position(opt_integer(args, 3, function_name, 1)?, source.len());
+
+    if init > source.len() + 1 {
+        return Ok(vec![EvalValue::Nil]);
+    }
+
+    let plain = args.get(3).is_some_and(EvalValue::is_true);
+    if find && (plain || !pattern::has_specials(&pattern)) {
+        let position = source[init - 1..]
+            .windows(pattern.len().max(1))
+            .position(|window| window == &pattern[..])
+            .or_else(|| pattern.is_empty().then_some(0));
+
+        return Ok(match position {
+            Some(position) => {
+                let start = init + position;
+                vec![
+                    EvalValue::Integer(start as i64),
+                    EvalValue::Integer((start + pattern.len() - 1) as i64),
+                ]
+            }
+            None => vec![EvalValue::Nil],
+        });
+    }
+
+    let found = pattern::find(&source, &pattern, init - 1).map_err(LuaError::runtime)?;
+    Ok(match found {
+        Some(found) if find => {
+            let mut values = vec![
+                EvalValue::Integer((found.start + 1) as i64),
+                EvalValue::Integer(found.end as i64),
+            ];
+            values.extend(capture_values(&source, &found, false));
+            values
+        }
+        Some(found) => capture_values(&source, &found, true),
+        None => vec![EvalValue::Nil],
+    })
+}
+
+fn find(_: &mut VirtualMachine, args: Vec<EvalValue>) -> Result<Vec<EvalValue>, LuaError> {
+    find_or_match(&args, "find", true)
+}
+
+fn string_match(_: &mut VirtualMachine, args: Vec<EvalValue>) -> Result<Vec<EvalValue>, LuaError> {
+    find_or_match(&args, "match", false)
+}
+
+fn gmatch(_: &mut VirtualMachine, args: Vec<EvalValue>) -> Result<Vec<EvalValue>, LuaError> {
+    let source = check_string(&args, 1, "gmatch")?;
+    let pattern = check_string(&args, 2, "gmatch")?;
+    let init = start_position(opt_integer(&args, 3, "gmatch", 1)?, source.len());
+
+    let position = Cell::new((init - 1).min(source.len()));
+    // End of the last match, so an empty match cannot follow right after it
+    let last_match = Cell::new(None);
+
+    let iterator = move |_: &mut VirtualMachine, _: Vec<EvalValue>| {
+        let mut start = position.get();
+
+        while start <= source.len() {
+            match pattern::match_at(&source, &pattern, start).map_err(LuaError::runtime)? {
+                Some(found) if Some(found.end) != last_match.get() => {
+                    position.set(found.end);
+                    last_match.set(Some(found.end));
+                    return Ok(capture_values(&source, &found, true));
+                }
+                _ => start += 1,
+            }
+        }
+
+        position.set(start);
+        Ok(vec![EvalValue::Nil])
+    };
+
+    Ok(vec![EvalValue::native_function(iterator)])
+}
+
+/// Appends the replacement string of `gsub` for a match, where `%0` to `%9` stand for
+/// the whole match and its captures.
+fn append_replacement(
+    output: &mut Vec<u8>,
+    replacement: &[u8],
+    source: &[u8],
+    found: &pattern::Match,
+) -> Result<(), LuaError> {
+    let mut bytes = replacement.iter();
+
+    while let Some(byte) = bytes.next() {
+        if *byte != b'%' {
+            output.push(*byte);
+            continue;
+        }
+
+        match bytes.next() {
+            Some(b'%') => output.push(b'%'),
+            Some(b'0') => output.extend_from_slice(&source[found.start..found.end]),
+            Some(digit @ b'1'..=b'9') => {
+                let index = (digit - b'1') as usize;
+                match found.captures.get(index) {
+                    Some(capture) => output.extend_from_slice(
+                        &capture_value(source, capture)
+                            .to_lua_string()
+                            .expect("Captures are strings or numbers"),
+                    ),
+                    // Without captures, `%1` is the whole match
+                    None if index == 0 && found.captures.is_empty() => {
+                        output.extend_from_slice(&source[found.start..found.end])
+                    }
+                    None => {
+                        return Err(LuaError::runtime(format!(
+                            "invalid capture index %{} in replacement string",
+                            index + 1
+                        )))
+                    }
+                }
+            }
+            _ => return Err("invalid use of '%' in replacement string".into()),
+        }
+    }
+
+    Ok(())
+}
+
+fn gsub(
+    virtual_machine: &mut VirtualMachine,
+    args: Vec<EvalValue>,
+) -> Result<Vec<EvalValue>, LuaError> {
+    let source = check_string(&args, 1, "gsub")?;
+    let pattern = check_string(&args, 2, "gsub")?;
+    let replacement = match args.get(2) {
+        Some(
+            replacement @ (EvalValue::String(_)
+            | EvalValue::Number(_)
+            | EvalValue::Integer(_)
+            | EvalValue::Table(_)
+            | EvalValue::NativeFunction(_)
+            | EvalValue::DeclaredFunction(_)),
+        ) => replacement.clone(),
+        other => return Err(type_error(3, "gsub", "string/function/table", other)),
+    };
+    let max_replacements = opt_integer(&args, 4, "gsub", source.len() as i64 + 1)?;
+
+    let (anchored, pattern) = match pattern.strip_prefix(b"^") {
+        Some(pattern) => (true, pattern),
+        None => (false, &pattern[..]),
+    };
+
+    let mut output = Vec::with_capacity(source.len());
+    let mut position = 0;
+    let mut replacements = 0;
+    let mut last_match = None;
+
+    while replacements < max_replacements {
+        let found = pattern::match_at(&source, pattern, position).map_err(LuaError::runtime)?;
+
+        match found {
+            Some(found) if Some(found.end) != last_match => {
+                replacements += 1;
+                let matched = &source[found.start..found.end];
+
+                let value = match &replacement {
+                    EvalValue::Table(table) => {
+                        let key = capture_values(&source, &found, true).swap_remove(0);
+                        table.borrow().get(&key)
+                    }
+                    EvalValue::NativeFunction(_) | EvalValue::DeclaredFunction(_) => {
+                        virtual_machine
+                            .call_function(
+                                replacement.clone(),
+                                capture_values(&source, &found, true),
+                            )?
+                            .into_iter()
+                            .next()
+                            .unwrap_or(EvalValue::Nil)
+                    }
+                    replacement => {
+                        let replacement = replacement.to_lua_string().expect("Value is a string");
+                        append_replacement(&mut output, &replacement, &source, &found)?;
+                        position = found.end;
+                        last_match = Some(found.end);
+                        continue;
+                    }
+                };
+
+                // A false or nil result from a table or function keeps the match
+                match value {
+                    EvalValue::Nil | EvalValue::Boolean(false) => output.extend_from_slice(matched),
+                    value => match value.to_lua_string() {
+                        Some(value) => output.extend_from_slice(&value),
+                        None => {
+                            return Err(LuaError::runtime(format!(
+                                "invalid replacement value (a {})",
+                                value.type_name()
+                            )))
+                        }
+                    },
+                }
+
+                position = found.end;
+                last_match = Some(found.end);
+            }
+            _ if position < source.len() => {
+                output.push(source[position]);
+                position += 1;
+            }
+            _ => break,
+        }
+
+        if anchored {
+            break;
+        }
+    }
+
+    output.extend_from_slice(&source[position.min(source.len())..]);
+
+    Ok(vec![
+        EvalValue::string(output),
+        EvalValue::Integer(replacements),
+    ])
+}
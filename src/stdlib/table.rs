@@ -0,0 +1,438 @@
+use std::{cell::RefCell, rc::Rc, time::SystemTime};
+
+use crate::{
+    ast::{compare_values, EvalValue, LuaString, Table},
+    error::LuaError,
+    vm::VirtualMachine,
+};
+
+use super::{argument_error, check_integer, check_string, create_library, opt_integer, type_error};
+
+/// Largest number of values `unpack` returns.
+const MAX_UNPACK_RESULTS: u64 = 1_000_000;
+/// Size of a range from which `sort` starts choosing pivots at random.
+const RANDOM_PIVOT_LIMIT: i64 = 100;
+
+pub fn open(_virtual_machine: &mut VirtualMachine) -> EvalValue {
+    create_library(&[
+        ("concat", concat),
+        ("insert", insert),
+        ("pack", pack),
+        ("unpack", unpack),
+        ("remove", remove),
+        ("move", table_move),
+        ("sort", sort),
+    ])
+}
+
+/// Argument `position` of a table function. Other values are accepted if their
+/// metatable has all the metamethods in `events`, which the function needs to use
+/// them as tables.
+fn check_table(
+    virtual_machine: &VirtualMachine,
+    args: &[EvalValue],
+    position: usize,
+    function_name: &str,
+    events: &[&str],
+) -> Result<EvalValue, LuaError> {
+    match args.get(position - 1) {
+        Some(value @ EvalValue::Table(_)) => Ok(value.clone()),
+        Some(value)
+            if virtual_machine.metatable(value).is_some()
+                && events.iter().all(|event| {
+                    virtual_machine.get_metamethod(value, event) != EvalValue::Nil
+                }) =>
+        {
+            Ok(value.clone())
+        }
+        other => Err(type_error(position, function_name, "table", other)),
+    }
+}
+
+/// The length of `table`, which has to be an integer even if `__len` computes it.
+fn length(virtual_machine: &mut VirtualMachine, table: &EvalValue) -> Result<i64, LuaError> {
+    virtual_machine
+        .length(table)?
+        .to_integer()
+        .ok_or_else(|| LuaError::runtime("object length is not an integer"))
+}
+
+fn get(
+    virtual_machine: &mut VirtualMachine,
+    table: &EvalValue,
+    index: i64,
+) -> Result<EvalValue, LuaError> {
+    virtual_machine.index(table.clone(), EvalValue::Integer(index))
+}
+
+fn set(
+    virtual_machine: &mut VirtualMachine,
+    table: &EvalValue,
+    index: i64,
+    value: EvalValue,
+) -> Result<(), LuaError> {
+    virtual_machine.set_index(table.clone(), EvalValue::Integer(index), value)
+}
+
+fn concat(
+    virtual_machine: &mut VirtualMachine,
+    args: Vec<EvalValue>,
+) -> Result<Vec<EvalValue>, LuaError> {
+    let table = check_table(virtual_machine, &args, 1, "concat", &["__index", "__len"])?;
+    let length = length(virtual_machine, &table)?;
+    let separator = match args.get(1) {
+        None | Some(EvalValue::Nil) => Default::default(),
+        Some(_) => check_string(&args, 2, "concat")?,
+    };
+    let first = opt_integer(&args, 3, "concat", 1)?;
+    let last = opt_integer(&args, 4, "concat", length)?;
+
+    let mut output = Vec::new();
+    let mut index = first;
+    while index <= last {
+        match get(virtual_machine, &table, index)?.to_lua_string() {
+            Some(value) => {
+                LuaString::reserve(&mut output, value.len() + separator.len())?;
+                output.extend_from_slice(&value);
+            }
+            None => {
+                return Err(LuaError::runtime(format!(
+                    "invalid value (at index {}) in table for 'concat'",
+                    index
+                )))
+            }
+        }
+        if index == last {
+            break;
+        }
+        output.extend_from_slice(&separator);
+        index += 1;
+    }
+
+    Ok(vec![EvalValue::string(output)])
+}
+
+fn insert(
+    virtual_machine: &mut VirtualMachine,
+    args: Vec<EvalValue>,
+) -> Result<Vec<EvalValue>, LuaError> {
+    let table = check_table(
+        virtual_machine,
+        &args,
+        1,
+        "insert",
+        &["__index", "__newindex", "__len"],
+    )?;
+    // The first empty position
+    let end = length(virtual_machine, &table)?.wrapping_add(1);
+
+    let (position, value) = match args.len() {
+        2 => (end, args[1].clone()),
+        3 => {
+            let position = check_integer(&args, 2, "insert")?;
+            if (position as u64).wrapping_sub(1) >= end as u64 {
+                return Err(argument_error(2, "insert", "position out of bounds"));
+            }
+            for index in (position + 1..=end).rev() {
+                let value = get(virtual_machine, &table, index - 1)?;
+                set(virtual_machine, &table, index, value)?;
+            }
+            (position, args[2].clone())
+        }
+        _ => return Err(LuaError::runtime("wrong number of arguments to 'insert'")),
+    };
+
+    set(virtual_machine, &table, position, value)?;
+    Ok(Vec::new())
+}
+
+fn remove(
+    virtual_machine: &mut VirtualMachine,
+    args: Vec<EvalValue>,
+) -> Result<Vec<EvalValue>, LuaError> {
+    let table = check_table(
+        virtual_machine,
+        &args,
+        1,
+        "remove",
+        &["__index", "__newindex", "__len"],
+    )?;
+    let size = length(virtual_machine, &table)?;
+    let mut position = opt_integer(&args, 2, "remove", size)?;
+
+    // Any position up to one past the end can be given explicitly
+    if position != size && (position as u64).wrapping_sub(1) > size as u64 {
+        return Err(argument_error(2, "remove", "position out of bounds"));
+    }
+
+    let removed = get(virtual_machine, &table, position)?;
+    while position < size {
+        let value = get(virtual_machine, &table, position + 1)?;
+        set(virtual_machine, &table, position, value)?;
+        position += 1;
+    }
+    set(virtual_machine, &table, position, EvalValue::Nil)?;
+
+    Ok(vec![removed])
+}
+
+fn table_move(
+    virtual_machine: &mut VirtualMachine,
+    args: Vec<EvalValue>,
+) -> Result<Vec<EvalValue>, LuaError> {
+    let first = check_integer(&args, 2, "move")?;
+    let last = check_integer(&args, 3, "move")?;
+    let target = check_integer(&args, 4, "move")?;
+    let destination_position = match args.get(4) {
+        None | Some(EvalValue::Nil) => 1,
+        Some(_) => 5,
+    };
+    let source = check_table(virtual_machine, &args, 1, "move", &["__index"])?;
+    let destination = check_table(
+        virtual_machine,
+        &args,
+        destination_position,
+        "move",
+        &["__newindex"],
+    )?;
+
+    if last >= first {
+        if first <= 0 && last >= i64::MAX + first {
+            return Err(argument_error(3, "move", "too many elements to move"));
+        }
+        let count = last - first + 1;
+        if target > i64::MAX - count + 1 {
+            return Err(argument_error(4, "move", "destination wrap around"));
+        }
+
+        // Overlapping ranges of the same table are copied from the end, so elements are
+        // read before they are overwritten
+        let is_same_table = destination_position == 1 || source == destination;
+        let offsets: Box<dyn Iterator<Item = i64>> =
+            if target > last || target <= first || !is_same_table {
+                Box::new(0..count)
+            } else {
+                Box::new((0..count).rev())
+            };
+
+        for offset in offsets {
+            let value = get(virtual_machine, &source, first + offset)?;
+            set(virtual_machine, &destination, target + offset, value)?;
+        }
+    }
+
+    Ok(vec![destination])
+}
+
+fn pack(_: &mut VirtualMachine, args: Vec<EvalValue>) -> Result<Vec<EvalValue>, LuaError> {
+    let mut table = Table::default();
+    let count = args.len();
+
+    for (index, value) in args.into_iter().enumerate() {
+        table.set(EvalValue::Integer(index as i64 + 1), value)?;
+    }
+    table.set(EvalValue::string("n"), EvalValue::Integer(count as i64))?;
+
+    Ok(vec![EvalValue::Table(Rc::new(RefCell::new(table)))])
+}
+
+fn unpack(
+    virtual_machine: &mut VirtualMachine,
+    args: Vec<EvalValue>,
+) -> Result<Vec<EvalValue>, LuaError> {
+    let table = args.first().cloned().unwrap_or(EvalValue::Nil);
+    let first = opt_integer(&args, 2, "unpack", 1)?;
+    let last = match args.get(2) {
+        None | Some(EvalValue::Nil) => length(virtual_machine, &table)?,
+        Some(_) => check_integer(&args, 3, "unpack")?,
+    };
+
+    if first > last {
+        return Ok(Vec::new());
+    }
+    if (last as u64).wrapping_sub(first as u64) >= MAX_UNPACK_RESULTS {
+        return Err(LuaError::runtime("too many results to unpack"));
+    }
+
+    (first..=last)
+        .map(|index| get(virtual_machine, &table, index))
+        .collect()
+}
+
+fn sort(
+    virtual_machine: &mut VirtualMachine,
+    args: Vec<EvalValue>,
+) -> Result<Vec<EvalValue>, LuaError> {
+    let table = check_table(
+        virtual_machine,
+        &args,
+        1,
+        "sort",
+        &["__index", "__newindex", "__len"],
+    )?;
+    let length = length(virtual_machine, &table)?;
+
+    if length > 1 {
+        if length >= i32::MAX as i64 {
+            return Err(argument_error(1, "sort", "array too big"));
+        }
+        let comparator = match args.get(1) {
+            None | Some(EvalValue::Nil) => None,
+            Some(function @ (EvalValue::NativeFunction(_) | EvalValue::DeclaredFunction(_))) => {
+                Some(function.clone())
+            }
+            other => return Err(type_error(2, "sort", "function", other)),
+        };
+
+        let mut sorter = Sorter {
+            virtual_machine,
+            table,
+            comparator,
+        };
+        sorter.sort(1, length, 0)?;
+    }
+
+    Ok(Vec::new())
+}
+
+/// The quicksort of `table.sort`. It follows the one of the reference implementation,
+/// which reads and writes the elements through metamethods and detects inconsistent
+/// comparators when a partition runs past the bounds of its range.
+struct Sorter<'a> {
+    virtual_machine: &'a mut VirtualMachine,
+    table: EvalValue,
+    comparator: Option<EvalValue>,
+}
+
+impl Sorter<'_> {
+    fn get(&mut self, index: i64) -> Result<EvalValue, LuaError> {
+        get(self.virtual_machine, &self.table, index)
+    }
+
+    fn set(&mut self, index: i64, value: EvalValue) -> Result<(), LuaError> {
+        set(self.virtual_machine, &self.table, index, value)
+    }
+
+    /// Swaps the elements at `i` and `j`, whose values are `a_i` and `a_j`.
+    fn swap(&mut self, i: i64, a_i: EvalValue, j: i64, a_j: EvalValue) -> Result<(), LuaError> {
+        self.set(i, a_j)?;
+        self.set(j, a_i)
+    }
+
+    fn less_than(&mut self, left: &EvalValue, right: &EvalValue) -> Result<bool, LuaError> {
+        match &self.comparator {
+            None => Ok(compare_values(left, right)?.is_some_and(|ordering| ordering.is_lt())),
+            Some(comparator) => Ok(self
+                .virtual_machine
+                .call_function(comparator.clone(), vec![left.clone(), right.clone()])?
+                .first()
+                .is_some_and(EvalValue::is_true)),
+        }
+    }
+
+    fn sort(&mut self, mut low: i64, mut up: i64, mut random: u32) -> Result<(), LuaError> {
+        while low < up {
+            // Sort the elements at `low`, the pivot and `up`
+            let (a_low, a_up) = (self.get(low)?, self.get(up)?);
+            if self.less_than(&a_up, &a_low)? {
+                self.swap(low, a_low, up, a_up)?;
+            }
+            if up - low == 1 {
+                break;
+            }
+
+            let mut pivot = if up - low < RANDOM_PIVOT_LIMIT || random == 0 {
+                (low + up) / 2
+            } else {
+                let quarter = (up - low) / 4;
+                random as i64 % (quarter * 2) + low + quarter
+            };
+
+            let (a_pivot, a_low) = (self.get(pivot)?, self.get(low)?);
+            if self.less_than(&a_pivot, &a_low)? {
+                self.swap(pivot, a_pivot, low, a_low)?;
+            } else {
+                let a_up = self.get(up)?;
+                if self.less_than(&a_up, &a_pivot)? {
+                    self.swap(pivot, a_pivot, up, a_up)?;
+                }
+            }
+            if up - low == 2 {
+                break;
+            }
+
+            // Move the pivot next to the end of the range and partition the rest
+            let pivot_value = self.get(pivot)?;
+            let a_before_up = self.get(up - 1)?;
+            self.swap(pivot, pivot_value.clone(), up - 1, a_before_up)?;
+            pivot = self.partition(low, up, &pivot_value)?;
+
+            // Recurse into the smaller part and loop over the larger one
+            let smaller_size;
+            if pivot - low < up - pivot {
+                self.sort(low, pivot - 1, random)?;
+                smaller_size = pivot - low;
+                low = pivot + 1;
+            } else {
+                self.sort(pivot + 1, up, random)?;
+                smaller_size = up - pivot;
+                up = pivot - 1;
+            }
+
+            // Badly unbalanced partitions switch to random pivots
+            if (up - low) / 128 > smaller_size {
+                random = random_seed();
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Partitions `low..=up` around `pivot`, which is at `up - 1`, and returns the final
+    /// position of the pivot.
+    fn partition(&mut self, low: i64, up: i64, pivot: &EvalValue) -> Result<i64, LuaError> {
+        let invalid_order = || LuaError::runtime("invalid order function for sorting");
+        let mut i = low;
+        let mut j = up - 1;
+
+        loop {
+            let a_i = loop {
+                i += 1;
+                let a_i = self.get(i)?;
+                if !self.less_than(&a_i, pivot)? {
+                    break a_i;
+                }
+                if i == up - 1 {
+                    return Err(invalid_order());
+                }
+            };
+
+            let a_j = loop {
+                j -= 1;
+                let a_j = self.get(j)?;
+                if !self.less_than(pivot, &a_j)? {
+                    break a_j;
+                }
+                if j < i {
+                    return Err(invalid_order());
+                }
+            };
+
+            if j < i {
+                self.set(up - 1, a_i)?;
+                self.set(i, pivot.clone())?;
+                return Ok(i);
+            }
+            self.swap(i, a_i, j, a_j)?;
+        }
+    }
+}
+
+/// A seed for choosing pivots at random, taken from the clock.
+fn random_seed() -> u32 {
+    SystemTime::now()
+        .duration_since(SystemTime::UNIX_EPOCH)
+        .map_or(0, |duration| {
+            duration.subsec_nanos() ^ duration.as_secs() as u32
+        })
+}
@@ -0,0 +1,278 @@
+use crate::{ast::EvalValue, error::LuaError, lex::encode_utf8, vm::VirtualMachine};
+
+use super::{
+    argument_error, check_integer, check_string, create_library, opt_integer, LibraryFunction,
+};
+
+/// Largest code point of Unicode, the limit of the strict mode.
+const MAX_UNICODE: u32 = 0x10ffff;
+/// Largest value the original UTF-8 encoding of up to six bytes can represent.
+const MAX_UTF: u32 = 0x7fffffff;
+/// Matches exactly one UTF-8 byte sequence, assuming the subject is valid UTF-8.
+const CHAR_PATTERN: &[u8] = b"[\0-\x7F\xC2-\xFD][\x80-\xBF]*";
+const INVALID_CODE: &str = "invalid UTF-8 code";
+
+pub fn open(_virtual_machine: &mut VirtualMachine) -> EvalValue {
+    let library = create_library(&[
+        ("char", char),
+        ("codepoint", codepoint),
+        ("len", len),
+        ("offset", offset),
+        ("codes", codes),
+    ]);
+    if let EvalValue::Table(table) = &library {
+        table
+            .borrow_mut()
+            .set(
+                EvalValue::string("charpattern"),
+                EvalValue::string(CHAR_PATTERN),
+            )
+            .expect("Library field names are valid table keys");
+    }
+
+    library
+}
+
+fn is_continuation(byte: u8) -> bool {
+    byte & 0xc0 == 0x80
+}
+
+/// Whether the byte at `position` is a continuation byte. Positions past the end of the
+/// string are not.
+fn is_continuation_at(string: &[u8], position: usize) -> bool {
+    string
+        .get(position)
+        .is_some_and(|byte| is_continuation(*byte))
+}
+
+/// Decodes the byte sequence starting at `position`, returning the code point and the
+/// position after the sequence. Sequences of up to six bytes are accepted, and in
+/// strict mode surrogates and values above the Unicode range are rejected.
+fn decode(string: &[u8], position: usize, strict: bool) -> Option<(u32, usize)> {
+    // The smallest value of a sequence with the given number of continuation bytes,
+    // smaller values are overlong encodings
+    const LIMITS: [u32; 6] = [u32::MAX, 0x80, 0x800, 0x10000, 0x200000, 0x4000000];
+
+    let mut first = string[position] as u32;
+    let mut code = 0;
+    let mut count = 0;
+
+    if first < 0x80 {
+        code = first;
+    } else {
+        while first & 0x40 != 0 {
+            count += 1;
+            let byte = *string.get(position + count).unwrap_or(&0);
+            if !is_continuation(byte) {
+                return None;
+            }
+            code = (code << 6) | (byte & 0x3f) as u32;
+            first <<= 1;
+        }
+        if count > 5 {
+            return None;
+        }
+        code |= (first & 0x7f) << (count * 5);
+        if code > MAX_UTF || code < LIMITS[count] {
+            return None;
+        }
+    }
+
+    if strict && (code > MAX_UNICODE || (0xd800..=0xdfff).contains(&code)) {
+        return None;
+    }
+    Some((code, position + count + 1))
+}
+
+/// Converts a possibly negative string position to one counting from 1. Positions
+/// before the start of the string become 0.
+fn relative_position(position: i64, length: usize) -> i64 {
+    if position >= 0 {
+        position
+    } else if position.unsigned_abs() > length as u64 {
+        0
+    } else {
+        length as i64 + position + 1
+    }
+}
+
+fn char(_: &mut VirtualMachine, args: Vec<EvalValue>) -> Result<Vec<EvalValue>, LuaError> {
+    let mut output = Vec::new();
+
+    for position in 1..=args.len() {
+        let code = check_integer(&args, position, "char")? as u64;
+        if code > MAX_UTF as u64 {
+            return Err(argument_error(position, "char", "value out of range"));
+        }
+        encode_utf8(code as u32, &mut output);
+    }
+
+    Ok(vec![EvalValue::string(output)])
+}
+
+fn codepoint(_: &mut VirtualMachine, args: Vec<EvalValue>) -> Result<Vec<EvalValue>, LuaError> {
+    let string = check_string(&args, 1, "codepoint")?;
+    let start = relative_position(opt_integer(&args, 2, "codepoint", 1)?, string.len());
+    let end = relative_position(opt_integer(&args, 3, "codepoint", start)?, string.len());
+    let strict = !args.get(3).is_some_and(EvalValue::is_true);
+
+    if start < 1 {
+        return Err(argument_error(2, "codepoint", "out of bounds"));
+    }
+    if end > string.len() as i64 {
+        return Err(argument_error(3, "codepoint", "out of bounds"));
+    }
+
+    let mut codes = Vec::new();
+    let mut position = start as usize - 1;
+    while (position as i64) < end {
+        let (code, next) =
+            decode(&string, position, strict).ok_or_else(|| LuaError::runtime(INVALID_CODE))?;
+        codes.push(EvalValue::Integer(code as i64));
+        position = next;
+    }
+
+    Ok(codes)
+}
+
+fn len(_: &mut VirtualMachine, args: Vec<EvalValue>) -> Result<Vec<EvalValue>, LuaError> {
+    let string = check_string(&args, 1, "len")?;
+    let start = relative_position(opt_integer(&args, 2, "len", 1)?, string.len());
+    let end = relative_position(opt_integer(&args, 3, "len", -1)?, string.len());
+    let strict = !args.get(3).is_some_and(EvalValue::is_true);
+
+    if start < 1 || start - 1 > string.len() as i64 {
+        return Err(argument_error(2, "len", "initial position out of bounds"));
+    }
+    if end > string.len() as i64 {
+        return Err(argument_error(3, "len", "final position out of bounds"));
+    }
+
+    let mut count = 0;
+    let mut position = start as usize - 1;
+    while (position as i64) < end {
+        match decode(&string, position, strict) {
+            Some((_, next)) => position = next,
+            // Invalid sequences are reported with their position
+            None => {
+                return Ok(vec![
+                    EvalValue::Nil,
+                    EvalValue::Integer(position as i64 + 1),
+                ])
+            }
+        }
+        count += 1;
+    }
+
+    Ok(vec![EvalValue::Integer(count)])
+}
+
+fn offset(_: &mut VirtualMachine, args: Vec<EvalValue>) -> Result<Vec<EvalValue>, LuaError> {
+    let string = check_string(&args, 1, "offset")?;
+    let mut n = check_integer(&args, 2, "offset")?;
+    let default = if n >= 0 { 1 } else { string.len() as i64 + 1 };
+    let start = relative_position(opt_integer(&args, 3, "offset", default)?, string.len());
+
+    if start < 1 || start - 1 > string.len() as i64 {
+        return Err(argument_error(3, "offset", "position out of bounds"));
+    }
+
+    let mut position = start as usize - 1;
+    if n == 0 {
+        // The start of the character containing the position
+        while position > 0 && is_continuation_at(&string, position) {
+            position -= 1;
+        }
+    } else if is_continuation_at(&string, position) {
+        return Err(LuaError::runtime("initial position is a continuation byte"));
+    } else if n < 0 {
+        while n < 0 && position > 0 {
+            position -= 1;
+            while position > 0 && is_continuation_at(&string, position) {
+                position -= 1;
+            }
+            n += 1;
+        }
+    } else {
+        // The character at the position is the first one
+        n -= 1;
+        while n > 0 && position < string.len() {
+            position += 1;
+            while is_continuation_at(&string, position) {
+                position += 1;
+            }
+            n -= 1;
+        }
+    }
+
+    if n == 0 {
+        Ok(vec![EvalValue::Integer(position as i64 + 1)])
+    } else {
+        Ok(vec![EvalValue::Nil])
+    }
+}
+
+fn codes(_: &mut VirtualMachine, args: Vec<EvalValue>) -> Result<Vec<EvalValue>, LuaError> {
+    let string = check_string(&args, 1, "codes")?;
+    let lax = args.get(1).is_some_and(EvalValue::is_true);
+
+    if is_continuation_at(&string, 0) {
+        return Err(argument_error(1, "codes", INVALID_CODE));
+    }
+
+    let iterator: LibraryFunction = if lax {
+        iterate_codes_lax
+    } else {
+        iterate_codes_strict
+    };
+    Ok(vec![
+        EvalValue::native_function(iterator),
+        EvalValue::String(string),
+        EvalValue::Integer(0),
+    ])
+}
+
+fn iterate_codes_strict(
+    virtual_machine: &mut VirtualMachine,
+    args: Vec<EvalValue>,
+) -> Result<Vec<EvalValue>, LuaError> {
+    iterate_codes(virtual_machine, args, true)
+}
+
+fn iterate_codes_lax(
+    virtual_machine: &mut VirtualMachine,
+    args: Vec<EvalValue>,
+) -> Result<Vec<EvalValue>, LuaError> {
+    iterate_codes(virtual_machine, args, false)
+}
+
+/// The iterator of `codes`: the control variable is the position of the previous
+/// character, and its continuation bytes are skipped to find the next one.
+fn iterate_codes(
+    _: &mut VirtualMachine,
+    args: Vec<EvalValue>,
+    strict: bool,
+) -> Result<Vec<EvalValue>, LuaError> {
+    let string = check_string(&args, 1, "for iterator")?;
+    let previous = args.get(1).and_then(EvalValue::to_integer).unwrap_or(0);
+
+    // Negative control values end the loop, like positions past the end
+    let mut position = previous as u64 as usize;
+    if previous < 0 || position >= string.len() {
+        return Ok(Vec::new());
+    }
+    while is_continuation_at(&string, position) {
+        position += 1;
+    }
+    if position >= string.len() {
+        return Ok(Vec::new());
+    }
+
+    match decode(&string, position, strict) {
+        Some((code, next)) if !is_continuation_at(&string, next) => Ok(vec![
+            EvalValue::Integer(position as i64 + 1),
+            EvalValue::Integer(code as i64),
+        ]),
+        _ => Err(LuaError::runtime(INVALID_CODE)),
+    }
+}
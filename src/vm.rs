@@ -1,83 +1,1071 @@
-use crate::ast::EvalValue;
-use crate::ast::Statement;
+use crate::ast::{
+    memory_estimate, ControlFlow, EvalValue, FunctionDefinition, LuaFunction, LuaString, Statement,
+    Table, TableRef, Variable,
+};
+use crate::coroutine::{Coroutine, CoroutineYielder, ThreadRef};
+use crate::error::{LuaError, SourceLocation, TracebackEntry};
+use crate::lex::Position;
+use crate::stdlib;
+use corosensei::stack::{DefaultStack, Stack};
+use std::cell::{Cell, RefCell};
 use std::collections::HashMap;
+use std::fmt;
+use std::rc::{Rc, Weak};
 
-type ValueMap = HashMap<String, EvalValue>;
+/// Size of the native stack the main thread runs Lua code on.
+const MAIN_STACK_SIZE: usize = 256 * 1024 * 1024;
+
+/// Native stack space kept free below the deepest Lua call, for native functions.
+const STACK_RESERVE: usize = 256 * 1024;
+
+/// How many `__index` metamethods an index operation follows before giving up.
+const MAX_METAMETHOD_CHAIN: usize = 2000;
+
+/// Events a debug hook can be set for, as a bit mask.
+pub const HOOK_CALL: u8 = 1;
+pub const HOOK_RETURN: u8 = 2;
+pub const HOOK_LINE: u8 = 4;
+pub const HOOK_COUNT: u8 = 8;
+
+#[derive(Debug, Default)]
+struct Scope {
+    variables: Vec<(String, Variable)>,
+    to_be_closed: Vec<EvalValue>,
+}
+
+/// A variable or field a value was read from, e.g. `local 'x'`.
+#[derive(Debug, Clone)]
+pub struct VariableName {
+    pub kind: &'static str,
+    pub name: String,
+}
+
+impl fmt::Display for VariableName {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{} '{}'", self.kind, self.name)
+    }
+}
+
+/// The variables of the Lua function a thread is running.
+#[derive(Default)]
+struct ActivationState {
+    scopes_stack: Vec<Scope>,
+    function: Option<Rc<LuaFunction>>,
+    varargs: Vec<EvalValue>,
+}
+
+/// A function activation on the call stack of a thread.
+pub struct CallFrame {
+    pub function: EvalValue,
+    /// How the caller referred to the function, if it read it from a variable or a field.
+    pub name: Option<VariableName>,
+    /// Position of the last operation that can fail in a Lua function.
+    pub current_position: Position,
+    /// Line last reported to the line hook.
+    last_line: usize,
+    /// For Lua functions, the variables of the Lua function that was running when this
+    /// one was called, restored when it returns.
+    caller: ActivationState,
+}
+
+impl CallFrame {
+    /// Where the frame is executing, if it runs a Lua function.
+    pub fn location(&self) -> Option<SourceLocation> {
+        match &self.function {
+            EvalValue::DeclaredFunction(function) => Some(SourceLocation {
+                chunk_name: function.definition.chunk_name.clone(),
+                line: self.current_position.line,
+                column: self.current_position.column,
+            }),
+            _ => None,
+        }
+    }
+
+    /// The frame as shown in a stack traceback.
+    pub fn traceback_entry(&self) -> TracebackEntry {
+        let description = match (&self.name, &self.function) {
+            (Some(name), _) if name.kind == "global" => format!("function '{}'", name.name),
+            (Some(name), _) => name.to_string(),
+            (None, EvalValue::DeclaredFunction(function)) => match function.definition.line_defined
+            {
+                0 => "main chunk".to_string(),
+                line => format!("function <{}:{}>", function.definition.chunk_name, line),
+            },
+            (None, _) => "?".to_string(),
+        };
+
+        TracebackEntry {
+            location: self.location(),
+            description,
+        }
+    }
+}
+
+/// A function on the call stack of a thread, as debug functions inspect it.
+pub struct Activation<'a> {
+    pub frame: &'a CallFrame,
+    scopes: &'a [Scope],
+    pub varargs: &'a [EvalValue],
+}
+
+impl<'a> Activation<'a> {
+    /// The local variables in scope in the function, in declaration order. Native
+    /// functions have none.
+    pub fn locals(&self) -> impl Iterator<Item = &'a (String, Variable)> {
+        self.scopes.iter().flat_map(|scope| scope.variables.iter())
+    }
+}
+
+/// The call stack of a thread that is not running, kept in the thread so debug
+/// functions can inspect it.
+#[derive(Default)]
+pub struct ParkedStack {
+    call_stack: Vec<CallFrame>,
+    active: ActivationState,
+}
+
+impl ParkedStack {
+    pub fn activation(&self, level: usize) -> Option<Activation<'_>> {
+        activation(
+            &self.call_stack,
+            &self.active.scopes_stack,
+            &self.active.varargs,
+            level,
+        )
+    }
+
+    pub fn traceback(&self) -> Vec<TracebackEntry> {
+        traceback(&self.call_stack)
+    }
+}
+
+/// The function at `level` of `call_stack`, where `scopes_stack` and `varargs` belong
+/// to the innermost Lua function. The variables of the other Lua functions are kept in
+/// the frame of the next Lua function they called.
+fn activation<'a>(
+    call_stack: &'a [CallFrame],
+    scopes_stack: &'a [Scope],
+    varargs: &'a [EvalValue],
+    level: usize,
+) -> Option<Activation<'a>> {
+    let index = call_stack.len().checked_sub(level + 1)?;
+    let frame = &call_stack[index];
+    if !matches!(frame.function, EvalValue::DeclaredFunction(_)) {
+        return Some(Activation {
+            frame,
+            scopes: &[],
+            varargs: &[],
+        });
+    }
+
+    let (scopes, varargs) = call_stack[index + 1..]
+        .iter()
+        .find(|frame| matches!(frame.function, EvalValue::DeclaredFunction(_)))
+        .map_or((scopes_stack, varargs), |callee| {
+            (&callee.caller.scopes_stack, &callee.caller.varargs)
+        });
+
+    Some(Activation {
+        frame,
+        scopes,
+        varargs,
+    })
+}
+
+fn traceback(call_stack: &[CallFrame]) -> Vec<TracebackEntry> {
+    call_stack
+        .iter()
+        .rev()
+        .map(CallFrame::traceback_entry)
+        .collect()
+}
+
+/// Something that happens while a thread runs, which a debug hook can be called for.
+#[derive(Debug, Clone, Copy)]
+pub enum HookEvent {
+    Call,
+    Return,
+    /// A statement on a new line is about to run, or a loop starts another iteration.
+    Line(usize),
+    /// The hook count of statements has run.
+    Count,
+}
+
+impl HookEvent {
+    fn mask(self) -> u8 {
+        match self {
+            HookEvent::Call => HOOK_CALL,
+            HookEvent::Return => HOOK_RETURN,
+            HookEvent::Line(_) => HOOK_LINE,
+            HookEvent::Count => HOOK_COUNT,
+        }
+    }
+
+    fn name(self) -> &'static str {
+        match self {
+            HookEvent::Call => "call",
+            HookEvent::Return => "return",
+            HookEvent::Line(_) => "line",
+            HookEvent::Count => "count",
+        }
+    }
+}
+
+/// The function `debug.sethook` sets for a thread, and the events it is called for.
+pub struct DebugHook {
+    function: RefCell<EvalValue>,
+    mask: Cell<u8>,
+    count: Cell<usize>,
+    /// Statements left to run before the next count event.
+    countdown: Cell<usize>,
+    /// Whether the hook function is running. Events are not reported while it runs.
+    running: Cell<bool>,
+}
+
+impl Default for DebugHook {
+    fn default() -> Self {
+        DebugHook {
+            function: RefCell::new(EvalValue::Nil),
+            mask: Cell::new(0),
+            count: Cell::new(0),
+            countdown: Cell::new(0),
+            running: Cell::new(false),
+        }
+    }
+}
+
+impl DebugHook {
+    /// Sets the hook, or removes it if `mask` is empty. A count event is reported every
+    /// `count` statements if `mask` has `HOOK_COUNT`.
+    pub fn set(&self, function: EvalValue, mask: u8, count: usize) {
+        *self.function.borrow_mut() = function;
+        self.mask.set(mask);
+        self.count.set(count);
+        self.countdown.set(count);
+    }
+
+    /// The hook function, its event mask and its count.
+    pub fn get(&self) -> (EvalValue, u8, usize) {
+        (
+            self.function.borrow().clone(),
+            self.mask.get(),
+            self.count.get(),
+        )
+    }
+}
 
-#[derive(Debug)]
 pub struct VirtualMachine {
-    scopes_stack: Vec<ValueMap>,
+    globals: TableRef,
+    main_thread: Option<ThreadRef>,
+    thread: Weak<RefCell<Coroutine>>,
+    pub(crate) yielder: Option<*const CoroutineYielder>,
+    /// Lowest native stack address calls may grow to, or zero outside of Lua code.
+    pub(crate) stack_limit: usize,
+    call_stack: Vec<CallFrame>,
+    current_function: Option<Rc<LuaFunction>>,
+    scopes_stack: Vec<Scope>,
+    varargs: Vec<EvalValue>,
+    /// Whether `warn` prints its messages, shared by all threads.
+    warnings_enabled: Rc<Cell<bool>>,
+    /// Metatables shared by all values of a type other than table, by type name.
+    type_metatables: Rc<RefCell<HashMap<&'static str, TableRef>>>,
+    /// Loaders of the modules registered with `register_module`, by module name.
+    native_modules: Rc<RefCell<HashMap<LuaString, EvalValue>>>,
+    /// The debug hook of the thread, shared with the thread object.
+    hook: Rc<DebugHook>,
+    /// Whether the libraries ignore environment variables such as `LUA_PATH`.
+    ignore_environment: bool,
+}
+
+/// Settings of a new virtual machine.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct LuaOptions {
+    /// Ignore environment variables such as `LUA_PATH`, like the `-E` option of `lua`.
+    pub ignore_environment: bool,
+}
+
+impl Default for VirtualMachine {
+    fn default() -> Self {
+        VirtualMachine::new()
+    }
 }
 
 impl VirtualMachine {
     pub fn new() -> Self {
+        VirtualMachine::with_options(LuaOptions::default())
+    }
+
+    pub fn with_options(options: LuaOptions) -> Self {
+        let main_thread = Coroutine::main();
+        let hook = main_thread.borrow().hook.clone();
         let mut virtual_machine = VirtualMachine {
-            scopes_stack: vec![ValueMap::new()],
+            globals: Rc::new(RefCell::new(Table::default())),
+            thread: Rc::downgrade(&main_thread),
+            main_thread: Some(main_thread),
+            yielder: None,
+            stack_limit: 0,
+            call_stack: Vec::new(),
+            current_function: None,
+            scopes_stack: Vec::new(),
+            varargs: Vec::new(),
+            warnings_enabled: Rc::new(Cell::new(false)),
+            type_metatables: Rc::default(),
+            native_modules: Rc::default(),
+            hook,
+            ignore_environment: options.ignore_environment,
         };
 
-        virtual_machine.declare_variable(
-            String::from("print"),
-            EvalValue::NativeFunction(|args| {
-                for arg in args {
-                    match arg {
-                        EvalValue::Number(n) => print!("{}\t", n),
-                        EvalValue::Boolean(b) => print!("{}\t", b),
-                        EvalValue::String(s) => print!("{}\t", s),
-                        EvalValue::Nil => print!("nil\t"),
-                        _ => return Err("Invalid argument".to_string()),
-                    }
-                }
-                println!();
-                Ok(EvalValue::Nil)
-            }),
-        );
+        stdlib::open_libs(&mut virtual_machine);
 
         virtual_machine
     }
 
-    pub fn enter_scope(&mut self) {
-        self.scopes_stack.push(ValueMap::new());
+    /// Creates the virtual machine a coroutine runs on. It shares the global state of
+    /// `self` but has its own call stack.
+    pub(crate) fn new_thread(&self, thread: &ThreadRef) -> Self {
+        VirtualMachine {
+            globals: self.globals.clone(),
+            main_thread: None,
+            thread: Rc::downgrade(thread),
+            yielder: None,
+            stack_limit: 0,
+            call_stack: Vec::new(),
+            current_function: None,
+            scopes_stack: Vec::new(),
+            varargs: Vec::new(),
+            warnings_enabled: self.warnings_enabled.clone(),
+            type_metatables: self.type_metatables.clone(),
+            native_modules: self.native_modules.clone(),
+            hook: thread.borrow().hook.clone(),
+            ignore_environment: self.ignore_environment,
+        }
+    }
+
+    pub fn current_thread(&self) -> ThreadRef {
+        self.thread
+            .upgrade()
+            .expect("A running thread is always referenced")
+    }
+
+    pub fn is_main_thread(&self) -> bool {
+        self.main_thread.is_some()
+    }
+
+    pub fn warnings_enabled(&self) -> bool {
+        self.warnings_enabled.get()
+    }
+
+    pub fn set_warnings_enabled(&mut self, enabled: bool) {
+        self.warnings_enabled.set(enabled);
     }
 
-    pub fn exit_scope(&mut self) {
-        self.scopes_stack.pop();
+    pub fn ignores_environment(&self) -> bool {
+        self.ignore_environment
     }
 
-    pub fn declare_variable(&mut self, name: String, value: EvalValue) {
+    /// The table holding the global variables.
+    pub fn globals(&self) -> TableRef {
+        self.globals.clone()
+    }
+
+    /// Estimates the memory in bytes taken by the values the thread can reach: the
+    /// globals, the registered modules and metatables, and the variables of the running
+    /// functions. See [`memory_estimate`] for what it leaves out.
+    pub(crate) fn memory_in_use(&self) -> usize {
+        fn activation_values<'a>(
+            scopes: &'a [Scope],
+            function: &'a Option<Rc<LuaFunction>>,
+            varargs: &'a [EvalValue],
+        ) -> impl Iterator<Item = EvalValue> + 'a {
+            scopes
+                .iter()
+                .flat_map(|scope| {
+                    scope
+                        .variables
+                        .iter()
+                        .map(|(_, variable)| variable.borrow().clone())
+                        .chain(scope.to_be_closed.iter().cloned())
+                })
+                .chain(function.clone().map(EvalValue::DeclaredFunction))
+                .chain(varargs.iter().cloned())
+        }
+
+        let callers = self.call_stack.iter().flat_map(|frame| {
+            std::iter::once(frame.function.clone()).chain(activation_values(
+                &frame.caller.scopes_stack,
+                &frame.caller.function,
+                &frame.caller.varargs,
+            ))
+        });
+
+        memory_estimate(
+            std::iter::once(EvalValue::Table(self.globals.clone()))
+                .chain(
+                    self.type_metatables
+                        .borrow()
+                        .values()
+                        .cloned()
+                        .map(EvalValue::Table),
+                )
+                .chain(self.native_modules.borrow().values().cloned())
+                .chain(activation_values(
+                    &self.scopes_stack,
+                    &self.current_function,
+                    &self.varargs,
+                ))
+                .chain(callers)
+                .collect::<Vec<_>>(),
+        )
+    }
+
+    /// Makes a module written in Rust available to `require`. Like the loader of a Lua
+    /// module, `loader` is called with the module name and returns the module.
+    pub fn register_module(
+        &mut self,
+        name: &str,
+        loader: impl Fn(&mut VirtualMachine, Vec<EvalValue>) -> Result<Vec<EvalValue>, LuaError>
+            + 'static,
+    ) {
+        self.native_modules
+            .borrow_mut()
+            .insert(LuaString::from(name), EvalValue::native_function(loader));
+    }
+
+    /// The loader of the module `name` registered with `register_module`.
+    pub fn native_module(&self, name: &LuaString) -> Option<EvalValue> {
+        self.native_modules.borrow().get(name).cloned()
+    }
+
+    pub fn set_global(&mut self, name: &str, value: EvalValue) {
+        self.globals
+            .borrow_mut()
+            .set(EvalValue::string(name), value)
+            .expect("Global names are valid table keys");
+    }
+
+    pub(crate) fn enter_scope(&mut self) {
+        self.scopes_stack.push(Scope::default());
+    }
+
+    /// Leaves the innermost scope, calling the `__close` metamethod of its to-be-closed
+    /// variables in reverse declaration order. An error raised by a closing method
+    /// replaces `result`.
+    pub(crate) fn exit_scope(
+        &mut self,
+        result: Result<ControlFlow, LuaError>,
+    ) -> Result<ControlFlow, LuaError> {
+        let scope = self.scopes_stack.pop().expect("No scope found");
+        let mut result = result.map_err(|err| self.locate_error(err, 0));
+
+        for value in scope.to_be_closed.into_iter().rev() {
+            let error = match &result {
+                Err(LuaError::CoroutineClosed) | Ok(_) => EvalValue::Nil,
+                Err(err) => err.clone().into_value(),
+            };
+            let close = self.get_metamethod(&value, "__close");
+
+            if let Err(err) = self.call_function(close, vec![value, error]) {
+                result = Err(self.locate_error(err, 0));
+            }
+        }
+
+        result
+    }
+
+    /// Leaves every scope, closing the pending to-be-closed variables as closing the
+    /// state does before the program exits. Errors raised by closing methods are ignored.
+    pub(crate) fn close_scopes(&mut self) {
+        while !self.scopes_stack.is_empty() {
+            let _ = self.exit_scope(Ok(ControlFlow::Normal));
+        }
+    }
+
+    pub(crate) fn declare_variable(&mut self, name: String, value: EvalValue) {
         self.scopes_stack
             .last_mut()
             .expect("No scope found")
-            .insert(name, value);
+            .variables
+            .push((name, Rc::new(RefCell::new(value))));
     }
 
-    pub fn lookup_variable(&self, name: &str) -> Option<EvalValue> {
-        for scope in self.scopes_stack.iter().rev() {
-            if let Some(value) = scope.get(name) {
-                return Some(value.clone());
+    pub(crate) fn declare_to_be_closed(
+        &mut self,
+        name: String,
+        value: EvalValue,
+    ) -> Result<(), LuaError> {
+        if value.is_true() {
+            if let EvalValue::Nil = self.get_metamethod(&value, "__close") {
+                return Err(LuaError::type_mismatch(format!(
+                    "variable '{}' got a non-closable value",
+                    name
+                )));
             }
+
+            self.scopes_stack
+                .last_mut()
+                .expect("No scope found")
+                .to_be_closed
+                .push(value.clone());
         }
-        None
+
+        self.declare_variable(name, value);
+        Ok(())
+    }
+
+    fn find_local_variable(&self, name: &str) -> Option<Variable> {
+        let scopes = self.scopes_stack.iter().rev().map(|scope| &scope.variables);
+        let captured = self
+            .current_function
+            .as_deref()
+            .map(|function| &function.captured_variables);
+
+        scopes
+            .chain(captured)
+            .find_map(|variables| variables.iter().rev().find(|(n, _)| n == name))
+            .map(|(_, variable)| variable.clone())
     }
 
-    pub fn change_or_create_value(&mut self, name: String, value: EvalValue) {
-        let mut target_scope = self
+    /// How `name` resolves in the current scope, as named in error messages.
+    pub(crate) fn variable_kind(&self, name: &str) -> &'static str {
+        let is_local = self
             .scopes_stack
-            .iter_mut()
-            .rev()
-            .find(|v| v.contains_key(&name));
+            .iter()
+            .any(|scope| scope.variables.iter().any(|(n, _)| n == name));
 
-        if target_scope.is_none() {
-            target_scope = self.scopes_stack.first_mut()
+        if is_local {
+            "local"
+        } else if self.find_local_variable(name).is_some() {
+            "upvalue"
+        } else {
+            "global"
+        }
+    }
+
+    /// The value of the variable `name` without calling metamethods, for use outside
+    /// of Lua code.
+    pub fn lookup_variable(&self, name: &str) -> Option<EvalValue> {
+        if let Some(variable) = self.find_local_variable(name) {
+            return Some(variable.borrow().clone());
+        }
+
+        match self.globals.borrow().get(&EvalValue::string(name)) {
+            EvalValue::Nil => None,
+            value => Some(value),
+        }
+    }
+
+    /// The value global variables are fields of: the `_ENV` variable every chunk has,
+    /// or the global table outside of any chunk.
+    fn environment(&self) -> EvalValue {
+        match self.find_local_variable("_ENV") {
+            Some(environment) => environment.borrow().clone(),
+            None => EvalValue::Table(self.globals.clone()),
+        }
+    }
+
+    /// Reads the variable `name`: the visible local variable or upvalue of that name, or
+    /// otherwise a field of the environment.
+    pub(crate) fn read_variable(&mut self, name: &str) -> Result<EvalValue, LuaError> {
+        if let Some(variable) = self.find_local_variable(name) {
+            return Ok(variable.borrow().clone());
         }
 
-        target_scope.unwrap().insert(name, value);
+        let environment = self.environment();
+        self.index(environment, EvalValue::string(name))
     }
 
-    pub fn execute(&mut self, ast: &Vec<Statement>) -> Result<(), String> {
-        for stat in ast {
-            stat.execute(self)?;
+    pub(crate) fn change_or_create_value(
+        &mut self,
+        name: String,
+        value: EvalValue,
+    ) -> Result<(), LuaError> {
+        match self.find_local_variable(&name) {
+            Some(variable) => {
+                *variable.borrow_mut() = value;
+                Ok(())
+            }
+            None => {
+                let environment = self.environment();
+                self.set_index(environment, EvalValue::string(name), value)
+            }
         }
+    }
+
+    /// Creates the function of a main chunk. Its `_ENV` variable is `environment`, or
+    /// the global table if that is `None`.
+    pub(crate) fn load(
+        &self,
+        chunk: Rc<FunctionDefinition>,
+        environment: Option<EvalValue>,
+    ) -> EvalValue {
+        let environment = environment.unwrap_or_else(|| EvalValue::Table(self.globals.clone()));
 
+        EvalValue::DeclaredFunction(Rc::new(LuaFunction {
+            definition: chunk,
+            captured_variables: vec![("_ENV".to_string(), Rc::new(RefCell::new(environment)))],
+        }))
+    }
+
+    /// Creates a function value of `definition`, capturing the variables it uses as
+    /// upvalues. Variables are shared with the scope they are declared in, not copied.
+    pub(crate) fn create_closure(&self, definition: Rc<FunctionDefinition>) -> EvalValue {
+        let captured_variables = definition
+            .upvalues
+            .iter()
+            .filter_map(|name| Some((name.clone(), self.find_local_variable(name)?)))
+            .collect();
+
+        EvalValue::DeclaredFunction(Rc::new(LuaFunction {
+            definition,
+            captured_variables,
+        }))
+    }
+
+    /// The metatable of `value`. Tables and userdata have their own, values of other
+    /// types share the metatable of their type.
+    pub fn metatable(&self, value: &EvalValue) -> Option<TableRef> {
+        match value {
+            EvalValue::Table(table) => table.borrow().metatable(),
+            EvalValue::UserData(data) => data.metatable(),
+            value => self
+                .type_metatables
+                .borrow()
+                .get(value.type_name())
+                .cloned(),
+        }
+    }
+
+    /// Sets the metatable shared by all values of the type `type_name`.
+    pub fn set_type_metatable(&mut self, type_name: &'static str, metatable: Option<TableRef>) {
+        let mut type_metatables = self.type_metatables.borrow_mut();
+        match metatable {
+            Some(metatable) => type_metatables.insert(type_name, metatable),
+            None => type_metatables.remove(type_name),
+        };
+    }
+
+    /// Converts `value` to a string like the `tostring` function does.
+    pub fn tostring(&mut self, value: &EvalValue) -> Result<LuaString, LuaError> {
+        stdlib::tostring(self, value)
+    }
+
+    pub fn get_metamethod(&self, value: &EvalValue, event: &str) -> EvalValue {
+        match self.metatable(value) {
+            Some(metatable) => metatable.borrow().get(&EvalValue::string(event)),
+            None => EvalValue::Nil,
+        }
+    }
+
+    /// Reads `object[key]`, following the `__index` metamethod when a table has no
+    /// such field or the object is not a table.
+    pub fn index(&mut self, object: EvalValue, key: EvalValue) -> Result<EvalValue, LuaError> {
+        let mut object = object;
+
+        for _ in 0..MAX_METAMETHOD_CHAIN {
+            let handler = match &object {
+                EvalValue::Table(table) => {
+                    let value = table.borrow().get(&key);
+                    if value != EvalValue::Nil {
+                        return Ok(value);
+                    }
+                    match self.get_metamethod(&object, "__index") {
+                        EvalValue::Nil => return Ok(EvalValue::Nil),
+                        handler => handler,
+                    }
+                }
+                _ => match self.get_metamethod(&object, "__index") {
+                    EvalValue::Nil => {
+                        return Err(LuaError::type_mismatch(format!(
+                            "attempt to index a {} value",
+                            object.type_name()
+                        )))
+                    }
+                    handler => handler,
+                },
+            };
+
+            match handler {
+                EvalValue::NativeFunction(_) | EvalValue::DeclaredFunction(_) => {
+                    let values = self.call_function(handler, vec![object, key])?;
+                    return Ok(values.into_iter().next().unwrap_or(EvalValue::Nil));
+                }
+                handler => object = handler,
+            }
+        }
+
+        Err(LuaError::runtime("'__index' chain too long; possible loop"))
+    }
+
+    /// Writes `object[key] = value`, following the `__newindex` metamethod when a table
+    /// has no such field or the object is not a table.
+    pub fn set_index(
+        &mut self,
+        object: EvalValue,
+        key: EvalValue,
+        value: EvalValue,
+    ) -> Result<(), LuaError> {
+        let mut object = object;
+
+        for _ in 0..MAX_METAMETHOD_CHAIN {
+            let handler = match &object {
+                EvalValue::Table(table) => {
+                    let is_present = table.borrow().get(&key) != EvalValue::Nil;
+                    match self.get_metamethod(&object, "__newindex") {
+                        EvalValue::Nil => return Ok(table.borrow_mut().set(key, value)?),
+                        _ if is_present => return Ok(table.borrow_mut().set(key, value)?),
+                        handler => handler,
+                    }
+                }
+                _ => match self.get_metamethod(&object, "__newindex") {
+                    EvalValue::Nil => {
+                        return Err(LuaError::type_mismatch(format!(
+                            "attempt to index a {} value",
+                            object.type_name()
+                        )))
+                    }
+                    handler => handler,
+                },
+            };
+
+            match handler {
+                EvalValue::NativeFunction(_) | EvalValue::DeclaredFunction(_) => {
+                    self.call_function(handler, vec![object, key, value])?;
+                    return Ok(());
+                }
+                handler => object = handler,
+            }
+        }
+
+        Err(LuaError::runtime(
+            "'__newindex' chain too long; possible loop",
+        ))
+    }
+
+    /// The length of `value` as the `#` operator computes it. Tables and values other
+    /// than strings can define it with the `__len` metamethod.
+    pub fn length(&mut self, value: &EvalValue) -> Result<EvalValue, LuaError> {
+        if let EvalValue::String(s) = value {
+            return Ok(EvalValue::Integer(s.len() as i64));
+        }
+
+        match (value, self.get_metamethod(value, "__len")) {
+            (EvalValue::Table(table), EvalValue::Nil) => {
+                Ok(EvalValue::Integer(table.borrow().border() as i64))
+            }
+            (_, EvalValue::Nil) => Err(LuaError::type_mismatch(format!(
+                "attempt to get length of a {} value",
+                value.type_name()
+            ))),
+            (_, handler) => Ok(self
+                .call_function(handler, vec![value.clone(), value.clone()])?
+                .into_iter()
+                .next()
+                .unwrap_or(EvalValue::Nil)),
+        }
+    }
+
+    pub fn varargs(&self) -> &[EvalValue] {
+        &self.varargs
+    }
+
+    /// Records the position the running Lua function is executing.
+    pub(crate) fn set_position(&mut self, position: Position) {
+        if let Some(frame) = self.call_stack.last_mut() {
+            frame.current_position = position;
+        }
+    }
+
+    /// Where the function at `level` of the call stack is executing, where level 0 is
+    /// the running function and level 1 the function that called it. Native functions
+    /// have no location.
+    pub fn location_at(&self, level: usize) -> Option<SourceLocation> {
+        let frame = self.call_stack.len().checked_sub(level + 1)?;
+
+        self.call_stack[frame].location()
+    }
+
+    /// The current call stack, innermost function first.
+    pub fn traceback(&self) -> Vec<TracebackEntry> {
+        traceback(&self.call_stack)
+    }
+
+    /// The function at `level` of the call stack, where level 0 is the running function.
+    pub fn activation(&self, level: usize) -> Option<Activation<'_>> {
+        activation(&self.call_stack, &self.scopes_stack, &self.varargs, level)
+    }
+
+    /// Moves the call stack out of the virtual machine while its thread is not running.
+    pub(crate) fn park_stack(&mut self) -> ParkedStack {
+        ParkedStack {
+            call_stack: std::mem::take(&mut self.call_stack),
+            active: ActivationState {
+                scopes_stack: std::mem::take(&mut self.scopes_stack),
+                function: self.current_function.take(),
+                varargs: std::mem::take(&mut self.varargs),
+            },
+        }
+    }
+
+    /// Puts back the call stack moved out by `park_stack` when the thread runs again.
+    pub(crate) fn unpark_stack(&mut self, stack: ParkedStack) {
+        self.call_stack = stack.call_stack;
+        self.scopes_stack = stack.active.scopes_stack;
+        self.current_function = stack.active.function;
+        self.varargs = stack.active.varargs;
+    }
+
+    /// Records that the loop on `line` starts another iteration. The iteration counts
+    /// towards the count hook, and the line hook reports the loop line and then the lines
+    /// of its body again, even if they are the lines last reported.
+    pub(crate) fn jump_back(&mut self, line: usize) -> Result<(), LuaError> {
+        if let Some(frame) = self.call_stack.last_mut() {
+            frame.last_line = 0;
+        }
+
+        if self.hook.mask.get() & (HOOK_LINE | HOOK_COUNT) != 0 {
+            self.statement_hooks(line)?;
+        }
         Ok(())
     }
+
+    /// Counts an expression or a statement towards the count hook, which is called every
+    /// `count` of them.
+    pub(crate) fn count_hook(&mut self) -> Result<(), LuaError> {
+        if self.hook.mask.get() & HOOK_COUNT == 0 || self.hook.running.get() {
+            return Ok(());
+        }
+
+        let countdown = self.hook.countdown.get().saturating_sub(1);
+        if countdown == 0 {
+            self.hook.countdown.set(self.hook.count.get());
+            self.run_hook(HookEvent::Count)
+        } else {
+            self.hook.countdown.set(countdown);
+            Ok(())
+        }
+    }
+
+    /// Calls the debug hook for `event` if it is set for it, unless the hook is running.
+    fn run_hook(&mut self, event: HookEvent) -> Result<(), LuaError> {
+        if self.hook.mask.get() & event.mask() == 0 || self.hook.running.get() {
+            return Ok(());
+        }
+
+        let line = match event {
+            HookEvent::Line(line) => Some(line),
+            _ => self
+                .location_at(0)
+                .map(|location| location.line)
+                .filter(|line| *line > 0),
+        };
+        let args = vec![
+            EvalValue::string(event.name()),
+            line.map_or(EvalValue::Nil, |line| EvalValue::Integer(line as i64)),
+        ];
+        let function = self.hook.function.borrow().clone();
+
+        self.hook.running.set(true);
+        let result = self.call_function(function, args);
+        self.hook.running.set(false);
+
+        result.map(|_| ())
+    }
+
+    /// Reports the statement about to run on `line` to the line and count hooks.
+    fn statement_hooks(&mut self, line: usize) -> Result<(), LuaError> {
+        self.count_hook()?;
+
+        if self.hook.mask.get() & HOOK_LINE != 0 && !self.hook.running.get() {
+            let frame = self
+                .call_stack
+                .last_mut()
+                .expect("Statements run in a frame");
+            if frame.last_line != line {
+                frame.last_line = line;
+                self.run_hook(HookEvent::Line(line))?;
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Completes an error that was just raised with the location of the function at
+    /// `level` and the current call stack.
+    fn locate_error(&self, mut err: LuaError, level: usize) -> LuaError {
+        if let Some(details) = err.details_mut() {
+            if details.traceback.is_none() {
+                details.location = self.location_at(level);
+                details.traceback = Some(self.traceback());
+            }
+        }
+
+        err
+    }
+
+    /// Whether the native stack is too close to its end to run more Lua code on it.
+    pub(crate) fn is_stack_exhausted(&self) -> bool {
+        let marker = 0u8;
+        let address = &marker as *const u8 as usize;
+
+        self.stack_limit != 0 && address < self.stack_limit + STACK_RESERVE
+    }
+
+    pub fn call_function(
+        &mut self,
+        function: EvalValue,
+        args: Vec<EvalValue>,
+    ) -> Result<Vec<EvalValue>, LuaError> {
+        self.call_function_with_name(function, args, None)
+    }
+
+    /// Calls `function`, remembering the name the caller used for it for tracebacks.
+    pub fn call_function_with_name(
+        &mut self,
+        function: EvalValue,
+        args: Vec<EvalValue>,
+        name: Option<VariableName>,
+    ) -> Result<Vec<EvalValue>, LuaError> {
+        if !matches!(
+            function,
+            EvalValue::NativeFunction(_) | EvalValue::DeclaredFunction(_)
+        ) {
+            return Err(LuaError::type_mismatch(format!(
+                "attempt to call a {} value",
+                function.type_name()
+            )));
+        }
+
+        if self.is_stack_exhausted() {
+            return Err(LuaError::stack_overflow());
+        }
+
+        self.call_stack.push(CallFrame {
+            function: function.clone(),
+            name,
+            current_position: Position::default(),
+            last_line: 0,
+            caller: ActivationState::default(),
+        });
+
+        let result = self
+            .run_hook(HookEvent::Call)
+            .and_then(|()| self.run_function(function, args))
+            .and_then(|values| self.run_hook(HookEvent::Return).map(|()| values));
+
+        self.call_stack.pop();
+        result
+    }
+
+    /// Runs `function` in the frame pushed for it.
+    fn run_function(
+        &mut self,
+        function: EvalValue,
+        args: Vec<EvalValue>,
+    ) -> Result<Vec<EvalValue>, LuaError> {
+        match function {
+            // Errors raised by native functions are reported at the position of their caller
+            EvalValue::NativeFunction(f) => f(self, args).map_err(|err| self.locate_error(err, 1)),
+            EvalValue::DeclaredFunction(function) => {
+                let mut args = args.into_iter();
+                let arguments = function
+                    .definition
+                    .arguments
+                    .iter()
+                    .map(|name| (name.clone(), args.next().unwrap_or(EvalValue::Nil)))
+                    .collect();
+                let varargs = if function.definition.is_variadic {
+                    args.collect()
+                } else {
+                    Vec::new()
+                };
+
+                let caller = ActivationState {
+                    scopes_stack: std::mem::take(&mut self.scopes_stack),
+                    function: self.current_function.replace(function.clone()),
+                    varargs: std::mem::replace(&mut self.varargs, varargs),
+                };
+                self.call_stack.last_mut().expect("Frame was pushed").caller = caller;
+
+                let result = self.execute_block(&function.definition.body, arguments);
+
+                let frame = self.call_stack.last_mut().expect("Frame was pushed");
+                let caller = std::mem::take(&mut frame.caller);
+                self.scopes_stack = caller.scopes_stack;
+                self.current_function = caller.function;
+                self.varargs = caller.varargs;
+
+                result.map(|control_flow| match control_flow {
+                    ControlFlow::Return(values) => values,
+                    _ => Vec::new(),
+                })
+            }
+            _ => unreachable!(),
+        }
+    }
+
+    /// Runs `block` in a fresh scope with `locals` already declared in it.
+    pub(crate) fn execute_block(
+        &mut self,
+        block: &[Statement],
+        locals: Vec<(String, EvalValue)>,
+    ) -> Result<ControlFlow, LuaError> {
+        self.enter_scope();
+
+        for (name, value) in locals {
+            self.declare_variable(name, value);
+        }
+
+        let result = self.execute_statements(block);
+        self.exit_scope(result)
+    }
+
+    /// Runs `block` in the current scope, stopping at the first `break`, `return` or error.
+    pub(crate) fn execute_statements(
+        &mut self,
+        block: &[Statement],
+    ) -> Result<ControlFlow, LuaError> {
+        for statement in block {
+            let position = statement.span().start;
+            self.set_position(position);
+            if self.hook.mask.get() & (HOOK_LINE | HOOK_COUNT) != 0 {
+                self.statement_hooks(position.line)
+                    .map_err(|err| self.locate_error(err, 0))?;
+            }
+
+            match statement
+                .execute(self)
+                .map_err(|err| self.locate_error(err, 0))?
+            {
+                ControlFlow::Normal => {}
+                control_flow => return Ok(control_flow),
+            }
+        }
+
+        Ok(ControlFlow::Normal)
+    }
+
+    /// Calls `function` from outside of any Lua code, e.g. a library loaded by the
+    /// command line.
+    pub fn call(
+        &mut self,
+        function: EvalValue,
+        args: Vec<EvalValue>,
+    ) -> Result<Vec<EvalValue>, LuaError> {
+        self.on_interpreter_stack(|virtual_machine| virtual_machine.call_function(function, args))
+    }
+
+    /// Runs `f` on a native stack large enough for deeply nested Lua calls, whose
+    /// exhaustion is reported as a Lua error instead of crashing the process.
+    pub(crate) fn on_interpreter_stack<R>(&mut self, f: impl FnOnce(&mut Self) -> R) -> R {
+        if self.stack_limit != 0 {
+            return f(self);
+        }
+
+        let stack = DefaultStack::new(MAIN_STACK_SIZE).expect("Cannot allocate the Lua stack");
+        self.stack_limit = stack.limit().get();
+        let result = corosensei::on_stack(stack, || f(self));
+        self.stack_limit = 0;
+
+        result
+    }
 }
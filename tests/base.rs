@@ -0,0 +1,135 @@
+#![cfg(feature = "cli")]
+
+mod common;
+
+use common::{check_errors, check_results, output};
+
+#[test]
+fn names_types() {
+    check_results(&[(
+        "type(nil), type(1), type(\"s\"), type({}), type(print), type(coroutine.create(print))",
+        "nil\tnumber\tstring\ttable\tfunction\tthread",
+    )]);
+}
+
+#[test]
+fn converts_values_to_strings() {
+    check_results(&[
+        (
+            "tostring(nil), tostring(1.5), tostring(true)",
+            "nil\t1.5\ttrue",
+        ),
+        (r#"tostring({}):match("^table: 0x%x+$") ~= nil"#, "true"),
+        (
+            r#"tostring(print):match("^function: 0x%x+$") ~= nil"#,
+            "true",
+        ),
+        (
+            r#"tostring(setmetatable({}, {__tostring = function() return "custom" end}))"#,
+            "custom",
+        ),
+        (
+            r#"tostring(setmetatable({}, {__name = "My"})):match("^My: ") ~= nil"#,
+            "true",
+        ),
+    ]);
+}
+
+#[test]
+fn converts_strings_to_numbers() {
+    check_results(&[
+        (
+            r#"tonumber("  10  "), tonumber("0x10"), tonumber("1e2")"#,
+            "10\t16\t100.0",
+        ),
+        (
+            r#"tonumber("z", 36), tonumber("ff", 16), tonumber("10", 2)"#,
+            "35\t255\t2",
+        ),
+        (
+            r#"tonumber("8", 8), tonumber("abc"), tonumber("")"#,
+            "nil\tnil\tnil",
+        ),
+    ]);
+}
+
+#[test]
+fn bypasses_metamethods_with_raw_access() {
+    let source = r#"
+        local t = setmetatable({}, {
+            __index = function() return "meta" end,
+            __newindex = function() error("no") end,
+            __len = function() return 9 end,
+        })
+        rawset(t, "k", "v")
+        print(rawget(t, "k"), rawget(t, "x"), t.x, rawlen(t), #t, rawlen("abc"))
+        print(rawequal(t, t), rawequal(t, {}))
+    "#;
+
+    assert_eq!(output(source), "v\tnil\tmeta\t0\t9\t3\ntrue\tfalse");
+}
+
+#[test]
+fn iterates_and_selects() {
+    check_results(&[
+        ("next({})", "nil"),
+        ("next({10})", "1\t10"),
+        (r##"select("#", 1, nil, 3), select(-1, "a", "b")"##, "3\tb"),
+        (r#"select(2, "a", "b", "c")"#, "b\tc"),
+        (
+            r#"getmetatable(setmetatable({}, {__metatable = "locked"}))"#,
+            "locked",
+        ),
+        ("_VERSION", "Lua 5.4"),
+    ]);
+
+    let source = r#"
+        local count = 0
+        for _ in pairs({a = 1, b = 2, 3}) do count = count + 1 end
+        print(count)
+        for i, v in ipairs({"a", "b", nil, "d"}) do print(i, v) end
+    "#;
+    assert_eq!(output(source), "3\n1\ta\n2\tb");
+}
+
+#[test]
+fn prints_any_value() {
+    let output = output("print({}, nil, 1, print)");
+
+    assert!(output.starts_with("table: 0x"), "{}", output);
+    assert!(output.contains("\tnil\t1\tfunction: 0x"), "{}", output);
+}
+
+#[test]
+fn reports_invalid_arguments() {
+    check_errors(&[
+        (
+            "select, 0, 1",
+            "bad argument #1 to 'select' (index out of range)",
+        ),
+        (
+            r#"tonumber, "10", 99"#,
+            "bad argument #2 to 'tonumber' (base out of range)",
+        ),
+        (
+            "setmetatable, 1, {}",
+            "bad argument #1 to 'setmetatable' (table expected, got number)",
+        ),
+    ]);
+}
+
+#[test]
+fn estimates_memory_in_use() {
+    let source = r#"
+        local before = collectgarbage("count")
+        print(before > 0, math.type(before))
+        big = {}
+        for i = 1, 10000 do big[i] = "item" .. i end
+        local grown = collectgarbage("count")
+        print(grown > before + 100)
+        big = nil
+        print(collectgarbage(), collectgarbage("count") < grown)
+    "#;
+
+    assert_eq!(output(source), "true\tfloat\ntrue\n0\ttrue");
+}
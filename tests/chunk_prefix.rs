@@ -0,0 +1,60 @@
+#![cfg(feature = "cli")]
+
+mod common;
+
+use common::{luir, output, run, Script};
+
+#[test]
+fn skips_shebang_line() {
+    assert_eq!(output("#!/usr/bin/env luir\nprint(\"ran\")\n"), "ran");
+}
+
+#[test]
+fn skips_byte_order_mark() {
+    assert_eq!(output("\u{feff}print(\"ran\")\n"), "ran");
+    assert_eq!(output("\u{feff}# comment\nprint(\"ran\")\n"), "ran");
+}
+
+#[test]
+fn keeps_line_numbers_after_shebang() {
+    let run = run("#!/usr/bin/env luir\nlocal x = nil + 1\n");
+
+    assert!(
+        run.stderr
+            .contains(".lua:2: attempt to perform arithmetic on a nil value"),
+        "{}",
+        run.stderr
+    );
+}
+
+#[test]
+fn skips_shebang_on_standard_input() {
+    assert_eq!(
+        luir(&["-"], "#!/usr/bin/env luir\nprint(\"ran\")\n").stdout,
+        "ran"
+    );
+}
+
+#[test]
+fn runs_scripts_that_are_not_utf8() {
+    let script = b"\xef\xbb\xbf#!/usr/bin/env luir\nprint(select(-1, (\"caf\xe9\"):byte(1, -1)))\n";
+
+    assert_eq!(output(script), "233");
+}
+
+#[test]
+fn loads_files_that_are_not_utf8() {
+    let module = Script::new(b"#!/usr/bin/env luir\nreturn \"caf\xe9\"\n");
+    let source = format!(
+        r#"
+        local path = {:?}
+        print(dofile(path):byte(-1))
+        print(loadfile(path)():byte(-1))
+        package.path = path:gsub("[^/\\]*$", "?.lua")
+        print(require(path:match("([^/\\]*)%.lua$")):byte(-1))
+        "#,
+        module.path()
+    );
+
+    assert_eq!(output(source), "233\n233\n233");
+}
@@ -0,0 +1,68 @@
+#![cfg(feature = "cli")]
+
+mod common;
+
+use common::{output, run};
+
+#[test]
+fn shares_captured_variables() {
+    let source = r#"
+        local function counter()
+            local count = 0
+            return function() count = count + 1 return count end,
+                function() return count end
+        end
+        local increment, get = counter()
+        increment()
+        increment()
+        print(get())
+    "#;
+
+    assert_eq!(output(source), "2");
+}
+
+#[test]
+fn captures_variables_used_by_nested_functions() {
+    let source = r#"
+        local x = "outer"
+        local function middle()
+            return function() return x end
+        end
+        local inner = middle()
+        x = "changed"
+        print(inner())
+    "#;
+
+    assert_eq!(output(source), "changed");
+}
+
+#[test]
+fn captures_recursive_local_functions() {
+    let source = r#"
+        local function fact(n)
+            if n <= 1 then return 1 end
+            return n * fact(n - 1)
+        end
+        print(fact(10))
+    "#;
+
+    assert_eq!(output(source), "3628800");
+}
+
+#[test]
+fn drops_long_chains_of_tables() {
+    let run = run(r#"
+        coroutine.wrap(function()
+            local list = nil
+            for i = 1, 200000 do list = {next = list} end
+            list = nil
+            local current = {}
+            local root = current
+            for i = 1, 200000 do current.next = {} current = current.next end
+        end)()
+        print("done")
+    "#);
+
+    assert_eq!(run.stdout, "done");
+    assert_eq!(run.status, 0);
+}
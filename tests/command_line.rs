@@ -0,0 +1,83 @@
+#![cfg(feature = "cli")]
+
+mod common;
+
+use common::{luir, luir_with_env, Script};
+
+#[test]
+fn runs_statements_in_order() {
+    let run = luir(&["-e", "x = 1", "-e", "print(x + 1)"], "");
+
+    assert_eq!(run.stdout, "2");
+}
+
+#[test]
+fn passes_script_arguments() {
+    let script =
+        Script::new("print(...)\nprint(#arg, arg[0] == SCRIPT, arg[1], arg[2], arg[-2])\n");
+    let source = format!("SCRIPT = {:?}", script.path());
+    let run = luir(&["-e", &source, script.path(), "x", "y"], "");
+
+    assert_eq!(run.stdout, "x\ty\n2\ttrue\tx\ty\t-e");
+}
+
+#[test]
+fn reads_the_script_from_standard_input() {
+    assert_eq!(
+        luir(&["-", "a", "b"], "print(\"stdin\", ...)").stdout,
+        "stdin\ta\tb"
+    );
+    assert_eq!(luir(&[] as &[&str], "print(\"piped\")").stdout, "piped");
+}
+
+#[test]
+fn stops_handling_options_after_double_dash() {
+    let script = Script::new("print(...)");
+    let run = luir(&["--", script.path(), "-e"], "");
+
+    assert_eq!(run.stdout, "-e");
+}
+
+#[test]
+fn shows_version() {
+    let run = luir(&["-v"], "");
+
+    assert!(run.stdout.starts_with("luir "), "{}", run.stdout);
+}
+
+#[test]
+fn runs_lua_init_unless_ignoring_environment() {
+    let init = [("LUA_INIT", "print(\"init\")")];
+    let run = |args: &[&str]| luir_with_env(args, "", &init).stdout;
+
+    assert_eq!(run(&["-e", "print(2)"]), "init\n2");
+    assert_eq!(run(&["-E", "-e", "print(2)"]), "2");
+}
+
+#[test]
+fn turns_warnings_on() {
+    assert_eq!(
+        luir(&["-W", "-e", "warn(\"hi\")"], "").stderr,
+        "Lua warning: hi\n"
+    );
+    assert_eq!(luir(&["-e", "warn(\"hi\")"], "").stderr, "");
+}
+
+#[test]
+fn accepts_attached_option_values() {
+    let run = luir(
+        &["-lstring", "-ex = 1", "-eprint(x, string.upper(\"a\"))"],
+        "",
+    );
+
+    assert_eq!(run.stdout, "1\tA");
+    assert_eq!(run.status, 0);
+}
+
+#[test]
+fn leaves_script_arguments_that_look_like_options_alone() {
+    let script = Script::new("print(...)");
+    let run = luir(&["-e", "-- -lx", script.path(), "-eprint(1)"], "");
+
+    assert_eq!(run.stdout, "-eprint(1)");
+}
@@ -0,0 +1,130 @@
+//! Runs Lua code with the `luir` binary for the integration tests.
+#![allow(dead_code)]
+
+use std::{
+    io::Write,
+    path::PathBuf,
+    process::{Command, Stdio},
+    sync::atomic::{AtomicUsize, Ordering},
+};
+
+/// What a run of the interpreter printed and how it exited.
+pub struct Run {
+    /// Standard output with trailing whitespace removed from every line.
+    pub stdout: String,
+    pub stderr: String,
+    pub status: i32,
+}
+
+/// A script written to a temporary file, removed when dropped.
+pub struct Script {
+    path: PathBuf,
+}
+
+impl Script {
+    pub fn new(source: impl AsRef<[u8]>) -> Self {
+        static SCRIPTS: AtomicUsize = AtomicUsize::new(0);
+        let path = std::env::temp_dir().join(format!(
+            "luir-test-{}-{}.lua",
+            std::process::id(),
+            SCRIPTS.fetch_add(1, Ordering::Relaxed)
+        ));
+        std::fs::write(&path, source).unwrap();
+        Script { path }
+    }
+
+    pub fn path(&self) -> &str {
+        self.path.to_str().expect("Temporary paths are valid UTF-8")
+    }
+}
+
+impl Drop for Script {
+    fn drop(&mut self) {
+        let _ = std::fs::remove_file(&self.path);
+    }
+}
+
+/// Runs the interpreter with `args`, writing `stdin` to its standard input.
+pub fn luir(args: &[&str], stdin: &str) -> Run {
+    luir_with_env(args, stdin, &[])
+}
+
+/// Like [`luir`], with the environment variables `env` set.
+pub fn luir_with_env(args: &[&str], stdin: &str, env: &[(&str, &str)]) -> Run {
+    let mut child = Command::new(env!("CARGO_BIN_EXE_luir"))
+        .args(args)
+        .envs(env.iter().copied())
+        .stdin(Stdio::piped())
+        .stdout(Stdio::piped())
+        .stderr(Stdio::piped())
+        .spawn()
+        .unwrap();
+    child
+        .stdin
+        .take()
+        .unwrap()
+        .write_all(stdin.as_bytes())
+        .unwrap();
+    let output = child.wait_with_output().unwrap();
+
+    Run {
+        stdout: String::from_utf8_lossy(&output.stdout)
+            .lines()
+            .map(str::trim_end)
+            .collect::<Vec<_>>()
+            .join("\n"),
+        stderr: String::from_utf8_lossy(&output.stderr).into_owned(),
+        status: output.status.code().unwrap_or(-1),
+    }
+}
+
+/// Runs `source` as a script file.
+pub fn run(source: impl AsRef<[u8]>) -> Run {
+    let script = Script::new(source);
+    luir(&[script.path()], "")
+}
+
+/// Runs `source`, which must succeed, and returns what it printed.
+pub fn output(source: impl AsRef<[u8]>) -> String {
+    let run = run(source);
+    assert_eq!(run.status, 0, "{}", run.stderr);
+    run.stdout
+}
+
+/// Prints each expression of `cases` in one script and checks that it prints the
+/// expected line, with multiple values separated by tabs.
+pub fn check_results(cases: &[(&str, &str)]) {
+    check_results_after("", cases);
+}
+
+/// Like [`check_results`], with the expressions evaluated after running `prelude`.
+pub fn check_results_after(prelude: &str, cases: &[(&str, &str)]) {
+    let mut source = format!("{}\n", prelude);
+    for (expression, _) in cases {
+        source += &format!("print({})\n", expression);
+    }
+    let output = output(source);
+    let lines: Vec<&str> = output.lines().collect();
+
+    assert_eq!(lines.len(), cases.len(), "{}", output);
+    for ((expression, expected), line) in cases.iter().zip(lines) {
+        assert_eq!(line, *expected, "{}", expression);
+    }
+}
+
+/// Checks that calling each function of `cases` with its arguments, given as
+/// `function, arguments`, raises the expected error message.
+pub fn check_errors(cases: &[(&str, &str)]) {
+    let cases: Vec<(String, &str)> = cases
+        .iter()
+        .map(|(call, message)| {
+            let expression = format!("(function(_, err) return err end)(pcall({}))", call);
+            (expression, *message)
+        })
+        .collect();
+    let cases: Vec<(&str, &str)> = cases
+        .iter()
+        .map(|(expression, message)| (expression.as_str(), *message))
+        .collect();
+    check_results(&cases);
+}
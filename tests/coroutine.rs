@@ -0,0 +1,146 @@
+#![cfg(feature = "cli")]
+
+mod common;
+
+use common::output;
+
+#[test]
+fn passes_values_across_nested_calls() {
+    let source = r#"
+        local function inner(x)
+            local y = coroutine.yield(x + 1)
+            return y * 2
+        end
+        local co = coroutine.create(function(a, b)
+            print("start", a, b)
+            local c, d = coroutine.yield(inner(a))
+            return c, d
+        end)
+        print(coroutine.resume(co, 1, 2))
+        print(coroutine.resume(co, 10))
+        print(coroutine.resume(co, "x", "y"))
+        print(coroutine.status(co))
+        print(coroutine.resume(co))
+    "#;
+
+    assert_eq!(
+        output(source),
+        "start\t1\t2\n\
+         true\t2\n\
+         true\t20\n\
+         true\tx\ty\n\
+         dead\n\
+         false\tcannot resume dead coroutine"
+    );
+}
+
+#[test]
+fn tracks_status() {
+    let source = r#"
+        local outer
+        local inner = coroutine.create(function()
+            print("inner sees", coroutine.status(outer))
+        end)
+        outer = coroutine.create(function()
+            print("outer sees", coroutine.status(outer), coroutine.isyieldable())
+            coroutine.resume(inner)
+            coroutine.yield()
+        end)
+        print(coroutine.status(outer), coroutine.isyieldable())
+        coroutine.resume(outer)
+        print(coroutine.status(outer))
+        coroutine.resume(outer)
+        print(coroutine.status(outer))
+    "#;
+
+    assert_eq!(
+        output(source),
+        "suspended\tfalse\n\
+         outer sees\trunning\ttrue\n\
+         inner sees\tnormal\n\
+         suspended\n\
+         dead"
+    );
+}
+
+#[test]
+fn wrap_propagates_errors() {
+    let source = r#"
+        local wrapped = coroutine.wrap(function()
+            coroutine.yield(1)
+            undefined_function()
+        end)
+        print(wrapped())
+        print(coroutine.resume(coroutine.create(function()
+            wrapped()
+            print("not reached")
+        end)))
+    "#;
+
+    let output = output(source);
+    let lines: Vec<&str> = output.lines().collect();
+    assert_eq!(lines.len(), 2, "{}", output);
+    assert_eq!(lines[0], "1");
+    assert!(lines[1].starts_with("false\t"), "{}", lines[1]);
+    assert!(
+        lines[1].contains("attempt to call a nil value"),
+        "{}",
+        lines[1]
+    );
+}
+
+#[test]
+fn close_runs_pending_to_be_closed_variables() {
+    let source = r#"
+        local co = coroutine.create(function()
+            local a <close> = setmetatable({}, {__close = function(_, e) print("close a", e) end})
+            local b <close> = setmetatable({}, {__close = function(_, e) print("close b", e) end})
+            coroutine.yield()
+            print("not reached")
+        end)
+        coroutine.resume(co)
+        print(coroutine.close(co))
+        print(coroutine.status(co))
+    "#;
+
+    assert_eq!(output(source), "close b\tnil\nclose a\tnil\ntrue\ndead");
+}
+
+#[test]
+fn creates_coroutines_without_running_them() {
+    let source = r#"
+        local threads = {}
+        for i = 1, 40000 do threads[i] = coroutine.create(function() return i end) end
+        print(#threads, coroutine.resume(threads[40000]))
+    "#;
+
+    assert_eq!(output(source), "40000\ttrue\t40000");
+}
+
+#[test]
+fn frees_abandoned_coroutines() {
+    let source = r#"
+        for i = 1, 40000 do
+            coroutine.wrap(function() coroutine.yield(i) end)()
+        end
+        print("done")
+    "#;
+
+    assert_eq!(output(source), "done");
+}
+
+#[test]
+fn survives_deeply_nested_expressions() {
+    // How deep expressions can nest depends on the size of the native stack frames, but
+    // running out of stack must be a Lua error
+    let source = r#"
+        local source = "return " .. string.rep("1+", 200000) .. "1"
+        local function check(ok, result)
+            return ok and result == 200001 or not ok and result:find("stack overflow") ~= nil
+        end
+        print(check(pcall(load(source))))
+        print(coroutine.wrap(function() return check(pcall(load(source))) end)())
+    "#;
+
+    assert_eq!(output(source), "true\ntrue");
+}
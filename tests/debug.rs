@@ -0,0 +1,109 @@
+#![cfg(feature = "cli")]
+
+mod common;
+
+use common::{check_results, output};
+
+#[test]
+fn describes_functions() {
+    let source = r#"
+        local function f(a, b, ...)
+            local info = debug.getinfo(1, "nSlu")
+            print(info.currentline, info.name, info.what, info.nparams, info.isvararg, info.linedefined)
+            print(info.source:sub(1, 1), info.short_src:sub(-4))
+        end
+        f(1, 2)
+        print(debug.getinfo(print).what, debug.getinfo(100))
+    "#;
+
+    assert_eq!(output(source), "3\tf\tLua\t2\ttrue\t2\n@\t.lua\nC\tnil");
+}
+
+#[test]
+fn reads_and_writes_locals_and_upvalues() {
+    let source = r#"
+        local function f(a, b)
+            print(debug.getlocal(1, 1), debug.getlocal(1, 2))
+            debug.setlocal(1, 1, "changed")
+            print(a)
+        end
+        f(1, 2)
+        local up = 10
+        local function g() return up end
+        local function h() return up end
+        print(debug.getupvalue(g, 1))
+        debug.setupvalue(g, 1, 20)
+        print(g(), up, debug.upvalueid(g, 1) == debug.upvalueid(h, 1))
+    "#;
+
+    assert_eq!(output(source), "a\tb\t2\nchanged\nup\t10\n20\t20\ttrue");
+}
+
+#[test]
+fn builds_tracebacks() {
+    let output = output(
+        "local function f()\n\
+         return debug.traceback(\"message\", 1)\n\
+         end\n\
+         print(f())\n",
+    );
+    let lines: Vec<&str> = output.lines().collect();
+
+    assert_eq!(lines[..2], ["message", "stack traceback:"]);
+    assert!(lines[2].ends_with(".lua:2: in local 'f'"), "{}", output);
+    assert!(lines[3].ends_with(".lua:4: in main chunk"), "{}", output);
+}
+
+#[test]
+fn calls_hooks() {
+    let source = r#"
+        local calls = 0
+        debug.sethook(function() calls = calls + 1 end, "c")
+        local function k() end
+        k()
+        k()
+        print(select(2, debug.gethook()))
+        debug.sethook()
+        print(calls >= 2, debug.gethook())
+        local lines = {}
+        debug.sethook(function(event, line) lines[#lines + 1] = line end, "l")
+        local x = 1
+        local y = 2
+        debug.sethook()
+        print(table.concat(lines, ","))
+    "#;
+
+    assert_eq!(output(source), "c\t0\ntrue\tnil\n12,13,14");
+}
+
+#[test]
+fn calls_hooks_inside_loops() {
+    let source = r#"
+        print(pcall(function()
+            debug.sethook(function() error("timeout", 0) end, "", 1000)
+            while true do end
+        end))
+        debug.sethook()
+        local counts = 0
+        debug.sethook(function() counts = counts + 1 end, "", 100)
+        for i = 1, 1000 do end
+        debug.sethook()
+        print(counts)
+        local lines = {}
+        debug.sethook(function(event, line) lines[#lines + 1] = line end, "l")
+        for i = 1, 3 do
+        end
+        debug.sethook()
+        print(table.concat(lines, ","))
+    "#;
+
+    assert_eq!(output(source), "false\ttimeout\n10\n14,14,14,14,16");
+}
+
+#[test]
+fn accesses_any_metatable() {
+    check_results(&[
+        ("debug.getmetatable(\"\").__index == string", "true"),
+        ("debug.setmetatable(5, nil)", "5"),
+    ]);
+}
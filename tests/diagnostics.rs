@@ -0,0 +1,77 @@
+#![cfg(feature = "cli")]
+
+mod common;
+
+use common::{luir, Script};
+
+/// Runs `source` with `options`, returning the exit status and standard error with
+/// the script path replaced by `script.lua`.
+fn diagnose(options: &[&str], source: &str) -> (i32, String) {
+    let script = Script::new(source);
+    let mut args = options.to_vec();
+    args.push(script.path());
+    let run = luir(&args, "");
+    (run.status, run.stderr.replace(script.path(), "script.lua"))
+}
+
+#[test]
+fn shows_syntax_errors_with_source_snippet() {
+    let (status, stderr) = diagnose(&["--color", "never"], "x = = 1\n");
+
+    assert_eq!(status, 3);
+    assert_eq!(
+        stderr,
+        "luir: script.lua:1: unexpected symbol near '='\n \
+         --> script.lua:1:5\n  \
+         |\n\
+         1 | x = = 1\n  \
+         |     ^ expression expected\n"
+    );
+}
+
+#[test]
+fn shows_runtime_errors_with_location_and_traceback() {
+    let (status, stderr) = diagnose(&["--color", "never"], "local a = 1\nlocal b = a + nil\n");
+
+    assert_eq!(status, 1);
+    assert!(
+        stderr.starts_with(
+            "luir: script.lua:2: attempt to perform arithmetic on a nil value\n \
+             --> script.lua:2:"
+        ),
+        "{}",
+        stderr
+    );
+    assert!(stderr.contains("2 | local b = a + nil\n"), "{}", stderr);
+    assert!(
+        stderr.ends_with("stack traceback:\n\tscript.lua:2: in main chunk\n"),
+        "{}",
+        stderr
+    );
+}
+
+#[test]
+fn colours_only_when_asked() {
+    let (_, plain) = diagnose(&[], "x = = 1\n");
+    let (_, coloured) = diagnose(&["--color", "always"], "x = = 1\n");
+
+    assert!(!plain.contains('\x1b'), "{}", plain);
+    assert!(
+        coloured.starts_with("\x1b[1;31mluir:\x1b[0m"),
+        "{}",
+        coloured
+    );
+}
+
+#[test]
+fn reports_unreadable_scripts() {
+    let run = luir(&["/nonexistent/script.lua"], "");
+
+    assert_eq!(run.status, 1);
+    assert!(
+        run.stderr
+            .starts_with("luir: cannot open /nonexistent/script.lua"),
+        "{}",
+        run.stderr
+    );
+}
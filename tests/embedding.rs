@@ -0,0 +1,192 @@
+use luir::{EvalValue, Function, Lua, LuaError, Table};
+
+#[test]
+fn exchanges_values_with_globals() {
+    let lua = Lua::new();
+    let globals = lua.globals();
+    globals.set("number", 42).unwrap();
+    globals.set("text", "hello").unwrap();
+    globals.set("missing", None::<i64>).unwrap();
+
+    assert_eq!(globals.get::<_, i64>("number").unwrap(), 42);
+    assert_eq!(globals.get::<_, String>("text").unwrap(), "hello");
+    assert_eq!(globals.get::<_, Option<i64>>("missing").unwrap(), None);
+    assert!(globals.get::<_, i64>("text").is_err());
+}
+
+#[test]
+fn evaluates_and_runs_chunks() {
+    let mut lua = Lua::new();
+    lua.load("values = {10, 20, 30}").exec().unwrap();
+
+    let values: Table = lua.globals().get("values").unwrap();
+    assert_eq!(values.len(), 3);
+    assert_eq!(lua.load("values[2] + 1").eval::<i64>().unwrap(), 21);
+    assert_eq!(
+        lua.load("return ..., 'x'")
+            .call::<_, (i64, String)>(5)
+            .unwrap(),
+        (5, "x".to_string())
+    );
+}
+
+#[test]
+fn evaluates_statements_that_are_not_expressions() {
+    let mut lua = Lua::new();
+    lua.load("n = 0 function inc() n = n + 1 end")
+        .exec()
+        .unwrap();
+
+    lua.load("inc() inc()").eval::<()>().unwrap();
+    assert_eq!(lua.globals().get::<_, i64>("n").unwrap(), 2);
+    assert_eq!(lua.load("inc() return n").eval::<i64>().unwrap(), 3);
+    assert_eq!(lua.load("n, n * 2").eval::<(i64, i64)>().unwrap(), (3, 6));
+}
+
+#[test]
+fn calls_lua_functions() {
+    let mut lua = Lua::new();
+    lua.load("function add(a, b) return a + b, a * b end")
+        .exec()
+        .unwrap();
+
+    let add: Function = lua.globals().get("add").unwrap();
+    let (sum, product): (i64, i64) = add.call(&mut lua, (3, 4)).unwrap();
+    assert_eq!((sum, product), (7, 12));
+}
+
+#[test]
+fn runs_chunks_in_environments() {
+    let mut lua = Lua::new();
+    let environment = lua.create_table();
+    environment.set("x", 1).unwrap();
+    lua.load("y = x + 1")
+        .set_environment(environment.clone())
+        .exec()
+        .unwrap();
+
+    assert_eq!(environment.get::<_, i64>("y").unwrap(), 2);
+    assert_eq!(
+        lua.globals().get::<_, EvalValue>("y").unwrap(),
+        EvalValue::Nil
+    );
+}
+
+#[test]
+fn loads_source_code_that_is_not_utf8() {
+    let mut lua = Lua::new();
+
+    let bytes: (i64, i64, i64, i64) = lua
+        .load(b"return (\"caf\xe9\"):byte(1, -1)")
+        .eval()
+        .unwrap();
+    assert_eq!(bytes, (99, 97, 102, 233));
+
+    let err = lua.load(b"x = \xe9").set_name("=chunk").exec().unwrap_err();
+    assert!(
+        err.message()
+            .starts_with("chunk:1: unexpected symbol near '<\\233>'"),
+        "{}",
+        err.message()
+    );
+}
+
+#[test]
+fn returns_errors() {
+    let mut lua = Lua::new();
+
+    let err = lua.load("x = = 1").exec().unwrap_err();
+    assert!(matches!(err, LuaError::Syntax(_)));
+
+    let err = lua
+        .load("error('failed')")
+        .set_name("=chunk")
+        .exec()
+        .unwrap_err();
+    assert_eq!(err.message(), "chunk:1: failed");
+}
+
+#[test]
+fn converts_values_with_metamethods() {
+    let mut lua = Lua::new();
+    let point: EvalValue = lua
+        .load("setmetatable({}, {__tostring = function() return 'point' end})")
+        .eval()
+        .unwrap();
+
+    assert!(matches!(
+        lua.get_metamethod(&point, "__tostring"),
+        EvalValue::DeclaredFunction(_)
+    ));
+    assert_eq!(lua.get_metamethod(&point, "__index"), EvalValue::Nil);
+    assert_eq!(lua.tostring(&point).unwrap().to_str_lossy(), "point");
+}
+
+#[test]
+fn calls_rust_closures_with_state() {
+    let mut lua = Lua::new();
+    let prefix = String::from("> ");
+    let quote = lua.create_function(move |_, (text, times): (String, usize)| {
+        Ok((format!("{}{}", prefix, text.repeat(times)), times))
+    });
+    let mut total = 0;
+    let accumulate = lua.create_function_mut(move |_, amount: i64| {
+        total += amount;
+        Ok(total)
+    });
+    lua.globals().set("quote", quote).unwrap();
+    lua.globals().set("accumulate", accumulate).unwrap();
+
+    let (text, times): (String, i64) = lua.load("quote('ab', 2)").eval().unwrap();
+    assert_eq!((text.as_str(), times), ("> abab", 2));
+    let total: i64 = lua
+        .load("accumulate(1) accumulate(2) return accumulate(3)")
+        .eval()
+        .unwrap();
+    assert_eq!(total, 6);
+}
+
+#[test]
+fn raises_errors_from_rust_closures() {
+    let mut lua = Lua::new();
+    let fail = lua.create_function(|_, ()| Err::<(), _>(LuaError::runtime("from rust")));
+    lua.globals().set("fail", fail).unwrap();
+
+    let result: (bool, String) = lua.load("pcall(fail)").eval().unwrap();
+    assert_eq!(result, (false, "from rust".to_string()));
+}
+
+#[test]
+fn resumes_and_yields_from_rust_closures() {
+    let mut lua = Lua::new();
+    let coroutine: Table = lua.globals().get("coroutine").unwrap();
+    let resume: Function = coroutine.get("resume").unwrap();
+    let yield_: Function = coroutine.get("yield").unwrap();
+
+    let double_and_yield = lua.create_function(move |lua, value: i64| {
+        let reply: i64 = yield_.call(lua, value * 2)?;
+        Ok(reply + 1)
+    });
+    let resume_twice = lua.create_function(move |lua, thread: EvalValue| {
+        let (_, first): (bool, i64) = resume.call(lua, (thread.clone(), 5))?;
+        let (_, second): (bool, i64) = resume.call(lua, (thread, first))?;
+        Ok((first, second))
+    });
+    lua.globals()
+        .set("double_and_yield", double_and_yield)
+        .unwrap();
+    lua.globals().set("resume_twice", resume_twice).unwrap();
+
+    let results: (i64, i64) = lua
+        .load(
+            r#"
+            local thread = coroutine.create(function(value)
+                return double_and_yield(value) * 10
+            end)
+            return resume_twice(thread)
+            "#,
+        )
+        .eval()
+        .unwrap();
+    assert_eq!(results, (10, 110));
+}
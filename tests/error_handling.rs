@@ -0,0 +1,91 @@
+#![cfg(feature = "cli")]
+
+mod common;
+
+use common::{output, run};
+
+#[test]
+fn catches_errors_with_any_value() {
+    let source = r#"
+        print(pcall(error, "message"))
+        local ok, err = pcall(function() error({code = 42}) end)
+        print(ok, err.code)
+        print(pcall(error))
+        print(pcall(function(a, b) return a + b end, 1, 2))
+    "#;
+
+    assert_eq!(
+        output(source),
+        "false\tmessage\nfalse\t42\nfalse\tnil\ntrue\t3"
+    );
+}
+
+#[test]
+fn prefixes_string_errors_with_position() {
+    let output = output(
+        "local function f() error(\"deep\", 2) end\n\
+         print(pcall(function() error(\"here\") end))\n\
+         print(pcall(function()\n f() end))\n\
+         print(pcall(error, \"no position\", 0))\n\
+         print(pcall(function() local x = nil + 1 end))\n",
+    );
+    let lines: Vec<&str> = output.lines().collect();
+
+    assert!(lines[0].ends_with(".lua:2: here"), "{}", lines[0]);
+    assert!(lines[1].ends_with(".lua:4: deep"), "{}", lines[1]);
+    assert_eq!(lines[2], "false\tno position");
+    assert!(
+        lines[3].ends_with(".lua:6: attempt to perform arithmetic on a nil value"),
+        "{}",
+        lines[3]
+    );
+}
+
+#[test]
+fn calls_message_handlers() {
+    let source = r#"
+        print(xpcall(function() error("x", 0) end, function(m) return "handled: " .. m end))
+        print(xpcall(function(a, b) return a + b end, print, 1, 2))
+    "#;
+
+    assert_eq!(output(source), "false\thandled: x\ntrue\t3");
+}
+
+#[test]
+fn assert_returns_its_arguments() {
+    let source = r#"
+        print(assert(1, 2, 3))
+        print(pcall(assert, false))
+        print(pcall(assert, nil, "custom"))
+    "#;
+
+    assert_eq!(
+        output(source),
+        "1\t2\t3\nfalse\tassertion failed!\nfalse\tcustom"
+    );
+}
+
+#[test]
+fn yields_across_protected_calls() {
+    let source = r#"
+        local co = coroutine.wrap(function()
+            return pcall(function()
+                coroutine.yield(1)
+                error("after", 0)
+            end)
+        end)
+        print(co())
+        print(co())
+    "#;
+
+    assert_eq!(output(source), "1\nfalse\tafter");
+}
+
+#[test]
+fn uncaught_errors_stop_the_script() {
+    let run = run("print(\"before\")\nerror(\"top\")\nprint(\"after\")\n");
+
+    assert_eq!(run.stdout, "before");
+    assert_ne!(run.status, 0);
+    assert!(run.stderr.contains(".lua:2: top"), "{}", run.stderr);
+}
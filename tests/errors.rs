@@ -0,0 +1,90 @@
+#![cfg(feature = "cli")]
+
+mod common;
+
+use common::{output, run, Script};
+
+#[test]
+fn reports_uncaught_errors_with_traceback() {
+    let run = run("local function inner()\n\
+                   local t = nil\n\
+                   return t.x\n\
+                   end\n\
+                   local function outer()\n\
+                   inner()\n\
+                   end\n\
+                   outer()\n");
+    let (message, traceback) = run
+        .stderr
+        .split_once("\nstack traceback:\n")
+        .expect("Uncaught errors have a traceback");
+    let traceback: Vec<&str> = traceback.lines().collect();
+
+    assert_eq!(run.status, 1);
+    assert!(
+        message
+            .lines()
+            .next()
+            .unwrap()
+            .ends_with(".lua:3: attempt to index a nil value (local 't')"),
+        "{}",
+        run.stderr
+    );
+    assert!(traceback[0].contains(".lua:3: in "), "{}", run.stderr);
+    assert!(traceback[1].contains(".lua:6: in "), "{}", run.stderr);
+    assert!(
+        traceback[2].ends_with(".lua:8: in main chunk"),
+        "{}",
+        run.stderr
+    );
+}
+
+#[test]
+fn reports_runtime_error_kinds() {
+    let output = output(
+        "local function f() return f() + 1 end\n\
+         print(pcall(f))\n\
+         print(pcall(function() return {} < {} end))\n\
+         print(pcall(function() return #5 end))\n",
+    );
+    let lines: Vec<&str> = output.lines().collect();
+
+    assert!(lines[0].ends_with(".lua:1: stack overflow"), "{}", output);
+    assert!(
+        lines[1].ends_with(".lua:3: attempt to compare two table values"),
+        "{}",
+        output
+    );
+    assert!(
+        lines[2].ends_with(".lua:4: attempt to get length of a number value"),
+        "{}",
+        output
+    );
+}
+
+#[cfg(unix)]
+#[test]
+fn reports_allocation_failures_as_memory_errors() {
+    let script = Script::new(
+        r#"
+        print(pcall(string.rep, "x", 2^30))
+        local s = string.rep(string.rep("x", 2^10), 2^17)
+        print(pcall(function() return s .. s .. s .. s .. s .. s .. s .. s end))
+        print(pcall(string.format, "%s%s%s%s%s%s%s%s", s, s, s, s, s, s, s, s))
+        print(pcall(table.concat, {s, s, s, s, s, s, s, s}))
+        print(pcall(function() return io.open("/dev/zero"):read("a") end))
+        "#,
+    );
+    // Limit the address space so allocations fail instead of exhausting the machine
+    let output = std::process::Command::new("sh")
+        .args(["-c", "ulimit -v 800000 && exec \"$0\" \"$1\""])
+        .args([env!("CARGO_BIN_EXE_luir"), script.path()])
+        .output()
+        .unwrap();
+
+    assert_eq!(
+        String::from_utf8_lossy(&output.stdout),
+        "false\tnot enough memory\n".repeat(5)
+    );
+    assert!(output.status.success());
+}
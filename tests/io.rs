@@ -0,0 +1,86 @@
+#![cfg(feature = "cli")]
+
+mod common;
+
+use common::{luir, output, Script};
+
+/// Runs `source` with `PATH` set to the path of an empty temporary file.
+fn with_file(source: &str) -> String {
+    let file = Script::new("");
+    output(format!("local PATH = {:?}\n{}", file.path(), source))
+}
+
+#[test]
+fn writes_and_reads_files() {
+    let source = r#"
+        local f = assert(io.open(PATH, "w"))
+        print(f:write("12 3.5 line\n", "second\n", "third") == f)
+        f:close()
+        f = io.open(PATH)
+        print(f:read("n", "n", "l"))
+        print(f:read("L") == "second\n")
+        print(f:read(2), f:read("a"), f:read("a") == "", f:read("l"))
+        print(f:seek("set", 3), f:read(3), f:seek("cur"), f:seek("end"))
+        f:close()
+        print(io.type(f), io.type(io.stdout), io.type(1))
+        print(pcall(f.read, f))
+    "#;
+
+    assert_eq!(
+        with_file(source),
+        "true\n\
+         12\t3.5\t line\n\
+         true\n\
+         th\tird\ttrue\tnil\n\
+         3\t3.5\t6\t24\n\
+         closed file\tfile\tnil\n\
+         false\tattempt to use a closed file"
+    );
+}
+
+#[test]
+fn iterates_lines() {
+    let source = r#"
+        local f = io.open(PATH, "w")
+        f:write("one\ntwo\nthree")
+        f:close()
+        for line in io.lines(PATH) do io.write("[", line, "]") end
+        io.write("\n")
+        local reads = 0
+        for _ in io.lines(PATH, 1, 2) do reads = reads + 1 end
+        print(reads)
+    "#;
+
+    assert_eq!(with_file(source), "[one][two][three]\n5");
+}
+
+#[test]
+fn returns_failures_as_values() {
+    assert_eq!(
+        output("print(io.open(\"/nonexistent/file\"))"),
+        "nil\t/nonexistent/file: No such file or directory\t2"
+    );
+}
+
+#[test]
+fn reads_standard_input() {
+    let script = Script::new("print(io.read(\"l\", \"n\"))\nprint(io.read(\"a\"))");
+    let run = luir(&[script.path()], "from stdin\n42 rest\n");
+
+    assert_eq!(run.stdout, "from stdin\t42\n rest\n");
+}
+
+#[test]
+#[cfg(unix)]
+fn opens_processes_and_temporary_files() {
+    let source = r#"
+        local process = io.popen("echo hi")
+        print(process:read("l"), process:close())
+        local file = io.tmpfile()
+        file:write("temporary")
+        file:seek("set")
+        print(file:read("a"))
+    "#;
+
+    assert_eq!(output(source), "hi\ttrue\texit\t0\ntemporary");
+}
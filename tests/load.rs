@@ -0,0 +1,83 @@
+#![cfg(feature = "cli")]
+
+mod common;
+
+use common::{check_results, output, Script};
+
+#[test]
+fn loads_strings_and_reader_functions() {
+    let source = r#"
+        print(load("return 1 + ...")(2))
+        local parts = {"return ", "'pie", "ces'"}
+        local i = 0
+        print(load(function() i = i + 1 return parts[i] end)())
+        print(load("return _ENV")() == _G)
+    "#;
+
+    assert_eq!(output(source), "3\npieces\ntrue");
+}
+
+#[test]
+fn loads_strings_that_are_not_utf8() {
+    let source = r#"
+        print(select(-1, load("return \"caf\233\"")():byte(1, -1)))
+        print(load("return '\255' == '\\255'")())
+    "#;
+
+    assert_eq!(output(source), "233\ntrue");
+}
+
+#[test]
+fn returns_syntax_errors() {
+    check_results(&[
+        (
+            r#"load("x = ")"#,
+            "nil\t[string \"x = \"]:1: unexpected symbol near <eof>",
+        ),
+        (
+            r#"load("syntax error here", "=mychunk")"#,
+            "nil\tmychunk:1: syntax error near 'error'",
+        ),
+        (
+            r#"load("return 1", "chunk", "b")"#,
+            "nil\tattempt to load a text chunk (mode is 'b')",
+        ),
+        (
+            r#"pcall(load("error('inner')", "=name"))"#,
+            "false\tname:1: inner",
+        ),
+    ]);
+}
+
+#[test]
+fn binds_environments() {
+    let source = r#"
+        local env = {y = 5}
+        print(load("return y", "chunk", "t", env)(), load("y = 1 return y", "c", "t", env)(), env.y, y)
+    "#;
+
+    assert_eq!(output(source), "5\t1\t1\tnil");
+}
+
+#[test]
+fn loads_files() {
+    let module = Script::new("#!/usr/bin/env luir\nreturn ..., 'file'");
+    let source = format!(
+        "local name = {:?}\n\
+         print(loadfile(name)(7))\n\
+         print(dofile(name))\n\
+         print(loadfile(name, \"t\", {{}})(\"a\"))\n\
+         print(loadfile(\"/nonexistent.lua\"))\n\
+         print(pcall(dofile, \"/nonexistent.lua\"))\n",
+        module.path()
+    );
+
+    assert_eq!(
+        output(source),
+        "7\tfile\n\
+         nil\tfile\n\
+         a\tfile\n\
+         nil\tcannot open /nonexistent.lua: No such file or directory\n\
+         false\tcannot open /nonexistent.lua: No such file or directory"
+    );
+}
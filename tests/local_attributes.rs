@@ -0,0 +1,76 @@
+#![cfg(feature = "cli")]
+
+mod common;
+
+use common::{output, run};
+
+#[test]
+fn closes_variables_in_reverse_order() {
+    let source = r#"
+        local function closer(name)
+            return setmetatable({}, {__close = function() print("close", name) end})
+        end
+        do
+            local a <close> = closer("a")
+            local b <close> = closer("b")
+            print("body")
+        end
+        for i = 1, 3 do
+            local c <close> = closer(i)
+            if i == 2 then break end
+        end
+        local function f()
+            local d <close> = closer("d")
+            return "returned"
+        end
+        print(f())
+    "#;
+
+    assert_eq!(
+        output(source),
+        "body\nclose\tb\nclose\ta\nclose\t1\nclose\t2\nclose\td\nreturned"
+    );
+}
+
+#[test]
+fn closes_variables_on_errors() {
+    let run = run(r#"
+        local x <close> = setmetatable({}, {__close = function() print("closed") end})
+        undefined_function()
+    "#);
+
+    assert_eq!(run.stdout, "closed");
+    assert_ne!(run.status, 0);
+}
+
+#[test]
+fn accepts_nil_and_false_close_values() {
+    let source = r#"
+        local x <close> = nil
+        local y <close> = false
+        local z <const> = 10
+        print(z + 1)
+    "#;
+
+    assert_eq!(output(source), "11");
+}
+
+#[test]
+fn rejects_assignments_to_const_variables() {
+    let run = run("print(\"ran\")\nlocal x <const> = 1\nx = 2\n");
+
+    assert_eq!(run.stdout, "");
+    assert!(run
+        .stderr
+        .contains("attempt to assign to const variable 'x'"));
+}
+
+#[test]
+fn rejects_unknown_attributes_and_values_without_close() {
+    assert!(run("local x <foo> = 1")
+        .stderr
+        .contains("unknown attribute 'foo'"));
+    assert!(run("local x <close> = 1")
+        .stderr
+        .contains("variable 'x' got a non-closable value"));
+}
@@ -0,0 +1,97 @@
+#![cfg(feature = "cli")]
+
+mod common;
+
+use common::{check_errors, check_results, output};
+
+#[test]
+fn divides_and_raises_to_powers() {
+    check_results(&[
+        ("7 // 2, 7.0 // 2, -7 // 2, 1 // 0.0", "3\t3.0\t-4\tinf"),
+        ("7 % 3, -7 % 3, 7 % -3, 5.5 % 2", "1\t2\t-2\t1.5"),
+        ("-1 % math.huge", "inf"),
+        ("2 ^ 10", "1024.0"),
+    ]);
+}
+
+#[test]
+fn computes_functions() {
+    check_results(&[
+        (
+            "math.abs(-3), math.ceil(1.2), math.floor(-1.2), math.sqrt(16)",
+            "3\t2\t-2\t4.0",
+        ),
+        (
+            "math.huge, -math.huge, math.pi",
+            "inf\t-inf\t3.1415926535898",
+        ),
+        ("math.max(1, 5, 3), math.min(2, -1)", "5\t-1"),
+        (
+            "math.fmod(7, 3), math.fmod(-7, 3), math.modf(3.7)",
+            "1\t-1\t3.0\t0.7",
+        ),
+        (
+            "math.log(8, 2), math.log(100, 10), math.exp(0)",
+            "3.0\t2.0\t1.0",
+        ),
+        (
+            "math.atan(1, 1) == math.pi / 4, math.ult(1, -1)",
+            "true\ttrue",
+        ),
+    ]);
+}
+
+#[test]
+fn distinguishes_integers() {
+    check_results(&[
+        (
+            "math.maxinteger, math.mininteger",
+            "9223372036854775807\t-9223372036854775808",
+        ),
+        ("math.tointeger(3.0), math.tointeger(3.5)", "3\tnil"),
+        (
+            r#"math.type(1), math.type(1.0), math.type("1")"#,
+            "integer\tfloat\tnil",
+        ),
+        ("math.type(math.floor(3.5))", "integer"),
+    ]);
+}
+
+#[test]
+fn repeats_seeded_random_sequences() {
+    let source = r#"
+        math.randomseed(42)
+        local first = {math.random(1, 100), math.random(), math.random(0)}
+        math.randomseed(42)
+        local second = {math.random(1, 100), math.random(), math.random(0)}
+        print(first[1] == second[1], first[2] == second[2], first[3] == second[3])
+        print(first[1] >= 1 and first[1] <= 100, first[2] >= 0 and first[2] < 1)
+    "#;
+
+    assert_eq!(output(source), "true\ttrue\ttrue\ntrue\ttrue");
+}
+
+#[test]
+fn reports_invalid_arguments() {
+    check_errors(&[
+        (
+            "math.random, 2, 1",
+            "bad argument #1 to 'random' (interval is empty)",
+        ),
+        (
+            r#"math.floor, "x""#,
+            "bad argument #1 to 'floor' (number expected, got string)",
+        ),
+    ]);
+}
+
+#[test]
+fn rejects_integer_division_by_zero() {
+    let output = output("print(pcall(function() return 1 // 0 end))");
+
+    assert!(
+        output.ends_with(":1: attempt to perform 'n//0'"),
+        "{}",
+        output
+    );
+}
@@ -0,0 +1,51 @@
+#![cfg(feature = "cli")]
+
+mod common;
+
+use common::{check_results, output};
+
+#[test]
+fn calls_string_methods() {
+    check_results(&[
+        (r#"("abc"):upper(), ("%d-%s"):format(3, "x")"#, "ABC\t3-x"),
+        (r#"("hello"):sub(2, 3):rep(2)"#, "elel"),
+        (r#"getmetatable("").__index == string"#, "true"),
+        (r#"("x").len == string.len"#, "true"),
+    ]);
+}
+
+#[test]
+fn calls_methods_on_tables() {
+    let source = r#"
+        local account = {balance = 10}
+        function account:deposit(amount)
+            self.balance = self.balance + amount
+            return self
+        end
+        print(account:deposit(5):deposit(1).balance)
+    "#;
+
+    assert_eq!(output(source), "16");
+}
+
+#[test]
+fn looks_up_missing_fields_with_index() {
+    let source = r#"
+        local base = {greet = function(self) return "hi " .. self.name end}
+        local object = setmetatable({name = "lua"}, {__index = base})
+        local computed = setmetatable({}, {__index = function(t, key) return key .. "!" end})
+        print(object:greet(), computed.wow, object.name)
+    "#;
+
+    assert_eq!(output(source), "hi lua\twow!\tlua");
+}
+
+#[test]
+fn string_methods_extend_with_the_string_table() {
+    let source = r#"
+        function string.shout(s) return s:upper() .. "!" end
+        print(("hey"):shout())
+    "#;
+
+    assert_eq!(output(source), "HEY!");
+}
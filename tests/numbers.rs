@@ -0,0 +1,38 @@
+#![cfg(feature = "cli")]
+
+mod common;
+
+use common::check_results;
+
+#[test]
+fn keeps_integers_and_floats_apart() {
+    check_results(&[
+        ("1, 1.0, -0.0, 1e2", "1\t1.0\t-0.0\t100.0"),
+        ("2 * 3, 2 * 3.0, 5 - 0.5", "6\t6.0\t4.5"),
+        ("3 / 2, 4 / 2", "1.5\t2.0"),
+        (
+            "0x10, 0xff, 0x7fffffffffffffff",
+            "16\t255\t9223372036854775807",
+        ),
+        ("1 == 1.0", "true"),
+    ]);
+}
+
+#[test]
+fn wraps_integer_overflow() {
+    check_results(&[
+        ("9223372036854775807 + 1", "-9223372036854775808"),
+        ("9223372036854775808", "9.2233720368548e+18"),
+    ]);
+}
+
+#[test]
+fn normalizes_float_keys() {
+    check_results(&[
+        (
+            "(function() local t = {} t[1.0] = \"a\" return t[1], #t end)()",
+            "a\t1",
+        ),
+        ("string.format(\"%d\", 3.0)", "3"),
+    ]);
+}
@@ -0,0 +1,94 @@
+#![cfg(feature = "cli")]
+
+mod common;
+
+use common::{check_errors, check_results, luir, luir_with_env, output};
+
+#[test]
+fn formats_dates() {
+    check_results(&[
+        (
+            r#"os.date("!%Y-%m-%d %H:%M:%S", 86400 + 3661)"#,
+            "1970-01-02 01:01:01",
+        ),
+        (
+            r#"os.date("!%A %B %j %p %y %%", 0)"#,
+            "Thursday January 001 AM 70 %",
+        ),
+        (
+            r#"(function(t) return t.year, t.month, t.day, t.hour, t.isdst, t.yday, t.wday end)(os.date("!*t", 0))"#,
+            "1970\t1\t1\t0\tfalse\t1\t5",
+        ),
+    ]);
+}
+
+#[test]
+fn normalizes_date_tables() {
+    let source = r#"
+        local noon = {year = 2020, month = 1, day = 1, hour = 12}
+        print(os.time(noon) == os.time({year = 2019, month = 13, day = 1, hour = 12}))
+        local date = {year = 2021, month = 2, day = 31, hour = 12}
+        os.time(date)
+        print(date.month, date.day, date.yday)
+        print(os.difftime(10, 4), math.type(os.clock()), math.type(os.time()))
+    "#;
+
+    assert_eq!(output(source), "true\n3\t3\t62\n6.0\tfloat\tinteger");
+}
+
+#[test]
+fn manages_files() {
+    let source = r#"
+        local name = os.tmpname()
+        io.open(name, "w"):close()
+        print(os.rename(name, name .. ".renamed"), os.remove(name .. ".renamed"))
+        print(os.remove(name .. ".renamed") == nil)
+    "#;
+
+    assert_eq!(output(source), "true\ttrue\ntrue");
+}
+
+#[test]
+fn reads_the_environment() {
+    let run = luir_with_env(
+        &[
+            "-e",
+            "print(os.getenv(\"LUIR_TEST_VAR\"), os.getenv(\"LUIR_MISSING_VAR\"))",
+        ],
+        "",
+        &[("LUIR_TEST_VAR", "value")],
+    );
+
+    assert_eq!(run.stdout, "value\tnil");
+}
+
+#[test]
+#[cfg(unix)]
+fn executes_commands() {
+    assert_eq!(
+        output("print(os.execute(\"exit 3\"))\nprint(os.execute())"),
+        "nil\texit\t3\ntrue"
+    );
+}
+
+#[test]
+fn exits_with_status() {
+    let run = luir(&["-e", "os.exit(5)", "-e", "print(\"not reached\")"], "");
+
+    assert_eq!((run.status, run.stdout.as_str()), (5, ""));
+    assert_eq!(luir(&["-e", "os.exit(false)"], "").status, 1);
+}
+
+#[test]
+fn reports_invalid_arguments() {
+    check_errors(&[
+        (
+            r#"os.date, "%Ez""#,
+            "bad argument #1 to 'date' (invalid conversion specifier '%Ez')",
+        ),
+        (
+            "os.time, {year = 2020}",
+            "field 'month' missing in date table",
+        ),
+    ]);
+}
@@ -0,0 +1,80 @@
+#![cfg(feature = "cli")]
+
+mod common;
+
+use common::{check_errors, check_results, check_results_after};
+
+/// Defines `hex`, which shows the bytes of a string in hexadecimal.
+const HEX: &str = r#"
+    function hex(s)
+        return (s:gsub(".", function(c) return string.format("%02x", c:byte()) end))
+    end
+"#;
+
+#[test]
+fn packs_byte_exact_layouts() {
+    check_results_after(
+        HEX,
+        &[
+            (r#"hex(string.pack("<i4", 1))"#, "01000000"),
+            (r#"hex(string.pack(">i4", 1))"#, "00000001"),
+            (r#"hex(string.pack("<h", -2))"#, "feff"),
+            (r#"hex(string.pack(">I3", 0x010203))"#, "010203"),
+            (r#"hex(string.pack("<j", -1))"#, "ffffffffffffffff"),
+            (r#"hex(string.pack("!<i1i4", 1, 2))"#, "0100000002000000"),
+            (r#"hex(string.pack("<i1Xi4i4", 1, 2))"#, "0102000000"),
+            (r#"hex(string.pack("<d", 1.5))"#, "000000000000f83f"),
+            (r#"hex(string.pack(">f", -2))"#, "c0000000"),
+            (r#"hex(string.pack("z", "ab"))"#, "616200"),
+            (r#"hex(string.pack("<s1", "ab"))"#, "026162"),
+            (r#"hex(string.pack("<s2", "ab"))"#, "02006162"),
+            (r#"hex(string.pack("c4", "ab"))"#, "61620000"),
+        ],
+    );
+}
+
+#[test]
+fn unpacks_and_measures_layouts() {
+    check_results(&[
+        (r#"string.unpack("<i4", "\1\0\0\0")"#, "1\t5"),
+        (r#"string.unpack("<i3", "\255\255\255")"#, "-1\t4"),
+        (
+            r#"string.unpack(">I2 z s1", "\1\2ab\0\3xyz")"#,
+            "258\tab\txyz\t10",
+        ),
+        (r#"string.unpack("<d", string.pack("<d", 1.5))"#, "1.5\t9"),
+        (r#"string.packsize("!8<i1d")"#, "16"),
+        (r#"string.packsize("<i3i7")"#, "10"),
+        (r#"string.packsize("!4 b h i")"#, "8"),
+    ]);
+}
+
+#[test]
+fn reports_invalid_formats_and_data() {
+    check_errors(&[
+        (
+            r#"string.pack, "i17", 1"#,
+            "integral size (17) out of limits [1,16]",
+        ),
+        (
+            r#"string.pack, "<i1", 200"#,
+            "bad argument #2 to 'pack' (integer overflow)",
+        ),
+        (
+            r#"string.packsize, "s""#,
+            "bad argument #1 to 'packsize' (variable-length format)",
+        ),
+        (
+            r#"string.unpack, "<i4", "\1\0""#,
+            "bad argument #2 to 'unpack' (data string too short)",
+        ),
+        (
+            r#"string.unpack, "<i9", "\1\0\0\0\0\0\0\0\1""#,
+            "9-byte integer does not fit into Lua Integer",
+        ),
+        (
+            r#"string.pack, "!3 i4", 1"#,
+            "bad argument #1 to 'pack' (format asks for alignment not power of 2)",
+        ),
+    ]);
+}
@@ -0,0 +1,88 @@
+#![cfg(feature = "cli")]
+
+mod common;
+
+use common::{check_errors, check_results, output};
+
+#[test]
+fn matches_like_lua() {
+    check_results(&[
+        (r#"string.find("hello world", "o w")"#, "5\t7"),
+        (r#"string.find("a+b", "+", 1, true)"#, "2\t2"),
+        (
+            r#"string.find("abc", "^b"), string.find("abc", "c$")"#,
+            "nil\t3\t3",
+        ),
+        (
+            r#"string.match("key = value", "(%w+)%s*=%s*(%w+)")"#,
+            "key\tvalue",
+        ),
+        (
+            r#"string.match("x = 0x1F;", "%x+", 6), string.match("a1!", "%p")"#,
+            "1F\t!",
+        ),
+        (r#"string.match("[abc]", "[%]b-c]+")"#, "bc]"),
+        (r#"string.match("hello", "()ll()")"#, "3\t5"),
+        (r#"string.match("f(a(b)c)d", "%b()")"#, "(a(b)c)"),
+        (
+            r#"string.match("THE (quick) fox", "%f[%a]%a+%f[%A]", 5)"#,
+            "quick",
+        ),
+        (r#"string.match("abcabc", "(a)(b)c%1%2")"#, "a\tb"),
+        (
+            r#"string.match("aaa", "a-b"), string.match("aaab", "a-b")"#,
+            "nil\taaab",
+        ),
+        (r#"string.match("  trim  ", "^%s*(.-)%s*$")"#, "trim"),
+        (r#"string.gsub("hello world", "o", "0")"#, "hell0 w0rld\t2"),
+        (r#"string.gsub("abc", "%w", "%0%0")"#, "aabbcc\t3"),
+        (r#"string.gsub("abc", "", "-")"#, "-a-b-c-\t4"),
+        (
+            r#"string.gsub("hello world", "%w+", "<%0>", 1)"#,
+            "<hello> world\t1",
+        ),
+    ]);
+}
+
+#[test]
+fn iterates_matches() {
+    let source = r#"
+        for key, value in string.gmatch("a=1, b=2, c=3", "(%w+)=(%w+)") do
+            print(key, value)
+        end
+    "#;
+
+    assert_eq!(output(source), "a\t1\nb\t2\nc\t3");
+}
+
+#[test]
+fn reports_malformed_patterns() {
+    check_errors(&[
+        (
+            r#"string.find, "a", "%""#,
+            "malformed pattern (ends with '%')",
+        ),
+        (
+            r#"string.find, "a", "[a""#,
+            "malformed pattern (missing ']')",
+        ),
+        (
+            r#"string.match, "a", "[%a""#,
+            "malformed pattern (missing ']')",
+        ),
+        (r#"string.find, "a", "(a""#, "unfinished capture"),
+        (r#"string.find, "a", ".)""#, "invalid pattern capture"),
+        (
+            r#"string.find, "a", "%b""#,
+            "malformed pattern (missing arguments to '%b')",
+        ),
+        (
+            r#"string.find, "a", "%fa""#,
+            "missing '[' after '%f' in pattern",
+        ),
+        (
+            r#"string.gsub, "abc", "%w", "%""#,
+            "invalid use of '%' in replacement string",
+        ),
+    ]);
+}
@@ -0,0 +1,94 @@
+#![cfg(feature = "cli")]
+
+mod common;
+
+use common::{luir, Script};
+
+/// What the REPL printed for `input`, without the version banner.
+fn repl(args: &[&str], input: &str) -> (String, String) {
+    let mut args = args.to_vec();
+    args.insert(0, "-i");
+    let run = luir(&args, input);
+    assert_eq!(run.status, 0, "{}", run.stderr);
+
+    let (banner, output) = run.stdout.split_once('\n').unwrap_or((&run.stdout, ""));
+    assert!(banner.starts_with("luir "), "{}", banner);
+    (output.to_string(), run.stderr)
+}
+
+#[test]
+fn prints_values_of_expressions() {
+    let (output, _) = repl(&[], "x = 1 + 1\n=x\nx * 10\nprint(\"statement\")\n");
+
+    assert_eq!(output, "2\n20\nstatement");
+}
+
+#[test]
+fn continues_incomplete_input() {
+    let (output, _) = repl(&[], "function f()\nreturn 5\nend\nprint(f(\n))\n");
+
+    assert_eq!(output, "5");
+}
+
+#[test]
+fn reports_errors_and_keeps_running() {
+    let (output, stderr) = repl(
+        &["--color", "never"],
+        "error(\"e\", 0)\nx = nil + 1\nprint(\"alive\")\n",
+    );
+
+    assert_eq!(output, "alive");
+    assert!(stderr.contains("luir: e\n"), "{}", stderr);
+    assert!(
+        stderr.contains("luir: stdin:1: attempt to perform arithmetic on a nil value"),
+        "{}",
+        stderr
+    );
+}
+
+#[test]
+fn runs_the_script_first() {
+    let script = Script::new("greeting = \"hello\"\n");
+    let (output, _) = repl(&[script.path()], "greeting\n");
+
+    assert_eq!(output, "hello");
+}
+
+#[test]
+fn runs_several_statements_on_one_line() {
+    let (output, _) = repl(&[], "print(1) print(2)\nx = 1 y = 2\n=x + y\n");
+
+    assert_eq!(output, "1\n2\n3");
+}
+
+#[test]
+fn reports_errors_against_the_typed_input() {
+    let (_, stderr) = repl(&["--color", "never"], "error(\"e\")\n=nil + 1\n");
+
+    assert!(
+        stderr.contains("luir: stdin:1: e\n --> stdin:1:1\n  |\n1 | error(\"e\")\n  | ^\n"),
+        "{}",
+        stderr
+    );
+    assert!(
+        stderr.contains(" --> stdin:1:2\n  |\n1 | =nil + 1\n  |  ^\n"),
+        "{}",
+        stderr
+    );
+}
+
+#[test]
+fn reports_syntax_errors_raised_by_the_input() {
+    let script = Script::new("return (\n");
+    let (output, stderr) = repl(
+        &["--color", "never"],
+        &format!("dofile({:?})\nprint(\"next\")\n", script.path()),
+    );
+
+    assert_eq!(output, "next");
+    assert!(
+        stderr.contains("unexpected symbol near <eof>"),
+        "{}",
+        stderr
+    );
+}
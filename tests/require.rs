@@ -0,0 +1,131 @@
+#![cfg(feature = "cli")]
+
+mod common;
+
+use std::path::PathBuf;
+
+use common::{luir_with_env, output};
+
+/// A temporary directory of Lua modules, removed when dropped.
+struct Modules {
+    path: PathBuf,
+}
+
+impl Modules {
+    fn new(name: &str, files: &[(&str, &str)]) -> Self {
+        let path = std::env::temp_dir().join(format!("luir-test-{}-{}", std::process::id(), name));
+        for (file, source) in files {
+            let file = path.join(file);
+            std::fs::create_dir_all(file.parent().unwrap()).unwrap();
+            std::fs::write(file, source).unwrap();
+        }
+        Modules { path }
+    }
+
+    /// A `package.path` finding `?.lua` and `?/init.lua` files in the directory.
+    fn search_path(&self) -> String {
+        let directory = self.path.to_str().unwrap();
+        format!("{0}/?.lua;{0}/?/init.lua", directory)
+    }
+}
+
+impl Drop for Modules {
+    fn drop(&mut self) {
+        let _ = std::fs::remove_dir_all(&self.path);
+    }
+}
+
+fn modules(name: &str) -> Modules {
+    Modules::new(
+        name,
+        &[
+            (
+                "mymod.lua",
+                "count = (count or 0) + 1\nreturn {name = ..., file = select(2, ...)}\n",
+            ),
+            ("pkg/init.lua", "return \"init \" .. ..."),
+        ],
+    )
+}
+
+#[test]
+fn loads_and_caches_modules() {
+    let modules = modules("caches");
+    let source = format!(
+        "package.path = {:?}\n\
+         local m = require(\"mymod\")\n\
+         print(m.name, m.file:sub(-9), count)\n\
+         print(require(\"mymod\") == m, count, package.loaded.mymod == m)\n\
+         print((require(\"pkg\")))\n",
+        modules.search_path()
+    );
+
+    assert_eq!(
+        output(source),
+        "mymod\tmymod.lua\t1\ntrue\t1\ttrue\ninit pkg"
+    );
+}
+
+#[test]
+fn uses_preload_and_custom_searchers() {
+    let source = r#"
+        package.path = ""
+        package.preload.virtual = function(name, extra) return "preloaded " .. name .. " " .. extra end
+        print(require("virtual"))
+        print(select(2, pcall(require, "missing")):match("module 'missing' not found") ~= nil)
+        table.insert(package.searchers, function(name)
+            return function() return "custom " .. name end, "data"
+        end)
+        print(require("anything"))
+    "#;
+
+    assert_eq!(
+        output(source),
+        "preloaded virtual :preload:\t:preload:\ntrue\ncustom anything\tdata"
+    );
+}
+
+#[test]
+fn searches_paths() {
+    let source = r#"print(package.searchpath("missing", "/a/?.lua;/b/?.x"))"#;
+
+    assert_eq!(
+        output(source),
+        "nil\tno file '/a/missing.lua'\n\tno file '/b/missing.x'"
+    );
+}
+
+#[test]
+fn reads_search_paths_from_the_environment() {
+    let modules = modules("environment");
+    let path = modules.search_path();
+    let args = [
+        "-l",
+        "mymod",
+        "-l",
+        "alias=pkg",
+        "-e",
+        "print(mymod.name, alias)",
+    ];
+
+    let run = luir_with_env(&args, "", &[("LUA_PATH", &path)]);
+    assert_eq!(run.stdout, "mymod\tinit pkg");
+
+    let versioned = [
+        ("LUA_PATH_5_4", path.as_str()),
+        ("LUA_PATH", "/ignored/?.lua"),
+    ];
+    let run = luir_with_env(&args, "", &versioned);
+    assert_eq!(run.stdout, "mymod\tinit pkg");
+
+    let default = format!("{};;", path);
+    let run = luir_with_env(
+        &[
+            "-e",
+            "print(package.path:find(\"./?.lua\", 1, true) ~= nil)",
+        ],
+        "",
+        &[("LUA_PATH", &default)],
+    );
+    assert_eq!(run.stdout, "true");
+}
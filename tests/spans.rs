@@ -0,0 +1,34 @@
+#![cfg(feature = "cli")]
+
+mod common;
+
+use common::{luir, run, Script};
+
+#[test]
+fn errors_point_at_their_line() {
+    let syntax = run("local a = 1\nlocal b = = 2\n");
+    assert!(syntax.stderr.contains(".lua:2:"), "{}", syntax.stderr);
+
+    let runtime = run("local a = 1\n\n  local b = a +\n   nil\n");
+    assert!(
+        runtime
+            .stderr
+            .contains(".lua:3: attempt to perform arithmetic on a nil value"),
+        "{}",
+        runtime.stderr
+    );
+}
+
+#[test]
+fn syntax_tree_nodes_have_spans() {
+    let script = Script::new("x = 1 + 2\n");
+    let run = luir(&["--print-ast", script.path()], "");
+
+    // The literal `1` starts at byte 4, the fifth column of the first line
+    let ast: String = run.stdout.split_whitespace().collect();
+    assert!(
+        ast.contains("start:Position{offset:4,line:1,column:5,}"),
+        "{}",
+        run.stdout
+    );
+}
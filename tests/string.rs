@@ -0,0 +1,82 @@
+#![cfg(feature = "cli")]
+
+mod common;
+
+use common::{check_errors, check_results};
+
+#[test]
+fn slices_and_transforms_strings() {
+    check_results(&[
+        (r#"string.len("hello"), string.len("\0a")"#, "5\t2"),
+        (
+            r#"string.sub("hello", 2, -2), string.sub("hello", -3)"#,
+            "ell\tllo",
+        ),
+        (
+            r#"string.sub("hello", 0), string.sub("hello", 10) == """#,
+            "hello\ttrue",
+        ),
+        (r#"string.upper("abC"), string.lower("AbC")"#, "ABC\tabc"),
+        (
+            r#"string.rep("ab", 3, ","), string.rep("x", -1) == """#,
+            "ab,ab,ab\ttrue",
+        ),
+        (r#"string.reverse("abc")"#, "cba"),
+        (r#"string.byte("ABC", 1, -1)"#, "65\t66\t67"),
+        (r#"string.byte("\xff\x80", 1, 2)"#, "255\t128"),
+        (r#"string.char(72, 105)"#, "Hi"),
+        (r#"string.len(123), string.rep(5, 2)"#, "3\t55"),
+    ]);
+}
+
+#[test]
+fn formats_values() {
+    check_results(&[
+        (
+            r#"string.format("%5d|%-5s|%.3f|%x|%X|%o", 42, "ab", 3.14159, 255, 255, 8)"#,
+            "   42|ab   |3.142|ff|FF|10",
+        ),
+        (
+            r#"string.format("%e|%g|%g", 12345.678, 0.1, 1 / 3)"#,
+            "1.234568e+04|0.1|0.333333",
+        ),
+        (
+            r#"string.format("%10.4s|%c%c|%i|%%", "abcdefg", 76, 117, 3)"#,
+            "      abcd|Lu|3|%",
+        ),
+        (r#"string.format("%5.1f", 2.25)"#, "  2.2"),
+        (r#"string.format("%a", 1)"#, "0x1p+0"),
+        (r#"string.format("%s %s", 1, true)"#, "1 true"),
+        (r#"string.format("%q", "a\"b\0") == '"a\\"b\\0"'"#, "true"),
+        (r#"string.format("%q", 1/0)"#, "1e9999"),
+    ]);
+}
+
+#[test]
+fn searches_strings() {
+    check_results(&[
+        (r#"string.find("hello", "l")"#, "3\t3"),
+        (r#"string.find("hello", "xyz")"#, "nil"),
+        (r#"string.find("a.b", ".", 1, true)"#, "2\t2"),
+        (r#"string.match("key=val", "(%w+)=(%w+)")"#, "key\tval"),
+        (r#"string.gsub("hello", "l", "L")"#, "heLLo\t2"),
+    ]);
+}
+
+#[test]
+fn checks_arguments() {
+    check_errors(&[
+        (
+            "string.rep",
+            "bad argument #1 to 'rep' (string expected, got no value)",
+        ),
+        (
+            "string.sub, {}",
+            "bad argument #1 to 'sub' (string expected, got table)",
+        ),
+        (
+            r#"string.format, "%d", 1.5"#,
+            "bad argument #2 to 'format' (number has no integer representation)",
+        ),
+    ]);
+}
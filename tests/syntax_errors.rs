@@ -0,0 +1,80 @@
+#![cfg(feature = "cli")]
+
+mod common;
+
+use common::{output, run};
+
+#[test]
+fn reports_every_syntax_error() {
+    let run = run("local a = = 1\nprint(a)\nlocal b = (2\nif x then\nend\nf(1,,2)\n");
+    let messages = [
+        ".lua:1: unexpected symbol near '='",
+        ".lua:4: ')' expected near 'if'",
+        ".lua:6: unexpected symbol near ','",
+    ];
+
+    assert_eq!(run.stdout, "");
+    assert_ne!(run.status, 0);
+    for message in messages {
+        assert!(run.stderr.contains(message), "{}", run.stderr);
+    }
+    assert_eq!(run.stderr.matches("luir:").count(), messages.len());
+}
+
+#[test]
+fn reports_errors_in_argument_lists() {
+    let run = run("print(1, local)\nprint(2)\n");
+
+    assert_eq!(run.stdout, "");
+    assert!(
+        run.stderr
+            .contains(".lua:1: unexpected symbol near 'local'"),
+        "{}",
+        run.stderr
+    );
+}
+
+#[test]
+fn requires_return_to_end_its_block() {
+    let main = run("print(1)\nreturn 1 print(2)\n");
+
+    assert_eq!(main.stdout, "");
+    assert!(
+        main.stderr
+            .contains(".lua:2: '<eof>' expected near 'print'"),
+        "{}",
+        main.stderr
+    );
+
+    let nested = run("local function f() return 1; print(2) end\n");
+
+    assert!(
+        nested
+            .stderr
+            .contains(".lua:1: 'end' expected near 'print'"),
+        "{}",
+        nested.stderr
+    );
+}
+
+#[test]
+fn limits_nesting_depth() {
+    let source = r#"
+        print(load("return " .. string.rep("(", 50000) .. "1" .. string.rep(")", 50000)))
+        print(load("return " .. string.rep("{", 50000) .. string.rep("}", 50000)))
+        print(load(string.rep("do ", 50000) .. string.rep("end ", 50000)))
+        print(load("return " .. string.rep("(", 100) .. "1" .. string.rep(")", 100))())
+    "#;
+    let output = output(source);
+    let lines: Vec<&str> = output.lines().collect();
+
+    for (line, near) in lines[..3].iter().zip(["(", "{", "do"]) {
+        let message = format!(
+            ":1: too many C levels (limit is 200) in main function near '{}'",
+            near
+        );
+        assert!(line.starts_with("nil\t[string"), "{}", output);
+        assert!(line.ends_with(&message), "{}", output);
+    }
+    assert_eq!(lines[3], "1");
+}
@@ -0,0 +1,98 @@
+#![cfg(feature = "cli")]
+
+mod common;
+
+use common::{check_errors, check_results, output};
+
+#[test]
+fn inserts_and_removes() {
+    let source = r#"
+        local t = {1, 2, 3}
+        table.insert(t, 4)
+        table.insert(t, 1, 0)
+        print(table.concat(t, ","))
+        print(table.remove(t), table.remove(t, 1), table.concat(t, ","))
+    "#;
+
+    assert_eq!(output(source), "0,1,2,3,4\n4\t0\t1,2,3");
+}
+
+#[test]
+fn concatenates_packs_and_unpacks() {
+    check_results(&[
+        (r#"table.concat({1, 2, 3, 4}, "-", 2, 3)"#, "2-3"),
+        (r#"table.concat({}, "x") == """#, "true"),
+        ("table.unpack({1, 2, 3}, 2)", "2\t3"),
+        ("table.pack(1, nil, 3).n, table.pack(1, nil, 3)[3]", "3\t3"),
+    ]);
+}
+
+#[test]
+fn moves_elements() {
+    let source = r#"
+        local overlapping = {1, 2, 3, 4, 5}
+        table.move(overlapping, 1, 3, 3)
+        print(table.concat(overlapping, ","))
+        print(table.concat(table.move({1, 2}, 1, 2, 2, {9}), ","))
+    "#;
+
+    assert_eq!(output(source), "1,2,1,2,3\n9,1,2");
+}
+
+#[test]
+fn sorts_with_comparators() {
+    let source = r#"
+        local t = {5, 2, 8, 1}
+        table.sort(t)
+        print(table.concat(t, ","))
+        table.sort(t, function(a, b) return a > b end)
+        print(table.concat(t, ","))
+        local sort = coroutine.wrap(function()
+            local u = {3, 1, 2}
+            table.sort(u, function(a, b)
+                coroutine.yield()
+                return a < b
+            end)
+            return table.concat(u, ",")
+        end)
+        local result
+        repeat result = sort() until result
+        print(result)
+    "#;
+
+    assert_eq!(output(source), "1,2,5,8\n8,5,2,1\n1,2,3");
+}
+
+#[test]
+fn respects_metamethods() {
+    let source = r#"
+        local store = {}
+        local proxy = setmetatable({}, {
+            __newindex = function(t, k, v) store[k] = v end,
+            __index = store,
+            __len = function() return 2 end,
+        })
+        table.insert(proxy, "x")
+        print(store[3], proxy[3], #proxy)
+    "#;
+
+    assert_eq!(output(source), "x\tx\t2");
+}
+
+#[test]
+fn reports_invalid_arguments() {
+    check_errors(&[
+        (
+            "table.sort, {3, 1, 2, 5, 4, 7, 6, 9, 8, 10, 12, 11}, function() return true end",
+            "invalid order function for sorting",
+        ),
+        (
+            "table.insert, {}, 5, 1",
+            "bad argument #2 to 'insert' (position out of bounds)",
+        ),
+        (
+            "table.concat, {1, {}, 3}",
+            "invalid value (at index 2) in table for 'concat'",
+        ),
+    ]);
+}
@@ -0,0 +1,68 @@
+#![cfg(feature = "cli")]
+
+mod common;
+
+use common::{check_errors, check_results, output};
+
+#[test]
+fn encodes_and_decodes_code_points() {
+    check_results(&[
+        ("utf8.char(72, 228, 8364, 128512)", "Hä€😀"),
+        (r#"utf8.codepoint("häx", 1, -1)"#, "104\t228\t120"),
+        (
+            r#"utf8.charpattern == "[\0-\x7F\xC2-\xFD][\x80-\xBF]*""#,
+            "true",
+        ),
+    ]);
+}
+
+#[test]
+fn measures_and_indexes_strings() {
+    check_results(&[
+        (r#"utf8.len("häx")"#, "3"),
+        (r#"utf8.len("\xffabc")"#, "nil\t1"),
+        (r#"utf8.len("häx", 3)"#, "nil\t3"),
+        (r#"utf8.offset("häx", 3), utf8.offset("häx", -1)"#, "4\t4"),
+    ]);
+}
+
+#[test]
+fn accepts_extended_sequences_in_lax_mode() {
+    check_results(&[
+        (r#"utf8.len("\u{7FFFFFFF}")"#, "nil\t1"),
+        (r#"utf8.len("\u{7FFFFFFF}", 1, -1, true)"#, "1"),
+        (r#"utf8.codepoint("\u{D800}", 1, 1, true)"#, "55296"),
+    ]);
+}
+
+#[test]
+fn iterates_code_points() {
+    let source = r#"
+        for position, code in utf8.codes("aé!") do
+            print(position, code)
+        end
+        print(pcall(function()
+            for _ in utf8.codes("a\xffb") do end
+        end))
+    "#;
+    let output = output(source);
+
+    assert!(
+        output.starts_with("1\t97\n2\t233\n4\t33\nfalse\t"),
+        "{}",
+        output
+    );
+    assert!(output.ends_with(":6: invalid UTF-8 code"), "{}", output);
+}
+
+#[test]
+fn rejects_invalid_input() {
+    check_errors(&[
+        (r#"utf8.codepoint, "\xff""#, "invalid UTF-8 code"),
+        (r#"utf8.codepoint, "\u{D800}""#, "invalid UTF-8 code"),
+        (
+            "utf8.char, -1",
+            "bad argument #1 to 'char' (value out of range)",
+        ),
+    ]);
+}